@@ -0,0 +1,14 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use rft::app::frame::{parse, AppTlvParseResult};
+use rft::common::Cursor;
+
+// Chunk data is parsed into app tlvs until it is exhausted or an error occurs.
+fuzz_target!(|data: &[u8]| {
+    let mut cursor = Cursor::new(data);
+    while (cursor.position() as usize) < data.len() {
+        if let AppTlvParseResult::Err(_) = parse(&mut cursor) {
+            break;
+        }
+    }
+});
@@ -0,0 +1,19 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use rft::common::{Cursor, WireFormat};
+use rft::transport::frame::{ChecksumAlgorithm, MessageFrame};
+
+// Reading a datagram must never panic or hang, whatever the peer sends.
+fuzz_target!(|data: &[u8]| {
+    for &checksum in &[
+        ChecksumAlgorithm::Fnv1a32,
+        ChecksumAlgorithm::Crc32c,
+        ChecksumAlgorithm::XxHash32,
+    ] {
+        let mut frame = MessageFrame {
+            checksum,
+            ..MessageFrame::default()
+        };
+        let _ = frame.read(&mut Cursor::new(data));
+    }
+});
@@ -0,0 +1,233 @@
+//! `#[derive(WireFormat)]` for the messages of rft, see `rft::common::WireFormat`.
+//!
+//! Structs are encoded field by field, in order of declaration, with the
+//! `WireFormat` of the field's type. Attributes:
+//!
+//! - `#[wire(tlv = "TlvType::ObjectAck")]` on a struct wraps it into a TLV
+//!   with the given type code and a LEB128 length.
+//! - `#[wire(leb128)]` on an integer field encodes it as unsigned LEB128
+//!   instead of its fixed width.
+//! - `#[wire(with = "module")]` on a field encodes it with `module::write`
+//!   and `module::read` instead, for fields with a custom encoding.
+//!
+//! Fieldless enums are encoded as a single byte, their discriminant.
+//! Unknown codes are an error, or the `Default` with `#[wire(or_default)]`.
+//!
+//! The generated code refers to `crate::common`, so it only works inside rft.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Lit, Meta, NestedMeta};
+
+#[proc_macro_derive(WireFormat, attributes(wire))]
+pub fn derive_wire_format(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let result = match &input.data {
+        Data::Struct(data) => derive_struct(&input, &data.fields),
+        Data::Enum(data) => derive_enum(&input, data),
+        Data::Union(_) => Err(syn::Error::new_spanned(
+            &input.ident,
+            "WireFormat can't be derived for unions",
+        )),
+    };
+    match result {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/////////////////////////////////
+// Attributes
+
+#[derive(Default)]
+struct WireAttributes {
+    tlv: Option<syn::Path>,
+    leb128: bool,
+    with: Option<syn::Path>,
+    or_default: bool,
+}
+
+fn parse_attributes(attrs: &[syn::Attribute]) -> syn::Result<WireAttributes> {
+    let mut result = WireAttributes::default();
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("wire")) {
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            meta => return Err(syn::Error::new_spanned(meta, "expected #[wire(...)]")),
+        };
+        for nested in list.nested {
+            match nested {
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("leb128") => {
+                    result.leb128 = true
+                }
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("or_default") => {
+                    result.or_default = true
+                }
+                NestedMeta::Meta(Meta::NameValue(nv))
+                    if nv.path.is_ident("tlv") || nv.path.is_ident("with") =>
+                {
+                    let path = match &nv.lit {
+                        Lit::Str(s) => s.parse::<syn::Path>()?,
+                        lit => return Err(syn::Error::new_spanned(lit, "expected a string")),
+                    };
+                    if nv.path.is_ident("tlv") {
+                        result.tlv = Some(path);
+                    } else {
+                        result.with = Some(path);
+                    }
+                }
+                nested => return Err(syn::Error::new_spanned(nested, "unknown wire attribute")),
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// The lifetime of `WireFormat<'a>`: the one of the type, or a new one.
+fn impl_lifetime(input: &DeriveInput) -> syn::Result<(TokenStream2, TokenStream2)> {
+    let lifetimes: Vec<_> = input.generics.lifetimes().collect();
+    if input.generics.type_params().next().is_some() || lifetimes.len() > 1 {
+        return Err(syn::Error::new_spanned(
+            &input.generics,
+            "WireFormat can only be derived for types with at most one lifetime",
+        ));
+    }
+    Ok(match lifetimes.first() {
+        Some(def) => {
+            let lifetime = &def.lifetime;
+            (quote!(#lifetime), quote!(<#lifetime>))
+        }
+        None => (quote!('a), quote!()),
+    })
+}
+
+/////////////////////////////////
+// Structs
+
+fn derive_struct(input: &DeriveInput, fields: &Fields) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let (lifetime, type_generics) = impl_lifetime(input)?;
+    let attributes = parse_attributes(&input.attrs)?;
+
+    let mut writes = Vec::new();
+    let mut reads = Vec::new();
+    for (i, field) in fields.iter().enumerate() {
+        let member = match &field.ident {
+            Some(ident) => quote!(#ident),
+            None => {
+                let index = syn::Index::from(i);
+                quote!(#index)
+            }
+        };
+        let field_attributes = parse_attributes(&field.attrs)?;
+        if let Some(with) = &field_attributes.with {
+            writes.push(quote! { #with::write(&self.#member, buf); });
+            reads.push(quote! {
+                #with::read(&mut self.#member, cursor)?;
+            });
+        } else if field_attributes.leb128 {
+            writes.push(quote! { crate::common::wire::write_leb128(buf, self.#member as u64); });
+            reads.push(quote! {
+                self.#member = crate::common::wire::read_leb128(cursor)? as _;
+            });
+        } else {
+            writes.push(quote! { crate::common::WireFormat::write(&self.#member, buf); });
+            reads.push(quote! {
+                crate::common::WireFormat::read(&mut self.#member, cursor)?;
+            });
+        }
+    }
+
+    let (write, read) = match &attributes.tlv {
+        Some(tlv) => (
+            quote! {
+                crate::common::wire::write_tlv(buf, #tlv as u8, |buf| { #(#writes)* });
+            },
+            quote! {
+                crate::common::wire::read_tlv(cursor, #tlv as u8, |cursor| {
+                    #(#reads)*
+                    Ok(())
+                })
+            },
+        ),
+        None => (
+            quote! { #(#writes)* },
+            quote! {
+                #(#reads)*
+                Ok(())
+            },
+        ),
+    };
+    Ok(quote! {
+        impl<#lifetime> crate::common::WireFormat<#lifetime> for #name #type_generics {
+            fn write(&self, buf: &mut Vec<u8>) {
+                #write
+            }
+
+            fn read(
+                &mut self,
+                cursor: &mut crate::common::Cursor<#lifetime>,
+            ) -> Result<(), crate::error::RftError> {
+                #read
+            }
+        }
+    })
+}
+
+/////////////////////////////////
+// Enums
+
+fn derive_enum(input: &DeriveInput, data: &syn::DataEnum) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let (lifetime, type_generics) = impl_lifetime(input)?;
+    let attributes = parse_attributes(&input.attrs)?;
+
+    let mut codes = Vec::new();
+    let mut variants = Vec::new();
+    for variant in &data.variants {
+        if !matches!(variant.fields, Fields::Unit) {
+            return Err(syn::Error::new_spanned(
+                variant,
+                "WireFormat can only be derived for enums without fields",
+            ));
+        }
+        let ident = &variant.ident;
+        codes.push(format_ident!("{}_CODE", ident.to_string().to_uppercase()));
+        variants.push(quote!(#name::#ident));
+    }
+    let unknown = if attributes.or_default {
+        quote!(Default::default())
+    } else {
+        let msg = format!("Unknown {} code {{}}.", name);
+        quote! {
+            return Err(crate::error::RftError::decode(
+                cursor.position() - 1,
+                format!(#msg, code).as_str(),
+            ))
+        }
+    };
+    Ok(quote! {
+        impl<#lifetime> crate::common::WireFormat<#lifetime> for #name #type_generics {
+            fn write(&self, buf: &mut Vec<u8>) {
+                buf.push(match self {
+                    #(#variants => #variants as u8,)*
+                });
+            }
+
+            fn read(
+                &mut self,
+                cursor: &mut crate::common::Cursor<#lifetime>,
+            ) -> Result<(), crate::error::RftError> {
+                #(const #codes: u8 = #variants as u8;)*
+                let code = crate::common::wire::read_byte(cursor)?;
+                *self = match code {
+                    #(#codes => #variants,)*
+                    _ => #unknown,
+                };
+                Ok(())
+            }
+        }
+    })
+}
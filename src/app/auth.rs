@@ -0,0 +1,147 @@
+//! Users of the server and the paths they may access, from a credentials file.
+//!
+//! The file has one user per line: the user name, the secret and the paths
+//! the user may fetch or list, separated by whitespace. Paths are relative to
+//! the working directory of the server and include everything below them,
+//! `*` grants access to all files. Empty lines and lines starting with `#`
+//! are ignored:
+//!
+//! ```text
+//! # user  secret   paths
+//! alice   s3cr3t   data reports/2020
+//! admin   hunter2  *
+//! ```
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Debug)]
+struct User {
+    secret: Vec<u8>,
+    /// Normalized paths the user may access, see `normalize()`.
+    paths: Vec<PathBuf>,
+    /// Wether the user may access all files.
+    all: bool,
+}
+
+/// Users which may authenticate, see the module documentation.
+#[derive(Debug)]
+pub struct UserDb {
+    users: HashMap<String, User>,
+}
+
+impl UserDb {
+    /// Read a credentials file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, &'static str> {
+        match fs::read_to_string(path) {
+            Ok(s) => UserDb::parse(&s),
+            Err(_) => Err("Couldn't read credentials file."),
+        }
+    }
+
+    /// Parse the content of a credentials file.
+    pub fn parse(s: &str) -> Result<Self, &'static str> {
+        let mut users = HashMap::new();
+        for line in s.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let name = words.next().unwrap();
+            let secret = match words.next() {
+                Some(secret) => secret.as_bytes().to_vec(),
+                None => return Err("Missing secret in credentials file."),
+            };
+            let mut user = User {
+                secret,
+                paths: Vec::new(),
+                all: false,
+            };
+            for path in words {
+                match (path, normalize(path)) {
+                    ("*", _) => user.all = true,
+                    (_, Some(path)) => user.paths.push(path),
+                    (_, None) => return Err("Invalid path in credentials file."),
+                }
+            }
+            if users.insert(name.to_string(), user).is_some() {
+                return Err("Duplicate user in credentials file.");
+            }
+        }
+        Ok(UserDb { users })
+    }
+
+    /// Secrets of all users by name, for `Config::users`.
+    pub fn secrets(&self) -> HashMap<String, Vec<u8>> {
+        self.users
+            .iter()
+            .map(|(name, user)| (name.clone(), user.secret.clone()))
+            .collect()
+    }
+
+    /// Wether `user` may fetch or list `path`. Unauthenticated clients
+    /// (`user` is None) may not access anything.
+    pub fn may_access(&self, user: Option<&str>, path: &str) -> bool {
+        let user = match user.and_then(|name| self.users.get(name)) {
+            Some(user) => user,
+            None => return false,
+        };
+        if user.all {
+            return true;
+        }
+        match normalize(path) {
+            Some(path) => user.paths.iter().any(|allowed| path.starts_with(allowed)),
+            None => false,
+        }
+    }
+}
+
+/// The path relative to the working directory without `.` components,
+/// or None if it is absolute or contains `..`.
+fn normalize(path: &str) -> Option<PathBuf> {
+    let mut result = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(name) => result.push(name),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(result)
+}
+
+mod test {
+    #[test]
+    fn may_access() {
+        use super::UserDb;
+
+        let users = UserDb::parse(
+            "# user secret paths\n\
+             alice s3cr3t data ./reports/2020\n\
+             \n\
+             admin hunter2 *\n\
+             nobody secret\n",
+        )
+        .unwrap();
+        assert_eq!(users.secrets()["alice"], b"s3cr3t");
+
+        let alice = Some("alice");
+        assert!(users.may_access(alice, "data/a.txt"));
+        assert!(users.may_access(alice, "./data/sub/b.txt"));
+        assert!(users.may_access(alice, "reports/2020/q1.pdf"));
+        assert!(!users.may_access(alice, "reports/2021/q1.pdf"));
+        assert!(!users.may_access(alice, "database.txt"));
+        assert!(!users.may_access(alice, "data/../secret.txt"));
+        assert!(!users.may_access(alice, "/etc/passwd"));
+        assert!(users.may_access(Some("admin"), "/etc/passwd"));
+        assert!(!users.may_access(Some("nobody"), "data/a.txt"));
+        assert!(!users.may_access(Some("mallory"), "data/a.txt"));
+        assert!(!users.may_access(None, "data/a.txt"));
+
+        assert!(UserDb::parse("alice\n").is_err());
+        assert!(UserDb::parse("alice s3cr3t ../data\n").is_err());
+        assert!(UserDb::parse("alice a\nalice b\n").is_err());
+    }
+}
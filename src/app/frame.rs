@@ -1,24 +1,29 @@
 use crate::common::*;
-use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use crate::error::RftError;
+use byteorder::{ReadBytesExt, WriteBytesExt};
 use leb128;
-use num::{FromPrimitive, ToPrimitive};
-use std::io::{Seek, SeekFrom, Write};
+use num::FromPrimitive;
 
 /////////////////////////////////
 // Basic Types/Functions
 
 type FileId = u64;
 
-pub enum AppTlvParseResult {
-    Ok(AppTlv),
-    Err(ReadError),
+pub enum AppTlvParseResult<'a> {
+    Ok(AppTlv<'a>),
+    /// An unknown TLV with this type code was skipped, see `TLV_CRITICAL_BIT`.
+    Skipped(u8),
+    Err(RftError),
 }
 
-pub fn parse(cursor: &mut Cursor) -> AppTlvParseResult {
-    let app_tlv_type = cursor.read_u8().unwrap();
-    cursor
-        .seek(SeekFrom::Current(-1))
-        .expect("Seek back (2) failed."); // TLV will read type again.
+pub fn parse<'a>(cursor: &mut Cursor<'a>) -> AppTlvParseResult<'a> {
+    let app_tlv_type = match cursor.read_u8() {
+        Ok(x) => x,
+        Err(e) => {
+            return AppTlvParseResult::Err(RftError::decode(cursor.position(), &e.to_string()))
+        }
+    };
+    cursor.set_position(cursor.position() - 1); // TLV will read type again.
     let mut app_tlv = match FromPrimitive::from_u8(app_tlv_type) {
         Some(AppTlvType::FileRequest) => AppTlv::FileRequest(FileRequest::default()),
         Some(AppTlvType::FileResume) => AppTlv::FileResume(FileResume::default()),
@@ -28,14 +33,15 @@ pub fn parse(cursor: &mut Cursor) -> AppTlvParseResult {
         Some(AppTlvType::FileListRequest) => AppTlv::FileListRequest(FileListRequest::default()),
         Some(AppTlvType::FileListResponse) => AppTlv::FileListResponse(FileListResponse::default()),
         None => {
-            return AppTlvParseResult::Err(ReadError::new(
-                format!("Unknown application message type code {}!", app_tlv_type).as_str(),
-            ))
+            return match skip_unknown_tlv(cursor) {
+                Ok(app_tlv_type) => AppTlvParseResult::Skipped(app_tlv_type),
+                Err(e) => AppTlvParseResult::Err(e),
+            }
         }
     };
     match app_tlv.read(cursor) {
-        ReadResult::Ok => AppTlvParseResult::Ok(app_tlv),
-        ReadResult::Err(e) => AppTlvParseResult::Err(e),
+        Ok(()) => AppTlvParseResult::Ok(app_tlv),
+        Err(e) => AppTlvParseResult::Err(e.in_tlv(app_tlv_type)),
     }
 }
 
@@ -67,31 +73,33 @@ pub enum AppObjectFieldType {
 /////////////////////////////////
 // AppTlv
 
+/// Type codes of application TLVs. Requests and the parts of files have the
+/// `TLV_CRITICAL_BIT` set, as skipping them would silently lose data.
 #[derive(FromPrimitive, ToPrimitive, Debug, PartialEq)]
 #[repr(u8)]
 pub enum AppTlvType {
-    FileRequest = 0x20,
-    FileResume = 0x21,
-    FileMetadata = 0x22,
-    FileContent = 0x23,
+    FileRequest = 0x20 | TLV_CRITICAL_BIT,
+    FileResume = 0x21 | TLV_CRITICAL_BIT,
+    FileMetadata = 0x22 | TLV_CRITICAL_BIT,
+    FileContent = 0x23 | TLV_CRITICAL_BIT,
     ApplicationError = 0x24,
-    FileListRequest = 0x25,
-    FileListResponse = 0x26,
+    FileListRequest = 0x25 | TLV_CRITICAL_BIT,
+    FileListResponse = 0x26 | TLV_CRITICAL_BIT,
 }
 
 #[derive(Debug, PartialEq)]
-pub enum AppTlv {
+pub enum AppTlv<'a> {
     FileRequest(FileRequest),
     FileResume(FileResume),
     FileMetadata(FileMetadata),
-    FileContent(FileContent),
+    FileContent(FileContent<'a>),
     ApplicationError(ApplicationError),
     FileListRequest(FileListRequest),
     FileListResponse(FileListResponse),
 }
 
-impl WireFormat for AppTlv {
-    fn write(&self, cursor: &mut Cursor) {
+impl<'a> WireFormat<'a> for AppTlv<'a> {
+    fn write(&self, cursor: &mut Vec<u8>) {
         match self {
             AppTlv::FileRequest(x) => x.write(cursor),
             AppTlv::FileResume(x) => x.write(cursor),
@@ -103,7 +111,7 @@ impl WireFormat for AppTlv {
         }
     }
 
-    fn read(&mut self, cursor: &mut Cursor) -> ReadResult {
+    fn read(&mut self, cursor: &mut Cursor<'a>) -> Result<(), RftError> {
         return match self {
             AppTlv::FileRequest(x) => x.read(cursor),
             AppTlv::FileResume(x) => x.read(cursor),
@@ -119,71 +127,29 @@ impl WireFormat for AppTlv {
 /////////////////////////////////
 // FileRequest
 
-#[derive(Default, Debug, PartialEq)]
+#[derive(Default, Debug, PartialEq, WireFormat)]
+#[wire(tlv = "AppTlvType::FileRequest")]
 pub struct FileRequest {
     pub file_paths: Vec<String>,
 }
 
-impl WireFormat for FileRequest {
-    fn write(&self, cursor: &mut Cursor) {
-        write_tlv!(cursor, AppTlvType::FileRequest, {
-            write_u8!(cursor, self.file_paths.len() as u8);
-            for path in &self.file_paths {
-                write_str!(cursor, path);
-            }
-        })
-    }
-
-    fn read(&mut self, cursor: &mut Cursor) -> ReadResult {
-        read_tlv!(cursor, AppTlvType::FileRequest, {
-            let mut num_paths = read_u8!(cursor);
-            while num_paths > 0 {
-                self.file_paths.push(read_str!(cursor));
-                num_paths -= 1;
-            }
-        });
-        ReadResult::Ok
-    }
-}
-
 /////////////////////////////////
 // FileResume
 
-#[derive(Default, Debug, PartialEq)]
+#[derive(Default, Debug, PartialEq, WireFormat)]
+#[wire(tlv = "AppTlvType::FileResume")]
 pub struct FileResume {
     pub file_ids_and_chunk_ids: Vec<(FileId, i64)>,
 }
 
-impl WireFormat for FileResume {
-    fn write(&self, cursor: &mut Cursor) {
-        write_tlv!(cursor, AppTlvType::FileRequest, {
-            write_u8!(cursor, self.file_ids_and_chunk_ids.len() as u8);
-            for pair in &self.file_ids_and_chunk_ids {
-                write_u64!(cursor, pair.0);
-                write_i128!(cursor, pair.1);
-            }
-        });
-    }
-
-    fn read(&mut self, cursor: &mut Cursor) -> ReadResult {
-        read_tlv!(cursor, AppTlvType::FileRequest, {
-            let mut num_pairs = read_u8!(cursor);
-            while num_pairs > 0 {
-                self.file_ids_and_chunk_ids
-                    .push((read_u64!(cursor), read_i128!(cursor)));
-                num_pairs -= 1;
-            }
-        });
-        ReadResult::Ok
-    }
-}
-
 /////////////////////////////////
 // FileMetadata
 
-#[derive(FromPrimitive, ToPrimitive, Debug, PartialEq)]
+#[derive(FromPrimitive, ToPrimitive, Debug, PartialEq, WireFormat, Default)]
 #[repr(u8)]
+#[wire(or_default)]
 pub enum MetadataEntryType {
+    #[default]
     None = 0x00,
     FileName = 0x01,
     FilePath = 0x02,
@@ -199,101 +165,50 @@ pub enum MetadataEntryType {
     SHA3 = 0x15,
 }
 
-impl Default for MetadataEntryType {
-    fn default() -> MetadataEntryType {
-        MetadataEntryType::None
-    }
-}
-
-#[derive(Default, Debug, PartialEq)]
+#[derive(Default, Debug, PartialEq, WireFormat)]
 pub struct MetadataEntry {
     pub code: MetadataEntryType,
     pub content: Vec<u8>,
 }
 
-#[derive(Default, Debug, PartialEq)]
+#[derive(Default, Debug, PartialEq, WireFormat)]
+#[wire(tlv = "AppTlvType::FileMetadata")]
 pub struct FileMetadata {
     pub metadata_entries: Vec<MetadataEntry>,
 }
 
-impl WireFormat for FileMetadata {
-    fn write(&self, cursor: &mut Cursor) {
-        write_tlv!(cursor, AppTlvType::FileMetadata, {
-            write_u8!(cursor, self.metadata_entries.len() as u8);
-            for entry in &self.metadata_entries {
-                write_u8!(cursor, entry.code.to_u8().unwrap());
-                write_u8!(cursor, entry.content.len() as u8);
-                if cursor.write(&entry.content).is_err() {
-                    todo!("Implement error handling.");
-                }
-            }
-        });
-    }
-
-    fn read(&mut self, cursor: &mut Cursor) -> ReadResult {
-        read_tlv!(cursor, AppTlvType::FileMetadata, {
-            let mut num_entries = read_u8!(cursor);
-            while num_entries > 0 {
-                self.metadata_entries.push(MetadataEntry {
-                    code: match FromPrimitive::from_u8(read_u8!(cursor)) {
-                        Some(x) => x,
-                        None => MetadataEntryType::None,
-                    },
-                    content: {
-                        let mut buf = Vec::new();
-                        let mut num_bytes = read_u8!(cursor);
-                        while num_bytes > 0 {
-                            buf.push(read_u8!(cursor));
-                            num_bytes -= 1;
-                        }
-                        buf
-                    },
-                });
-                num_entries -= 1;
-            }
-        });
-        ReadResult::Ok
-    }
-}
-
 /////////////////////////////////
 // FileContent
 
 #[derive(Default, Debug, PartialEq)]
-pub struct FileContent {
-    pub content: Vec<u8>,
+pub struct FileContent<'a> {
+    /// Borrowed from the chunk the content was read from.
+    pub content: &'a [u8],
 }
 
-impl WireFormat for FileContent {
-    fn write(&self, cursor: &mut Cursor) {
+impl<'a> WireFormat<'a> for FileContent<'a> {
+    fn write(&self, cursor: &mut Vec<u8>) {
         write_tlv!(cursor, AppTlvType::FileContent, {
-            if cursor.write(&self.content).is_err() {
-                todo!("Implement error handling.");
-            }
+            cursor.extend_from_slice(&self.content);
         });
     }
 
-    fn read(&mut self, cursor: &mut Cursor) -> ReadResult {
-        read_tlv!(cursor, AppTlvType::FileContent, {
-            cursor
-                .seek(SeekFrom::Current(-2))
-                .expect("Seek back (3) failed.");
-            let mut num_bytes = read_u16!(cursor);
-            while num_bytes > 0 {
-                self.content.push(read_u8!(cursor));
-                num_bytes -= 1;
-            }
+    fn read(&mut self, cursor: &mut Cursor<'a>) -> Result<(), RftError> {
+        read_tlv!(cursor, AppTlvType::FileContent, num_bytes, {
+            self.content = read_slice!(cursor, num_bytes);
         });
-        ReadResult::Ok
+        Ok(())
     }
 }
 
 /////////////////////////////////
 // ApplicationError
 
-#[derive(FromPrimitive, ToPrimitive, Debug, PartialEq)]
+#[derive(FromPrimitive, ToPrimitive, Debug, PartialEq, Eq, Hash, Clone, WireFormat, Default)]
 #[repr(u8)]
+#[wire(or_default)]
 pub enum AppErrorCode {
+    #[default]
     None = 0x00,
     FileNotFound = 0x01,
     FileChanged = 0x02,
@@ -303,37 +218,30 @@ pub enum AppErrorCode {
     InvalidFileResumeRequest = 0x06,
     InvalidDepthForList = 0x07,
     UnknownFormatCode = 0x08,
+    /// The client did not authenticate or may not access the paths.
+    AccessDenied = 0x09,
+    /// The server does not support the request, e.g. listing files.
+    UnsupportedRequest = 0x0a,
 }
 
-impl Default for AppErrorCode {
-    fn default() -> AppErrorCode {
-        AppErrorCode::None
-    }
-}
-
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Default)]
 pub enum AppErrorData {
+    #[default]
     Empty,
     Paths(Vec<String>),
     FormatCodes(Vec<u8>),
 }
 
-impl Default for AppErrorData {
-    fn default() -> AppErrorData {
-        AppErrorData::Empty
-    }
-}
-
 #[derive(Default, Debug, PartialEq)]
 pub struct ApplicationError {
     pub error_code: AppErrorCode,
     pub error_data: AppErrorData,
 }
 
-impl WireFormat for ApplicationError {
-    fn write(&self, cursor: &mut Cursor) {
+impl<'a> WireFormat<'a> for ApplicationError {
+    fn write(&self, cursor: &mut Vec<u8>) {
         write_tlv!(cursor, AppTlvType::ApplicationError, {
-            write_u8!(cursor, self.error_code.to_u8().unwrap());
+            self.error_code.write(cursor);
             match (&self.error_code, &self.error_data) {
                 (AppErrorCode::InvalidDepthForList, AppErrorData::Paths(paths)) => {
                     assert!(paths.len() > 0);
@@ -342,14 +250,15 @@ impl WireFormat for ApplicationError {
                 (AppErrorCode::FileNotFound, AppErrorData::Paths(paths))
                 | (AppErrorCode::FileChanged, AppErrorData::Paths(paths))
                 | (AppErrorCode::FileHashError, AppErrorData::Paths(paths))
-                | (AppErrorCode::FileAbort, AppErrorData::Paths(paths)) => {
-                    write_u8!(cursor, paths.len() as u8);
+                | (AppErrorCode::FileAbort, AppErrorData::Paths(paths))
+                | (AppErrorCode::AccessDenied, AppErrorData::Paths(paths)) => {
+                    write_len!(cursor, paths.len());
                     for path in paths {
                         write_str!(cursor, path);
                     }
                 }
                 (AppErrorCode::UnknownFormatCode, AppErrorData::FormatCodes(codes)) => {
-                    write_u8!(cursor, codes.len() as u8);
+                    write_len!(cursor, codes.len());
                     for code in codes {
                         write_u8!(cursor, *code);
                     }
@@ -359,19 +268,17 @@ impl WireFormat for ApplicationError {
         });
     }
 
-    fn read(&mut self, cursor: &mut Cursor) -> ReadResult {
+    fn read(&mut self, cursor: &mut Cursor<'a>) -> Result<(), RftError> {
         read_tlv!(cursor, AppTlvType::ApplicationError, {
-            self.error_code = match FromPrimitive::from_u8(read_u8!(cursor)) {
-                Some(x) => x,
-                None => AppErrorCode::None,
-            };
+            self.error_code.read(cursor)?;
             self.error_data = match self.error_code {
                 AppErrorCode::InvalidDepthForList => AppErrorData::Paths(vec![read_str!(cursor)]),
                 AppErrorCode::FileNotFound
                 | AppErrorCode::FileChanged
                 | AppErrorCode::FileHashError
-                | AppErrorCode::FileAbort => AppErrorData::Paths({
-                    let mut num_paths = read_u8!(cursor);
+                | AppErrorCode::FileAbort
+                | AppErrorCode::AccessDenied => AppErrorData::Paths({
+                    let mut num_paths = read_len!(cursor);
                     let mut paths = Vec::new();
                     while num_paths > 0 {
                         paths.push(read_str!(cursor));
@@ -380,7 +287,7 @@ impl WireFormat for ApplicationError {
                     paths
                 }),
                 AppErrorCode::UnknownFormatCode => AppErrorData::FormatCodes({
-                    let mut num_codes = read_u8!(cursor);
+                    let mut num_codes = read_len!(cursor);
                     let mut codes = Vec::new();
                     while num_codes > 0 {
                         codes.push(read_u8!(cursor));
@@ -391,14 +298,15 @@ impl WireFormat for ApplicationError {
                 _ => AppErrorData::Empty,
             }
         });
-        ReadResult::Ok
+        Ok(())
     }
 }
 
 /////////////////////////////////
 // FileListRequest
 
-#[derive(Default, Debug, PartialEq)]
+#[derive(Default, Debug, PartialEq, WireFormat)]
+#[wire(tlv = "AppTlvType::FileListRequest")]
 pub struct FileListRequest {
     pub path: String,
     pub level_of_recursion: u8,
@@ -407,47 +315,25 @@ pub struct FileListRequest {
 
 pub const DEFAULT_FORMAT_CODE: u8 = 0x01;
 
-impl WireFormat for FileListRequest {
-    fn write(&self, cursor: &mut Cursor) {
-        write_tlv!(cursor, AppTlvType::FileListRequest, {
-            write_str!(cursor, self.path);
-            write_u8!(cursor, self.level_of_recursion);
-            write_u8!(cursor, self.format_code);
-        });
-    }
-
-    fn read(&mut self, cursor: &mut Cursor) -> ReadResult {
-        read_tlv!(cursor, AppTlvType::FileListRequest, {
-            self.path = read_str!(cursor);
-            self.level_of_recursion = read_u8!(cursor);
-            self.format_code = read_u8!(cursor);
-        });
-        ReadResult::Ok
-    }
-}
-
 /////////////////////////////////
 // FileListResponse
 
-#[derive(Default, Debug, PartialEq)]
+#[derive(Default, Debug, PartialEq, WireFormat)]
+#[wire(tlv = "AppTlvType::FileListResponse")]
 pub struct FileListResponse {
     pub file_list_entries: Vec<FileListEntry>,
 }
 
-#[derive(FromPrimitive, ToPrimitive, Debug, PartialEq)]
+#[derive(FromPrimitive, ToPrimitive, Debug, PartialEq, WireFormat, Default)]
 #[repr(u8)]
+#[wire(or_default)]
 pub enum FileListEntryType {
+    #[default]
     File = 0x00,
     Dir = 0x01,
 }
 
-impl Default for FileListEntryType {
-    fn default() -> Self {
-        FileListEntryType::File
-    }
-}
-
-#[derive(Default, Debug, PartialEq)]
+#[derive(Default, Debug, PartialEq, WireFormat)]
 pub struct FileListEntry {
     pub entry_type: FileListEntryType,
     pub parent: FileId,
@@ -455,35 +341,54 @@ pub struct FileListEntry {
     pub id: FileId,
 }
 
-impl WireFormat for FileListResponse {
-    fn write(&self, cursor: &mut Cursor) {
-        write_tlv!(cursor, AppTlvType::FileListResponse, {
-            write_u8!(cursor, self.file_list_entries.len() as u8);
-            for entry in &self.file_list_entries {
-                write_u8!(cursor, entry.entry_type.to_u8().unwrap());
-                write_u64!(cursor, entry.parent);
-                write_str!(cursor, entry.name);
-                write_u64!(cursor, entry.id);
-            }
+mod test {
+    #[test]
+    fn long_lists() {
+        use super::*;
+
+        let request = AppTlv::FileRequest(FileRequest {
+            file_paths: (0..300).map(|i| format!("dir/file-{}.txt", i)).collect(),
+        });
+        let resume = AppTlv::FileResume(FileResume {
+            file_ids_and_chunk_ids: vec![(1, 10), (2, -1)],
+        });
+        let metadata = AppTlv::FileMetadata(FileMetadata {
+            metadata_entries: vec![MetadataEntry {
+                code: MetadataEntryType::FilePath,
+                content: vec![b'a'; 1000],
+            }],
         });
+        for tlv in &[request, resume, metadata] {
+            let mut buf = Vec::new();
+            tlv.write(&mut buf);
+            match parse(&mut Cursor::new(&buf[..])) {
+                AppTlvParseResult::Ok(parsed) => assert_eq!(&parsed, tlv),
+                _ => panic!("Failed to parse {:?}", tlv),
+            }
+        }
     }
 
-    fn read(&mut self, cursor: &mut Cursor) -> ReadResult {
-        read_tlv!(cursor, AppTlvType::FileListResponse, {
-            let mut num_entries = read_u8!(cursor);
-            while num_entries > 0 {
-                self.file_list_entries.push(FileListEntry {
-                    entry_type: match FromPrimitive::from_u8(read_u8!(cursor)) {
-                        Some(x) => x,
-                        None => FileListEntryType::File,
-                    },
-                    parent: read_u64!(cursor),
-                    name: read_str!(cursor),
-                    id: read_u64!(cursor),
-                });
-                num_entries -= 1;
+    #[test]
+    fn fuzz_corpus() {
+        use super::*;
+        use crate::transport::test::fuzz_corpus;
+
+        // like fuzz/fuzz_targets/app_frame.rs
+        let corpus = fuzz_corpus("app_frame");
+        assert!(!corpus.is_empty());
+        for data in &corpus {
+            for len in 0..=data.len() {
+                let mut cursor = Cursor::new(&data[..len]);
+                while (cursor.position() as usize) < len {
+                    if let AppTlvParseResult::Err(_) = parse(&mut cursor) {
+                        break;
+                    }
+                }
             }
-        });
-        ReadResult::Ok
+        }
+        assert!(matches!(
+            parse(&mut Cursor::new(&[][..])),
+            AppTlvParseResult::Err(_)
+        ));
     }
 }
@@ -1,19 +1,21 @@
 use super::state::*;
+use crate::error::RftError;
 use crate::options::Options;
 use crate::transport::client;
-use log::info;
-use std::cell::RefCell;
+use crate::transport::config::Config;
+use crate::transport::connection::{Connection, Event};
+use log::{error, info, warn};
 use std::net::SocketAddr;
-use std::rc::Rc;
 use std::{thread, time};
 
 /// Run client for file retrieval.
-pub fn get(opt: Options, socket_addr: SocketAddr, files: Vec<&str>) -> std::result::Result<(), ()> {
+/// The given socket addresses are tried in order until a connection is established.
+pub fn get(opt: Options, socket_addrs: Vec<SocketAddr>, files: Vec<&str>) -> Result<(), RftError> {
     //////////////////////////////
     // Announce client startup.
     let mut s = format!(
-        "File client started with {} for socket address {} and file(s) '",
-        opt, socket_addr
+        "File client started with {} for socket address(es) {:?} and file(s) '",
+        opt, socket_addrs
     );
     for f in &files {
         s = format!("{} {}", s, f);
@@ -21,22 +23,20 @@ pub fn get(opt: Options, socket_addr: SocketAddr, files: Vec<&str>) -> std::resu
     info!("{} '", s);
 
     //////////////////////////////
-    // Create shared state machine.
-    let state_machine = Rc::new(RefCell::new(StateMachine::new()));
-    let state_machine_for_timeout_handler = Rc::clone(&state_machine);
-
-    //////////////////////////////
-    // Create event handlers to be used as callbacks.
-    // Right now they have no purpose for the server, so just ignore.
-    let incoming_object_handler = Box::new(move |recv_job| {}); // unused, does nothing
-    let timeout_handler = Box::new(move || {
-        state_machine_for_timeout_handler.borrow_mut().finished();
-    });
+    // Create state machine.
+    let mut state_machine = StateMachine::new();
 
     //////////////////////////////
     // Create connection.
-    let mut connection = client::connect(socket_addr, incoming_object_handler, timeout_handler);
-    state_machine.borrow_mut().connected(); // does not have an impact on anything
+    let config = opt.transport_config();
+    let mut connection = match connect_any(&socket_addrs, &config) {
+        Ok(connection) => connection,
+        Err(e) => {
+            error!("Could not connect to any of {:?}", socket_addrs);
+            return Err(e);
+        }
+    };
+    state_machine.connected(); // does not have an impact on anything
 
     //////////////////////////////
     // Request files by adding the corresponding send jobs to our client state(machine)
@@ -46,41 +46,102 @@ pub fn get(opt: Options, socket_addr: SocketAddr, files: Vec<&str>) -> std::resu
     }
     //////////////////////////////
     // Add initial jobs
-    connection
-        .send_jobs
-        .push(state_machine.borrow_mut().push_file_request_job(files_copy));
+    match state_machine.push_file_request_job(files_copy) {
+        Ok(job) => connection.send_jobs.push(job),
+        Err(e) => {
+            error!("Could not request the files: {}", e);
+            return Err(e);
+        }
+    }
 
     //////////////////////////////
     // Wait until reception is done.
-    // State of statemachine is used since it may also be changed via callbacks
-    while !state_machine.borrow().is_finished() {
-        connection.receive_and_send();
+    // State of statemachine is changed by events of the connection
+    while !state_machine.is_finished() {
+        step(&mut connection, &mut state_machine);
+        thread::sleep(time::Duration::from_millis(1));
+    }
+    print_summary(&connection, &state_machine);
 
-        ///////////////////////////////////
-        // Register new receive jobs from connection to state
-        for recv_job in &mut connection.recv_jobs {
-            if !state_machine.borrow().has_recv_job(recv_job) {
-                StateMachine::push_recv_job(&state_machine, recv_job);
-            }
+    match state_machine.take_error() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// One iteration of the client loop, without waiting.
+pub fn step(connection: &mut Connection, state_machine: &mut StateMachine) {
+    connection.receive_and_send();
+
+    ///////////////////////////////////
+    // Process events of the connection
+    while let Some(event) = connection.poll_event() {
+        state_machine.handle_event(event);
+    }
+
+    ///////////////////////////////////
+    // Push out new send jobs
+    while let Some(job) = state_machine.pop_new_send_job() {
+        connection.send_jobs.push(job);
+    }
+
+    ///////////////////////////////////
+    // Stop loop if files were received
+    if state_machine.all_files_received() {
+        state_machine.finished();
+    }
+}
+
+/// Print the transfer statistics of the connection and of each received file.
+fn print_summary(connection: &Connection, state_machine: &StateMachine) {
+    eprintln!("Transfer: {}", connection.stats());
+    for job in &connection.recv_jobs {
+        let object_id = job.object.object_id;
+        if let Some(name) = state_machine.received_file_name(object_id) {
+            eprintln!("  {}: {}", name, job.stats());
         }
+    }
+}
 
-        ///////////////////////////////////
-        // Push out new send jobs
+/// Try to connect to the given addresses in order.
+/// Returns the first connection which is established before it times out,
+/// or the error of the last address.
+fn connect_any(socket_addrs: &[SocketAddr], config: &Config) -> Result<Connection, RftError> {
+    let mut error = RftError::Timeout;
+    for socket_addr in socket_addrs {
+        info!("Connecting to {}", socket_addr);
+        let mut connection = match client::connect_with(*socket_addr, config.clone()) {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("Could not connect to {}: {}", socket_addr, e);
+                error = e;
+                continue;
+            }
+        };
         loop {
-            match state_machine.borrow_mut().pop_new_send_job() {
-                Some(job) => connection.send_jobs.push(job),
-                None => break,
+            connection.receive_and_send();
+            if connection.is_established() {
+                return Ok(connection);
             }
+            match connection.poll_event() {
+                Some(Event::Timeout) => {
+                    error = RftError::Timeout;
+                    break;
+                }
+                Some(Event::HandshakeFailed(code)) => {
+                    warn!("Handshake with {} failed: {:?}", socket_addr, code);
+                    error = RftError::PeerError(code);
+                    break;
+                }
+                Some(event) => warn!(
+                    "Ignoring event before connection is established: {:?}",
+                    event
+                ),
+                None => {}
+            }
+            thread::sleep(time::Duration::from_millis(1));
         }
-
-        ///////////////////////////////////
-        // Stop loop if files were received
-        if state_machine.borrow().all_files_received() {
-            state_machine.borrow_mut().finished();
-        }
-
-        thread::sleep(time::Duration::from_millis(1));
+        warn!("Could not connect to {}", socket_addr);
     }
-
-    Ok(())
+    Err(error)
 }
@@ -1,13 +1,13 @@
+use crate::error::RftError;
 use crate::options::Options;
 use log::*;
 use std::net::SocketAddr;
 
 /// Run client for file list retrieval.
-pub fn ls(opt: Options, socket_addr: SocketAddr, directory: &str) -> std::result::Result<(), ()> {
+pub fn ls(opt: Options, socket_addrs: Vec<SocketAddr>, directory: &str) -> Result<(), RftError> {
     info!(
-        "File list client startet with {} for socket address {} and directory {}",
-        opt, socket_addr, directory
+        "File list client startet with {} for socket address(es) {:?} and directory {}",
+        opt, socket_addrs, directory
     );
-    unimplemented!("This feature isn't available.");
-    //Ok(())
+    Err(RftError::Unsupported(String::from("Listing files")))
 }
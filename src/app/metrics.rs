@@ -0,0 +1,260 @@
+//! Metrics of the server in the Prometheus text format, served over HTTP
+//! at `/metrics` if the server is started with `--metrics`.
+//!
+//! The counters add up all connections of the server, the closed ones and the
+//! ones which are being served.
+
+use super::frame::AppErrorCode;
+use super::state::StateMachine;
+use crate::transport::connection::Connection;
+use crate::transport::stats::Stats;
+use num::ToPrimitive;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Longest HTTP request head which is read.
+const MAX_REQUEST_LEN: usize = 8192;
+/// Time a client of the metrics endpoint may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Counters and gauges of the server, updated by its main loop.
+#[derive(Default)]
+pub struct Metrics {
+    /// Connections accepted so far.
+    sessions: u64,
+    active_sessions: u64,
+    /// Summed up numbers of the closed connections.
+    closed: Totals,
+    /// Summed up numbers of the connections which are being served.
+    open: Totals,
+}
+
+/// Numbers of some connections and of their state machines.
+#[derive(Clone, Default)]
+struct Totals {
+    stats: Stats,
+    files_served: u64,
+    errors: HashMap<AppErrorCode, u64>,
+}
+
+impl Totals {
+    fn add(&mut self, other: &Totals) {
+        self.stats.add(&other.stats);
+        self.files_served += other.files_served;
+        for (code, n) in &other.errors {
+            *self.errors.entry(code.clone()).or_default() += n;
+        }
+    }
+
+    fn of(connection: &Connection, state_machine: &StateMachine) -> Self {
+        Totals {
+            stats: connection.stats(),
+            files_served: state_machine.files_sent(),
+            errors: state_machine.errors_sent().clone(),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn session_started(&mut self) {
+        self.sessions += 1;
+    }
+
+    /// Takes the current numbers of the connections which are being served
+    /// and of their state machines.
+    pub fn update<'a, I>(&mut self, sessions: I)
+    where
+        I: IntoIterator<Item = (&'a Connection, &'a StateMachine)>,
+    {
+        self.active_sessions = 0;
+        self.open = Totals::default();
+        for (connection, state_machine) in sessions {
+            self.active_sessions += connection.is_established() as u64;
+            self.open.add(&Totals::of(connection, state_machine));
+        }
+    }
+
+    /// Adds the numbers of a connection which is closed to the closed ones.
+    /// It must not be passed to `update()` anymore.
+    pub fn session_closed(&mut self, connection: &Connection, state_machine: &StateMachine) {
+        self.closed.add(&Totals::of(connection, state_machine));
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut totals = self.closed.clone();
+        totals.add(&self.open);
+        let stats = &totals.stats;
+        let retransmission_ratio = if stats.packets_sent > 0 {
+            stats.retransmissions as f64 / stats.packets_sent as f64
+        } else {
+            0.0
+        };
+
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        };
+        metric(
+            "rft_server_sessions_total",
+            "counter",
+            "Connections accepted.",
+            self.sessions.to_string(),
+        );
+        metric(
+            "rft_server_active_sessions",
+            "gauge",
+            "Established connections.",
+            self.active_sessions.to_string(),
+        );
+        metric(
+            "rft_server_sent_bytes_total",
+            "counter",
+            "Bytes of all datagrams sent to clients.",
+            stats.bytes_sent.to_string(),
+        );
+        metric(
+            "rft_server_received_bytes_total",
+            "counter",
+            "Bytes of all datagrams received from clients.",
+            stats.bytes_received.to_string(),
+        );
+        metric(
+            "rft_server_sent_packets_total",
+            "counter",
+            "Datagrams sent to clients.",
+            stats.packets_sent.to_string(),
+        );
+        metric(
+            "rft_server_retransmissions_total",
+            "counter",
+            "Handshake and authentication frames sent again because the client did not answer.",
+            stats.retransmissions.to_string(),
+        );
+        metric(
+            "rft_server_retransmission_ratio",
+            "gauge",
+            "Retransmissions per datagram sent.",
+            retransmission_ratio.to_string(),
+        );
+        metric(
+            "rft_server_checksum_failures_total",
+            "counter",
+            "Received frames dropped because of their checksum.",
+            stats.checksum_failures.to_string(),
+        );
+        metric(
+            "rft_server_files_served_total",
+            "counter",
+            "Files fully transmitted to clients.",
+            totals.files_served.to_string(),
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP rft_server_errors_total Errors reported to clients, by code."
+        );
+        let _ = writeln!(out, "# TYPE rft_server_errors_total counter");
+        let mut errors: Vec<_> = totals.errors.iter().collect();
+        errors.sort_by_key(|(code, _)| code.to_u8());
+        for (code, n) in errors {
+            let _ = writeln!(out, "rft_server_errors_total{{code=\"{:?}\"}} {}", code, n);
+        }
+        out
+    }
+}
+
+/// Serves `metrics` at `http://<bind>/metrics` from a background thread.
+/// Returns the bound address, or fails if the listener can't be bound.
+pub fn serve(bind: SocketAddr, metrics: Arc<Mutex<Metrics>>) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(bind)?;
+    let addr = listener.local_addr()?;
+    log::info!("Serving metrics at http://{}/metrics", addr);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| respond(stream, &metrics));
+            if let Err(e) = result {
+                log::warn!("Failed to serve metrics: {}", e);
+            }
+        }
+    });
+    Ok(addr)
+}
+
+/// Answers a single HTTP request and closes the connection.
+fn respond(mut stream: TcpStream, metrics: &Mutex<Metrics>) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_LEN {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request_line = request.split(|c| *c == b'\n').next().unwrap_or_default();
+    let mut parts = request_line.split(|c| *c == b' ');
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some(b"GET"), Some(b"/metrics")) => ("200 OK", metrics.lock().unwrap().render()),
+        _ => ("404 Not Found", String::from("Not found, try /metrics\n")),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+mod test {
+    #[test]
+    fn render() {
+        use super::*;
+
+        let mut metrics = Metrics::default();
+        metrics.session_started();
+        metrics.session_started();
+        metrics.closed.stats.packets_sent = 8;
+        metrics.closed.stats.retransmissions = 2;
+        metrics.closed.errors.insert(AppErrorCode::AccessDenied, 1);
+        metrics.closed.errors.insert(AppErrorCode::FileNotFound, 3);
+        metrics.open.errors.insert(AppErrorCode::FileNotFound, 1);
+        let text = metrics.render();
+        assert!(text
+            .contains("# TYPE rft_server_sessions_total counter\nrft_server_sessions_total 2\n"));
+        assert!(text.contains("\nrft_server_active_sessions 0\n"));
+        assert!(text.contains("\nrft_server_retransmission_ratio 0.25\n"));
+        assert!(text.ends_with(
+            "rft_server_errors_total{code=\"FileNotFound\"} 4\n\
+             rft_server_errors_total{code=\"AccessDenied\"} 1\n"
+        ));
+    }
+
+    #[test]
+    fn serve() {
+        use super::*;
+
+        let metrics = Arc::new(Mutex::new(Metrics::default()));
+        let addr = super::serve("127.0.0.1:0".parse().unwrap(), metrics).unwrap();
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\n\r\n# HELP rft_server_sessions_total"));
+        assert!(get("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
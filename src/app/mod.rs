@@ -1,6 +1,8 @@
+pub mod auth;
 pub mod frame;
 pub mod get;
 pub mod ls;
+pub mod metrics;
 pub mod server;
 pub mod state;
 pub mod test;
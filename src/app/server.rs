@@ -1,80 +1,122 @@
+use super::metrics::{self, Metrics};
 use super::state::*;
+use crate::error::RftError;
 use crate::options::Options;
+use crate::transport::connection::Connection;
 use crate::transport::server::Listener;
 use log::*;
-use std::cell::RefCell;
 use std::env::current_dir;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::rc::Rc;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{thread, time};
 
-/// Run server on current working directory, using the given options and address for listening
-pub fn run(opt: Options, listen_addr: Ipv4Addr) -> std::result::Result<(), ()> {
+/// Interval in which the server prints the statistics of its connections.
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A client which is being served.
+struct Session {
+    connection: Connection,
+    state_machine: StateMachine,
+    next_stats: Instant,
+}
+
+/// Run server on current working directory, using the given options and address for listening.
+/// Serves any number of clients at once, until it is killed.
+pub fn run(opt: Options, listen_addr: IpAddr) -> Result<(), RftError> {
     //////////////////////////////
     // Announce server startup.
     info!(
         "File server started with {}, working directory {}",
         opt,
-        current_dir().unwrap().display()
+        current_dir()?.display()
     );
 
     //////////////////////////////
-    // Create shared state machine.
-    let state_machine = Rc::new(RefCell::new(StateMachine::new()));
+    // Serve metrics, if asked to
+    let metrics = Arc::new(Mutex::new(Metrics::default()));
+    if let Some(addr) = opt.metrics {
+        metrics::serve(addr, Arc::clone(&metrics))?;
+    }
 
     //////////////////////////////
     // Create listener (basically a UDO socket)
-    let mut server = Listener::new(SocketAddr::V4(SocketAddrV4::new(listen_addr, opt.port)));
+    let mut server = Listener::with_config(
+        SocketAddr::new(listen_addr, opt.port),
+        opt.transport_config(),
+    )?;
 
     //////////////////////////////
     // State changes may be triggered by received messages
-    while !state_machine.borrow().is_finished() {
+    let mut sessions: Vec<Session> = Vec::new();
+    loop {
         thread::sleep(time::Duration::from_millis(1));
 
         ///////////////////////////////////
-        // Create potential event handlers to be used as callbacks.
-        // Right now they have no purpose for the server, so just ignore.
-        let incoming_object_handler = Box::new(move |recv_job| {}); // unused, does nothing
-        let state_machine_for_timeout_handler = Rc::clone(&state_machine);
-        let timeout_handler = Box::new(move || {
-            state_machine_for_timeout_handler.borrow_mut().finished();
-        });
-
-        ///////////////////////////////////
-        // Listen for connection
+        // Listen for connections
         // Kind of busy waiting
-        let mut connection = match server.listen_once(incoming_object_handler, timeout_handler) {
-            Some(connection) => connection,
-            None => continue,
-        };
+        while let Some(connection) = server.listen_once() {
+            // each client gets its own state machine
+            let mut state_machine = StateMachine::new();
+            if let Some(users) = &opt.users {
+                state_machine.set_users(Arc::clone(users));
+            }
+            metrics.lock().unwrap().session_started();
+            sessions.push(Session {
+                connection,
+                state_machine,
+                next_stats: Instant::now() + STATS_INTERVAL,
+            });
+        }
 
         ///////////////////////////////////
-        // See outer loop.
-        while !state_machine.borrow().is_finished() {
-            ///////////////////////////////////
-            // progress send and receive jobs
-            connection.receive_and_send();
-
-            ///////////////////////////////////
-            // Register state for new receive job
-            for recv_job in &mut connection.recv_jobs {
-                if !state_machine.borrow().has_recv_job(recv_job) {
-                    StateMachine::push_recv_job(&state_machine, recv_job);
+        // Serve all clients, and forget the ones which are finished
+        let mut metrics = metrics.lock().unwrap();
+        for session in &mut sessions {
+            step(&mut session.connection, &mut session.state_machine);
+            if Instant::now() >= session.next_stats {
+                let connection = &session.connection;
+                eprintln!(
+                    "Connection to {}: {}",
+                    connection.peer(),
+                    connection.stats()
+                );
+                for job in &connection.send_jobs {
+                    eprintln!("  object #{}: {}", job.object_id(), job.stats());
                 }
+                session.next_stats += STATS_INTERVAL;
             }
-
-            ///////////////////////////////////
-            // Push out new send jobs
-            loop {
-                match state_machine.borrow_mut().pop_new_send_job() {
-                    Some(job) => connection.send_jobs.push(job),
-                    None => break,
-                }
+        }
+        sessions.retain(|session| {
+            if session.state_machine.is_finished() {
+                metrics.session_closed(&session.connection, &session.state_machine);
             }
+            !session.state_machine.is_finished()
+        });
+        metrics.update(
+            sessions
+                .iter()
+                .map(|session| (&session.connection, &session.state_machine)),
+        );
+    }
+}
 
-            thread::sleep(time::Duration::from_millis(1));
-        }
+/// One iteration of the server loop on an accepted connection, without waiting.
+pub fn step(connection: &mut Connection, state_machine: &mut StateMachine) {
+    ///////////////////////////////////
+    // progress send and receive jobs
+    connection.receive_and_send();
+
+    ///////////////////////////////////
+    // Process events of the connection
+    while let Some(event) = connection.poll_event() {
+        state_machine.handle_event(event);
     }
 
-    Ok(())
+    ///////////////////////////////////
+    // Push out new send jobs, and read ahead their chunks
+    while let Some(job) = state_machine.pop_new_send_job() {
+        connection.send_jobs.push(job);
+    }
+    state_machine.feed_send_jobs();
 }
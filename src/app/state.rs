@@ -1,32 +1,141 @@
+use crate::app::auth::UserDb;
 use crate::app::frame::*;
 use crate::common::*;
+use crate::error::RftError;
+use crate::transport::connection::Event;
 use crate::transport::frame::*;
 use crate::transport::jobs::*;
 use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use bytes::Bytes;
 use log::*;
 use num::{FromPrimitive, ToPrimitive};
 use sha3::{Digest, Sha3_512};
-use std::cell::RefCell;
 use std::cmp::min;
 use std::cmp::PartialEq;
 use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::ops::{Deref, DerefMut};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::path::Path;
-use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::{thread, time};
 
 const DEFAULT_CHUNK_SIZE: u64 = 512;
 
+/// Maximum number of chunks which are read ahead into a send job.
+const SEND_QUEUE_DEPTH: usize = 16;
+
 //////////////////////////////
 // FileSendState
 // Holds state while sending a file via a file response
 pub struct FileSendState {
     pub device: fs::File,
+    pub size: u64,
     pub num_content_chunks: u64,
     pub path: String,
+    /// Chunk to be read next by `feed_some()`.
+    pub next_chunk: ChunkId,
 }
 
+impl FileSendState {
+    /// Returns the serialized TLV for the chunk with the given id of the file response object
+    /// (here it is assumed that one tlv per chunk is sufficient)
+    pub fn read_chunk(&mut self, chunk_id: ChunkId) -> io::Result<Vec<u8>> {
+        let mut content = Vec::new();
+        let tlv_to_send = match chunk_id {
+            // Chunk 0 is Metadata tlv with multiple entries
+            0 => {
+                log::info!(" Sending metadata for {}", self.path);
+                let mut size = Vec::new();
+                size.write_u64::<NetworkEndian>(self.size)?;
+                let name = Path::new(&self.path)
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidInput, "path has no file name")
+                    })?;
+                let mut buffer = Vec::new();
+                self.device.read_to_end(&mut buffer)?;
+                let mut hasher = Sha3_512::new();
+                hasher.update(buffer);
+                AppTlv::FileMetadata(FileMetadata {
+                    metadata_entries: vec![
+                        MetadataEntry {
+                            code: MetadataEntryType::FileSize,
+                            content: size,
+                        },
+                        MetadataEntry {
+                            code: MetadataEntryType::FileName,
+                            content: name.as_bytes().to_vec(),
+                        },
+                        MetadataEntry {
+                            code: MetadataEntryType::SHA3,
+                            content: hasher.finalize()[..].to_vec(),
+                        },
+                    ],
+                })
+            }
+            // All other chunks are FileContent TLVs that have to be read from the file
+            _ => {
+                let content_chunk_idx = chunk_id as u64 - 1; // 1 metadata chunk
+                log::info!(
+                    " Sending chunk {}/{} for {}",
+                    content_chunk_idx + 1,
+                    self.num_content_chunks,
+                    self.path
+                );
+                let start_pos = content_chunk_idx * DEFAULT_CHUNK_SIZE;
+                let end_pos = min(start_pos + DEFAULT_CHUNK_SIZE, self.size);
+                content.resize(end_pos.saturating_sub(start_pos) as usize, 0);
+                self.device.seek(SeekFrom::Start(start_pos))?;
+                self.device.read_exact(&mut content)?;
+                AppTlv::FileContent(FileContent { content: &content })
+            }
+        };
+        let mut buf = Vec::new();
+        tlv_to_send.write(&mut buf);
+        Ok(buf)
+    }
+
+    /// Reads all chunks of the file response object ahead into the send job behind `sender`.
+    /// Meant to run on its own thread, so that disk I/O does not block the network loop.
+    /// A read error is sent to `errors`, for `StateMachine::feed_send_jobs()` to report it.
+    pub fn feed(mut self, sender: ChunkSender, errors: Sender<FeedError>) {
+        loop {
+            match self.feed_some(&sender) {
+                Ok(true) => thread::sleep(time::Duration::from_millis(1)),
+                Ok(false) => return,
+                Err(e) => {
+                    // the state machine may be gone already, then nobody is left to tell
+                    let _ = errors.send((self.path, e));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Reads chunks ahead into the send job behind `sender` until its queue is full.
+    /// Returns false once all chunks were read or the send job is gone.
+    pub fn feed_some(&mut self, sender: &ChunkSender) -> io::Result<bool> {
+        let num_chunks = self.num_content_chunks as ChunkId + 1; // 1 metadata chunk
+        while self.next_chunk < num_chunks {
+            if sender.is_closed() {
+                log::info!("Stopped reading {}, send job is gone.", self.path);
+                return Ok(false);
+            }
+            if sender.queued_chunks() >= SEND_QUEUE_DEPTH {
+                return Ok(true);
+            }
+            sender.push_chunk(self.read_chunk(self.next_chunk)?, 1);
+            self.next_chunk += 1;
+        }
+        Ok(false)
+    }
+}
+
+/// Path of a file which could not be read while it was sent, and why.
+pub type FeedError = (String, io::Error);
+
 //////////////////////////////
 // FileRecvState
 // Holds state while receiving a file via a file response
@@ -43,7 +152,7 @@ pub struct FileRecvState {
 
 impl FileRecvState {
     /// Adds a metadata to the state of this file
-    pub fn notify_metadata(&mut self, metadata: &FileMetadata) -> Result<(), ()> {
+    pub fn notify_metadata(&mut self, metadata: &FileMetadata) -> Result<(), RftError> {
         for entry in &metadata.metadata_entries {
             match entry.code {
                 MetadataEntryType::FileName => {
@@ -59,11 +168,9 @@ impl FileRecvState {
                         }
                     };
                     log::info!(" Got a file name: {}", self.name);
-                    let file = fs::File::create(self.name.to_string()).unwrap();
+                    let file = fs::File::create(self.name.to_string())?;
                     if self.size > 0 {
-                        if file.set_len(self.size).is_err() {
-                            todo!("Implement error handling.");
-                        }
+                        file.set_len(self.size)?;
                     }
                     self.device = Option::Some(file);
                 }
@@ -72,7 +179,7 @@ impl FileRecvState {
                         log::error!("Got nonzero size metadata twice!");
                         continue;
                     }
-                    let mut cursor = Cursor::new(entry.content.clone());
+                    let mut cursor = Cursor::new(&entry.content[..]);
                     self.size = match cursor.read_u64::<NetworkEndian>() {
                         Ok(size) => size,
                         Err(_) => {
@@ -81,13 +188,13 @@ impl FileRecvState {
                         }
                     };
                     log::info!(" Got a file size: {}", self.size);
-                    self.num_chunks = (self.size + DEFAULT_CHUNK_SIZE - 1) / DEFAULT_CHUNK_SIZE;
+                    self.num_chunks = self.size.div_ceil(DEFAULT_CHUNK_SIZE);
                     for i in 0..self.num_chunks {
                         self.missing_chunks.insert(i as ChunkId);
                     }
                     log::info!("  Expecting {} chunks.", self.num_chunks);
                     match &mut self.device {
-                        Some(file) => file.set_len(self.size).expect("Set length failed."),
+                        Some(file) => file.set_len(self.size)?,
                         _ => {}
                     }
                 }
@@ -112,24 +219,26 @@ impl FileRecvState {
         Ok(())
     }
 
-    /// Adds the given chunk of file content data the state of this file
+    /// Adds the given chunk of file content data the state of this file.
+    /// Unexpected chunks are ignored, failing to write the file or a hash
+    /// mismatch are errors.
     pub fn notify_content(
         &mut self,
         content: &FileContent,
         mut chunk_id: ChunkId,
         fields: &HashMap<ObjectFieldType, ChunkId>,
-    ) -> Result<(), ()> {
+    ) -> Result<(), RftError> {
         // Check if file can be written
         if self.device.is_none() {
             log::warn!("Ignoring chunk received before file was initialised.");
-            return Err(());
+            return Ok(());
         }
         if self.done() {
             log::warn!("Ignoring unexpected chunk, I am done or I haven't started.");
-            return Err(());
+            return Ok(());
         }
 
-        /// Get absolute position of this chunk in file
+        // Get absolute position of this chunk in file
         let num_metadata_chunks =
             match fields.get(&AppObjectFieldType::FileResponseMetadata.to_u8().unwrap()) {
                 Some(val) => *val,
@@ -143,7 +252,7 @@ impl FileRecvState {
         // Remove chunk from missing chunks
         if !self.missing_chunks.contains(&chunk_id) {
             log::warn!("Ignoring unexpected chunk with ID {}", chunk_id);
-            return Err(());
+            return Ok(());
         }
         self.missing_chunks.remove(&chunk_id);
 
@@ -156,15 +265,8 @@ impl FileRecvState {
         );
         match &mut self.device {
             Some(file) => {
-                if file
-                    .seek(SeekFrom::Start(chunk_id as u64 * DEFAULT_CHUNK_SIZE))
-                    .is_err()
-                {
-                    return Err(());
-                }
-                if file.write(content.content.as_ref()).is_err() {
-                    return Err(());
-                }
+                file.seek(SeekFrom::Start(chunk_id as u64 * DEFAULT_CHUNK_SIZE))?;
+                file.write_all(content.content.as_ref())?;
 
                 // check the sha3 hash if all chunks were received
                 if chunk_id as u64 + 1 == self.num_chunks {
@@ -172,24 +274,19 @@ impl FileRecvState {
 
                     let mut buffer = Vec::new();
                     // Must reopen file because it was closed by last
-                    match fs::File::open(&self.name).unwrap().read_to_end(&mut buffer) {
+                    match fs::File::open(&self.name)?.read_to_end(&mut buffer) {
                         Err(e) => {
                             error!("Could not produce hash for file.");
-                            return Err(());
+                            return Err(RftError::Io(e));
                         }
                         Ok(_) => {
                             let mut hasher = Sha3_512::new();
                             hasher.update(buffer);
 
                             let result = hasher.finalize();
-
-                            let mut cursor = Cursor::new(Vec::new());
-                            if cursor.write(&result[..]).is_err() {
-                                todo!("Implement error handling");
-                            }
-                            if !self.sha3.eq(&cursor.into_inner()) {
+                            if self.sha3[..] != result[..] {
                                 warn!("Received file has invalid hash.");
-                                return Err(());
+                                return Err(RftError::HashMismatch(self.name.clone()));
                             } else {
                                 trace!("Hashes matched!");
                             }
@@ -199,7 +296,7 @@ impl FileRecvState {
             }
             _ => {
                 log::error!("The file handle is gone.");
-                return Err(());
+                return Ok(());
             }
         }
         Ok(())
@@ -243,8 +340,25 @@ pub struct StateMachine {
     state: State,
     next_object_id: ObjectId,
     expected_files: Vec<String>,
-    recv_state: HashMap<(ObjectType, ObjectId), RefCell<ObjectRecvState>>,
+    recv_state: HashMap<ObjectId, ObjectRecvState>,
+    recv_fields: HashMap<ObjectId, HashMap<ObjectFieldType, ChunkId>>,
+    send_paths: HashMap<ObjectId, String>,
     send_job_outbox: Vec<ObjectSendJob>, // will be pushed to the corresponding conection in the server/ client run methods
+    /// Files which are read by `feed_send_jobs()` instead of a thread each, if single threaded.
+    feeders: Option<Vec<(FileSendState, ChunkSender)>>,
+    /// Read errors of the feeder threads, reported by `feed_send_jobs()`.
+    feed_errors: Receiver<FeedError>,
+    feed_error_sender: Sender<FeedError>,
+    /// If set, only authenticated clients are served, and only the paths they may access.
+    users: Option<Arc<UserDb>>,
+    /// User the client authenticated as.
+    user: Option<String>,
+    /// First error which made the transfer fail, see `error()`.
+    error: Option<RftError>,
+    /// Number of files which were fully transmitted.
+    files_sent: u64,
+    /// Number of errors reported to the peer, by code.
+    errors_sent: HashMap<AppErrorCode, u64>,
 }
 
 /// Startup/ Connected state should be considered the same. It just matters if it is finished or not.
@@ -257,15 +371,68 @@ pub enum State {
 
 impl StateMachine {
     pub fn new() -> Self {
+        let (feed_error_sender, feed_errors) = mpsc::channel();
         StateMachine {
             state: State::Startup,
             next_object_id: 0,
             expected_files: vec![],
             recv_state: HashMap::new(),
+            recv_fields: HashMap::new(),
+            send_paths: HashMap::new(),
             send_job_outbox: vec![],
+            feeders: None,
+            feed_errors,
+            feed_error_sender,
+            users: None,
+            user: None,
+            error: None,
+            files_sent: 0,
+            errors_sent: HashMap::new(),
         }
     }
 
+    /// State machine which does not spawn threads, e.g. for simulation.
+    /// `feed_send_jobs()` must be called regularly instead.
+    pub fn new_single_threaded() -> Self {
+        StateMachine {
+            feeders: Some(vec![]),
+            ..StateMachine::new()
+        }
+    }
+
+    /// Reads ahead chunks of files being sent, if single threaded, and reports
+    /// files which could not be read to the peer.
+    pub fn feed_send_jobs(&mut self) {
+        let mut failed = Vec::new();
+        if let Some(feeders) = &mut self.feeders {
+            let mut i = 0;
+            while i < feeders.len() {
+                let (send_state, sender) = &mut feeders[i];
+                match send_state.feed_some(sender) {
+                    Ok(true) => i += 1,
+                    Ok(false) => {
+                        feeders.remove(i);
+                    }
+                    Err(e) => failed.push((feeders.remove(i).0.path, e)),
+                }
+            }
+        }
+        failed.extend(self.feed_errors.try_iter());
+        for (path, e) in failed {
+            log::error!("Failed to read {}: {}", path, e);
+            self.push_error_send_job(ApplicationError {
+                error_code: AppErrorCode::FileAbort,
+                error_data: AppErrorData::Paths(vec![path]),
+            });
+        }
+    }
+
+    /// Serve only clients which authenticate as one of `users`, and only
+    /// the paths they may access.
+    pub fn set_users(&mut self, users: Arc<UserDb>) {
+        self.users = Some(users);
+    }
+
     pub fn get_next_object_id(&mut self) -> ObjectId {
         self.next_object_id += 1;
         self.next_object_id
@@ -283,11 +450,42 @@ impl StateMachine {
         self.state == State::Finished
     }
 
+    /// Records `error`, unless an earlier one was recorded.
+    /// The transfer goes on, e.g. for other files.
+    fn fail(&mut self, error: RftError) {
+        log::error!("{}", error);
+        if self.error.is_none() {
+            self.error = Some(error);
+        }
+    }
+
+    /// Takes the first error of the transfer, e.g. once it is finished.
+    pub fn take_error(&mut self) -> Option<RftError> {
+        self.error.take()
+    }
+
+    /// Name of the file received as the given object, if its metadata arrived.
+    pub fn received_file_name(&self, object_id: ObjectId) -> Option<&str> {
+        match self.recv_state.get(&object_id) {
+            Some(ObjectRecvState::File(f)) if !f.name.is_empty() => Some(&f.name),
+            _ => None,
+        }
+    }
+
+    /// Number of files which were fully transmitted to the peer.
+    pub fn files_sent(&self) -> u64 {
+        self.files_sent
+    }
+
+    /// Number of errors reported to the peer, by code.
+    pub fn errors_sent(&self) -> &HashMap<AppErrorCode, u64> {
+        &self.errors_sent
+    }
+
     pub fn all_files_received(&self) -> bool {
         let mut num_received_files = 0;
-        for transfer_state in &self.recv_state {
-            let state = transfer_state.1.borrow();
-            match state.deref() {
+        for state in self.recv_state.values() {
+            match state {
                 ObjectRecvState::File(f) => {
                     if f.done() {
                         num_received_files += 1;
@@ -301,11 +499,11 @@ impl StateMachine {
 
     /// Creates and returns a ObjectSendJob corresponding to the file request for the given files
     /// The ObjectSendJob is an abstraction that represent a file request object to be sent by the transport layer
-    /// ObjectSendJop has callback to get arbitrary tlvs Chunks for the object and information to produce object header
-    pub fn push_file_request_job(&mut self, files: Vec<String>) -> ObjectSendJob {
-        let files_len = files.len();
+    /// The single chunk of the request is pushed to the ObjectSendJob right away
+    /// Fails if the request does not fit into a single chunk.
+    pub fn push_file_request_job(&mut self, files: Vec<String>) -> Result<ObjectSendJob, RftError> {
         self.expected_files = files.clone();
-        ObjectSendJob::new(
+        let mut job = ObjectSendJob::new(
             // Define properties of the object to be sent (here is is a FileRequestObject with a single TLV, thus only one field with length 1)
             Object {
                 object_type: AppObjectType::FileRequest.to_u8().unwrap(),
@@ -314,25 +512,19 @@ impl StateMachine {
                     field_type: AppObjectFieldType::FileRequestSend.to_u8().unwrap(),
                     length: 1, // in nr. of chunks
                 }],
-                transmission_finished_callback: Box::new(move || {
-                    log::info!("Transmitted request for {} files.", files_len);
-                }),
             },
-            // Callback to get the tlv chunks (here it is assumed that one tlv is sufficient, thus no further checks on chunk_id)
-            Box::new(move |chunk_id: ChunkId| {
-                let result = FileRequest {
-                    file_paths: files.clone(),
-                };
-                let mut cursor = Cursor::new(Vec::new());
-                result.write(&mut cursor);
-                (cursor.into_inner(), 1)
-            }),
-        )
+        );
+        // Here it is assumed that one tlv is sufficient
+        let request = FileRequest { file_paths: files };
+        let mut buf = Vec::new();
+        request.write_limited(&mut buf, DEFAULT_CHUNK_SIZE as usize)?;
+        job.push_chunk(buf, 1);
+        Ok(job)
     }
 
     /// Creates a ObjectSendJob corresponding to the file response for the given file, that will be put this state machine's outbox
     /// The ObjectSendJob is an abstraction that represent a file response object to be sent by the transport layer
-    /// The ObjectSendJob has callback to get arbitrary tlvs Chunks for the object and information to produce object header
+    /// Its chunks are read from the file by a separate thread, or by `feed_send_jobs()`
     pub fn push_file_send_job(&mut self, file_path: String) {
         let file = match fs::File::open(file_path.clone()) {
             Ok(file_obj) => file_obj,
@@ -356,16 +548,19 @@ impl StateMachine {
                 return;
             }
         };
-        let mut send_state = FileSendState {
+        let send_state = FileSendState {
             device: file,
-            num_content_chunks: (meta.len() + DEFAULT_CHUNK_SIZE - 1) / DEFAULT_CHUNK_SIZE,
+            size: meta.len(),
+            num_content_chunks: meta.len().div_ceil(DEFAULT_CHUNK_SIZE),
             path: file_path.clone(),
+            next_chunk: 0,
         };
+        let object_id = self.get_next_object_id();
         let new_send_job = ObjectSendJob::new(
             // Define properties of the object to be sent (here is is a FileResponse object with on field of type metadata with a single chunk and one Field of type FileContent with a variable number of chunks)
             Object {
                 object_type: AppObjectType::FileResponse.to_u8().unwrap(),
-                object_id: self.get_next_object_id(),
+                object_id,
                 fields: vec![
                     ObjectFieldDescription {
                         field_type: AppObjectFieldType::FileResponseMetadata.to_u8().unwrap(),
@@ -376,120 +571,31 @@ impl StateMachine {
                         length: send_state.num_content_chunks as i64, // in nr. of chunks
                     },
                 ],
-                transmission_finished_callback: {
-                    let file_path = file_path.clone();
-                    Box::new(move || {
-                        log::info!("File {} fully transmitted.", file_path);
-                    })
-                },
             },
-            // Callback to get the chunk with the given id (here it is assumed that one tlv is sufficient, thus no further checks on chunk_id)
-            Box::new(move |chunk_id: ChunkId| {
-                let path = Path::new(&send_state.path);
-                let tlv_to_send = match chunk_id {
-                    // Chunk 0 is Metadata tlv with multiple entries
-                    0 => AppTlv::FileMetadata(FileMetadata {
-                        metadata_entries: vec![
-                            MetadataEntry {
-                                code: MetadataEntryType::FileSize,
-                                content: {
-                                    log::info!(" Sending metadata for {}", send_state.path);
-                                    let mut cursor = Cursor::new(Vec::new());
-                                    if cursor.write_u64::<NetworkEndian>(meta.len()).is_err() {
-                                        error!("Write failed.");
-                                        todo!("Implement error handling");
-                                    }
-                                    cursor.into_inner()
-                                },
-                            },
-                            MetadataEntry {
-                                code: MetadataEntryType::FileName,
-                                content: {
-                                    let mut cursor = Cursor::new(Vec::new());
-                                    if cursor
-                                        .write(
-                                            path.file_name().unwrap().to_str().unwrap().as_bytes(),
-                                        )
-                                        .is_err()
-                                    {
-                                        error!("Write failed.");
-                                        todo!("Implement error handling");
-                                    }
-                                    cursor.into_inner()
-                                },
-                            },
-                            MetadataEntry {
-                                code: MetadataEntryType::SHA3,
-                                content: {
-                                    let mut buffer = Vec::new();
-
-                                    match send_state.device.read_to_end(&mut buffer) {
-                                        Err(e) => {
-                                            error!("Could not produce hash for file.");
-                                            todo!("Implement error handling.");
-                                        }
-                                        Ok(_) => {
-                                            let mut hasher = Sha3_512::new();
-                                            hasher.update(buffer);
-
-                                            let result = hasher.finalize();
-
-                                            let mut cursor = Cursor::new(Vec::new());
-                                            if cursor.write(&result[..]).is_err() {
-                                                error!("Write failed!");
-                                                todo!("Implement error handling");
-                                            }
-                                            cursor.into_inner()
-                                        }
-                                    }
-                                },
-                            },
-                        ],
-                    }),
-                    // All other chunks are FileContent TLVs that have to be read from the file
-                    _ => AppTlv::FileContent(FileContent {
-                        content: {
-                            let content_chunk_idx = chunk_id - 1; // 1 metadata chunk
-                            log::info!(
-                                " Sending chunk {}/{} for {}",
-                                content_chunk_idx + 1,
-                                send_state.num_content_chunks,
-                                send_state.path
-                            );
-                            let mut result = Vec::new();
-                            let start_pos = content_chunk_idx * DEFAULT_CHUNK_SIZE as i64;
-                            if send_state
-                                .device
-                                .seek(SeekFrom::Start(start_pos as u64))
-                                .is_err()
-                            {
-                                error!("Write failed!");
-                                todo!("Implement error handling.");
-                            }
-                            let end_pos =
-                                min(start_pos + DEFAULT_CHUNK_SIZE as i64, meta.len() as i64);
-                            for _ in start_pos..end_pos {
-                                result.push(send_state.device.read_u8().unwrap());
-                            }
-                            result
-                        },
-                    }),
-                };
-                let mut cursor = Cursor::new(Vec::new());
-                tlv_to_send.write(&mut cursor);
-                (cursor.into_inner(), 1)
-            }),
         );
+        self.send_paths.insert(object_id, file_path);
+        let sender = new_send_job.chunk_sender();
+        match &mut self.feeders {
+            Some(feeders) => feeders.push((send_state, sender)),
+            None => {
+                let errors = self.feed_error_sender.clone();
+                thread::spawn(move || send_state.feed(sender, errors));
+            }
+        }
         // Adds jobs to the out box (from where they will finally be put to the transport layer (connection))
         self.send_job_outbox.push(new_send_job);
     }
 
     /// Creates a ObjectSendJob for the given application error, that will be put this state machine's outbox
     /// The ObjectSendJob is an abstraction that represent a error report object to be sent by the transport layer
-    /// The ObjectSendJob has a callback to get arbitrary tlvs Chunks for the object and information to produce object header
+    /// The single chunk of the error report is pushed to the ObjectSendJob right away
     pub fn push_error_send_job(&mut self, app_err: ApplicationError) {
+        *self
+            .errors_sent
+            .entry(app_err.error_code.clone())
+            .or_insert(0) += 1;
         // The given application error will be sent via a single TLV (thus will not work for large payloads)
-        let new_send_job = ObjectSendJob::new(
+        let mut new_send_job = ObjectSendJob::new(
             // Define properties of the object to be sent (has one field with a single junk)
             Object {
                 object_type: AppObjectType::ErrorReport.to_u8().unwrap(),
@@ -498,103 +604,187 @@ impl StateMachine {
                     field_type: AppObjectFieldType::ErrorReportContent.to_u8().unwrap(),
                     length: 1, // in nr. of chunks
                 }],
-                transmission_finished_callback: Box::new(move || {
-                    log::info!("Error fully transmitted.");
-                }),
             },
-            // Callback to get the chunk with the given id (there is only a single Chunk)
-            Box::new(move |chunk_id: ChunkId| {
-                let mut cursor = Cursor::new(Vec::new());
-                app_err.write(&mut cursor);
-                (cursor.into_inner(), 1)
-            }),
         );
+        let mut buf = Vec::new();
+        if let Err(e) = app_err.write_limited(&mut buf, DEFAULT_CHUNK_SIZE as usize) {
+            // the error code alone still tells the peer what went wrong
+            warn!("Dropping the paths of {:?}: {}", app_err.error_code, e);
+            ApplicationError {
+                error_code: app_err.error_code,
+                error_data: AppErrorData::Paths(vec![String::new()]),
+            }
+            .write(&mut buf);
+        }
+        new_send_job.push_chunk(buf, 1);
         self.send_job_outbox.push(new_send_job);
     }
 
-    pub fn has_recv_job(&self, recv_job: &ObjectReceiveJob) -> bool {
-        self.recv_state
-            .contains_key(&(recv_job.object.object_type, recv_job.object.object_id))
+    /// Updates the state according to an event of the transport layer
+    /// ((Server + Client) Application layer logic for receiving requests/ responses implemented here!)
+    pub fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::NewObject(object) => self.push_recv_object(&object),
+            Event::Chunk {
+                object_id,
+                chunk_id,
+                data,
+                num_tlvs,
+            } => self.notify_chunk(object_id, chunk_id, data, num_tlvs),
+            Event::ObjectAcked {
+                object_id,
+                chunk_id,
+            } => {
+                log::trace!("Object #{} acked until chunk #{}.", object_id, chunk_id);
+            }
+            Event::ObjectComplete(object_id) => match self.send_paths.remove(&object_id) {
+                Some(path) => {
+                    log::info!("File {} fully transmitted.", path);
+                    self.files_sent += 1;
+                }
+                None => log::info!("Object #{} fully transmitted.", object_id),
+            },
+            Event::PeerError(err) => self.fail(RftError::PeerError(err.code)),
+            Event::Timeout => {
+                if !self.all_files_received() {
+                    self.fail(RftError::Timeout);
+                }
+                self.finished();
+            }
+            Event::HandshakeFailed(code) => {
+                log::error!("Handshake failed: {:?}.", code);
+                self.fail(RftError::PeerError(code));
+                self.finished();
+            }
+            Event::Authenticated(user) => {
+                log::info!("Serving user {}.", user);
+                self.user = Some(user);
+            }
+        }
     }
 
-    /// Adds a callback to the recv_job that abstracts the receiving of object chunks
-    /// Adds recv_state to given state_machine
-    /// ((Server + Client) Application layer logic for recceveing requests/ responses implemented here!)
-    pub fn push_recv_job(
-        state_machine: &Rc<RefCell<StateMachine>>,
-        recv_job: &mut ObjectReceiveJob,
-    ) {
-        //////////////////
-        // Adds a new object state to it hash map
-        let object_info = (recv_job.object.object_type, recv_job.object.object_id);
+    /// Adds recv_state for a new object announced by the peer
+    fn push_recv_object(&mut self, object: &Object) {
         let mut field_length = HashMap::new();
-        for field in &recv_job.object.fields {
+        for field in &object.fields {
             field_length.insert(field.field_type, field.length);
         }
-        state_machine.borrow_mut().recv_state.insert(
-            object_info.clone(),
-            RefCell::new(ObjectRecvState::new(recv_job.object.object_type)),
-        );
+        self.recv_fields.insert(object.object_id, field_length);
+        self.recv_state
+            .insert(object.object_id, ObjectRecvState::new(object.object_type));
+    }
 
-        let state_machine_ref = Rc::clone(state_machine);
-        ////////////////
-        // Adds callback to the receive job
-        // Callback to be called on received chunks / tlvs
-        // Combined application layer logic for receiving objects
-        recv_job.chunk_received_callback =
-            Box::new(move |data: Vec<u8>, chunk_id: ChunkId, num_tlv: u8| {
-                let mut state_machine = state_machine_ref.borrow_mut();
-                let mut cursor = Cursor::new(data);
-                log::info!(
-                    "Received chunk #{} for object #{}, {} tlvs.",
-                    chunk_id,
-                    object_info.1,
-                    num_tlv
-                );
-                let mut tlv_idx = 0;
-                while tlv_idx < num_tlv {
-                    log::info!("Parsing TLV #{} ...", tlv_idx);
-                    let tlv = match parse(&mut cursor) {
-                        AppTlvParseResult::Ok(tlv) => tlv,
-                        AppTlvParseResult::Err(e) => {
-                            log::error!(" Error: {}", e);
-                            return;
-                        }
-                    };
-                    let obj_state = state_machine.recv_state.get(&object_info).unwrap();
-                    let mut finished = false;
-                    let mut new_file_send_jobs = vec![];
-                    match (&tlv, obj_state.borrow_mut().deref_mut()) {
-                        (AppTlv::FileMetadata(metadata_tlv), ObjectRecvState::File(f)) => {
-                            f.notify_metadata(metadata_tlv);
-                        }
-                        (AppTlv::FileContent(content_tlv), ObjectRecvState::File(f)) => {
-                            f.notify_content(content_tlv, chunk_id, &field_length);
-                        }
-                        (AppTlv::ApplicationError(err_tlv), ObjectRecvState::Empty) => {
-                            log::error!(
-                                " Received server error (code {})",
-                                err_tlv.error_code.to_u8().unwrap()
-                            );
-                            finished = true;
-                        }
-                        (AppTlv::FileRequest(request_tlv), ObjectRecvState::Empty) => {
-                            new_file_send_jobs = request_tlv.file_paths.clone();
-                        }
-                        _ => {
-                            log::error!(" Encountered unexpected TLV type.");
-                            finished = true;
-                        }
-                    }
+    /// Parses the application tlvs in a received chunk and processes them
+    fn notify_chunk(&mut self, object_id: ObjectId, chunk_id: ChunkId, data: Bytes, num_tlv: u8) {
+        let mut cursor = Cursor::new(&data[..]);
+        log::info!(
+            "Received chunk #{} for object #{}, {} tlvs.",
+            chunk_id,
+            object_id,
+            num_tlv
+        );
+        let mut tlv_idx = 0;
+        while tlv_idx < num_tlv {
+            log::info!("Parsing TLV #{} ...", tlv_idx);
+            let tlv = match parse(&mut cursor) {
+                AppTlvParseResult::Ok(tlv) => tlv,
+                AppTlvParseResult::Skipped(app_tlv_type) => {
+                    log::debug!(" Skipped unknown TLV type {}.", app_tlv_type);
                     tlv_idx += 1;
-                    if finished {
-                        state_machine.finished();
-                    }
-                    for file in &new_file_send_jobs {
-                        state_machine.push_file_send_job(file.clone());
+                    continue;
+                }
+                AppTlvParseResult::Err(e) => {
+                    self.fail(e);
+                    return;
+                }
+            };
+            let (obj_state, field_length) = match (
+                self.recv_state.get_mut(&object_id),
+                self.recv_fields.get(&object_id),
+            ) {
+                (Some(obj_state), Some(field_length)) => (obj_state, field_length),
+                _ => {
+                    log::warn!("Received chunk for unknown object #{}.", object_id);
+                    return;
+                }
+            };
+            let mut finished = false;
+            let mut error = None;
+            let mut new_file_send_jobs = vec![];
+            let mut denied_paths = vec![];
+            let mut unsupported = false;
+            // wether the client may fetch or list a path
+            let (users, user) = (&self.users, self.user.as_deref());
+            let may_access = |path: &str| match users {
+                Some(users) => users.may_access(user, path),
+                None => true,
+            };
+            match (&tlv, obj_state) {
+                (AppTlv::FileMetadata(metadata_tlv), ObjectRecvState::File(f)) => {
+                    error = f.notify_metadata(metadata_tlv).err();
+                }
+                (AppTlv::FileContent(content_tlv), ObjectRecvState::File(f)) => {
+                    error = f.notify_content(content_tlv, chunk_id, field_length).err();
+                }
+                (AppTlv::ApplicationError(err_tlv), ObjectRecvState::Empty) => {
+                    log::error!(
+                        " Received server error (code {})",
+                        err_tlv.error_code.to_u8().unwrap()
+                    );
+                    error = Some(RftError::from(err_tlv));
+                    finished = true;
+                }
+                (AppTlv::FileRequest(request_tlv), ObjectRecvState::Empty) => {
+                    for path in &request_tlv.file_paths {
+                        if may_access(path) {
+                            new_file_send_jobs.push(path.clone());
+                        } else {
+                            denied_paths.push(path.clone());
+                        }
                     }
                 }
-            });
+                (AppTlv::FileListRequest(request_tlv), ObjectRecvState::Empty)
+                    if !may_access(&request_tlv.path) =>
+                {
+                    denied_paths.push(request_tlv.path.clone());
+                }
+                (AppTlv::FileListRequest(_), ObjectRecvState::Empty) => {
+                    log::warn!(" Refusing to list files, which is not supported.");
+                    unsupported = true;
+                }
+                _ => {
+                    log::error!(" Encountered unexpected TLV type.");
+                    finished = true;
+                }
+            }
+            tlv_idx += 1;
+            if let Some(error) = error {
+                self.fail(error);
+            }
+            if finished {
+                self.finished();
+            }
+            if !denied_paths.is_empty() {
+                log::warn!(
+                    "Denied access to {:?} for user {:?}.",
+                    denied_paths,
+                    self.user
+                );
+                self.push_error_send_job(ApplicationError {
+                    error_code: AppErrorCode::AccessDenied,
+                    error_data: AppErrorData::Paths(denied_paths),
+                });
+            }
+            if unsupported {
+                self.push_error_send_job(ApplicationError {
+                    error_code: AppErrorCode::UnsupportedRequest,
+                    error_data: AppErrorData::Empty,
+                });
+            }
+            for file in &new_file_send_jobs {
+                self.push_file_send_job(file.clone());
+            }
+        }
     }
 
     pub fn pop_new_send_job(&mut self) -> Option<ObjectSendJob> {
@@ -1,41 +1,363 @@
-use super::get::get;
+use super::auth::UserDb;
+use super::get;
 use super::server;
+use super::state::StateMachine;
 use crate::options::*;
+use crate::transport::client;
+use crate::transport::config::{Config, Credentials};
+use crate::transport::server::Listener;
+use crate::transport::sim::{Env, LossModel, SimClock, SimNetwork};
 
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use std::fs;
-use std::io::Read;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::thread;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[test]
 fn test_basic_file_transfer() {
     let srv_opts = Options {
         port: 38134,
-        transition_probabilities: (1.0, 0.0),
+        ..Default::default()
     };
     let cli_opts = Options {
         port: 38135,
-        transition_probabilities: (1.0, 0.0),
+        ..Default::default()
     };
-    let srv_addr = Ipv4Addr::new(0, 0, 0, 0);
-    let cli_srv_addr = SocketAddr::V4(SocketAddrV4::new(
-        Ipv4Addr::new(127, 0, 0, 1),
-        srv_opts.port,
-    ));
-
-    let srv = thread::spawn(move || {
-        server::run(srv_opts, srv_addr);
-    });
 
-    if fs::metadata("test.txt").is_ok() {
-        fs::remove_file("test.txt");
+    let _ = fs::remove_file("test.txt");
+    simulate_get(
+        0,
+        LossModel::new(0.0, 0.0),
+        srv_opts.transport_config(),
+        cli_opts.transport_config(),
+        srv_opts.users.clone(),
+        "testdata/test.txt",
+    );
+    let content = fs::read_to_string("test.txt").unwrap();
+    assert_eq!(content, "Hello General Kenobi\n");
+    fs::remove_file("test.txt").unwrap();
+}
+
+/// Simulates the transfer of a file with `size` random bytes, from a server to a client
+/// on a single thread, until the client is finished.
+/// Returns the virtual time taken, the number of datagrams sent and lost,
+/// and the content of the received file, if any.
+#[allow(dead_code)]
+fn simulate_transfer(seed: u64, size: usize, loss: LossModel) -> (Duration, usize, usize, Vec<u8>) {
+    simulate_transfer_as(seed, size, loss, None, None)
+}
+
+/// Like `simulate_transfer()`, with the client authenticating with `credentials`
+/// to a server which only serves `users`.
+#[allow(dead_code)]
+fn simulate_transfer_as(
+    seed: u64,
+    size: usize,
+    loss: LossModel,
+    credentials: Option<Credentials>,
+    users: Option<Arc<UserDb>>,
+) -> (Duration, usize, usize, Vec<u8>) {
+    let name = format!("rft-sim-{}.bin", seed);
+    let src_path = std::env::temp_dir().join(&name);
+    let content: Vec<u8> = StdRng::seed_from_u64(seed)
+        .sample_iter(rand::distributions::Standard)
+        .take(size)
+        .collect();
+    fs::write(&src_path, &content).unwrap();
+    let _ = fs::remove_file(&name);
+
+    let srv_config = Config {
+        users: users.as_ref().map(|users| Arc::new(users.secrets())),
+        ..Config::default()
+    };
+    let cli_config = Config {
+        credentials,
+        ..Config::default()
+    };
+    let path = src_path.to_str().unwrap();
+    let (elapsed, n_sent, n_lost) = simulate_get(seed, loss, srv_config, cli_config, users, path);
+
+    let received = fs::read(&name).unwrap_or_default();
+    let _ = fs::remove_file(&name);
+    fs::remove_file(&src_path).unwrap();
+    (elapsed, n_sent, n_lost, received)
+}
+
+/// Simulates a client with `cli_config` fetching the file at `path` from a server with
+/// `srv_config` which only serves `users`, on a single thread, until the client is finished.
+/// Returns the virtual time taken and the number of datagrams sent and lost.
+#[allow(dead_code)]
+fn simulate_get(
+    seed: u64,
+    loss: LossModel,
+    srv_config: Config,
+    cli_config: Config,
+    users: Option<Arc<UserDb>>,
+    path: &str,
+) -> (Duration, usize, usize) {
+    let clock = SimClock::new();
+    let network = SimNetwork::new(&clock, seed);
+    network.set_latency(Duration::from_millis(5));
+    network.set_loss(loss);
+    let (cli_transport, srv_transport) = network.pair();
+    let mut listener = Listener::with_transport(
+        Box::new(srv_transport),
+        Env::simulated(&clock, seed + 1),
+        srv_config,
+    );
+    let mut cli_conn = client::connect_transport(
+        Box::new(cli_transport),
+        Env::simulated(&clock, seed + 2),
+        cli_config,
+    );
+
+    let mut srv_state = StateMachine::new_single_threaded();
+    if let Some(users) = users {
+        srv_state.set_users(users);
+    }
+    let mut cli_state = StateMachine::new_single_threaded();
+    cli_conn.send_jobs.push(
+        cli_state
+            .push_file_request_job(vec![path.to_string()])
+            .unwrap(),
+    );
+
+    let mut srv_conn = None;
+    while !cli_state.is_finished() {
+        clock.advance(Duration::from_millis(1));
+        if srv_conn.is_none() {
+            srv_conn = listener.listen_once();
+        }
+        if let Some(srv_conn) = &mut srv_conn {
+            server::step(srv_conn, &mut srv_state);
+        }
+        get::step(&mut cli_conn, &mut cli_state);
+    }
+    (clock.elapsed(), network.num_sent(), network.num_lost())
+}
+
+#[test]
+fn test_simulated_file_transfer() {
+    let size = 20 * 512 + 100;
+    let (elapsed, _, n_lost, received) = simulate_transfer(1, size, LossModel::new(0.0, 0.0));
+    assert_eq!(n_lost, 0);
+    assert_eq!(received.len(), size);
+    let content: Vec<u8> = StdRng::seed_from_u64(1)
+        .sample_iter(rand::distributions::Standard)
+        .take(size)
+        .collect();
+    assert_eq!(received, content);
+    // no waiting for timeouts
+    assert!(elapsed < Duration::from_secs(1));
+}
+
+#[test]
+fn test_simulated_file_transfer_is_reproducible() {
+    let run = |seed| simulate_transfer(seed, 20 * 512, LossModel::new(0.05, 0.3));
+    let first = run(2);
+    assert!(first.2 > 0, "nothing was lost");
+    assert_eq!(first, run(2));
+}
+
+#[test]
+fn test_simulated_authenticated_file_transfer() {
+    use crate::transport::frame::AuthMethod;
+
+    let users = Arc::new(UserDb::parse("alice s3cr3t *\nbob hunter2 data\n").unwrap());
+    let transfer = |seed, user: &str, secret: &str, method| {
+        let credentials = Credentials {
+            user: user.to_string(),
+            secret: secret.as_bytes().to_vec(),
+            method,
+        };
+        let (_, _, _, received) = simulate_transfer_as(
+            seed,
+            1000,
+            LossModel::new(0.0, 0.0),
+            Some(credentials),
+            Some(Arc::clone(&users)),
+        );
+        received
+    };
+    assert_eq!(
+        transfer(3, "alice", "s3cr3t", AuthMethod::ChallengeResponse).len(),
+        1000
+    );
+    assert_eq!(
+        transfer(4, "alice", "s3cr3t", AuthMethod::Token).len(),
+        1000
+    );
+    // may not access the file
+    assert!(transfer(5, "bob", "hunter2", AuthMethod::ChallengeResponse).is_empty());
+    // wrong secret
+    assert!(transfer(6, "alice", "guess", AuthMethod::ChallengeResponse).is_empty());
+    // not authenticated
+    let (_, _, _, received) =
+        simulate_transfer_as(7, 1000, LossModel::new(0.0, 0.0), None, Some(users));
+    assert!(received.is_empty());
+}
+
+#[test]
+fn test_simulated_concurrent_file_transfers() {
+    let clock = SimClock::new();
+    let network = SimNetwork::new(&clock, 8);
+    network.set_latency(Duration::from_millis(5));
+    let (first, srv_transport) = network.pair();
+    let second = network.connect(&srv_transport);
+    let mut listener = Listener::with_transport(
+        Box::new(srv_transport),
+        Env::simulated(&clock, 9),
+        Config::default(),
+    );
+
+    // both clients fetch a file of their own at the same time
+    let mut clients = Vec::new();
+    let mut contents = Vec::new();
+    for (i, transport) in vec![first, second].into_iter().enumerate() {
+        let name = format!("rft-concurrent-{}.bin", i);
+        let src_path = std::env::temp_dir().join(&name);
+        let content = vec![i as u8; 3000 + 1000 * i];
+        fs::write(&src_path, &content).unwrap();
+        let _ = fs::remove_file(&name);
+        let mut cli_conn = client::connect_transport(
+            Box::new(transport),
+            Env::simulated(&clock, 10 + i as u64),
+            Config::default(),
+        );
+        let mut cli_state = StateMachine::new_single_threaded();
+        cli_conn.send_jobs.push(
+            cli_state
+                .push_file_request_job(vec![src_path.to_str().unwrap().to_string()])
+                .unwrap(),
+        );
+        clients.push((cli_conn, cli_state));
+        contents.push((name, src_path, content));
     }
-    get(cli_opts, cli_srv_addr, vec!["testdata/test.txt"]);
+
+    let mut servers = Vec::new();
+    let mut max_open = 0;
+    while clients
+        .iter()
+        .any(|(_, cli_state)| !cli_state.is_finished())
     {
-        let mut file = fs::File::open("test.txt").unwrap();
-        let mut content = String::new();
-        file.read_to_string(&mut content);
-        assert_eq!(content, "Hello General Kenobi\n");
+        clock.advance(Duration::from_millis(1));
+        while let Some(srv_conn) = listener.listen_once() {
+            servers.push((srv_conn, StateMachine::new_single_threaded()));
+        }
+        for (srv_conn, srv_state) in &mut servers {
+            server::step(srv_conn, srv_state);
+        }
+        max_open = max_open.max(servers.len());
+        for (cli_conn, cli_state) in &mut clients {
+            if !cli_state.is_finished() {
+                get::step(cli_conn, cli_state);
+            }
+        }
+        assert!(clock.elapsed() < Duration::from_secs(10));
+    }
+
+    assert_eq!(max_open, 2);
+    assert_ne!(servers[0].0.peer(), servers[1].0.peer());
+    for (name, src_path, content) in contents {
+        assert_eq!(fs::read(&name).unwrap(), content);
+        fs::remove_file(&name).unwrap();
+        fs::remove_file(&src_path).unwrap();
+    }
+}
+
+#[test]
+fn test_file_list_request_is_refused() {
+    use super::frame::{AppErrorCode, AppObjectType, FileListRequest};
+    use crate::common::WireFormat;
+    use crate::transport::connection::Event;
+    use crate::transport::jobs::Object;
+    use bytes::Bytes;
+    use num::ToPrimitive;
+
+    let users = Arc::new(UserDb::parse("alice s3cr3t *\n").unwrap());
+    let mut state_machine = StateMachine::new_single_threaded();
+    state_machine.set_users(users);
+    state_machine.handle_event(Event::Authenticated(String::from("alice")));
+    state_machine.handle_event(Event::NewObject(Object {
+        object_type: AppObjectType::FileListRequest.to_u8().unwrap(),
+        object_id: 1,
+        fields: vec![],
+    }));
+    let mut chunk = Vec::new();
+    FileListRequest {
+        path: String::from("data"),
+        ..FileListRequest::default()
     }
-    fs::remove_file("test.txt");
+    .write(&mut chunk);
+    state_machine.handle_event(Event::Chunk {
+        object_id: 1,
+        chunk_id: 0,
+        data: Bytes::from(chunk),
+        num_tlvs: 1,
+    });
+
+    // permitted, but not supported: the client is told so and may go on
+    assert!(!state_machine.is_finished());
+    let error_job = state_machine.pop_new_send_job().unwrap();
+    assert_eq!(
+        error_job.object_type(),
+        AppObjectType::ErrorReport.to_u8().unwrap()
+    );
+    assert_eq!(
+        state_machine
+            .errors_sent()
+            .get(&AppErrorCode::UnsupportedRequest),
+        Some(&1)
+    );
+}
+
+#[test]
+fn test_unreadable_file_is_aborted() {
+    use super::frame::AppObjectType;
+    use super::state::FileSendState;
+    use crate::transport::frame::ObjectFieldDescription;
+    use crate::transport::jobs::{Object, ObjectSendJob};
+    use num::ToPrimitive;
+    use std::io::ErrorKind;
+    use std::sync::mpsc;
+
+    let path = std::env::temp_dir().join("rft-truncated.bin");
+    fs::write(&path, vec![7; 3 * 512]).unwrap();
+    let path = path.to_str().unwrap().to_string();
+
+    // a feeder thread sends read errors back
+    let job = ObjectSendJob::new(Object {
+        object_type: AppObjectType::FileResponse.to_u8().unwrap(),
+        object_id: 1,
+        fields: vec![ObjectFieldDescription {
+            field_type: 0,
+            length: 5,
+        }],
+    });
+    let send_state = FileSendState {
+        device: fs::File::open(&path).unwrap(),
+        size: 4 * 512,
+        num_content_chunks: 4,
+        path: path.clone(),
+        next_chunk: 0,
+    };
+    let (errors, feed_errors) = mpsc::channel();
+    send_state.feed(job.chunk_sender(), errors);
+    let (failed_path, e) = feed_errors.try_recv().unwrap();
+    assert_eq!(failed_path, path);
+    assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+
+    // and the state machine reports them to the peer
+    let mut state_machine = StateMachine::new_single_threaded();
+    state_machine.push_file_send_job(path.clone());
+    fs::write(&path, vec![7; 512]).unwrap();
+    state_machine.feed_send_jobs();
+    let error_job = state_machine.pop_new_send_job().unwrap();
+    assert_eq!(
+        error_job.object_type(),
+        AppObjectType::ErrorReport.to_u8().unwrap()
+    );
+    assert_eq!(state_machine.errors_sent().len(), 1);
+    let _ = fs::remove_file(&path);
 }
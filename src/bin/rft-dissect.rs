@@ -0,0 +1,83 @@
+use clap;
+use env_logger;
+use rft::common::json::Json;
+use rft::dissect;
+use std::fs;
+use std::io::{self, Read};
+
+/// Exit code for invalid command line options, as of `rft`.
+const EX_USAGE: i32 = 64;
+
+fn main() {
+    // Initialize logger.
+    env_logger::init();
+
+    // Specify command line interface:
+    let matches = clap::App::new("rft-dissect")
+        .version(clap::crate_version!())
+        .author(clap::crate_authors!("\n"))
+        .about("Decodes RFT frames in captured UDP datagrams")
+        .settings(&[clap::AppSettings::DeriveDisplayOrder])
+        .arg(clap::Arg::with_name("input")
+            .help("pcap capture, e.g. of `tcpdump -w`, or hex dump with one datagram per line\n`-` reads from stdin")
+            .index(1)
+            .required(true)
+            .takes_value(true)
+        )
+        .arg(clap::Arg::with_name("t")
+            .short("t")
+            .help("only decode datagrams from or to this UDP port")
+            .takes_value(true)
+        )
+        .arg(clap::Arg::with_name("json")
+            .long("json")
+            .help("print one JSON object per datagram instead of text")
+        )
+        .get_matches();
+
+    let port = match matches.value_of("t").map(str::parse::<u16>) {
+        Some(Err(e)) => {
+            eprintln!("Error while parsing command line options (t): {}", e);
+            std::process::exit(EX_USAGE);
+        }
+        Some(Ok(port)) => Some(port),
+        None => None,
+    };
+
+    // Read the datagrams:
+    let input = matches.value_of("input").unwrap(); // required by clap
+    let mut data = Vec::new();
+    let read = if input == "-" {
+        io::stdin().read_to_end(&mut data).map(|_| ())
+    } else {
+        fs::read(input).map(|d| data = d)
+    };
+    if let Err(e) = read {
+        eprintln!("Error: Could not read {}: {}", input, e);
+        std::process::exit(rft::error::RftError::Io(e).exit_code());
+    }
+    let datagrams = match dissect::capture::read_capture(&data) {
+        Ok(datagrams) => datagrams,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(e.exit_code());
+        }
+    };
+
+    // Decode and print them:
+    let on_port = |addr: &Option<std::net::SocketAddr>| match (port, addr) {
+        (Some(port), Some(addr)) => addr.port() == port,
+        _ => false,
+    };
+    for (i, datagram) in datagrams.iter().enumerate() {
+        if port.is_some() && !on_port(&datagram.src) && !on_port(&datagram.dst) {
+            continue;
+        }
+        let json: Json = dissect::dissect(datagram);
+        if matches.is_present("json") {
+            println!("{}", json);
+        } else {
+            println!("{}", dissect::to_text(i + 1, &json));
+        }
+    }
+}
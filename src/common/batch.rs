@@ -0,0 +1,509 @@
+//! Batched datagram I/O.
+//!
+//! On Linux, many datagrams are sent and received per syscall using
+//! `sendmmsg`/`recvmmsg`. Consecutive datagrams of equal size are handed to
+//! the kernel as one buffer using UDP segmentation offload (GSO), and
+//! datagrams coalesced by the kernel (GRO) are split up again on reception.
+//! Everything which is not supported by the kernel is switched off at runtime,
+//! falling back to one `send`/`recv` call per datagram on a plain `UdpSocket`.
+
+use bytes::Bytes;
+use std::io;
+use std::net::UdpSocket;
+
+/// Maximum number of datagrams which are sent or received at once.
+pub const MAX_BATCH: usize = 32;
+/// Maximum number of datagrams which are merged into one GSO buffer.
+const MAX_GSO_SEGMENTS: usize = 64;
+/// Maximum size of one GSO or GRO buffer, leaving room for IP and UDP headers.
+const MAX_GSO_BUFSIZE: usize = 65000;
+/// Number of datagrams which are received at once.
+const RECV_BATCH: usize = 16;
+
+/// Batched sending and receiving of datagrams on a connected socket.
+/// Datagrams to be sent are serialized back to back into one buffer.
+pub struct BatchIo {
+    buf: Vec<u8>,
+    /// End offset of each datagram in `buf`.
+    ends: Vec<usize>,
+    /// Buffer for received datagrams, which is reused for every batch.
+    /// Each datagram is copied out of it, so a buffered datagram doesn't
+    /// keep the whole batch alive.
+    recv_buf: Vec<u8>,
+    /// Wether `sendmmsg`/`recvmmsg` are used.
+    mmsg: bool,
+    /// Wether UDP segmentation offload is used for sending.
+    gso: bool,
+    /// Wether UDP generic receive offload is enabled on the socket.
+    gro: bool,
+}
+
+impl BatchIo {
+    /// Probe which offloads are available for `socket`.
+    /// Enables generic receive offload on the socket if possible.
+    pub fn new(socket: &UdpSocket) -> Self {
+        let (mmsg, gso, gro) = sys::probe(socket);
+        log::debug!("Batched I/O: mmsg {}, gso {}, gro {}", mmsg, gso, gro);
+        BatchIo {
+            buf: Vec::new(),
+            ends: Vec::new(),
+            recv_buf: Vec::new(),
+            mmsg,
+            gso,
+            gro,
+        }
+    }
+
+    /// Batched I/O which only uses plain `send`/`recv` calls.
+    pub fn fallback() -> Self {
+        BatchIo {
+            buf: Vec::new(),
+            ends: Vec::new(),
+            recv_buf: Vec::new(),
+            mmsg: false,
+            gso: false,
+            gro: false,
+        }
+    }
+
+    /// Number of datagrams pushed since the last `clear()`.
+    pub fn len(&self) -> usize {
+        self.ends.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ends.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() >= MAX_BATCH
+    }
+
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.ends.clear();
+    }
+
+    /// Append a datagram, which is serialized by `write`.
+    pub fn push_with<F: FnOnce(&mut Vec<u8>)>(&mut self, write: F) {
+        write(&mut self.buf);
+        self.ends.push(self.buf.len());
+    }
+
+    /// Returns the i-th pushed datagram.
+    pub fn datagram(&self, i: usize) -> &[u8] {
+        let start = if i == 0 { 0 } else { self.ends[i - 1] };
+        &self.buf[start..self.ends[i]]
+    }
+
+    /// Send all pushed datagrams in order via the connected `socket`.
+    /// Returns the number of datagrams which were sent, which may be less
+    /// than `len()`. Fails only if not a single datagram was sent.
+    pub fn send(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        if self.ends.is_empty() {
+            return Ok(0);
+        }
+        if self.mmsg {
+            let groups = self.groups();
+            match sys::send(socket, &self.buf, &groups) {
+                Ok(n_groups) => return Ok(groups[..n_groups].iter().map(|g| g.count).sum()),
+                Err(e) if sys::is_unsupported(&e) => {
+                    log::warn!("Batched sending failed ({}), falling back.", e);
+                    self.mmsg = false;
+                    self.gso = false;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        self.send_each(|datagram| socket.send(datagram))
+    }
+
+    /// Send all pushed datagrams in order, one call of `send` per datagram.
+    /// Returns the number of datagrams which were sent like `send()`.
+    pub fn send_each<F>(&self, mut send: F) -> io::Result<usize>
+    where
+        F: FnMut(&[u8]) -> io::Result<usize>,
+    {
+        let mut n_sent = 0;
+        while n_sent < self.len() {
+            match send(self.datagram(n_sent)) {
+                Ok(_) => n_sent += 1,
+                Err(e) if n_sent == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(n_sent)
+    }
+
+    /// Split the pushed datagrams into groups which are sent as one buffer each.
+    fn groups(&self) -> Vec<Group> {
+        let mut groups: Vec<Group> = Vec::new();
+        for i in 0..self.len() {
+            let start = if i == 0 { 0 } else { self.ends[i - 1] };
+            let size = self.ends[i] - start;
+            if self.gso {
+                if let Some(group) = groups.last_mut() {
+                    // all segments but the last one must have the same size
+                    let last_size =
+                        group.end - group.start - group.segment_size * (group.count - 1);
+                    if last_size == group.segment_size
+                        && size <= group.segment_size
+                        && group.count < MAX_GSO_SEGMENTS
+                        && self.ends[i] - group.start <= MAX_GSO_BUFSIZE
+                    {
+                        group.end = self.ends[i];
+                        group.count += 1;
+                        continue;
+                    }
+                }
+            }
+            groups.push(Group {
+                start,
+                end: self.ends[i],
+                segment_size: size,
+                count: 1,
+            });
+        }
+        groups
+    }
+
+    /// Receive all datagrams which are available on `socket` without blocking,
+    /// up to a batch size.
+    pub fn recv(&mut self, socket: &UdpSocket, max_datagram_size: usize) -> Vec<Bytes> {
+        if !self.mmsg {
+            return self.recv_each(max_datagram_size, |slot| socket.recv(slot));
+        }
+        let slot_size = if self.gro {
+            MAX_GSO_BUFSIZE.max(max_datagram_size)
+        } else {
+            max_datagram_size
+        };
+        let total = self.reserve(slot_size);
+
+        match sys::recv(socket, &mut self.recv_buf[..total], slot_size, self.gro) {
+            Ok(received) => self.split_received(slot_size, received),
+            Err(e) if sys::is_unsupported(&e) => {
+                log::warn!("Batched receiving failed ({}), falling back.", e);
+                self.mmsg = false;
+                if self.gro {
+                    sys::disable_gro(socket);
+                    self.gro = false;
+                }
+                self.recv_each(max_datagram_size, |slot| socket.recv(slot))
+            }
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Receive datagrams up to a batch size, one call of `recv` per datagram,
+    /// until `recv` fails.
+    pub fn recv_each<F>(&mut self, max_datagram_size: usize, mut recv: F) -> Vec<Bytes>
+    where
+        F: FnMut(&mut [u8]) -> io::Result<usize>,
+    {
+        let total = self.reserve(max_datagram_size);
+        let mut received: Vec<(usize, usize)> = Vec::new();
+        for slot in self.recv_buf[..total].chunks_mut(max_datagram_size) {
+            match recv(slot) {
+                Ok(n_bytes) => received.push((n_bytes, 0)),
+                Err(_) => break,
+            }
+        }
+        self.split_received(max_datagram_size, received)
+    }
+
+    /// Make room for a batch of received datagrams, returns the size of the batch.
+    fn reserve(&mut self, slot_size: usize) -> usize {
+        let total = slot_size * RECV_BATCH;
+        if self.recv_buf.len() < total {
+            self.recv_buf.resize(total, 0);
+        }
+        total
+    }
+
+    /// Copy out the datagrams received into the slots at the front of `recv_buf`,
+    /// given as (length, segment size or 0) for each used slot.
+    fn split_received(&self, slot_size: usize, received: Vec<(usize, usize)>) -> Vec<Bytes> {
+        let region = &self.recv_buf;
+        let mut datagrams = Vec::new();
+        for (i, (len, segment_size)) in received.into_iter().enumerate() {
+            let base = i * slot_size;
+            if segment_size == 0 || segment_size >= len {
+                datagrams.push(Bytes::copy_from_slice(&region[base..base + len]));
+                continue;
+            }
+            // split datagrams which were coalesced by GRO
+            let mut offset = 0;
+            while offset < len {
+                let end = (offset + segment_size).min(len);
+                datagrams.push(Bytes::copy_from_slice(&region[base + offset..base + end]));
+                offset = end;
+            }
+        }
+        datagrams
+    }
+}
+
+/// Consecutive datagrams in `BatchIo::buf` which are sent as one buffer.
+/// All datagrams but the last one are `segment_size` bytes long.
+struct Group {
+    start: usize,
+    end: usize,
+    segment_size: usize,
+    count: usize,
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use super::Group;
+    use std::io;
+    use std::mem;
+    use std::net::UdpSocket;
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+
+    // from linux/udp.h
+    const UDP_SEGMENT: libc::c_int = 103;
+    const UDP_GRO: libc::c_int = 104;
+
+    fn set_udp_option(socket: &UdpSocket, option: libc::c_int, value: libc::c_int) -> bool {
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_UDP,
+                option,
+                &value as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        ret == 0
+    }
+
+    /// Returns wether mmsg calls, GSO and GRO are available.
+    pub fn probe(socket: &UdpSocket) -> (bool, bool, bool) {
+        // setting segment size 0 leaves GSO switched off for regular sends
+        let gso = set_udp_option(socket, UDP_SEGMENT, 0);
+        let gro = set_udp_option(socket, UDP_GRO, 1);
+        (true, gso, gro)
+    }
+
+    pub fn disable_gro(socket: &UdpSocket) {
+        set_udp_option(socket, UDP_GRO, 0);
+    }
+
+    pub fn is_unsupported(e: &io::Error) -> bool {
+        matches!(
+            e.raw_os_error(),
+            Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) | Some(libc::EIO) | Some(libc::EINVAL)
+        )
+    }
+
+    /// Space for one control message carrying a value of type T.
+    fn cmsg_space<T>() -> usize {
+        unsafe { libc::CMSG_SPACE(mem::size_of::<T>() as u32) as usize }
+    }
+
+    /// Returns the number of groups sent.
+    pub fn send(socket: &UdpSocket, buf: &[u8], groups: &[Group]) -> io::Result<usize> {
+        let space = cmsg_space::<u16>();
+        // u64 for proper alignment of the control messages,
+        // CMSG_SPACE is a multiple of it
+        let mut control: Vec<u64> = vec![0; space * groups.len() / 8];
+        let control_ptr = control.as_mut_ptr() as *mut u8;
+        let mut iovecs: Vec<libc::iovec> = groups
+            .iter()
+            .map(|group| libc::iovec {
+                iov_base: buf[group.start..].as_ptr() as *mut libc::c_void,
+                iov_len: group.end - group.start,
+            })
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = Vec::with_capacity(groups.len());
+        for (i, group) in groups.iter().enumerate() {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_iov = &mut iovecs[i];
+            msg.msg_hdr.msg_iovlen = 1;
+            if group.count > 1 {
+                unsafe {
+                    msg.msg_hdr.msg_control = control_ptr.add(i * space) as *mut libc::c_void;
+                    msg.msg_hdr.msg_controllen = space as _;
+                    let cmsg = libc::CMSG_FIRSTHDR(&msg.msg_hdr);
+                    (*cmsg).cmsg_level = libc::SOL_UDP;
+                    (*cmsg).cmsg_type = UDP_SEGMENT;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+                    ptr::write_unaligned(
+                        libc::CMSG_DATA(cmsg) as *mut u16,
+                        group.segment_size as u16,
+                    );
+                }
+            }
+            msgs.push(msg);
+        }
+        let n = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                msgs.len() as libc::c_uint,
+                0,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    /// Receives into consecutive slots of `slot_size` bytes in `buf`.
+    /// Returns length and GRO segment size (or 0) of each used slot.
+    pub fn recv(
+        socket: &UdpSocket,
+        buf: &mut [u8],
+        slot_size: usize,
+        gro: bool,
+    ) -> io::Result<Vec<(usize, usize)>> {
+        let n_slots = buf.len() / slot_size;
+        let space = cmsg_space::<libc::c_int>();
+        let mut control: Vec<u64> = vec![0; space * n_slots / 8];
+        let control_ptr = control.as_mut_ptr() as *mut u8;
+        let mut iovecs: Vec<libc::iovec> = buf
+            .chunks_mut(slot_size)
+            .map(|slot| libc::iovec {
+                iov_base: slot.as_mut_ptr() as *mut libc::c_void,
+                iov_len: slot.len(),
+            })
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = Vec::with_capacity(n_slots);
+        for (i, iovec) in iovecs.iter_mut().enumerate() {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_iov = iovec;
+            msg.msg_hdr.msg_iovlen = 1;
+            if gro {
+                msg.msg_hdr.msg_control =
+                    unsafe { control_ptr.add(i * space) } as *mut libc::c_void;
+                msg.msg_hdr.msg_controllen = space as _;
+            }
+            msgs.push(msg);
+        }
+        let n = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                msgs.len() as libc::c_uint,
+                libc::MSG_DONTWAIT,
+                ptr::null_mut(),
+            )
+        };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::WouldBlock {
+                return Ok(Vec::new());
+            }
+            return Err(e);
+        }
+        let mut received = Vec::with_capacity(n as usize);
+        for msg in &msgs[..n as usize] {
+            let mut segment_size = 0;
+            if gro {
+                unsafe {
+                    let mut cmsg = libc::CMSG_FIRSTHDR(&msg.msg_hdr);
+                    while !cmsg.is_null() {
+                        if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == UDP_GRO {
+                            segment_size =
+                                ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int)
+                                    as usize;
+                        }
+                        cmsg = libc::CMSG_NXTHDR(&msg.msg_hdr, cmsg);
+                    }
+                }
+            }
+            received.push((msg.msg_len as usize, segment_size));
+        }
+        Ok(received)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use super::Group;
+    use std::io;
+    use std::net::UdpSocket;
+
+    pub fn probe(_socket: &UdpSocket) -> (bool, bool, bool) {
+        (false, false, false)
+    }
+
+    pub fn disable_gro(_socket: &UdpSocket) {}
+
+    pub fn is_unsupported(_e: &io::Error) -> bool {
+        true
+    }
+
+    pub fn send(_socket: &UdpSocket, _buf: &[u8], _groups: &[Group]) -> io::Result<usize> {
+        Err(io::ErrorKind::Other.into())
+    }
+
+    pub fn recv(
+        _socket: &UdpSocket,
+        _buf: &mut [u8],
+        _slot_size: usize,
+        _gro: bool,
+    ) -> io::Result<Vec<(usize, usize)>> {
+        Err(io::ErrorKind::Other.into())
+    }
+}
+
+mod test {
+    #[allow(unused)]
+    fn roundtrip(batched: bool) {
+        use super::BatchIo;
+        use std::net::UdpSocket;
+
+        let rx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        tx.connect(rx.local_addr().unwrap()).unwrap();
+        rx.connect(tx.local_addr().unwrap()).unwrap();
+        rx.set_nonblocking(true).unwrap();
+        let (mut a, mut b) = if batched {
+            (BatchIo::new(&tx), BatchIo::new(&rx))
+        } else {
+            (BatchIo::fallback(), BatchIo::fallback())
+        };
+
+        // 20 equal sized datagrams and a shorter one, suitable for GSO
+        let mut sent = Vec::new();
+        for i in 0..20u8 {
+            sent.push(vec![i; 500]);
+        }
+        sent.push(vec![42; 100]);
+        for datagram in &sent {
+            a.push_with(|buf| buf.extend_from_slice(datagram));
+        }
+        assert_eq!(a.send(&tx).unwrap(), sent.len());
+
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let mut received = Vec::new();
+        let mut recv_buf = None;
+        loop {
+            let datagrams = b.recv(&rx, 9000);
+            if datagrams.is_empty() {
+                break;
+            }
+            // the receive buffer is reused for every batch
+            assert_eq!(
+                *recv_buf.get_or_insert(b.recv_buf.as_ptr()),
+                b.recv_buf.as_ptr()
+            );
+            received.extend(datagrams);
+        }
+        assert_eq!(received, sent);
+    }
+
+    #[test]
+    fn batched() {
+        roundtrip(true);
+    }
+
+    #[test]
+    fn fallback() {
+        roundtrip(false);
+    }
+}
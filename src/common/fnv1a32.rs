@@ -9,13 +9,11 @@ extern crate digest;
 #[cfg(feature = "generic")]
 extern crate generic_array;
 
-use crate::common::Cursor;
 use core::hash::Hasher;
 #[cfg(feature = "generic")]
 use digest;
 #[cfg(feature = "generic")]
 use generic_array;
-use std::io::Read;
 
 const FNV_OFFSET: u32 = 0x811C9DC5;
 const FNV_PRIME: u32 = 0x01000193;
@@ -66,21 +64,10 @@ pub struct Fnv32a {
 }
 
 impl Fnv32a {
-    /// return 32bit FNV1a hash of `cursor` between `from` until excluding `to`
-    pub fn hash(cursor: &mut Cursor, from: u64, to: u64) -> u32 {
-        let pos = cursor.position();
-
+    /// return 32bit FNV1a hash of `buf`
+    pub fn hash(buf: &[u8]) -> u32 {
         let mut hasher = Fnv32a::default();
-        let mut byte: [u8; 1] = [0];
-        cursor.set_position(from);
-        for _ in from..to {
-            if cursor.read_exact(&mut byte).is_err() {
-                todo!("Implement error handling.");
-            }
-            hasher.write(&byte);
-        }
-
-        cursor.set_position(pos);
+        hasher.write(buf);
         return hasher.finish() as u32;
     }
 
@@ -0,0 +1,158 @@
+//! Minimal JSON values, for machine readable output such as `rft-dissect --json`.
+//! Only writing is supported.
+
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i128),
+    Float(f64),
+    Str(String),
+    Array(Vec<Json>),
+    /// Members in the order they are written.
+    Object(Vec<(&'static str, Json)>),
+}
+
+impl Json {
+    pub fn object(members: Vec<(&'static str, Json)>) -> Json {
+        Json::Object(members)
+    }
+
+    /// Appends a member, if this is an object.
+    pub fn push(&mut self, key: &'static str, value: Json) {
+        if let Json::Object(members) = self {
+            members.push((key, value));
+        }
+    }
+
+    /// The member `key`, if this is an object which has it.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(k, _)| *k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+}
+
+macro_rules! json_from_int {
+    ($($t:ty),*) => {
+        $(impl From<$t> for Json {
+            fn from(x: $t) -> Json {
+                Json::Int(x as i128)
+            }
+        })*
+    };
+}
+
+json_from_int!(u8, u16, u32, u64, usize, i64);
+
+impl From<bool> for Json {
+    fn from(x: bool) -> Json {
+        Json::Bool(x)
+    }
+}
+
+impl From<f64> for Json {
+    fn from(x: f64) -> Json {
+        Json::Float(x)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(x: &str) -> Json {
+        Json::Str(x.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(x: String) -> Json {
+        Json::Str(x)
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(x: Option<T>) -> Json {
+        match x {
+            Some(x) => x.into(),
+            None => Json::Null,
+        }
+    }
+}
+
+impl<T: Into<Json>> From<Vec<T>> for Json {
+    fn from(x: Vec<T>) -> Json {
+        Json::Array(x.into_iter().map(Into::into).collect())
+    }
+}
+
+fn write_str(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/// Compact JSON on a single line, e.g. for JSON lines.
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(x) => write!(f, "{}", x),
+            Json::Int(x) => write!(f, "{}", x),
+            Json::Float(x) if x.is_finite() => write!(f, "{}", x),
+            Json::Float(_) => write!(f, "null"),
+            Json::Str(x) => write_str(f, x),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_str(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+mod test {
+    #[test]
+    fn display() {
+        use super::*;
+
+        let json = Json::object(vec![
+            ("name", "a \"b\"\n".into()),
+            ("ids", vec![1u64, 2].into()),
+            ("rtt", 0.5.into()),
+            ("peer", Json::from(None::<u8>)),
+            ("ok", true.into()),
+        ]);
+        assert_eq!(
+            json.to_string(),
+            r#"{"name":"a \"b\"\n","ids":[1,2],"rtt":0.5,"peer":null,"ok":true}"#
+        );
+    }
+}
@@ -1,5 +1,5 @@
-use std::error::Error;
-use std::fmt;
+use crate::error::RftError;
+use byteorder::ReadBytesExt;
 use std::io;
 pub use std::str::FromStr;
 
@@ -9,55 +9,90 @@ pub use std::str::FromStr;
 pub mod fnv1a32;
 
 /////////////////////////////////
-// Custom UdpSocket
+// Datagram I/O, see also `transport::datagram`
 
+pub mod batch;
 pub mod mtu;
-pub mod udp;
 
 /////////////////////////////////
-// Basic Types
+// JSON output, e.g. of `dissect`
 
-pub type Cursor = io::Cursor<Vec<u8>>;
+pub mod json;
 
 /////////////////////////////////
-// ReadError
+// Basic Types
 
-#[derive(Debug)]
-pub struct ReadError {
-    what: String,
-}
+/// Reads from a borrowed buffer, e.g. a received datagram.
+/// Decoded messages may borrow from the buffer instead of copying.
+pub type Cursor<'a> = io::Cursor<&'a [u8]>;
 
-impl ReadError {
-    pub fn new(msg: &str) -> ReadError {
-        ReadError {
-            what: msg.to_string(),
-        }
-    }
-}
+/////////////////////////////////
+// WireFormat trait
 
-impl fmt::Display for ReadError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.what)
+/// Serialization of protocol messages.
+/// `write` appends to `buf`, so that a buffer can be reused for many messages.
+/// `read` may keep references into the buffer behind `cursor`.
+///
+/// Counts and lengths are LEB128 encoded, so lists and strings are not limited
+/// by the wire format. Where a message has to fit into a datagram or chunk,
+/// the limit is checked at encode time with `write_limited`.
+///
+/// Most messages derive it with `#[derive(WireFormat)]`, see `wire`.
+pub trait WireFormat<'a> {
+    fn write(&self, buf: &mut Vec<u8>);
+    fn read(&mut self, cursor: &mut Cursor<'a>) -> Result<(), RftError>;
+
+    /// Like `write`, but fails and leaves `buf` as it was if the message
+    /// takes more than `max_len` bytes.
+    fn write_limited(&self, buf: &mut Vec<u8>, max_len: usize) -> Result<(), RftError> {
+        let start = buf.len();
+        self.write(buf);
+        let len = buf.len() - start;
+        if len > max_len {
+            buf.truncate(start);
+            return Err(RftError::Encode(format!(
+                "Message of {} bytes exceeds the limit of {} bytes.",
+                len, max_len
+            )));
+        }
+        Ok(())
     }
 }
 
-impl Error for ReadError {
-    fn description(&self) -> &str {
-        &self.what
-    }
-}
-
-pub enum ReadResult {
-    Ok,
-    Err(ReadError),
-}
+pub use rft_derive::WireFormat;
 
 /////////////////////////////////
-// WireFormat trait
-
-pub trait WireFormat {
-    fn write(&self, cursor: &mut Cursor);
-    fn read(&mut self, cursor: &mut Cursor) -> ReadResult;
+// Unknown TLVs
+
+/// Set in the type code of TLVs which the receiver must understand.
+/// Receivers skip TLVs with unknown type codes via their length field,
+/// unless this bit is set. So new messages can be added without breaking
+/// older peers, as long as they may be ignored.
+pub const TLV_CRITICAL_BIT: u8 = 0x80;
+
+/// Skip the TLV at `cursor`, whose type code is unknown to the caller.
+/// Returns its type code, or an error if it is critical or truncated.
+pub fn skip_unknown_tlv(cursor: &mut Cursor) -> Result<u8, RftError> {
+    let type_code = match cursor.read_u8() {
+        Ok(x) => x,
+        Err(_) => return Err(RftError::decode(cursor.position(), "Truncated TLV header.")),
+    };
+    if type_code & TLV_CRITICAL_BIT != 0 {
+        return Err(RftError::decode(
+            cursor.position() - 1,
+            format!("Unknown critical message type code {}!", type_code).as_str(),
+        ));
+    }
+    let length = match leb128::read::unsigned(cursor) {
+        Ok(x) => x,
+        Err(_) => return Err(RftError::decode(cursor.position(), "Truncated TLV header.")),
+    };
+    let remaining = cursor.get_ref().len() as u64 - cursor.position();
+    if length > remaining {
+        return Err(RftError::decode(cursor.position(), "Truncated TLV.").in_tlv(type_code));
+    }
+    cursor.set_position(cursor.position() + length);
+    Ok(type_code)
 }
 
 /////////////////////////////////
@@ -67,8 +102,13 @@ macro_rules! read_u8 {
     ($cursor:ident) => {
         match $cursor.read_u8() {
             Ok(x) => x,
-            Err(e) => return ReadResult::Err(ReadError::new(&e.to_string())),
-        };
+            Err(e) => {
+                return Err($crate::error::RftError::decode(
+                    $cursor.position(),
+                    &e.to_string(),
+                ))
+            }
+        }
     };
 }
 
@@ -76,8 +116,13 @@ macro_rules! read_u16 {
     ($cursor:ident) => {
         match $cursor.read_u16::<NetworkEndian>() {
             Ok(x) => x,
-            Err(e) => return ReadResult::Err(ReadError::new(&e.to_string())),
-        };
+            Err(e) => {
+                return Err($crate::error::RftError::decode(
+                    $cursor.position(),
+                    &e.to_string(),
+                ))
+            }
+        }
     };
 }
 
@@ -85,8 +130,13 @@ macro_rules! read_u32 {
     ($cursor:ident) => {
         match $cursor.read_u32::<NetworkEndian>() {
             Ok(x) => x,
-            Err(e) => return ReadResult::Err(ReadError::new(&e.to_string())),
-        };
+            Err(e) => {
+                return Err($crate::error::RftError::decode(
+                    $cursor.position(),
+                    &e.to_string(),
+                ))
+            }
+        }
     };
 }
 
@@ -94,8 +144,13 @@ macro_rules! read_u64 {
     ($cursor:ident) => {
         match $cursor.read_u64::<NetworkEndian>() {
             Ok(x) => x,
-            Err(e) => return ReadResult::Err(ReadError::new(&e.to_string())),
-        };
+            Err(e) => {
+                return Err($crate::error::RftError::decode(
+                    $cursor.position(),
+                    &e.to_string(),
+                ))
+            }
+        }
     };
 }
 
@@ -103,8 +158,13 @@ macro_rules! read_u128 {
     ($cursor:ident) => {
         match leb128::read::unsigned($cursor) {
             Ok(x) => x,
-            Err(e) => return ReadResult::Err(ReadError::new(&e.to_string())),
-        };
+            Err(e) => {
+                return Err($crate::error::RftError::decode(
+                    $cursor.position(),
+                    &e.to_string(),
+                ))
+            }
+        }
     };
 }
 
@@ -112,37 +172,86 @@ macro_rules! read_i128 {
     ($cursor:ident) => {
         match leb128::read::signed($cursor) {
             Ok(x) => x,
-            Err(e) => return ReadResult::Err(ReadError::new(&e.to_string())),
-        };
+            Err(e) => {
+                return Err($crate::error::RftError::decode(
+                    $cursor.position(),
+                    &e.to_string(),
+                ))
+            }
+        }
     };
 }
 
+/// Read a LEB128 count or length. As every item takes at least one byte,
+/// it can't exceed the bytes left behind `cursor`.
+macro_rules! read_len {
+    ($cursor:ident) => {{
+        let len = read_u128!($cursor);
+        if len > ($cursor.get_ref().len() as u64).saturating_sub($cursor.position()) {
+            return Err($crate::error::RftError::decode(
+                $cursor.position(),
+                "Count or length exceeds the message.",
+            ));
+        }
+        len as usize
+    }};
+}
+
+macro_rules! read_slice {
+    ($cursor:ident, $len:expr) => {{
+        let buf: &[u8] = *$cursor.get_ref();
+        let start = $cursor.position() as usize;
+        let end = start + $len as usize;
+        if end > buf.len() {
+            return Err($crate::error::RftError::decode(
+                $cursor.position(),
+                "failed to fill whole buffer",
+            ));
+        }
+        $cursor.set_position(end as u64);
+        &buf[start..end]
+    }};
+}
+
+/// Read a TLV of type `$type_code` with `$read_block`, which must consume
+/// exactly the value. The length of the value is bound to `$length`, if given.
 macro_rules! read_tlv {
     ($cursor:ident, $type_code:expr, $read_block:block) => {
-        assert_eq!(read_u8!($cursor), $type_code as u8);
-        let length = read_u16!($cursor) as u64;
+        read_tlv!($cursor, $type_code, _length, $read_block)
+    };
+    ($cursor:ident, $type_code:expr, $length:ident, $read_block:block) => {
+        if read_u8!($cursor) != $type_code as u8 {
+            return Err($crate::error::RftError::decode(
+                $cursor.position(),
+                "Unexpected message type code.",
+            ));
+        }
+        let $length = read_len!($cursor) as u64;
         let mut final_length = $cursor.position();
         $read_block
         // Validate length field
         final_length = $cursor.position() - final_length;
-        if length != final_length {
-            return ReadResult::Err(ReadError::new("Object header length mismatch!"));
+        if $length != final_length {
+            return Err($crate::error::RftError::decode(
+                $cursor.position(),
+                "Object header length mismatch!",
+            )
+            .in_tlv($type_code as u8));
         }
     };
 }
 
 macro_rules! read_str {
     ($cursor:ident) => {{
-        let mut buf_len = read_u128!($cursor);
-        let mut buf = Vec::new();
-        buf.reserve(buf_len as usize);
-        while buf_len > 0 {
-            buf.push(read_u8!($cursor));
-            buf_len -= 1;
-        }
-        match String::from_utf8(buf) {
-            Ok(val) => val,
-            _ => return ReadResult::Err(ReadError::new("")),
+        let buf_len = read_len!($cursor);
+        match std::str::from_utf8(read_slice!($cursor, buf_len)) {
+            Ok(val) => val.to_string(),
+            _ => {
+                return Err($crate::error::RftError::decode(
+                    $cursor.position(),
+                    "Invalid UTF-8.",
+                ))
+            }
         }
     }};
 }
@@ -192,26 +301,35 @@ macro_rules! write_i128 {
     };
 }
 
+/// Write a LEB128 count or length.
+macro_rules! write_len {
+    ($cursor:ident, $value:expr) => {
+        write_u128!($cursor, $value as u64)
+    };
+}
+
+/// Write a TLV with the value written by `$write_block`. Room for the LEB128
+/// length is reserved before the value and filled in once it is known, see
+/// `wire::finish_tlv()`.
 macro_rules! write_tlv {
     ($cursor:ident, $type_code:expr, $write_block:block) => {{
         write_u8!($cursor, $type_code as u8);
-        let mut length = $cursor.position();
-        write_u16!($cursor, 0);
+        let length_pos = $cursor.len();
+        $cursor.extend_from_slice(&[0; crate::common::wire::TLV_LENGTH_RESERVED]);
         $write_block
-        length = $cursor.position() - length;
-        $cursor.seek(SeekFrom::Current(-(length as i64))).expect("seek failed.");
-        length -= 2; // -2 bc. of 2B length-field length
-        write_u16!($cursor, length as u16);
-        $cursor.seek(SeekFrom::Current(length as i64)).expect("seek failed.");
+        crate::common::wire::finish_tlv($cursor, length_pos);
     }};
 }
 
 macro_rules! write_str {
     ($cursor:ident, $value:expr) => {
-        let buf = $value.clone().into_bytes();
-        write_u128!($cursor, buf.len() as u64);
-        if $cursor.write(&buf).is_err() {
-            todo!("Implement error handling.")
-        }
+        let buf = $value.as_bytes();
+        write_len!($cursor, buf.len());
+        $cursor.extend_from_slice(buf);
     };
 }
+
+/////////////////////////////////
+// Derived WireFormat, after the macros it uses
+
+pub mod wire;
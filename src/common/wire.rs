@@ -0,0 +1,197 @@
+//! Building blocks of `#[derive(WireFormat)]`: the `WireFormat` of the types
+//! fields may have, and helpers for TLVs and LEB128 integers.
+//!
+//! Unsigned integers are written in their fixed width in network byte order,
+//! unless the field is marked `#[wire(leb128)]`. The only signed integers on
+//! the wire are chunk ids, which are signed LEB128. Strings and vectors are
+//! prefixed by their LEB128 length.
+
+use crate::common::*;
+use crate::error::RftError;
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+
+/////////////////////////////////
+// Helpers
+
+pub fn read_byte(cursor: &mut Cursor) -> Result<u8, RftError> {
+    match cursor.read_u8() {
+        Ok(x) => Ok(x),
+        Err(e) => Err(RftError::decode(cursor.position(), &e.to_string())),
+    }
+}
+
+pub fn write_leb128(buf: &mut Vec<u8>, value: u64) {
+    write_u128!(buf, value);
+}
+
+pub fn read_leb128(cursor: &mut Cursor) -> Result<u64, RftError> {
+    match leb128::read::unsigned(cursor) {
+        Ok(x) => Ok(x),
+        Err(e) => Err(RftError::decode(cursor.position(), &e.to_string())),
+    }
+}
+
+/// Bytes reserved for the length of a TLV by `write_tlv!`. Most values, e.g.
+/// chunks, are 128 to 16383 bytes long, which takes 2 bytes in LEB128.
+pub const TLV_LENGTH_RESERVED: usize = 2;
+
+/// Fills in the LEB128 length of the TLV value which was written after the
+/// `TLV_LENGTH_RESERVED` bytes at `length_pos`. The value is only moved if
+/// its length takes another number of bytes: back for short values, which
+/// is cheap, and forth for values of 16 KiB or more, which are rare.
+pub fn finish_tlv(buf: &mut Vec<u8>, length_pos: usize) {
+    let value_pos = length_pos + TLV_LENGTH_RESERVED;
+    let mut length = Vec::with_capacity(TLV_LENGTH_RESERVED);
+    write_leb128(&mut length, (buf.len() - value_pos) as u64);
+    if length.len() == TLV_LENGTH_RESERVED {
+        buf[length_pos..value_pos].copy_from_slice(&length);
+    } else if length.len() < TLV_LENGTH_RESERVED {
+        let end = length_pos + length.len();
+        buf[length_pos..end].copy_from_slice(&length);
+        buf.copy_within(value_pos.., end);
+        buf.truncate(buf.len() - (TLV_LENGTH_RESERVED - length.len()));
+    } else {
+        buf.splice(length_pos..value_pos, length);
+    }
+}
+
+/// Write a TLV with the value written by `write`, like `write_tlv!`.
+pub fn write_tlv<F: FnOnce(&mut Vec<u8>)>(buf: &mut Vec<u8>, type_code: u8, write: F) {
+    write_tlv!(buf, type_code, {
+        write(buf);
+    });
+}
+
+/// Read a TLV with the value read by `read`, like `read_tlv!`.
+pub fn read_tlv<'a, F: FnOnce(&mut Cursor<'a>) -> Result<(), RftError>>(
+    cursor: &mut Cursor<'a>,
+    type_code: u8,
+    read: F,
+) -> Result<(), RftError> {
+    read_tlv!(cursor, type_code, {
+        if let Err(e) = read(cursor) {
+            return Err(e.in_tlv(type_code));
+        }
+    });
+    Ok(())
+}
+
+/////////////////////////////////
+// Integers
+
+impl<'a> WireFormat<'a> for u8 {
+    fn write(&self, buf: &mut Vec<u8>) {
+        write_u8!(buf, *self);
+    }
+
+    fn read(&mut self, cursor: &mut Cursor<'a>) -> Result<(), RftError> {
+        *self = read_u8!(cursor);
+        Ok(())
+    }
+}
+
+impl<'a> WireFormat<'a> for u16 {
+    fn write(&self, buf: &mut Vec<u8>) {
+        write_u16!(buf, *self);
+    }
+
+    fn read(&mut self, cursor: &mut Cursor<'a>) -> Result<(), RftError> {
+        *self = read_u16!(cursor);
+        Ok(())
+    }
+}
+
+impl<'a> WireFormat<'a> for u32 {
+    fn write(&self, buf: &mut Vec<u8>) {
+        write_u32!(buf, *self);
+    }
+
+    fn read(&mut self, cursor: &mut Cursor<'a>) -> Result<(), RftError> {
+        *self = read_u32!(cursor);
+        Ok(())
+    }
+}
+
+impl<'a> WireFormat<'a> for u64 {
+    fn write(&self, buf: &mut Vec<u8>) {
+        write_u64!(buf, *self);
+    }
+
+    fn read(&mut self, cursor: &mut Cursor<'a>) -> Result<(), RftError> {
+        *self = read_u64!(cursor);
+        Ok(())
+    }
+}
+
+impl<'a> WireFormat<'a> for i64 {
+    fn write(&self, buf: &mut Vec<u8>) {
+        write_i128!(buf, *self);
+    }
+
+    fn read(&mut self, cursor: &mut Cursor<'a>) -> Result<(), RftError> {
+        *self = read_i128!(cursor);
+        Ok(())
+    }
+}
+
+/////////////////////////////////
+// Strings and vectors
+
+impl<'a> WireFormat<'a> for String {
+    fn write(&self, buf: &mut Vec<u8>) {
+        write_str!(buf, self);
+    }
+
+    fn read(&mut self, cursor: &mut Cursor<'a>) -> Result<(), RftError> {
+        *self = read_str!(cursor);
+        Ok(())
+    }
+}
+
+impl<'a, T: WireFormat<'a> + Default> WireFormat<'a> for Vec<T> {
+    fn write(&self, buf: &mut Vec<u8>) {
+        write_len!(buf, self.len());
+        for item in self {
+            item.write(buf);
+        }
+    }
+
+    fn read(&mut self, cursor: &mut Cursor<'a>) -> Result<(), RftError> {
+        let len = read_len!(cursor);
+        *self = Vec::with_capacity(len);
+        for _ in 0..len {
+            let mut item = T::default();
+            item.read(cursor)?;
+            self.push(item);
+        }
+        Ok(())
+    }
+}
+
+impl<'a, A: WireFormat<'a>, B: WireFormat<'a>> WireFormat<'a> for (A, B) {
+    fn write(&self, buf: &mut Vec<u8>) {
+        self.0.write(buf);
+        self.1.write(buf);
+    }
+
+    fn read(&mut self, cursor: &mut Cursor<'a>) -> Result<(), RftError> {
+        self.0.read(cursor)?;
+        self.1.read(cursor)
+    }
+}
+
+mod test {
+    #[test]
+    fn tlv_length() {
+        use super::*;
+
+        for &len in &[0, 127, 128, 16383, 16384] {
+            let mut buf = vec![0xff];
+            write_tlv(&mut buf, 0x42, |buf| buf.resize(buf.len() + len, 7));
+            let mut expected = vec![0xff, 0x42];
+            write_leb128(&mut expected, len as u64);
+            expected.resize(expected.len() + len, 7);
+            assert_eq!(buf, expected);
+        }
+    }
+}
@@ -0,0 +1,206 @@
+//! Reading UDP datagrams from pcap captures, e.g. of `tcpdump -w`, and from hex dumps.
+
+use super::Datagram;
+use crate::error::RftError;
+use byteorder::{BigEndian, ByteOrder, LittleEndian, NetworkEndian};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const PCAP_HEADER_LEN: usize = 24;
+const PCAP_RECORD_HEADER_LEN: usize = 16;
+
+// Link types of the captured packets
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const IP_PROTOCOL_UDP: u8 = 17;
+
+/// Datagrams in `data`, which is a pcap capture if it starts with the pcap
+/// magic number, otherwise a hex dump, see `read_hex`.
+pub fn read_capture(data: &[u8]) -> Result<Vec<Datagram>, RftError> {
+    if data.len() >= 4 {
+        let magics = [PCAP_MAGIC, PCAP_MAGIC_NANOS];
+        if magics.contains(&LittleEndian::read_u32(data))
+            || magics.contains(&BigEndian::read_u32(data))
+        {
+            return read_pcap(data);
+        }
+    }
+    match std::str::from_utf8(data) {
+        Ok(text) => read_hex(text),
+        Err(e) => Err(RftError::decode(
+            e.valid_up_to() as u64,
+            "Neither a pcap capture nor a hex dump.",
+        )),
+    }
+}
+
+/// UDP datagrams of a pcap capture. Other packets, and fragmented ones, are skipped.
+/// Times are relative to the first packet.
+pub fn read_pcap(data: &[u8]) -> Result<Vec<Datagram>, RftError> {
+    if data.len() < PCAP_HEADER_LEN {
+        return Err(RftError::decode(0, "Truncated pcap header."));
+    }
+    let little_endian = [PCAP_MAGIC, PCAP_MAGIC_NANOS].contains(&LittleEndian::read_u32(data));
+    let read_u32 = |buf: &[u8]| {
+        if little_endian {
+            LittleEndian::read_u32(buf)
+        } else {
+            BigEndian::read_u32(buf)
+        }
+    };
+    let nanos = read_u32(data) == PCAP_MAGIC_NANOS;
+    let link_type = read_u32(&data[20..]);
+
+    let mut datagrams = Vec::new();
+    let mut first_time = None;
+    let mut pos = PCAP_HEADER_LEN;
+    while pos < data.len() {
+        if data.len() - pos < PCAP_RECORD_HEADER_LEN {
+            return Err(RftError::decode(
+                pos as u64,
+                "Truncated pcap record header.",
+            ));
+        }
+        let header = &data[pos..];
+        let fraction = read_u32(&header[4..]) as f64 / if nanos { 1e9 } else { 1e6 };
+        let time = read_u32(header) as f64 + fraction;
+        let captured_len = read_u32(&header[8..]) as usize;
+        pos += PCAP_RECORD_HEADER_LEN;
+        if data.len() - pos < captured_len {
+            return Err(RftError::decode(pos as u64, "Truncated pcap record."));
+        }
+        let packet = &data[pos..pos + captured_len];
+        pos += captured_len;
+
+        let first_time = *first_time.get_or_insert(time);
+        if let Some(mut datagram) = udp_datagram(link_type, packet, little_endian) {
+            datagram.time = Some(time - first_time);
+            datagrams.push(datagram);
+        }
+    }
+    Ok(datagrams)
+}
+
+/// Datagrams in a hex dump with one datagram per line, e.g. of
+/// `tshark -T fields -e udp.payload`. Bytes may be separated by whitespace
+/// or colons, `#` starts a comment.
+pub fn read_hex(text: &str) -> Result<Vec<Datagram>, RftError> {
+    let mut datagrams = Vec::new();
+    let mut line_start = 0;
+    for line in text.split('\n') {
+        let offset = line_start as u64;
+        line_start += line.len() + 1;
+        let line = line.split('#').next().unwrap_or_default();
+        let digits: Vec<u8> = line
+            .bytes()
+            .filter(|c| !c.is_ascii_whitespace() && *c != b':')
+            .collect();
+        if digits.is_empty() {
+            continue;
+        }
+        let invalid = || RftError::decode(offset, "Invalid hex dump.");
+        if !digits.len().is_multiple_of(2) {
+            return Err(invalid());
+        }
+        let mut payload = Vec::with_capacity(digits.len() / 2);
+        for pair in digits.chunks(2) {
+            let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+            payload.push(u8::from_str_radix(pair, 16).map_err(|_| invalid())?);
+        }
+        datagrams.push(Datagram {
+            payload,
+            ..Datagram::default()
+        });
+    }
+    Ok(datagrams)
+}
+
+/// The UDP datagram in a captured packet, if it is one.
+fn udp_datagram(link_type: u32, packet: &[u8], little_endian: bool) -> Option<Datagram> {
+    let ip = match link_type {
+        LINKTYPE_NULL => {
+            // address family in the byte order of the capturing host
+            let family = if little_endian {
+                LittleEndian::read_u32(packet.get(..4)?)
+            } else {
+                BigEndian::read_u32(packet.get(..4)?)
+            };
+            match family {
+                2 | 24 | 28 | 30 => packet.get(4..)?,
+                _ => return None,
+            }
+        }
+        LINKTYPE_ETHERNET => {
+            let mut ethertype_pos = 12;
+            let mut ethertype = NetworkEndian::read_u16(packet.get(12..14)?);
+            if ethertype == ETHERTYPE_VLAN {
+                ethertype_pos += 4;
+                ethertype = NetworkEndian::read_u16(packet.get(16..18)?);
+            }
+            match ethertype {
+                ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => packet.get(ethertype_pos + 2..)?,
+                _ => return None,
+            }
+        }
+        LINKTYPE_LINUX_SLL => packet.get(16..)?,
+        LINKTYPE_LINUX_SLL2 => packet.get(20..)?,
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => packet,
+        _ => return None,
+    };
+    let (src, dst, udp) = match ip.first()? >> 4 {
+        4 => {
+            let header_len = (ip[0] & 0x0f) as usize * 4;
+            let fragment = NetworkEndian::read_u16(ip.get(6..8)?);
+            // more fragments or a fragment offset
+            if *ip.get(9)? != IP_PROTOCOL_UDP || fragment & 0x3fff != 0 {
+                return None;
+            }
+            let mut src = [0; 4];
+            let mut dst = [0; 4];
+            src.copy_from_slice(ip.get(12..16)?);
+            dst.copy_from_slice(ip.get(16..20)?);
+            (
+                IpAddr::V4(Ipv4Addr::from(src)),
+                IpAddr::V4(Ipv4Addr::from(dst)),
+                ip.get(header_len..)?,
+            )
+        }
+        6 => {
+            // extension headers are not supported
+            if *ip.get(6)? != IP_PROTOCOL_UDP {
+                return None;
+            }
+            let mut src = [0; 16];
+            let mut dst = [0; 16];
+            src.copy_from_slice(ip.get(8..24)?);
+            dst.copy_from_slice(ip.get(24..40)?);
+            (
+                IpAddr::V6(Ipv6Addr::from(src)),
+                IpAddr::V6(Ipv6Addr::from(dst)),
+                ip.get(40..)?,
+            )
+        }
+        _ => return None,
+    };
+    let src_port = NetworkEndian::read_u16(udp.get(0..2)?);
+    let dst_port = NetworkEndian::read_u16(udp.get(2..4)?);
+    let udp_len = NetworkEndian::read_u16(udp.get(4..6)?) as usize;
+    // the capture may be truncated to its snap length
+    let payload = udp.get(8..udp_len.min(udp.len()))?;
+    Some(Datagram {
+        time: None,
+        src: Some(SocketAddr::new(src, src_port)),
+        dst: Some(SocketAddr::new(dst, dst_port)),
+        payload: payload.to_vec(),
+    })
+}
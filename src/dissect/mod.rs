@@ -0,0 +1,492 @@
+//! Decoding of captured datagrams for troubleshooting, see the `rft-dissect` binary.
+//!
+//! Each datagram is decoded as a `MessageFrame`, and the chunks it carries
+//! with `app::frame::parse`. The result is a `Json` description, which is
+//! printed as is or as text by `to_text`.
+//!
+//! The checksum algorithm is negotiated and not transmitted, so every one is
+//! tried. Frames with an invalid checksum are decoded nevertheless. Sealed
+//! frames can't be decoded without their keys.
+
+pub mod capture;
+
+use crate::app::frame::*;
+use crate::common::json::Json;
+use crate::common::{Cursor, WireFormat};
+use crate::transport::frame::*;
+use byteorder::{ByteOrder, NetworkEndian};
+use num::FromPrimitive;
+use std::net::SocketAddr;
+
+/// A captured UDP datagram.
+#[derive(Default, Debug, PartialEq, Clone)]
+pub struct Datagram {
+    /// Seconds since the first packet of the capture, if known.
+    pub time: Option<f64>,
+    pub src: Option<SocketAddr>,
+    pub dst: Option<SocketAddr>,
+    pub payload: Vec<u8>,
+}
+
+/// Length of the version and session id in front of every frame.
+const FRAME_HEADER_LEN: usize = 9;
+const CHECKSUM_LEN: usize = 4;
+
+const CHECKSUM_ALGORITHMS: [ChecksumAlgorithm; 3] = [
+    ChecksumAlgorithm::Fnv1a32,
+    ChecksumAlgorithm::Crc32c,
+    ChecksumAlgorithm::XxHash32,
+];
+
+/// The algorithm of the trailing checksum of `frame`, None if it is invalid.
+pub fn checksum_algorithm(frame: &[u8]) -> Option<ChecksumAlgorithm> {
+    if frame.len() < CHECKSUM_LEN {
+        return None;
+    }
+    let (body, checksum) = frame.split_at(frame.len() - CHECKSUM_LEN);
+    let checksum = NetworkEndian::read_u32(checksum);
+    CHECKSUM_ALGORITHMS
+        .iter()
+        .copied()
+        .find(|algorithm| algorithm.hash(body) == checksum)
+}
+
+/// Describes the frame in `datagram`, as far as it can be decoded.
+pub fn dissect(datagram: &Datagram) -> Json {
+    let data = &datagram.payload;
+    let checksum = checksum_algorithm(data);
+    let mut json = Json::object(vec![
+        ("time", datagram.time.into()),
+        ("src", datagram.src.map(|addr| addr.to_string()).into()),
+        ("dst", datagram.dst.map(|addr| addr.to_string()).into()),
+        ("length", data.len().into()),
+        ("checksum", checksum.map(|c| format!("{:?}", c)).into()),
+        ("checksum_valid", checksum.is_some().into()),
+    ]);
+
+    // decode a frame with an invalid checksum as if it were valid
+    let mut frame = MessageFrame::default();
+    let mut patched;
+    let data = match checksum {
+        Some(algorithm) => {
+            frame.checksum = algorithm;
+            &data[..]
+        }
+        None if data.len() >= CHECKSUM_LEN => {
+            patched = data.clone();
+            let body_len = data.len() - CHECKSUM_LEN;
+            let fixed = frame.checksum.hash(&patched[..body_len]);
+            NetworkEndian::write_u32(&mut patched[body_len..], fixed);
+            &patched[..]
+        }
+        None => &data[..],
+    };
+    match frame.read(&mut Cursor::new(data)) {
+        Ok(()) => {
+            json.push("version", frame.version.into());
+            json.push("sid", frame.sid.into());
+            json.push("tlvs", Json::Array(frame.tlvs.iter().map(tlv).collect()));
+            if !frame.skipped_tlvs.is_empty() {
+                json.push("skipped_tlvs", frame.skipped_tlvs.clone().into());
+            }
+        }
+        Err(e) => {
+            if data.len() >= FRAME_HEADER_LEN {
+                json.push("version", data[0].into());
+                json.push("sid", NetworkEndian::read_u64(&data[1..]).into());
+            }
+            json.push("error", e.to_string().into());
+            if checksum.is_none() {
+                json.push("note", "not an RFT frame, or sealed".into());
+            }
+        }
+    }
+    json
+}
+
+fn object_chunks(ids: &[(ObjectId, ChunkId)]) -> Json {
+    Json::Array(
+        ids.iter()
+            .map(|(object_id, chunk_id)| vec![Json::from(*object_id), Json::from(*chunk_id)].into())
+            .collect(),
+    )
+}
+
+fn tlv(tlv: &Tlv) -> Json {
+    match tlv {
+        Tlv::HostInformation(info) => Json::object(vec![
+            ("type", "HostInformation".into()),
+            ("rcv_window_size", info.rcv_window_size.into()),
+            ("out_of_order_limit", info.out_of_order_limit.into()),
+            ("os", format!("{:?}", info.os).into()),
+            (
+                "app",
+                info.app.as_ref().map(|app| format!("{:?}", app)).into(),
+            ),
+            ("app_ver", info.app_ver.into()),
+            ("capabilities", format!("{:?}", info.capabilities).into()),
+        ]),
+        Tlv::ObjectHeader(header) => Json::object(vec![
+            ("type", "ObjectHeader".into()),
+            ("object_id", header.object_id.into()),
+            ("object_type", object_type(header.object_type)),
+            ("num_chunks", header.num_chunks.into()),
+            ("ack_req", header.ack_req.into()),
+            (
+                "fields",
+                Json::Array(
+                    header
+                        .fields
+                        .iter()
+                        .map(|field| {
+                            Json::object(vec![
+                                ("field_type", field.field_type.into()),
+                                ("length", field.length.into()),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+        ]),
+        Tlv::ObjectChunk(chunk) => Json::object(vec![
+            ("type", "ObjectChunk".into()),
+            ("object_id", chunk.object_id.into()),
+            ("chunk_id", chunk.chunk_id.into()),
+            ("more_chunks", chunk.more_chunks.into()),
+            ("ack_required", chunk.ack_required.into()),
+            ("data_length", chunk.data.len().into()),
+            ("app_tlvs", app_tlvs(chunk.data, chunk.num_enclosed_msgs)),
+        ]),
+        Tlv::ObjectSkip(skip) => Json::object(vec![
+            ("type", "ObjectSkip".into()),
+            ("skip", format!("{:?}", skip).into()),
+        ]),
+        Tlv::ObjectAck(ack) => Json::object(vec![
+            ("type", "ObjectAck".into()),
+            ("acks", object_chunks(&ack.acknowledged_object_chunks)),
+        ]),
+        Tlv::ObjectAckRequest(request) => Json::object(vec![
+            ("type", "ObjectAckRequest".into()),
+            ("requests", object_chunks(&request.req_ack_object_chunks)),
+        ]),
+        Tlv::ErrorMessage(err) => Json::object(vec![
+            ("type", "ErrorMessage".into()),
+            ("code", format!("{:?}", err.code).into()),
+            ("detail", format!("{:?}", err.detail).into()),
+        ]),
+        Tlv::Authentication(auth) => Json::object(vec![
+            ("type", "Authentication".into()),
+            ("user", auth.user.as_str().into()),
+            ("method", format!("{:?}", auth.method).into()),
+            ("proof_length", auth.proof.len().into()),
+        ]),
+        Tlv::RetryCookie(retry) => Json::object(vec![
+            ("type", "RetryCookie".into()),
+            ("cookie_length", retry.cookie.len().into()),
+        ]),
+        Tlv::AuthenticationAck(ack) => Json::object(vec![
+            ("type", "AuthenticationAck".into()),
+            ("user", ack.user.as_str().into()),
+        ]),
+    }
+}
+
+fn object_type(code: ObjectType) -> Json {
+    match AppObjectType::from_u8(code) {
+        Some(object_type) => format!("{:?}", object_type).into(),
+        None => code.into(),
+    }
+}
+
+/// The application TLVs in the data of a chunk.
+fn app_tlvs(data: &[u8], num_tlvs: u8) -> Json {
+    let mut cursor = Cursor::new(data);
+    let mut tlvs = Vec::new();
+    for _ in 0..num_tlvs {
+        match parse(&mut cursor) {
+            AppTlvParseResult::Ok(tlv) => tlvs.push(app_tlv(&tlv)),
+            AppTlvParseResult::Skipped(type_code) => tlvs.push(Json::object(vec![
+                ("type", "Unknown".into()),
+                ("type_code", type_code.into()),
+            ])),
+            AppTlvParseResult::Err(e) => {
+                tlvs.push(Json::object(vec![("error", e.to_string().into())]));
+                break;
+            }
+        }
+    }
+    Json::Array(tlvs)
+}
+
+fn hex(bytes: &[u8]) -> Json {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>()
+        .into()
+}
+
+fn metadata_entry(entry: &MetadataEntry) -> Json {
+    let content = match entry.code {
+        MetadataEntryType::FileName | MetadataEntryType::FilePath => {
+            String::from_utf8_lossy(&entry.content).into_owned().into()
+        }
+        MetadataEntryType::FileSize | MetadataEntryType::NumChunks if entry.content.len() == 8 => {
+            NetworkEndian::read_u64(&entry.content).into()
+        }
+        _ => hex(&entry.content),
+    };
+    Json::object(vec![
+        ("code", format!("{:?}", entry.code).into()),
+        ("content", content),
+    ])
+}
+
+fn app_tlv(tlv: &AppTlv) -> Json {
+    match tlv {
+        AppTlv::FileRequest(request) => Json::object(vec![
+            ("type", "FileRequest".into()),
+            ("paths", request.file_paths.clone().into()),
+        ]),
+        AppTlv::FileResume(resume) => Json::object(vec![
+            ("type", "FileResume".into()),
+            ("files", object_chunks(&resume.file_ids_and_chunk_ids)),
+        ]),
+        AppTlv::FileMetadata(metadata) => Json::object(vec![
+            ("type", "FileMetadata".into()),
+            (
+                "entries",
+                Json::Array(
+                    metadata
+                        .metadata_entries
+                        .iter()
+                        .map(metadata_entry)
+                        .collect(),
+                ),
+            ),
+        ]),
+        AppTlv::FileContent(content) => Json::object(vec![
+            ("type", "FileContent".into()),
+            ("length", content.content.len().into()),
+        ]),
+        AppTlv::ApplicationError(err) => Json::object(vec![
+            ("type", "ApplicationError".into()),
+            ("code", format!("{:?}", err.error_code).into()),
+            ("data", format!("{:?}", err.error_data).into()),
+        ]),
+        AppTlv::FileListRequest(request) => Json::object(vec![
+            ("type", "FileListRequest".into()),
+            ("path", request.path.as_str().into()),
+            ("level_of_recursion", request.level_of_recursion.into()),
+            ("format_code", request.format_code.into()),
+        ]),
+        AppTlv::FileListResponse(response) => Json::object(vec![
+            ("type", "FileListResponse".into()),
+            (
+                "entries",
+                Json::Array(
+                    response
+                        .file_list_entries
+                        .iter()
+                        .map(|entry| format!("{:?}", entry).into())
+                        .collect(),
+                ),
+            ),
+        ]),
+    }
+}
+
+/////////////////////////////////
+// Text output
+
+/// Wether `json` is printed on a single line.
+fn is_inline(json: &Json) -> bool {
+    match json {
+        Json::Object(_) => false,
+        Json::Array(items) => items.iter().all(is_inline),
+        _ => true,
+    }
+}
+
+fn inline(json: &Json) -> String {
+    match json {
+        Json::Null => "-".to_string(),
+        Json::Str(s) => s.clone(),
+        Json::Float(x) => format!("{:.6}", x),
+        Json::Array(items) => {
+            let items: Vec<String> = items.iter().map(inline).collect();
+            format!("[{}]", items.join(", "))
+        }
+        json => json.to_string(),
+    }
+}
+
+fn write_text(out: &mut String, json: &Json, indent: usize) {
+    let pad = " ".repeat(indent);
+    match json {
+        Json::Object(members) => {
+            for (key, value) in members {
+                if is_inline(value) {
+                    out.push_str(&format!("{}{}: {}\n", pad, key, inline(value)));
+                } else {
+                    out.push_str(&format!("{}{}:\n", pad, key));
+                    write_text(out, value, indent + 2);
+                }
+            }
+        }
+        Json::Array(items) => {
+            for item in items {
+                // the first line of each item starts with a dash
+                let mut item_text = String::new();
+                write_text(&mut item_text, item, indent + 2);
+                out.push_str(&format!("{}- {}", pad, &item_text[indent + 2..]));
+            }
+        }
+        json => out.push_str(&format!("{}{}\n", pad, inline(json))),
+    }
+}
+
+/// Human readable description of the `index`th datagram, from `dissect`.
+pub fn to_text(index: usize, json: &Json) -> String {
+    // time and addresses are only known for captures
+    let known = |key| match json.get(key) {
+        None | Some(Json::Null) => None,
+        Some(value) => Some(inline(value)),
+    };
+    let mut out = format!("#{}", index);
+    if let Some(time) = known("time") {
+        out.push_str(&format!(" {}", time));
+    }
+    if let (Some(src), Some(dst)) = (known("src"), known("dst")) {
+        out.push_str(&format!(" {} -> {}", src, dst));
+    }
+    out.push_str(&format!(
+        ", {} bytes\n",
+        known("length").unwrap_or_default()
+    ));
+    if let Json::Object(members) = json {
+        let details = members
+            .iter()
+            .filter(|(key, _)| !["time", "src", "dst", "length"].contains(key))
+            .cloned()
+            .collect();
+        write_text(&mut out, &Json::Object(details), 2);
+    }
+    out
+}
+
+mod test {
+    #[allow(dead_code)]
+    fn frame() -> Vec<u8> {
+        use super::*;
+
+        let mut request = Vec::new();
+        FileRequest {
+            file_paths: vec!["a.txt".to_string()],
+        }
+        .write(&mut request);
+        let frame = MessageFrame {
+            version: 3,
+            sid: 42,
+            tlvs: vec![
+                Tlv::ObjectChunk(ObjectChunk {
+                    object_id: 1,
+                    chunk_id: 0,
+                    num_enclosed_msgs: 1,
+                    data: &request,
+                    ..ObjectChunk::default()
+                }),
+                Tlv::ObjectAck(ObjectAck {
+                    acknowledged_object_chunks: vec![(2, 5)],
+                }),
+            ],
+            checksum: ChecksumAlgorithm::Crc32c,
+            ..MessageFrame::default()
+        };
+        let mut buf = Vec::new();
+        frame.write(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn dissect_frame() {
+        use super::*;
+
+        let datagram = Datagram {
+            payload: frame(),
+            ..Datagram::default()
+        };
+        let json = dissect(&datagram);
+        assert_eq!(json.get("checksum"), Some(&"Crc32c".into()));
+        assert_eq!(json.get("sid"), Some(&42u64.into()));
+        let text = to_text(1, &json);
+        assert!(text.starts_with("#1, 49 bytes\n"), "{}", text);
+        assert!(
+            text.contains("  tlvs:\n    - type: ObjectChunk\n"),
+            "{}",
+            text
+        );
+        assert!(text.contains("        - type: FileRequest\n          paths: [a.txt]\n"));
+        assert!(text.contains("      acks: [[2, 5]]\n"), "{}", text);
+
+        // still decoded with a corrupt checksum
+        let mut corrupt = datagram;
+        *corrupt.payload.last_mut().unwrap() ^= 1;
+        let json = dissect(&corrupt);
+        assert_eq!(json.get("checksum_valid"), Some(&false.into()));
+        assert_eq!(json.get("sid"), Some(&42u64.into()));
+        assert!(json.get("error").is_none());
+    }
+
+    #[test]
+    fn read_captures() {
+        use super::capture::*;
+        use super::*;
+
+        let payload = frame();
+        // pcap with an Ethernet, IPv4 and UDP header
+        let mut pcap = vec![];
+        // magic, version 2.4, time zone, accuracy, snap length, Ethernet
+        for x in &[0xa1b2_c3d4u32, 0x0004_0002, 0, 0, 65535, 1] {
+            pcap.extend_from_slice(&x.to_le_bytes()[..]);
+        }
+        let packet_len = 14 + 20 + 8 + payload.len();
+        for x in &[10u32, 500_000, packet_len as u32, packet_len as u32] {
+            pcap.extend_from_slice(&x.to_le_bytes()[..]);
+        }
+        pcap.extend_from_slice(&[0; 12]);
+        pcap.extend_from_slice(&[0x08, 0x00]);
+        let ip_len = (20 + 8 + payload.len()) as u16;
+        pcap.extend_from_slice(&[0x45, 0, (ip_len >> 8) as u8, ip_len as u8, 0, 0, 0, 0]);
+        pcap.extend_from_slice(&[64, 17, 0, 0, 127, 0, 0, 1, 127, 0, 0, 2]);
+        let udp_len = (8 + payload.len()) as u16;
+        pcap.extend_from_slice(&[
+            0x30,
+            0x39,
+            0xa5,
+            0x98,
+            (udp_len >> 8) as u8,
+            udp_len as u8,
+            0,
+            0,
+        ]);
+        pcap.extend_from_slice(&payload);
+
+        let datagrams = read_capture(&pcap).unwrap();
+        assert_eq!(
+            datagrams,
+            vec![Datagram {
+                time: Some(0.0),
+                src: Some("127.0.0.1:12345".parse().unwrap()),
+                dst: Some("127.0.0.2:42392".parse().unwrap()),
+                payload: payload.clone(),
+            }]
+        );
+
+        let hex: String = payload.iter().map(|b| format!("{:02x} ", b)).collect();
+        let datagrams = read_capture(format!("# comment\n{}\n\n", hex).as_bytes()).unwrap();
+        assert_eq!(datagrams.len(), 1);
+        assert_eq!(datagrams[0].payload, payload);
+        assert!(read_capture(b"0g").is_err());
+    }
+}
@@ -0,0 +1,179 @@
+//! Errors returned by the public APIs of rft.
+
+use crate::app::frame::{AppErrorCode, AppErrorData, ApplicationError};
+use crate::transport::frame::{ErrorCode, CHECKSUM_ERROR};
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Everything that can go wrong in rft. The CLI prints it and exits with
+/// `exit_code()`, so that scripts can tell the kinds apart.
+#[derive(Debug)]
+pub enum RftError {
+    /// A socket or file operation failed.
+    Io(io::Error),
+    /// A received message is malformed. `offset` is the position in the
+    /// decoded buffer, `tlv_type` the type code of the innermost TLV which
+    /// was being decoded, if any.
+    Decode {
+        offset: u64,
+        tlv_type: Option<u8>,
+        what: String,
+    },
+    /// A message can't be encoded, e.g. because it is too long.
+    Encode(String),
+    /// The peer did not answer in time.
+    Timeout,
+    /// The peer reported a transport error, or refused the handshake.
+    PeerError(ErrorCode),
+    /// The peer reported an application error other than the ones below.
+    AppError(AppErrorCode),
+    /// A received file does not match its hash.
+    HashMismatch(String),
+    /// The peer does not have the given path(s).
+    NotFound(String),
+    /// The path(s) may not be accessed, e.g. because authentication failed.
+    PermissionDenied(String),
+    /// The named feature is not available, e.g. listing files.
+    Unsupported(String),
+}
+
+impl RftError {
+    pub fn decode(offset: u64, what: &str) -> RftError {
+        RftError::Decode {
+            offset,
+            tlv_type: None,
+            what: what.to_string(),
+        }
+    }
+
+    /// Adds the type code of the TLV which failed to decode, unless an inner
+    /// TLV already did.
+    pub fn in_tlv(mut self, type_code: u8) -> RftError {
+        if let RftError::Decode { tlv_type, .. } = &mut self {
+            if tlv_type.is_none() {
+                *tlv_type = Some(type_code);
+            }
+        }
+        self
+    }
+
+    /// Wether a received frame was dropped because its checksum did not match.
+    pub fn is_checksum_error(&self) -> bool {
+        match self {
+            RftError::Decode { what, .. } => what == CHECKSUM_ERROR,
+            _ => false,
+        }
+    }
+
+    /// Exit code of the CLI for this error, following `sysexits.h`.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RftError::Encode(_) => 64,                            // EX_USAGE
+            RftError::HashMismatch(_) => 65,                      // EX_DATAERR
+            RftError::NotFound(_) => 66,                          // EX_NOINPUT
+            RftError::PeerError(_) | RftError::AppError(_) => 69, // EX_UNAVAILABLE
+            RftError::Unsupported(_) => 69,                       // EX_UNAVAILABLE
+            RftError::Io(_) => 74,                                // EX_IOERR
+            RftError::Timeout => 75,                              // EX_TEMPFAIL
+            RftError::Decode { .. } => 76,                        // EX_PROTOCOL
+            RftError::PermissionDenied(_) => 77,                  // EX_NOPERM
+        }
+    }
+}
+
+impl fmt::Display for RftError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RftError::Io(e) => write!(f, "I/O error: {}", e),
+            RftError::Decode {
+                offset,
+                tlv_type: Some(tlv_type),
+                what,
+            } => write!(
+                f,
+                "Malformed message at byte {} in TLV type {:#04x}: {}",
+                offset, tlv_type, what
+            ),
+            RftError::Decode { offset, what, .. } => {
+                write!(f, "Malformed message at byte {}: {}", offset, what)
+            }
+            RftError::Encode(what) => write!(f, "Can't encode message: {}", what),
+            RftError::Timeout => write!(f, "Timed out waiting for the peer."),
+            RftError::PeerError(code) => write!(f, "Peer reported error {:?}.", code),
+            RftError::AppError(code) => write!(f, "Peer reported error {:?}.", code),
+            RftError::HashMismatch(name) => write!(f, "{} does not match its hash.", name),
+            RftError::NotFound(paths) => write!(f, "Not found: {}", paths),
+            RftError::PermissionDenied(paths) => write!(f, "Permission denied: {}", paths),
+            RftError::Unsupported(feature) => write!(f, "{} is not supported.", feature),
+        }
+    }
+}
+
+impl Error for RftError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RftError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RftError {
+    fn from(e: io::Error) -> Self {
+        RftError::Io(e)
+    }
+}
+
+impl From<&ApplicationError> for RftError {
+    fn from(e: &ApplicationError) -> Self {
+        let paths = match &e.error_data {
+            AppErrorData::Paths(paths) => paths.join(", "),
+            _ => String::new(),
+        };
+        match e.error_code {
+            AppErrorCode::FileNotFound => RftError::NotFound(paths),
+            AppErrorCode::AccessDenied => RftError::PermissionDenied(paths),
+            AppErrorCode::FileHashError => RftError::HashMismatch(paths),
+            _ => RftError::AppError(e.error_code.clone()),
+        }
+    }
+}
+
+mod test {
+    #[test]
+    fn decode_error() {
+        use super::*;
+
+        let e = RftError::decode(7, "Truncated TLV.")
+            .in_tlv(0x32)
+            .in_tlv(0x01);
+        assert_eq!(
+            e.to_string(),
+            "Malformed message at byte 7 in TLV type 0x32: Truncated TLV."
+        );
+        assert_eq!(e.exit_code(), 76);
+    }
+
+    #[test]
+    fn application_error() {
+        use super::*;
+
+        let e = RftError::from(&ApplicationError {
+            error_code: AppErrorCode::AccessDenied,
+            error_data: AppErrorData::Paths(vec!["a".to_string(), "b".to_string()]),
+        });
+        assert_eq!(e.to_string(), "Permission denied: a, b");
+        assert_eq!(e.exit_code(), 77);
+    }
+
+    #[test]
+    fn unsupported() {
+        use crate::app::ls::ls;
+        use crate::options::Options;
+
+        let e = ls(Options::default(), vec![], ".").err().unwrap();
+        assert_eq!(e.to_string(), "Listing files is not supported.");
+        assert_eq!(e.exit_code(), 69);
+    }
+}
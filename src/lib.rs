@@ -8,6 +8,8 @@ extern crate num_derive;
 #[macro_use]
 pub mod common;
 pub mod app;
+pub mod dissect;
+pub mod error;
 pub mod options;
 pub mod transport;
 
@@ -1,9 +1,12 @@
 use clap;
 use env_logger;
 use rft::*;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr};
 use std::str::FromStr;
 
+/// Exit code for invalid command line options, see `RftError::exit_code()`.
+const EX_USAGE: i32 = 64;
+
 fn main() {
     // Initialize logger.
     env_logger::init();
@@ -23,7 +26,7 @@ fn main() {
             .conflicts_with_all(&["host","file", "list"])
         )
         .arg(clap::Arg::with_name("host")
-            .help("the host to send to or request from (hostname or IPv4 address)")
+            .help("the host to send to or request from (hostname, IPv4 or IPv6 address)")
             .index(1)
             .required(true)
             .takes_value(true)
@@ -31,7 +34,7 @@ fn main() {
         )
         .arg(clap::Arg::with_name("t")
             .short("t")
-            .help("specify the port number to use (use a default if not given)")
+            .help("specify the port number to use (use a default if not given)\nthe client uses it if <host> has no port")
             .takes_value(true)
         )
         .arg(clap::Arg::with_name("p")
@@ -54,11 +57,83 @@ fn main() {
         )
         // Other options:
         .arg(clap::Arg::with_name("u")
-            .help("address for listening")
+            .help("IPv4 or IPv6 address for listening, `::` listens on both")
             .short("u")
             .takes_value(true)
             .conflicts_with_all(&["file", "list"])
         )
+        .arg(clap::Arg::with_name("key")
+            .help("pre-shared passphrase: encrypt and authenticate all traffic\nthe peer must use the same key")
+            .short("k")
+            .long("key")
+            .takes_value(true)
+            .conflicts_with("key-file")
+        )
+        .arg(clap::Arg::with_name("key-file")
+            .help("read the pre-shared key from a file, see -k")
+            .long("key-file")
+            .takes_value(true)
+        )
+        .arg(clap::Arg::with_name("identity")
+            .help("server: file with the secret key for the key exchange, generated if missing\nthe public key is printed on startup, clients pin it with --server-key")
+            .long("identity")
+            .takes_value(true)
+            .conflicts_with_all(&["file", "list"])
+        )
+        .arg(clap::Arg::with_name("server-key")
+            .help("client: public key of the server (64 hex digits), the connection fails if the server can't prove it owns it")
+            .long("server-key")
+            .takes_value(true)
+            .conflicts_with("s")
+        )
+        .arg(clap::Arg::with_name("user")
+            .help("client: authenticate as this user, requires --secret")
+            .long("user")
+            .takes_value(true)
+            .requires("secret")
+            .conflicts_with("s")
+        )
+        .arg(clap::Arg::with_name("secret")
+            .help("client: secret of the user")
+            .long("secret")
+            .takes_value(true)
+            .requires("user")
+        )
+        .arg(clap::Arg::with_name("auth-method")
+            .help("client: `challenge` (default) proves knowing the secret without sending it,\n`token` sends the secret itself")
+            .long("auth-method")
+            .takes_value(true)
+            .possible_values(&["challenge", "token"])
+            .requires("user")
+        )
+        .arg(clap::Arg::with_name("credentials")
+            .help("server: file with users, their secrets and the paths they may access\nonly authenticated users are served if given")
+            .long("credentials")
+            .takes_value(true)
+            .conflicts_with_all(&["file", "list"])
+        )
+        .arg(clap::Arg::with_name("retry")
+            .help("server: answer handshakes with a cookie first and only accept clients which echo it,\nso spoofed handshakes create no state, at the cost of a round trip")
+            .long("retry")
+            .conflicts_with_all(&["file", "list"])
+        )
+        .arg(clap::Arg::with_name("connect-timeout")
+            .help("client: seconds to retransmit the handshake to each server before giving up (default 10)")
+            .long("connect-timeout")
+            .takes_value(true)
+            .conflicts_with("s")
+        )
+        .arg(clap::Arg::with_name("trace")
+            .help("write a JSON lines trace of the packets and state changes of all connections to this file")
+            .long("trace")
+            .takes_value(true)
+        )
+        .arg(clap::Arg::with_name("metrics")
+            .help("server: serve Prometheus metrics over HTTP at /metrics on this port (localhost only),\nor on this address and port")
+            .long("metrics")
+            .takes_value(true)
+            .conflicts_with_all(&["file", "list"])
+        )
         .arg(clap::Arg::with_name("list")
             .help("remote directory for file list retrival")
             .short("l")
@@ -75,66 +150,92 @@ fn main() {
         matches.value_of("t"),
         matches.value_of("p"),
         matches.value_of("q"),
-    ) {
+        matches.value_of("key"),
+        matches.value_of("key-file"),
+        matches.value_of("identity"),
+        matches.value_of("server-key"),
+    )
+    .and_then(|o| {
+        o.with_auth(
+            matches.value_of("user"),
+            matches.value_of("secret"),
+            matches.value_of("auth-method"),
+            matches.value_of("credentials"),
+        )
+    })
+    .and_then(|o| {
+        Ok(options::Options {
+            stateless_retry: matches.is_present("retry"),
+            connect_timeout: options::parse_connect_timeout(matches.value_of("connect-timeout"))?,
+            trace: options::parse_trace(matches.value_of("trace"))?,
+            metrics: options::parse_metrics(matches.value_of("metrics"))?,
+            ..o
+        })
+    }) {
         Err(e) => {
             eprintln!("Error while parsing command line options: {}", e);
-            std::process::exit(1);
+            std::process::exit(EX_USAGE);
         }
         Ok(o) => opt = o,
     }
 
     if matches.is_present("s") {
         let listen_addr = if let Some(u) = matches.value_of("u") {
-            if let Ok(v) = Ipv4Addr::from_str(u) {
-                v
-            } else {
-                Ipv4Addr::LOCALHOST
+            match IpAddr::from_str(u) {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!("Error while parsing command line options (u): {}", e);
+                    std::process::exit(EX_USAGE);
+                }
             }
         } else {
-            Ipv4Addr::LOCALHOST
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
         };
 
         // server mode
+        if let Some(public_key) = opt.identity_public_key() {
+            println!("Server public key: {}", public_key);
+        }
         std::process::exit(match app::server::run(opt, listen_addr) {
             Ok(_) => 0,
             Err(e) => {
-                eprintln!("{:?}", e);
-                1
+                eprintln!("Error: {}", e);
+                e.exit_code()
             }
         });
     } else {
         // client mode
 
-        let socket_addr;
-        match options::parse_host(matches.value_of("host").unwrap()) {
+        let socket_addrs;
+        match options::parse_host(matches.value_of("host").unwrap(), opt.port) {
             // unwrap() used since clap arg constraints should ensure that a host is present
             Err(e) => {
                 eprintln!("Error while parsing command line options (host): {}", e);
-                std::process::exit(1);
+                std::process::exit(EX_USAGE);
             }
-            Ok(o) => socket_addr = o,
+            Ok(o) => socket_addrs = o,
         }
 
         if matches.is_present("list") {
             // file list client
             let directory = matches.value_of("list").unwrap(); // unwrap() used since clap arg constraints should ensure that a directory is present
 
-            std::process::exit(match app::ls::ls(opt, socket_addr, directory) {
+            std::process::exit(match app::ls::ls(opt, socket_addrs, directory) {
                 Ok(_) => 0,
                 Err(e) => {
-                    eprintln!("{:?}", e);
-                    1
+                    eprintln!("Error: {}", e);
+                    e.exit_code()
                 }
             });
         } else {
             // regular client
             let files = matches.values_of("file").unwrap().collect(); // unwrap() used since clap arg constraints should ensure that files are present
 
-            std::process::exit(match app::get::get(opt, socket_addr, files) {
+            std::process::exit(match app::get::get(opt, socket_addrs, files) {
                 Ok(_) => 0,
                 Err(e) => {
-                    eprintln!("{:?}", e);
-                    1
+                    eprintln!("Error: {}", e);
+                    e.exit_code()
                 }
             });
         }
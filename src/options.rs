@@ -1,8 +1,23 @@
 //! Management of (command line) options for client and server.
 
+use crate::app::auth::UserDb;
+use crate::transport::config::{Config, Credentials, MAX_TOKEN_LEN};
+use crate::transport::crypto::{PublicKey, StaticSecret};
+use crate::transport::frame::AuthMethod;
+use crate::transport::trace::Tracer;
+use rand::{thread_rng, RngCore};
 use std::fmt::{Display, Formatter};
-use std::net::SocketAddr;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Port used if none is given.
+const DEFAULT_PORT: u16 = 42424;
+/// Transition probabilities used if none are given, i.e. no loss.
+const DEFAULT_P: f64 = 0.0;
+const DEFAULT_Q: f64 = 0.0;
 
 /// Basic options for client and server.
 #[derive(Debug)]
@@ -11,28 +26,159 @@ pub struct Options {
     pub port: u16,
     /// Transition probabilities for packet loss "simulation" via a markov chain (not lost->lost, not lost->not lost)
     pub transition_probabilities: (f64, f64),
+    /// Pre-shared key for encryption, none for cleartext
+    pub psk: Option<Vec<u8>>,
+    /// Static secret key of the server for the key exchange
+    pub identity: Option<[u8; 32]>,
+    /// Public key the server must present to the client
+    pub server_key: Option<[u8; 32]>,
+    /// Identity the client authenticates with
+    pub credentials: Option<Credentials>,
+    /// Users the server accepts, none to serve everyone
+    pub users: Option<Arc<UserDb>>,
+    /// Wether the server validates client addresses with a retry cookie
+    pub stateless_retry: bool,
+    /// How long the client tries to connect to each server, none for the default
+    pub connect_timeout: Option<Duration>,
+    /// Where connections write their event trace, none to not trace
+    pub trace: Option<Tracer>,
+    /// Address the server serves Prometheus metrics at, none to not serve them
+    pub metrics: Option<SocketAddr>,
 }
 
 impl Options {
-    /// Get options from given t, p and q. Use defaults if none is given.
-    pub fn parse(t: Option<&str>, p: Option<&str>, q: Option<&str>) -> Result<Self, &'static str> {
+    /// Get options from given t, p, q, key, key file, identity file and server key.
+    /// Use defaults if none is given.
+    pub fn parse(
+        t: Option<&str>,
+        p: Option<&str>,
+        q: Option<&str>,
+        key: Option<&str>,
+        key_file: Option<&str>,
+        identity: Option<&str>,
+        server_key: Option<&str>,
+    ) -> Result<Self, &'static str> {
         Ok(Options {
             port: parse_t(t)?,
             transition_probabilities: parse_p_q(p, q)?,
+            psk: parse_key(key, key_file)?,
+            identity: match identity {
+                Some(path) => Some(parse_identity(path)?),
+                None => None,
+            },
+            server_key: match server_key {
+                Some(s) => Some(parse_hex_key(s)?),
+                None => None,
+            },
+            ..Options::default()
         })
     }
+
+    /// Add authentication options from given user, secret and method (client),
+    /// or credentials file (server).
+    pub fn with_auth(
+        mut self,
+        user: Option<&str>,
+        secret: Option<&str>,
+        method: Option<&str>,
+        credentials: Option<&str>,
+    ) -> Result<Self, &'static str> {
+        self.credentials = parse_credentials(user, secret, method)?;
+        self.users = match credentials {
+            Some(path) => Some(Arc::new(UserDb::load(path)?)),
+            None => None,
+        };
+        Ok(self)
+    }
+
+    /// Wether traffic will be encrypted, or the peer refused.
+    pub fn is_encrypted(&self) -> bool {
+        self.psk.is_some() || self.identity.is_some() || self.server_key.is_some()
+    }
+
+    /// Public key of the server identity, to be pinned by clients.
+    pub fn identity_public_key(&self) -> Option<String> {
+        let secret = StaticSecret::from(self.identity?);
+        Some(format_hex_key(PublicKey::from(&secret).as_bytes()))
+    }
+
+    /// Settings for the transport layer.
+    pub fn transport_config(&self) -> Config {
+        Config {
+            psk: self.psk.clone(),
+            static_key: self.identity.map(StaticSecret::from),
+            server_key: self.server_key.map(PublicKey::from),
+            credentials: self.credentials.clone(),
+            users: self.users.as_ref().map(|users| Arc::new(users.secrets())),
+            stateless_retry: self.stateless_retry,
+            connect_timeout: self.connect_timeout,
+            trace: self.trace.clone(),
+        }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            port: DEFAULT_PORT,
+            transition_probabilities: (DEFAULT_P, DEFAULT_Q),
+            psk: None,
+            identity: None,
+            server_key: None,
+            credentials: None,
+            users: None,
+            stateless_retry: false,
+            connect_timeout: None,
+            trace: None,
+            metrics: None,
+        }
+    }
 }
 
 impl Display for Options {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-        write!(f, "Options: 'source port {} with transition probabilities p={} and q={} for markov chain'", self.port, self.transition_probabilities.0, self.transition_probabilities.1)
+        write!(f, "Options: 'source port {} with transition probabilities p={} and q={} for markov chain, {}'", self.port, self.transition_probabilities.0, self.transition_probabilities.1, if self.is_encrypted() { "encrypted" } else { "unencrypted" })
+    }
+}
+
+/// Get the connect timeout from a given number of seconds.
+pub fn parse_connect_timeout(s: Option<&str>) -> Result<Option<Duration>, &'static str> {
+    match s.map(str::parse::<f64>) {
+        None => Ok(None),
+        Some(Ok(secs)) if secs > 0.0 && secs.is_finite() => Ok(Some(Duration::from_secs_f64(secs))),
+        Some(_) => Err("Connect timeout must be a positive number of seconds."),
+    }
+}
+
+/// Open the trace file at a given path, if any.
+pub fn parse_trace(path: Option<&str>) -> Result<Option<Tracer>, &'static str> {
+    match path {
+        None => Ok(None),
+        Some(path) => match Tracer::create(path) {
+            Ok(tracer) => Ok(Some(tracer)),
+            Err(_) => Err("Could not create trace file."),
+        },
+    }
+}
+
+/// Get the address of the metrics endpoint from a given port, or address and port.
+/// A port alone listens on localhost only.
+pub fn parse_metrics(s: Option<&str>) -> Result<Option<SocketAddr>, &'static str> {
+    let s = match s {
+        None => return Ok(None),
+        Some(s) => s,
+    };
+    if let Ok(port) = s.parse::<u16>() {
+        return Ok(Some(SocketAddr::new(IpAddr::from([127, 0, 0, 1]), port)));
+    }
+    match SocketAddr::from_str(s) {
+        Ok(addr) => Ok(Some(addr)),
+        Err(_) => Err("Metrics address must be a port, or an address with port."),
     }
 }
 
 /// Get port number from given t. Uses default if none is given.
 fn parse_t(t: Option<&str>) -> Result<u16, &'static str> {
-    const DEFAULT_PORT: u16 = 42424;
-
     if let Some(s) = t {
         return match s.parse::<u16>() {
             Err(_) => Err("Couldn't parse port number."), //TODO don't ignore error kind
@@ -46,15 +192,12 @@ fn parse_t(t: Option<&str>) -> Result<u16, &'static str> {
 
 /// Get transition probabilities for the markov chain from given p and q. Uses default if none is given.
 fn parse_p_q(p: Option<&str>, q: Option<&str>) -> Result<(f64, f64), &'static str> {
-    const DEFAULT_P: f64 = 0.0;
-    const DEFAULT_Q: f64 = 0.0;
-
     let mut r = (DEFAULT_P, DEFAULT_Q);
 
     if let Some(s) = p {
         match s.parse::<f64>() {
             Err(_) => return Err("Couldn't parse p."),
-            Ok(x) if x > 1.0 || x < 0.0 => return Err("p must lie between 0 and 1."),
+            Ok(x) if !(0.0..=1.0).contains(&x) => return Err("p must lie between 0 and 1."),
             Ok(x) if q.is_none() => return Ok((x, 1.0 - x)),
             Ok(x) => r.0 = x,
         }
@@ -63,7 +206,7 @@ fn parse_p_q(p: Option<&str>, q: Option<&str>) -> Result<(f64, f64), &'static st
     if let Some(s) = q {
         match s.parse::<f64>() {
             Err(_) => return Err("Couldn't parse q."),
-            Ok(x) if x > 1.0 || x < 0.0 => return Err("q must lie between 0 and 1."),
+            Ok(x) if !(0.0..=1.0).contains(&x) => return Err("q must lie between 0 and 1."),
             Ok(x) if p.is_none() => return Ok((1.0 - x, x)),
             Ok(x) => r.1 = x,
         }
@@ -72,11 +215,140 @@ fn parse_p_q(p: Option<&str>, q: Option<&str>) -> Result<(f64, f64), &'static st
     return Ok(r);
 }
 
-/// Get socket address from given host. Return error if none is given.
-pub fn parse_host(host: &str) -> Result<SocketAddr, &'static str> {
-    return match SocketAddr::from_str(host) {
-        Err(_) => Err("Couldn't parse host."),
-        Ok(socket_addr) => Ok(socket_addr),
+/// Get pre-shared key from given passphrase or key file. A trailing newline
+/// in the file is ignored. No key is used if neither is given.
+fn parse_key(key: Option<&str>, key_file: Option<&str>) -> Result<Option<Vec<u8>>, &'static str> {
+    let psk = match (key, key_file) {
+        (Some(_), Some(_)) => return Err("Only one of key and key file may be given."),
+        (Some(key), None) => key.as_bytes().to_vec(),
+        (None, Some(path)) => {
+            let mut psk = match std::fs::read(path) {
+                Err(_) => return Err("Couldn't read key file."),
+                Ok(psk) => psk,
+            };
+            while psk.last() == Some(&b'\n') || psk.last() == Some(&b'\r') {
+                psk.pop();
+            }
+            psk
+        }
+        (None, None) => return Ok(None),
+    };
+    if psk.is_empty() {
+        return Err("Key must not be empty.");
+    }
+    return Ok(Some(psk));
+}
+
+/// Get credentials of the client from given user, secret and authentication
+/// method (`token` or `challenge`, the default).
+fn parse_credentials(
+    user: Option<&str>,
+    secret: Option<&str>,
+    method: Option<&str>,
+) -> Result<Option<Credentials>, &'static str> {
+    let method = match method {
+        None | Some("challenge") => AuthMethod::ChallengeResponse,
+        Some("token") => AuthMethod::Token,
+        Some(_) => return Err("Authentication method must be token or challenge."),
+    };
+    return match (user, secret) {
+        (Some(_), Some(secret)) if method == AuthMethod::Token && secret.len() > MAX_TOKEN_LEN => {
+            Err("Token must not be longer than 512 bytes.")
+        }
+        (Some(user), Some(secret)) => Ok(Some(Credentials {
+            user: user.to_string(),
+            secret: secret.as_bytes().to_vec(),
+            method,
+        })),
+        (None, None) => Ok(None),
+        _ => Err("User and secret must be given together."),
+    };
+}
+
+/// Get a 32 byte key from its hex representation.
+fn parse_hex_key(s: &str) -> Result<[u8; 32], &'static str> {
+    let s = s.trim();
+    if s.len() != 64 || !s.is_ascii() {
+        return Err("Key must consist of 64 hex digits.");
+    }
+    let mut key = [0; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = match u8::from_str_radix(&s[2 * i..2 * i + 2], 16) {
+            Ok(x) => x,
+            Err(_) => return Err("Key must consist of 64 hex digits."),
+        };
+    }
+    return Ok(key);
+}
+
+/// Hex representation of a 32 byte key, see `parse_hex_key()`.
+pub fn format_hex_key(key: &[u8; 32]) -> String {
+    key.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Get the server's secret key from given identity file. A new key is
+/// generated and saved if the file doesn't exist.
+fn parse_identity(path: &str) -> Result<[u8; 32], &'static str> {
+    match std::fs::read_to_string(path) {
+        Ok(s) => parse_hex_key(&s),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let mut key = [0; 32];
+            thread_rng().fill_bytes(&mut key);
+            match write_identity(path, &key) {
+                Err(_) => Err("Couldn't write identity file."),
+                Ok(()) => Ok(key),
+            }
+        }
+        Err(_) => Err("Couldn't read identity file."),
+    }
+}
+
+/// Save a new secret key to `path`, readable by the owner only.
+/// Fails if the file exists, so no file is overwritten.
+fn write_identity(path: &str, key: &[u8; 32]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    file.write_all((format_hex_key(key) + "\n").as_bytes())
+}
+
+/// Get socket addresses from given host, which is a hostname or an IP address,
+/// optionally followed by a port (`host:port`, `[v6]:port`). Hostnames are
+/// resolved through the system resolver, `port` is used if none is given.
+/// Returns all addresses of the host in the order given by the resolver.
+pub fn parse_host(host: &str, port: u16) -> Result<Vec<SocketAddr>, &'static str> {
+    // literal addresses, with or without port
+    if let Ok(socket_addr) = SocketAddr::from_str(host) {
+        return Ok(vec![socket_addr]);
+    }
+    let unbracketed = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip_addr) = IpAddr::from_str(unbracketed) {
+        return Ok(vec![SocketAddr::new(ip_addr, port)]);
+    }
+
+    // hostname, with or without port
+    let resolved = match host.rfind(':') {
+        Some(i) => match host[i + 1..].parse::<u16>() {
+            Err(_) => return Err("Couldn't parse host port."),
+            Ok(p) => (&host[..i], p).to_socket_addrs(),
+        },
+        None => (host, port).to_socket_addrs(),
+    };
+    return match resolved {
+        Err(_) => Err("Couldn't resolve host."),
+        Ok(addrs) => {
+            let addrs: Vec<SocketAddr> = addrs.collect();
+            if addrs.is_empty() {
+                Err("Host has no addresses.")
+            } else {
+                Ok(addrs)
+            }
+        }
     };
 }
 
@@ -86,4 +358,76 @@ mod tests {
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    #[cfg(unix)]
+    fn identity_is_private() {
+        use super::*;
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("rft-identity-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let path = path.to_str().unwrap();
+        let key = parse_identity(path).unwrap();
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // loaded again, not replaced
+        assert_eq!(parse_identity(path).unwrap(), key);
+        assert!(write_identity(path, &[0; 32]).is_err());
+        assert_eq!(parse_identity(path).unwrap(), key);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn parse_host() {
+        use super::parse_host;
+        use std::net::SocketAddr;
+
+        let addr = |s: &str| -> SocketAddr { s.parse().unwrap() };
+        assert_eq!(parse_host("127.0.0.1", 42), Ok(vec![addr("127.0.0.1:42")]));
+        assert_eq!(
+            parse_host("127.0.0.1:80", 42),
+            Ok(vec![addr("127.0.0.1:80")])
+        );
+        assert_eq!(parse_host("::1", 42), Ok(vec![addr("[::1]:42")]));
+        assert_eq!(parse_host("[::1]", 42), Ok(vec![addr("[::1]:42")]));
+        assert_eq!(parse_host("[::1]:80", 42), Ok(vec![addr("[::1]:80")]));
+        assert!(parse_host("localhost", 42)
+            .unwrap()
+            .iter()
+            .all(|a| a.ip().is_loopback() && a.port() == 42));
+        assert!(parse_host("localhost:80", 42)
+            .unwrap()
+            .iter()
+            .all(|a| a.ip().is_loopback() && a.port() == 80));
+        assert!(parse_host("localhost:port", 42).is_err());
+    }
+
+    #[test]
+    fn parse_metrics() {
+        use super::parse_metrics;
+
+        assert_eq!(parse_metrics(None), Ok(None));
+        assert_eq!(
+            parse_metrics(Some("9100")),
+            Ok(Some("127.0.0.1:9100".parse().unwrap()))
+        );
+        assert_eq!(
+            parse_metrics(Some("[::]:9100")),
+            Ok(Some("[::]:9100".parse().unwrap()))
+        );
+        assert!(parse_metrics(Some("localhost")).is_err());
+    }
+
+    #[test]
+    fn parse_hex_key() {
+        use super::{format_hex_key, parse_hex_key};
+
+        let key: Vec<u8> = (0..32).collect();
+        let s = format_hex_key(&parse_hex_key(&"0001020304050607".repeat(4)).unwrap());
+        assert_eq!(s, "0001020304050607".repeat(4));
+        assert_eq!(parse_hex_key(&s).unwrap()[..], key[..8].repeat(4)[..]);
+        assert!(parse_hex_key("0001").is_err());
+        assert!(parse_hex_key(&"zz".repeat(32)).is_err());
+    }
 }
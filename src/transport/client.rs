@@ -1,40 +1,40 @@
-use crate::transport::common::default_host_info;
+use crate::error::RftError;
+use crate::transport::config::Config;
 use crate::transport::connection::*;
-use std::net::{SocketAddr, UdpSocket};
-
-use rand::{thread_rng, Rng};
+use crate::transport::datagram::{DatagramTransport, UdpTransport};
+use crate::transport::sim::Env;
+use std::net::SocketAddr;
 
 /// Called by an application to create a `Connection`. Will bind
-/// to `0.0.0.0:random` as src addr.
+/// to `0.0.0.0:random` or `[::]:random` as src addr, depending on
+/// the address family of `dest`.
 /// Non-blocking as it only creates state. The `Connection` will then
 /// be established with handshake and everything while being granted
-/// cpu_time by `Connection.grant_cpu()`.
-pub fn connect(
-    dest: SocketAddr,
-    accept_callback: Box<ObjectListener>,
-    timeout_callback: Box<TimeoutListener>,
-) -> Connection {
-    // bind to a random local port from ephemeral port range
-    let bind: SocketAddr = "0.0.0.0:0".parse().unwrap();
-    let port: u16 = thread_rng().gen_range(49152, 65535);
-    bind.set_port(port);
+/// cpu_time by `Connection.receive_and_send()`. Events are retrieved
+/// with `Connection.poll_event()`.
+/// Fails if no socket could be bound.
+pub fn connect(dest: SocketAddr) -> Result<Connection, RftError> {
+    return connect_with(dest, Config::default());
+}
 
-    let socket: UdpSocket = UdpSocket::bind(bind).expect("Could not bind to Socket.");
-    socket.set_nonblocking(true).unwrap();
-    socket.connect(dest).unwrap();
+/// Like `connect()`, with the given settings.
+pub fn connect_with(dest: SocketAddr, config: Config) -> Result<Connection, RftError> {
+    let transport = UdpTransport::connect(dest)?;
+    return Ok(connect_transport(
+        Box::new(transport),
+        Env::default(),
+        config,
+    ));
+}
 
-    let mut conn = Connection {
-        send_jobs: Vec::new(),
-        recv_jobs: Vec::new(),
-        accept_callback,
-        timeout_callback,
-        socket,
-        dest,
-        is_server: false,
-        self_info: default_host_info(),
-        peer_info: None,
-        session: None,
-    };
+/// Like `connect_with()`, but on any transport which is connected to the server,
+/// and with the given clock and randomness.
+pub fn connect_transport(
+    transport: Box<dyn DatagramTransport>,
+    env: Env,
+    config: Config,
+) -> Connection {
+    let mut conn = Connection::new(transport, false, env, config);
 
     conn.send_handshake();
 
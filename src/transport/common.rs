@@ -1,8 +1,22 @@
 use crate::transport::frame::*;
+use std::time::Duration;
 
 pub const APP_VERSION: Version = 0;
-pub const PROTOCOL_VERSION: Version = 2;
+pub const PROTOCOL_VERSION: Version = 3;
 pub const MAX_UDP_BUFSIZE: usize = 9000;
+/// Bytes of a sealed frame with a single `ObjectChunk` besides the chunk data.
+pub const MAX_FRAME_OVERHEAD: usize = 64;
+/// A connection is considered dead if nothing was received for this long.
+pub const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+/// Default of `Config::connect_timeout`.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// A client sends its handshake again if the server did not answer for this long.
+/// The interval is doubled with every retransmission, up to `MAX_HANDSHAKE_RETRANSMISSION`.
+pub const HANDSHAKE_RETRANSMISSION: Duration = Duration::from_millis(200);
+pub const MAX_HANDSHAKE_RETRANSMISSION: Duration = Duration::from_secs(2);
+/// Until a client's address is validated, a server sends at most this many
+/// times the bytes it received from it.
+pub const AMPLIFICATION_FACTOR: usize = 3;
 
 //////////////////////
 // util
@@ -16,9 +30,22 @@ pub fn default_host_info() -> HostInformation {
     HostInformation {
         rcv_window_size: 50,
         out_of_order_limit: 50,
-        ack_freq: AckFreq::Max,
         os: get_host_os(),
-        app: ApplicationId::SOFT,
+        app: Some(ApplicationId::SOFT),
         app_ver: APP_VERSION,
+        capabilities: Capabilities {
+            max_frame_size: Some(MAX_UDP_BUFSIZE as u16),
+            checksums: vec![
+                ChecksumAlgorithm::Crc32c,
+                ChecksumAlgorithm::XxHash32,
+                ChecksumAlgorithm::Fnv1a32,
+            ],
+            // nothing implemented yet
+            compression: Vec::new(),
+            // only advertised if encryption is configured
+            encryption: None,
+            fec: Vec::new(),
+            ack_freq: Some(AckFreq::Max),
+        },
     }
 }
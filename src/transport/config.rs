@@ -0,0 +1,53 @@
+use crate::transport::crypto::{PublicKey, StaticSecret};
+use crate::transport::frame::AuthMethod;
+use crate::transport::trace::Tracer;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Settings of a `Connection`, which are given by the application.
+///
+/// Frames after the handshake are encrypted if a pre-shared key is set on
+/// both peers, or if the server has a static key. Peers with a key refuse
+/// to talk in cleartext.
+#[derive(Clone, Default)]
+pub struct Config {
+    /// Pre-shared key. If set, all frames after the handshake are encrypted
+    /// and authenticated, and peers which don't support that are refused.
+    /// Both peers must use the same key.
+    pub psk: Option<Vec<u8>>,
+    /// Static key of a server, which authenticates it to clients during the
+    /// ephemeral key exchange. Only used by servers.
+    pub static_key: Option<StaticSecret>,
+    /// Public key the server must authenticate with. Only used by clients.
+    /// If None, any server key is accepted.
+    pub server_key: Option<PublicKey>,
+    /// Identity to authenticate with right after the handshake. Only used by clients.
+    pub credentials: Option<Credentials>,
+    /// Secrets of the users which may authenticate, by user name. Only used
+    /// by servers. Clients which don't authenticate are not refused, the
+    /// application can tell them apart by `Connection::user()`.
+    pub users: Option<Arc<HashMap<String, Vec<u8>>>>,
+    /// Answer the first handshake of a client with a `RetryCookie`, and only
+    /// accept it once the client echoes the cookie, proving that it receives
+    /// datagrams at its address. Costs a round trip. Only used by servers.
+    pub stateless_retry: bool,
+    /// How long a client retransmits its handshake before giving up with
+    /// `Event::Timeout`. None for `CONNECT_TIMEOUT`. Only used by clients.
+    pub connect_timeout: Option<Duration>,
+    /// Write a structured event trace of the connection, see `super::trace`.
+    pub trace: Option<Tracer>,
+}
+
+/// Longest secret for `AuthMethod::Token`, so that the `Authentication`
+/// fits into a frame of any size a peer may advertise.
+pub const MAX_TOKEN_LEN: usize = 512;
+
+/// Identity of a client, see `Config::credentials`.
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    pub user: String,
+    /// Shared with the server. At most `MAX_TOKEN_LEN` bytes for `AuthMethod::Token`.
+    pub secret: Vec<u8>,
+    pub method: AuthMethod,
+}
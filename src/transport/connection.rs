@@ -1,78 +1,402 @@
 use super::frame::*;
-use crate::common::{Cursor, ReadResult, WireFormat};
+use crate::common::batch::BatchIo;
+use crate::common::json::Json;
+use crate::common::{Cursor, WireFormat};
 use crate::transport::common::*;
+use crate::transport::config::Config;
+use crate::transport::crypto::{
+    auth_proof, verify_auth_proof, AeadAlgorithm, FrameCipher, KeyExchange, PublicKey,
+};
+use crate::transport::datagram::DatagramTransport;
+use crate::transport::features::Features;
 use crate::transport::jobs::*;
+use crate::transport::sim::{Clock, Env};
+use crate::transport::stats::{ObjectStats, Stats};
+use crate::transport::stream::*;
+use crate::transport::trace::Trace;
+use crate::transport::CHUNKSIZE;
+use bytes::Bytes;
 use log;
-use rand::{thread_rng, Rng};
-use std::net::{SocketAddr, UdpSocket};
-use std::ops::DerefMut;
-use std::ops::FnMut;
+use rand::{Rng, RngCore};
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 //////////////////////////
-// Connection
+// Event
 
-/// Will be called by the transport layer to notify the application
-/// about new receiving Objects.
-/// The application returns None, if it is not interested in the Object.
-/// Otherwise the application returns its ChunkListener for that Object.
-pub type ObjectListener = dyn FnMut(ObjectReceiveJob) -> ();
+/// Notifications from the transport layer to the application.
+/// They are queued by the `Connection` and retrieved by the application
+/// via `Connection::poll_event()`.
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    /// The peer announced a new Object. Its chunks will follow as `Event::Chunk`.
+    NewObject(Object),
+    /// A chunk of a receiving Object arrived.
+    /// Number of application tlvs in this chunk in `num_tlvs`.
+    /// `data` shares memory with the received datagram.
+    Chunk {
+        object_id: ObjectId,
+        chunk_id: ChunkId,
+        data: Bytes,
+        num_tlvs: u8,
+    },
+    /// The peer acknowledged all chunks of a sending Object up to `chunk_id`.
+    ObjectAcked {
+        object_id: ObjectId,
+        chunk_id: ChunkId,
+    },
+    /// All chunks of a sending Object were transmitted.
+    /// The corresponding `ObjectSendJob` has been removed from the `Connection`.
+    ObjectComplete(ObjectId),
+    /// The peer reported an error.
+    PeerError(ErrorMessage),
+    /// Nothing was received from the peer for `CONNECTION_TIMEOUT`.
+    Timeout,
+    /// The handshake was refused by the peer or by us, e.g. with
+    /// `ErrorCode::EncryptionRequired`. The `Connection` will not be established.
+    HandshakeFailed(ErrorCode),
+    /// The client authenticated as the given user. Only emitted by servers.
+    Authenticated(String),
+}
 
-/// Will be called by the transport layer to inform the application
-/// about a timeout of a connection.
-pub type TimeoutListener = dyn FnMut() -> ();
+//////////////////////////
+// Connection
 
 /// Constructors for `Connection` are found in `super::{client, server}`.
+/// A `Connection` is `Send` and `Sync`, so it may be driven by a worker thread.
 pub struct Connection {
     pub send_jobs: Vec<ObjectSendJob>,
     pub recv_jobs: Vec<ObjectReceiveJob>,
 
-    /// TODO remove
-    pub(super) accept_callback: Box<ObjectListener>,
-    pub(super) timeout_callback: Box<TimeoutListener>,
-    pub(super) socket: UdpSocket,
-    /// Target of communication to send to.
-    dest: SocketAddr,
+    /// Events which were not yet polled by the application.
+    pub(super) events: VecDeque<Event>,
+    /// Batched I/O on `transport`, also holding the reused send and receive buffers.
+    /// Received datagrams are copied out of its buffer, so chunks kept by the application
+    /// only hold on to their own datagram.
+    pub(super) batch: BatchIo,
+    /// Last id of an object opened by `open_object()`.
+    pub(super) next_object_id: ObjectId,
+    /// Point in time at which the last packet was received from the peer.
+    pub(super) last_received: Instant,
+    pub(super) clock: Arc<dyn Clock>,
+    pub(super) rng: Box<dyn RngCore + Send + Sync>,
+    /// Channel to the peer.
+    pub(super) transport: Box<dyn DatagramTransport>,
     pub(super) is_server: bool,
+    pub(super) config: Config,
+    /// Our ephemeral key pair, if we offer a key exchange.
+    pub(super) key_exchange: Option<KeyExchange>,
     pub(super) self_info: HostInformation,
 
     /// ## Handshake Procedure
     ///
     /// 1. peer_info and session are None
     /// 2. If you are a server, peer_info is Some() on receiving a clients handshake. Afterwards
-    ///    you send your handshake.
-    /// 3. If you are a client, you send your handshake first.
-    /// 2. As soon as your handshake is sent, your session is Some().
-    /// 3. Now you have an ordinary, established connection.
+    ///    you send your handshake, and your session is Some().
+    /// 3. If you are a client, you send your handshake first and are `connecting`,
+    ///    retransmitting it until the server's handshake arrives. Then peer_info
+    ///    and session are Some().
+    /// 4. Now you have an ordinary, established connection.
     pub(super) peer_info: Option<HostInformation>,
     pub(super) session: Option<EstablishedState>,
+    /// Set while a client waits for the server's handshake.
+    pub(super) connecting: Option<Connecting>,
+    /// Set while a client waits for the server to acknowledge its authentication.
+    /// No objects are sent meanwhile.
+    pub(super) authenticating: Option<Authenticating>,
+    /// User the client authenticated as, if any. Only set on servers.
+    pub(super) user: Option<String>,
+    /// Number of bytes which may still be sent to the peer, while its address
+    /// is not validated, see `AMPLIFICATION_FACTOR`. None if it is validated,
+    /// which is the case for the server on clients and for a client which echoed
+    /// our session id or a `RetryCookie`.
+    pub(super) amplification_budget: Option<usize>,
+    /// Cookie the server asked us to repeat our handshake with. Only set on clients.
+    pub(super) retry_cookie: Option<Vec<u8>>,
+    /// Event trace, if `Config::trace` is set.
+    pub(super) trace: Option<Trace>,
+    /// Counters for `stats()`, which fills in `elapsed`.
+    pub(super) stats: Stats,
+    pub(super) created: Instant,
+    /// When our first handshake was sent, until the peer answers it.
+    /// None if it was retransmitted, so the answer can't be attributed.
+    pub(super) rtt_probe: Option<Instant>,
 }
 
 impl Connection {
+    pub(super) fn new(
+        transport: Box<dyn DatagramTransport>,
+        is_server: bool,
+        env: Env,
+        config: Config,
+    ) -> Self {
+        let mut rng = env.rng;
+        let now = env.clock.now();
+        let mut self_info = default_host_info();
+        // clients always offer a key exchange, servers only with a static key
+        let key_exchange = if !is_server || config.static_key.is_some() {
+            Some(KeyExchange::new(&mut rng))
+        } else {
+            None
+        };
+        if key_exchange.is_some() || config.psk.is_some() {
+            self_info.capabilities.encryption = Some(Encryption {
+                aeads: vec![AeadAlgorithm::ChaCha20Poly1305, AeadAlgorithm::Aes256Gcm],
+                ephemeral_key: key_exchange.as_ref().map(KeyExchange::public_key),
+                static_key: match (is_server, &config.static_key) {
+                    (true, Some(static_key)) => Some(PublicKey::from(static_key).to_bytes()),
+                    _ => None,
+                },
+                psk: config.psk.is_some(),
+            });
+        }
+        let trace = config.trace.clone().map(|tracer| {
+            let state = if is_server { "accepted" } else { "connecting" };
+            Trace::new(tracer, now, is_server, transport.peer(), state)
+        });
+        Connection {
+            send_jobs: Vec::new(),
+            recv_jobs: Vec::new(),
+            events: VecDeque::new(),
+            batch: transport.new_batch(),
+            next_object_id: 0,
+            last_received: now,
+            clock: env.clock,
+            rng,
+            transport,
+            is_server,
+            config,
+            key_exchange,
+            self_info,
+            peer_info: None,
+            session: None,
+            connecting: if is_server {
+                None
+            } else {
+                Some(Connecting::new(now))
+            },
+            authenticating: None,
+            user: None,
+            amplification_budget: if is_server { Some(0) } else { None },
+            retry_cookie: None,
+            trace,
+            stats: Stats::default(),
+            created: now,
+            // clients send their handshake right away
+            rtt_probe: if is_server { None } else { Some(now) },
+        }
+    }
+
     /// Should return within about 0.1s to allow the application to interact
     /// with the user still.
     /// Must be called by the application in its main loop.
     pub fn receive_and_send(&mut self) {
         self.send_acks();
-        for i in 0..self.send_jobs.len() {
-            self.send_once(i);
+        self.send_chunks();
+        self.remove_finished_send_jobs();
+        self.receive();
+        self.retransmit_handshake();
+        self.retransmit_authentication();
+        self.check_timeout();
+        self.trace_state();
+    }
+
+    /// Wether the handshake is complete, i.e. the peer assigned a session id.
+    pub fn is_established(&self) -> bool {
+        self.session.is_some()
+    }
+
+    /// Human readable address of the peer.
+    pub fn peer(&self) -> String {
+        self.transport.peer()
+    }
+
+    /// Features negotiated with the peer, None until the connection is established.
+    pub fn features(&self) -> Option<&Features> {
+        self.session.as_ref().map(|session| &session.features)
+    }
+
+    /// Largest chunk which fits into a frame the peer receives.
+    pub(super) fn max_chunk_size(&self) -> usize {
+        match self.features() {
+            Some(features) => {
+                CHUNKSIZE.min(features.max_frame_size.saturating_sub(MAX_FRAME_OVERHEAD))
+            }
+            None => CHUNKSIZE,
+        }
+    }
+
+    /// Transfer statistics of the connection so far.
+    /// Statistics of single objects are found at their jobs.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            elapsed: self.clock.now().duration_since(self.created),
+            ..self.stats.clone()
+        }
+    }
+
+    /// User the client authenticated as, see `Config::users`.
+    /// Always None on clients.
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    /// Returns the next pending event, or None if there is none.
+    /// Should be called by the application until it returns None
+    /// after every call to `receive_and_send()`.
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// Returns a `std::io::Write` adapter to send a new object of the given type.
+    /// The object is buffered in memory and sent once the writer is finished or dropped.
+    /// Object ids are allocated by the `Connection`; ids of manually created
+    /// `ObjectSendJob`s must not collide with them.
+    pub fn open_object(&mut self, object_type: ObjectType) -> BufferedObjectWriter<'_> {
+        self.next_object_id += 1;
+        while self
+            .send_jobs
+            .iter()
+            .any(|job| job.object_id() == self.next_object_id)
+        {
+            self.next_object_id += 1;
+        }
+        let object_id = self.next_object_id;
+        BufferedObjectWriter::new(self, object_type, object_id)
+    }
+
+    /// Returns a `std::io::Read` adapter for a receiving object announced by
+    /// `Event::NewObject`, or None if there is no such object.
+    /// Chunks of the object are passed to the reader instead of being
+    /// reported as `Event::Chunk` from now on.
+    pub fn read_object(&mut self, object_id: ObjectId) -> Option<ObjectReader> {
+        let job = self
+            .recv_jobs
+            .iter_mut()
+            .find(|job| job.object.object_id == object_id)?;
+        let num_chunks = job.object.fields.iter().map(|field| field.length).sum();
+        let buffer = Arc::new(Mutex::new(ReadBuffer::new(num_chunks)));
+
+        // move chunks which were not polled yet into the reader
+        let mut events = VecDeque::new();
+        for event in self.events.drain(..) {
+            match event {
+                Event::Chunk {
+                    object_id: id,
+                    chunk_id,
+                    data,
+                    ..
+                } if id == object_id => buffer.lock().unwrap().push_chunk(chunk_id, data),
+                event => events.push_back(event),
+            }
+        }
+        self.events = events;
+
+        job.reader = Some(Arc::clone(&buffer));
+        Some(ObjectReader::new(job.object.clone(), buffer))
+    }
+
+    /// drop send jobs which have transmitted all their chunks
+    fn remove_finished_send_jobs(&mut self) {
+        let events = &mut self.events;
+        self.send_jobs.retain(|job| {
+            if job.is_finished() {
+                events.push_back(Event::ObjectComplete(job.object_id()));
+                return false;
+            }
+            true
+        });
+    }
+
+    /// emit a timeout event once the peer has been silent for too long
+    fn check_timeout(&mut self) {
+        let now = self.clock.now();
+        if let Some(connecting) = &self.connecting {
+            let connect_timeout = self.config.connect_timeout.unwrap_or(CONNECT_TIMEOUT);
+            if now.duration_since(connecting.started) > connect_timeout {
+                log::warn!("Server did not answer the handshake, giving up.");
+                self.trace("connectivity:connection_timeout", Json::object(vec![]));
+                self.connecting = None;
+                self.events.push_back(Event::Timeout);
+                self.last_received = now;
+            }
+            return;
+        }
+        if now.duration_since(self.last_received) > CONNECTION_TIMEOUT {
+            log::warn!("Connection timed out.");
+            self.trace("connectivity:connection_timeout", Json::object(vec![]));
+            self.events.push_back(Event::Timeout);
+            // start over, to not flood the application with timeouts
+            self.last_received = now;
+        }
+    }
+
+    /// send the handshake again, if the server did not answer it in time
+    fn retransmit_handshake(&mut self) {
+        let now = self.clock.now();
+        let connecting = match &mut self.connecting {
+            Some(connecting) if now >= connecting.next_retransmission => connecting,
+            _ => return,
+        };
+        log::debug!(
+            "No answer from server after {:?}, retransmitting handshake.",
+            connecting.backoff
+        );
+        connecting.backoff = (connecting.backoff * 2).min(MAX_HANDSHAKE_RETRANSMISSION);
+        connecting.next_retransmission = now + connecting.backoff;
+        let backoff = connecting.backoff.as_secs_f64() * 1000.0;
+        self.stats.retransmissions += 1;
+        self.rtt_probe = None;
+        self.trace(
+            "recovery:handshake_retransmitted",
+            Json::object(vec![("backoff", backoff.into())]),
+        );
+        self.send_handshake();
+    }
+
+    /// send the authentication again, if the server did not acknowledge it in time
+    fn retransmit_authentication(&mut self) {
+        if self.session.is_none() {
+            self.authenticating = None;
+            return;
         }
-        self.receive_once();
+        let now = self.clock.now();
+        let authenticating = match &mut self.authenticating {
+            Some(authenticating) if now >= authenticating.next_retransmission => authenticating,
+            _ => return,
+        };
+        log::debug!(
+            "No acknowledgement from server after {:?}, retransmitting authentication.",
+            authenticating.backoff
+        );
+        authenticating.backoff = (authenticating.backoff * 2).min(MAX_HANDSHAKE_RETRANSMISSION);
+        authenticating.next_retransmission = now + authenticating.backoff;
+        let backoff = authenticating.backoff.as_secs_f64() * 1000.0;
+        let authentication = authenticating.authentication.clone();
+        self.stats.retransmissions += 1;
+        self.trace(
+            "recovery:authentication_retransmitted",
+            Json::object(vec![("backoff", backoff.into())]),
+        );
+        self.send_tlv(Tlv::Authentication(authentication));
     }
 
     /// send acks for all receiving objects, if required
     fn send_acks(&mut self) {
-        if self.session.is_none() {
-            log::warn!("Refusing to Ack on a Connection which is not fully established.");
+        if !self.is_established() {
+            log::trace!("Refusing to Ack on a Connection which is not fully established.");
             return;
         }
-        let session = self.session.as_ref().unwrap();
 
         // for all jobs collect ackables
         let mut acks: Vec<(ObjectId, ChunkId)> = Vec::new();
-        for job in &self.recv_jobs {
+        for job in &mut self.recv_jobs {
             if job.ack_req > -2 {
-                // ack required
+                // ack required, once
                 acks.push((job.object.object_id, job.ack_req));
+                job.ack_req = -2;
             }
         }
 
@@ -80,57 +404,136 @@ impl Connection {
             return;
         } // no acks to send
 
-        // build ObjectChunk message
-        let mut msg: MessageFrame = MessageFrame::default();
-        msg.sid = session.sessionid;
-        msg.version = PROTOCOL_VERSION;
-        msg.tlvs = Vec::new();
         let ack: ObjectAck = ObjectAck {
             acknowledged_object_chunks: acks,
         };
-        msg.tlvs.push(Tlv::ObjectAck(ack));
+        self.send_tlv(Tlv::ObjectAck(ack));
     }
 
-    fn send_once(&mut self, i: usize) {
-        //let mut job: ObjectSendJob = self.send_jobs.remove(i);
-        // only send, if state is established
-        if self.session.is_none() {
-            log::warn!("Connection.send_once(): Session not yet established.");
+    /// send the next chunks of all send jobs in one batch
+    fn send_chunks(&mut self) {
+        // only send, if state is established, as the checksum is not negotiated before
+        if !self.is_established() {
+            log::trace!("Connection.send_chunks(): Session not yet established.");
+            return;
+        }
+        // the server would refuse requests of a client which is not authenticated yet
+        if self.authenticating.is_some() {
+            log::trace!("Connection.send_chunks(): Authentication not yet acknowledged.");
             return;
         }
-
         let session = self.session.as_ref().unwrap();
-        let msg = self.send_jobs[i].send_next(&session);
 
-        // serialize and send frame
-        if let Some(msg) = &msg {
-            let mut cursor = Cursor::new(Vec::new());
-            msg.write(&mut cursor);
-            let buf = cursor.into_inner();
-            let n_sent = self.socket.send(&buf).unwrap();
-            assert_eq!(n_sent, buf.len());
-            self.send_jobs[i].next_chunk += 1;
+        // serialize frames, remembering which job they belong to
+        self.batch.clear();
+        let mut frames_per_job = vec![0; self.send_jobs.len()];
+        for (i, job) in self.send_jobs.iter_mut().enumerate() {
+            while !self.batch.is_full() {
+                match job.send_next(session, frames_per_job[i]) {
+                    Some(msg) => {
+                        if let Some(trace) = &mut self.trace {
+                            trace.queue_frame(&msg);
+                        }
+                        self.batch.push_with(|buf| session.write_frame(&msg, buf))
+                    }
+                    None => break,
+                }
+                frames_per_job[i] += 1;
+            }
+        }
+
+        let mut n_sent = match self.send_batch() {
+            Ok(n_sent) => n_sent,
+            Err(e) => {
+                // the chunks will be sent again on the next call
+                log::warn!("Failed to send chunks: {}", e);
+                return;
+            }
+        };
+
+        // frames were sent in order
+        let now = self.clock.now();
+        for (i, job) in self.send_jobs.iter_mut().enumerate() {
+            let n = frames_per_job[i].min(n_sent);
+            let bytes = job.stats().bytes;
+            job.advance(n, now);
+            self.stats.data_bytes += job.stats().bytes - bytes;
+            n_sent -= n;
         }
     }
 
-    /// receive the next packet
+    /// receive the next packets
     /// non-blocking
-    fn receive_once(&mut self) {
-        // try to receive a packet
-        let mut buf: [u8; MAX_UDP_BUFSIZE] = [0; MAX_UDP_BUFSIZE];
+    fn receive(&mut self) {
+        for datagram in self.transport.recv_batch(&mut self.batch, MAX_UDP_BUFSIZE) {
+            self.receive_once(datagram);
+        }
+    }
+
+    /// process a received packet
+    fn receive_once(&mut self, datagram: Bytes) {
+        self.stats.packets_received += 1;
+        self.stats.bytes_received += datagram.len() as u64;
         let mut message_frame = MessageFrame::default();
-        if let Ok(n_bytes) = self.socket.recv(&mut buf) {
-            let mut cursor = Cursor::new(buf[0..n_bytes].to_vec());
-            match message_frame.read(&mut cursor) {
-                ReadResult::Err(x) => {
-                    log::error!("MessageFrame read error: {}", &x.to_string());
-                    return;
+        let mut datagram = datagram;
+        // only a repeated handshake of the client may arrive unsealed
+        let mut handshake_only = false;
+        if let Some(session) = &mut self.session {
+            message_frame.checksum = session.features.checksum;
+            if let Some(cipher) = &mut session.cipher {
+                match cipher.open(&datagram) {
+                    Some(frame) => datagram = Bytes::from(frame),
+                    None if self.is_server && datagram.get(1..9) == Some(&[0; 8][..]) => {
+                        handshake_only = true;
+                    }
+                    None => {
+                        log::debug!("Dropping frame which could not be opened.");
+                        self.stats.checksum_failures += 1;
+                        self.trace_dropped(datagram.len(), "could not be opened");
+                        return;
+                    }
                 }
-                _ => {}
             }
-        } else {
-            // no packets received
-            return;
+        }
+        let mut cursor = Cursor::new(&datagram[..]);
+        match message_frame.read(&mut cursor) {
+            Err(x) => {
+                log::error!("MessageFrame read error: {}", &x.to_string());
+                if x.is_checksum_error() {
+                    self.stats.checksum_failures += 1;
+                }
+                self.trace_dropped(datagram.len(), &x.to_string());
+                return;
+            }
+            _ => {}
+        }
+        self.last_received = self.clock.now();
+        if let Some(trace) = &mut self.trace {
+            trace.frame_received(self.last_received, &message_frame, datagram.len());
+        }
+        // the client could only learn the session id from our handshake
+        match (&self.session, self.rtt_probe) {
+            (Some(session), Some(sent))
+                if self.is_server
+                    && message_frame.sid != 0
+                    && message_frame.sid == session.sessionid =>
+            {
+                self.rtt_probe = None;
+                self.update_rtt(self.last_received.duration_since(sent));
+            }
+            _ => {}
+        }
+        if let Some(budget) = &mut self.amplification_budget {
+            match &self.session {
+                // the client could only learn the session id from our handshake
+                Some(session)
+                    if message_frame.sid != 0 && message_frame.sid == session.sessionid =>
+                {
+                    log::debug!("Address of {} validated.", self.transport.peer());
+                    self.amplification_budget = None;
+                }
+                _ => *budget += AMPLIFICATION_FACTOR * datagram.len(),
+            }
         }
         log::trace!(
             "Received: proto version {}, sid {}, n_tlvs {}",
@@ -141,29 +544,97 @@ impl Connection {
 
         // check protocol version
         if message_frame.version != PROTOCOL_VERSION {
-            unimplemented!("Protocol version doesn't match.");
+            log::warn!(
+                "Dropping frame of unsupported protocol version {}.",
+                message_frame.version
+            );
+            self.trace_dropped(datagram.len(), "unsupported protocol version");
+            return;
         }
 
-        if message_frame.tlvs.len() <= 0 {
+        if !message_frame.skipped_tlvs.is_empty() {
+            log::debug!(
+                "Skipped unknown transport tlvs {:?}.",
+                message_frame.skipped_tlvs
+            );
+        } else if message_frame.tlvs.is_empty() {
             log::warn!("Received MessageFrame without tlvs.");
             return;
         }
 
         for tlv in &message_frame.tlvs {
-            self.accept_tlv(&message_frame, tlv);
+            if handshake_only && !matches!(tlv, Tlv::HostInformation(_)) {
+                log::debug!("Ignoring unsealed {:?}.", tlv);
+                continue;
+            }
+            self.accept_tlv(&datagram, &message_frame, tlv);
         }
     }
 
-    fn accept_tlv(&mut self, frame: &MessageFrame, tlv: &Tlv) {
+    /// `datagram` is the buffer `frame` was read from.
+    fn accept_tlv(&mut self, datagram: &Bytes, frame: &MessageFrame, tlv: &Tlv) {
         match (&self.peer_info, tlv) {
             // we are waiting for peer info
             (None, Tlv::HostInformation(hi)) => {
                 // save peer info and complete handshake
                 self.peer_info = Some(hi.clone());
+                let encryption = match self.negotiate_encryption() {
+                    Ok(encryption) => encryption,
+                    Err(code) => {
+                        log::error!("Refusing handshake: {:?}.", code);
+                        if self.is_server {
+                            self.send_error(ErrorMessage {
+                                code: code.clone(),
+                                detail: ErrorData::None,
+                            });
+                        }
+                        self.session = None;
+                        self.connecting = None;
+                        self.events.push_back(Event::HandshakeFailed(code));
+                        return;
+                    }
+                };
                 if self.is_server {
                     self.send_handshake();
                 } else {
-                    self.session = Some(EstablishedState::be_gentle(frame.sid));
+                    if let Some(sent) = self.rtt_probe.take() {
+                        self.update_rtt(self.clock.now().duration_since(sent));
+                    }
+                    self.connecting = None;
+                    self.session = Some(EstablishedState::be_gentle(
+                        frame.sid,
+                        self.negotiated_features(),
+                    ));
+                }
+                if let Some((aead, secret)) = encryption {
+                    let session = self.session.as_mut().unwrap();
+                    session.cipher = Some(FrameCipher::new(
+                        aead,
+                        &secret,
+                        session.sessionid,
+                        self.is_server,
+                    ));
+                }
+                // the ephemeral key must not outlive the handshake
+                self.key_exchange = None;
+                if let (false, Some(credentials)) = (self.is_server, &self.config.credentials) {
+                    let authentication = Authentication {
+                        user: credentials.user.clone(),
+                        method: credentials.method,
+                        proof: auth_proof(
+                            credentials.method,
+                            &credentials.secret,
+                            &self.auth_challenge(),
+                        ),
+                    };
+                    if authentication.method == AuthMethod::Token
+                        && self.session.as_ref().unwrap().cipher.is_none()
+                    {
+                        log::warn!("Sending authentication token in cleartext.");
+                    }
+                    self.send_tlv(Tlv::Authentication(authentication.clone()));
+                    self.authenticating =
+                        Some(Authenticating::new(self.clock.now(), authentication));
                 }
                 // if is_server: we have received and send HostInfos.
                 // if !is_server: we have sent and received HostInfos.
@@ -176,22 +647,65 @@ impl Connection {
                     self.session.as_ref().unwrap().sessionid
                 );
             }
+            (None, Tlv::ErrorMessage(err)) => {
+                log::error!("Peer refused handshake with error {:?}.", err.code);
+                self.session = None;
+                self.connecting = None;
+                self.events
+                    .push_back(Event::HandshakeFailed(err.code.clone()));
+            }
+            (None, Tlv::RetryCookie(retry)) if self.connecting.is_some() => {
+                log::debug!("Server asked to retry the handshake.");
+                self.retry_cookie = Some(retry.cookie.clone());
+                // the server answers the handshake with the cookie right away
+                self.rtt_probe = Some(self.clock.now());
+                self.send_handshake();
+            }
             (None, _) => {
                 log::debug!("This is not the HostInformation tlv we are waiting for. It must be the first TLV in a message.");
                 return;
             }
+            (Some(_), _) if self.session.is_none() => {
+                log::debug!("Ignoring {:?} after the handshake failed.", tlv);
+            }
+            (Some(_), Tlv::HostInformation(_)) if self.is_server => {
+                log::debug!("Client repeated its handshake, ours was probably lost.");
+                self.trace("recovery:handshake_retransmitted", Json::object(vec![]));
+                self.stats.retransmissions += 1;
+                self.rtt_probe = None;
+                self.send_handshake();
+            }
+            (Some(_), Tlv::HostInformation(_)) => {
+                log::debug!("Ignoring repeated handshake of the server.");
+            }
             (_, Tlv::ObjectHeader(oh)) => {
                 // todo!("Match peer info to correct connection.");
+                if self
+                    .recv_jobs
+                    .iter()
+                    .any(|job| job.object.object_id == oh.object_id)
+                {
+                    log::debug!("Ignoring duplicate header for object {}.", oh.object_id);
+                    self.stats.duplicates += 1;
+                    return;
+                }
+                let object = Object {
+                    object_type: oh.object_type,
+                    object_id: oh.object_id,
+                    fields: Clone::clone(&oh.fields),
+                };
+                let num_chunks: ChunkId = object.fields.iter().map(|field| field.length).sum();
+                self.events.push_back(Event::NewObject(object.clone()));
                 self.recv_jobs.push(ObjectReceiveJob {
-                    chunk_received_callback: Box::new(|_, _, _| {}),
-                    object: Object {
-                        object_type: oh.object_type,
-                        object_id: oh.object_id,
-                        fields: Clone::clone(&oh.fields),
-                        transmission_finished_callback: Box::new(|| {}),
-                    },
+                    object,
                     abort: false,
                     ack_req: if oh.ack_req { -1 } else { -2 },
+                    reader: None,
+                    received: vec![false; num_chunks.max(0) as usize],
+                    stats: ObjectStats {
+                        started: Some(self.clock.now()),
+                        ..ObjectStats::default()
+                    },
                 });
             }
             (_, Tlv::ObjectChunk(oc)) => {
@@ -205,8 +719,21 @@ impl Connection {
                         if oc.ack_required {
                             recv_job.ack_req = oc.chunk_id
                         }
-                        let fun = &mut recv_job.chunk_received_callback;
-                        fun(oc.data.clone(), oc.chunk_id, oc.num_enclosed_msgs);
+                        if recv_job.chunk_received(oc.chunk_id, oc.data.len(), self.clock.now()) {
+                            self.stats.data_bytes += oc.data.len() as u64;
+                        } else {
+                            self.stats.duplicates += 1;
+                        }
+                        let data = datagram.slice_ref(oc.data);
+                        match &recv_job.reader {
+                            Some(reader) => reader.lock().unwrap().push_chunk(oc.chunk_id, data),
+                            None => self.events.push_back(Event::Chunk {
+                                object_id: oc.object_id,
+                                chunk_id: oc.chunk_id,
+                                data,
+                                num_tlvs: oc.num_enclosed_msgs,
+                            }),
+                        }
                     }
                     None => log::warn!(
                         "Received chunk for object {} with no active receive job.",
@@ -232,92 +759,1226 @@ impl Connection {
                 }
             }
             (_, Tlv::ObjectAck(ack)) => {
-                log::trace!("Ack received, but not implemented yet.");
-                // todo!();
+                for (object_id, chunk_id) in &ack.acknowledged_object_chunks {
+                    self.events.push_back(Event::ObjectAcked {
+                        object_id: *object_id,
+                        chunk_id: *chunk_id,
+                    });
+                }
             }
-            (_, _) => unimplemented!(),
+            (_, Tlv::Authentication(auth)) if self.is_server => {
+                let verified = match self.config.users.as_ref().and_then(|u| u.get(&auth.user)) {
+                    Some(secret) => {
+                        verify_auth_proof(auth.method, secret, &self.auth_challenge(), &auth.proof)
+                    }
+                    None => false,
+                };
+                if verified {
+                    if self.user.as_ref() == Some(&auth.user) {
+                        log::debug!(
+                            "Client repeated its authentication, our ack was probably lost."
+                        );
+                    } else {
+                        log::info!("Client authenticated as {}.", auth.user);
+                        self.user = Some(auth.user.clone());
+                        self.events
+                            .push_back(Event::Authenticated(auth.user.clone()));
+                    }
+                    self.send_tlv(Tlv::AuthenticationAck(AuthenticationAck {
+                        user: auth.user.clone(),
+                    }));
+                } else {
+                    log::warn!("Authentication as {} failed.", auth.user);
+                    self.send_tlv(Tlv::ErrorMessage(ErrorMessage {
+                        code: ErrorCode::AuthenticationFailed,
+                        detail: ErrorData::None,
+                    }));
+                    self.session = None;
+                    self.events
+                        .push_back(Event::HandshakeFailed(ErrorCode::AuthenticationFailed));
+                }
+            }
+            (_, Tlv::Authentication(_)) => log::warn!("Ignoring authentication of the server."),
+            (_, Tlv::AuthenticationAck(ack)) if self.authenticating.is_some() => {
+                log::info!("Server accepted authentication as {}.", ack.user);
+                self.authenticating = None;
+            }
+            (_, Tlv::AuthenticationAck(_)) => {
+                log::debug!("Ignoring unexpected authentication ack.");
+            }
+            (_, Tlv::ErrorMessage(err)) if err.code == ErrorCode::AuthenticationFailed => {
+                log::error!("Server rejected authentication.");
+                self.authenticating = None;
+                self.session = None;
+                self.events
+                    .push_back(Event::HandshakeFailed(err.code.clone()));
+            }
+            (_, Tlv::ErrorMessage(err)) => {
+                log::warn!("Peer reported error {:?}.", err.code);
+                self.events.push_back(Event::PeerError(err.clone()));
+            }
+            (_, Tlv::RetryCookie(_)) => {
+                // checked by the Listener before the Connection was created
+            }
+            (_, Tlv::ObjectSkip(_)) => log::debug!("Ignoring ObjectSkip, which is not supported."),
         }
     }
 
-    /// must be called before anything is sent.
-    /// After calling this, self.session will be Some().
+    /// must be called before anything is sent, and again if the handshake was lost.
+    /// Afterwards, self.session will be Some() on servers.
     pub(super) fn send_handshake(&mut self) {
         // create message frame
-        let mut frame: MessageFrame = MessageFrame::default();
-        frame.version = PROTOCOL_VERSION;
-        if self.is_server {
+        let sid = match &self.session {
+            // repeated with the same session id
+            Some(session) => session.sessionid,
             // set random session id
-            frame.sid = thread_rng().gen_range(1, 2 ^ 64);
-        } else {
+            None if self.is_server => self.rng.gen_range(1, SessionId::MAX),
             // we are a client
-            frame.sid = 0;
-        }
+            None => 0,
+        };
+        let mut frame = MessageFrame {
+            version: PROTOCOL_VERSION,
+            sid,
+            ..MessageFrame::default()
+        };
         frame
             .tlvs
             .insert(0, Tlv::HostInformation(self.self_info.clone()));
+        if let Some(cookie) = &self.retry_cookie {
+            frame.tlvs.push(Tlv::RetryCookie(RetryCookie {
+                cookie: cookie.clone(),
+            }));
+        }
 
         // serialize and send frame
-        let mut cursor = Cursor::new(Vec::new());
-        frame.write(&mut cursor);
-        let buf = cursor.into_inner();
-        let n_sent = self.socket.send(&buf).unwrap();
+        self.batch.clear();
+        self.batch.push_with(|buf| frame.write(buf));
+        if let Some(trace) = &mut self.trace {
+            trace.queue_frame(&frame);
+        }
+        if let Err(e) = self.send_batch() {
+            log::warn!("Failed to send handshake: {}", e);
+        }
 
         // now we can carefully initialize the session
-        self.session = Some(EstablishedState::be_gentle(frame.sid));
+        if self.is_server && self.session.is_none() {
+            self.rtt_probe = Some(self.clock.now());
+            self.session = Some(EstablishedState::be_gentle(
+                frame.sid,
+                self.negotiated_features(),
+            ));
+        }
+    }
+
+    /// Send a single unencrypted `ErrorMessage` to the peer, e.g. during the handshake.
+    fn send_error(&mut self, err: ErrorMessage) {
+        let frame = MessageFrame {
+            version: PROTOCOL_VERSION,
+            tlvs: vec![Tlv::ErrorMessage(err)],
+            ..MessageFrame::default()
+        };
+
+        self.batch.clear();
+        self.batch.push_with(|buf| frame.write(buf));
+        if let Some(trace) = &mut self.trace {
+            trace.queue_frame(&frame);
+        }
+        if let Err(e) = self.send_batch() {
+            log::warn!("Failed to send error: {}", e);
+        }
+    }
+
+    /// Send a frame with a single tlv on the established session.
+    fn send_tlv(&mut self, tlv: Tlv) {
+        let session = self.session.as_ref().unwrap();
+        let frame = MessageFrame {
+            version: PROTOCOL_VERSION,
+            sid: session.sessionid,
+            checksum: session.features.checksum,
+            tlvs: vec![tlv],
+            ..MessageFrame::default()
+        };
+
+        self.batch.clear();
+        self.batch.push_with(|buf| session.write_frame(&frame, buf));
+        if let Some(trace) = &mut self.trace {
+            trace.queue_frame(&frame);
+        }
+        if let Err(e) = self.send_batch() {
+            log::warn!("Failed to send frame: {}", e);
+        }
+    }
+
+    /// Send the datagrams in `batch` like `send_batch_limited()`, and count and trace them.
+    fn send_batch(&mut self) -> io::Result<usize> {
+        let result = self.send_batch_limited();
+        let n_sent = *result.as_ref().unwrap_or(&0);
+        self.stats.packets_sent += n_sent as u64;
+        for i in 0..n_sent {
+            self.stats.bytes_sent += self.batch.datagram(i).len() as u64;
+        }
+        if let Some(trace) = &mut self.trace {
+            let batch = &self.batch;
+            let lengths: Vec<usize> = (0..n_sent).map(|i| batch.datagram(i).len()).collect();
+            trace.frames_sent(self.clock.now(), &lengths);
+        }
+        result
+    }
+
+    /// Send the datagrams in `batch` like `DatagramTransport::send_batch()`,
+    /// but not more than `amplification_budget` allows.
+    fn send_batch_limited(&mut self) -> io::Result<usize> {
+        let budget = match &mut self.amplification_budget {
+            Some(budget) => budget,
+            None => return self.transport.send_batch(&mut self.batch),
+        };
+        let mut n_sent = 0;
+        while n_sent < self.batch.len() {
+            let datagram = self.batch.datagram(n_sent);
+            if datagram.len() > *budget {
+                log::debug!(
+                    "Amplification limit reached, {} bytes left until the address of {} is validated.",
+                    budget,
+                    self.transport.peer()
+                );
+                break;
+            }
+            match self.transport.send(datagram) {
+                Ok(_) => *budget -= datagram.len(),
+                Err(e) if n_sent == 0 => return Err(e),
+                Err(_) => break,
+            }
+            n_sent += 1;
+        }
+        Ok(n_sent)
+    }
+
+    /// Write an event to the trace, if any.
+    fn trace(&mut self, name: &'static str, data: Json) {
+        if let Some(trace) = &mut self.trace {
+            trace.event(self.clock.now(), name, data);
+        }
+    }
+
+    /// Take a round trip time sample into the statistics.
+    fn update_rtt(&mut self, rtt: Duration) {
+        self.stats.rtt = Some(rtt);
+        let rtt = rtt.as_secs_f64() * 1000.0;
+        self.trace(
+            "recovery:rtt_updated",
+            Json::object(vec![("rtt", rtt.into())]),
+        );
+    }
+
+    fn trace_dropped(&mut self, length: usize, reason: &str) {
+        if self.trace.is_some() {
+            self.trace(
+                "transport:packet_dropped",
+                Json::object(vec![("length", length.into()), ("reason", reason.into())]),
+            );
+        }
+    }
+
+    /// Trace a transition to another state, see `super::trace`.
+    fn trace_state(&mut self) {
+        let new = match (&self.connecting, &self.session, &self.peer_info) {
+            (Some(_), _, _) => "connecting",
+            (None, Some(_), _) => "established",
+            (None, None, Some(_)) => "failed",
+            (None, None, None) if self.is_server => "accepted",
+            (None, None, None) => "closed",
+        };
+        let now = self.clock.now();
+        if let Some(trace) = &mut self.trace {
+            if trace.state != new {
+                let old = std::mem::replace(&mut trace.state, new);
+                trace.event(
+                    now,
+                    "connectivity:connection_state_updated",
+                    Json::object(vec![("old", old.into()), ("new", new.into())]),
+                );
+            }
+        }
+    }
+
+    /// Challenge for `AuthMethod::ChallengeResponse`, which is unique to the
+    /// session: the session id and the server's ephemeral key, if any.
+    fn auth_challenge(&self) -> Vec<u8> {
+        let server_info = if self.is_server {
+            &self.self_info
+        } else {
+            self.peer_info.as_ref().unwrap()
+        };
+        let mut challenge = self
+            .session
+            .as_ref()
+            .unwrap()
+            .sessionid
+            .to_be_bytes()
+            .to_vec();
+        if let Some(key) = server_info
+            .capabilities
+            .encryption
+            .as_ref()
+            .and_then(|encryption| encryption.ephemeral_key.as_ref())
+        {
+            challenge.extend_from_slice(key);
+        }
+        challenge
+    }
+
+    /// AEAD algorithm and session secret for frames after the handshake,
+    /// or None to stay in cleartext. Fails if encryption is required by us
+    /// but not possible, or if the keys of the peers don't fit together.
+    fn negotiate_encryption(&self) -> Result<Option<(AeadAlgorithm, Vec<u8>)>, ErrorCode> {
+        let none = Encryption::default();
+        let own = self
+            .self_info
+            .capabilities
+            .encryption
+            .as_ref()
+            .unwrap_or(&none);
+        let peer = self.peer_info.as_ref().unwrap();
+        let peer = peer.capabilities.encryption.as_ref().unwrap_or(&none);
+        if own.psk != peer.psk {
+            // one of us would mix in a key the other one doesn't have
+            return Err(ErrorCode::EncryptionRequired);
+        }
+
+        let aead = self.negotiated_features().aead;
+        let (exchanged, required) = if self.is_server {
+            let exchanged = match (
+                &self.key_exchange,
+                &self.config.static_key,
+                &peer.ephemeral_key,
+            ) {
+                (Some(key_exchange), Some(static_key), Some(client_ephemeral)) => Some(
+                    key_exchange
+                        .server_secret(static_key, client_ephemeral)
+                        .ok_or(ErrorCode::EncryptionRequired)?,
+                ),
+                _ => None,
+            };
+            (exchanged, self.config.static_key.is_some())
+        } else {
+            if let Some(server_key) = &self.config.server_key {
+                if peer.static_key != Some(server_key.to_bytes()) {
+                    return Err(ErrorCode::UnknownServerKey);
+                }
+            }
+            let exchanged = match (&self.key_exchange, &peer.ephemeral_key, &peer.static_key) {
+                (Some(key_exchange), Some(server_ephemeral), Some(server_static)) => Some(
+                    key_exchange
+                        .client_secret(server_ephemeral, server_static)
+                        .ok_or(ErrorCode::EncryptionRequired)?,
+                ),
+                _ => None,
+            };
+            (exchanged, self.config.server_key.is_some())
+        };
+
+        let mut secret = exchanged.unwrap_or_default();
+        if let Some(psk) = &self.config.psk {
+            secret.extend_from_slice(psk);
+        }
+        match aead {
+            Some(aead) if !secret.is_empty() => Ok(Some((aead, secret))),
+            _ if required || own.psk => Err(ErrorCode::EncryptionRequired),
+            _ => Ok(None),
+        }
+    }
+
+    /// Features for frames after the handshake.
+    fn negotiated_features(&self) -> Features {
+        let own = &self.self_info.capabilities;
+        match &self.peer_info {
+            None => Features::default(),
+            Some(peer_info) if self.is_server => Features::negotiate(&peer_info.capabilities, own),
+            Some(peer_info) => Features::negotiate(own, &peer_info.capabilities),
+        }
+    }
+}
+
+/// Authentication retransmission of a client, until the server acknowledges it.
+pub(super) struct Authenticating {
+    authentication: Authentication,
+    next_retransmission: Instant,
+    /// Time from the last to the next retransmission.
+    backoff: Duration,
+}
+
+impl Authenticating {
+    fn new(now: Instant, authentication: Authentication) -> Self {
+        Self {
+            authentication,
+            next_retransmission: now + HANDSHAKE_RETRANSMISSION,
+            backoff: HANDSHAKE_RETRANSMISSION,
+        }
+    }
+}
+
+/// Handshake retransmission of a client, until the server answers.
+pub(super) struct Connecting {
+    /// When the first handshake was sent, for `Config::connect_timeout`.
+    started: Instant,
+    next_retransmission: Instant,
+    /// Time from the last to the next retransmission.
+    backoff: Duration,
+}
+
+impl Connecting {
+    fn new(now: Instant) -> Self {
+        Self {
+            started: now,
+            next_retransmission: now + HANDSHAKE_RETRANSMISSION,
+            backoff: HANDSHAKE_RETRANSMISSION,
+        }
     }
 }
 
 pub(super) struct EstablishedState {
     pub(super) sessionid: SessionId,
+    pub(super) features: Features,
+    /// Seals and opens all frames after the handshake, if a pre-shared key is used.
+    pub(super) cipher: Option<FrameCipher>,
 }
 
 impl EstablishedState {
     /// returns a state that ensures the connection will be as gentle as
     /// possible to its peer.
-    fn be_gentle(sessionid: SessionId) -> Self {
-        Self { sessionid }
+    fn be_gentle(sessionid: SessionId, features: Features) -> Self {
+        Self {
+            sessionid,
+            features,
+            cipher: None,
+        }
+    }
+
+    /// Serialize `frame` to the end of `buf`, sealed if the session is encrypted.
+    pub(super) fn write_frame(&self, frame: &MessageFrame, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        frame.write(buf);
+        if let Some(cipher) = &self.cipher {
+            cipher.seal(buf, start);
+        }
     }
 }
 
 mod test {
     #[test]
     fn handshake() {
-        use env_logger;
-        env_logger::init();
         use crate::transport::client;
+        use crate::transport::config::Config;
         use crate::transport::connection::*;
         use crate::transport::server;
-        use std::thread;
+        use crate::transport::sim::{Env, SimClock, SimNetwork};
         use std::time::Duration;
 
-        let mut connection_listener = server::Listener::new("0.0.0.0:8080".parse().unwrap());
-        let mut server_conn: Option<Connection> =
-            connection_listener.listen_once(Box::new(|a| {}), Box::new(|| {}));
-        assert_eq!(server_conn.is_none(), true);
+        let clock = SimClock::new();
+        let network = SimNetwork::new(&clock, 1);
+        network.set_latency(Duration::from_millis(10));
+        let (client, server) = network.pair();
+        let mut connection_listener = server::Listener::with_transport(
+            Box::new(server),
+            Env::simulated(&clock, 2),
+            Config::default(),
+        );
+        let mut server_conn: Option<Connection> = connection_listener.listen_once();
+        assert!(server_conn.is_none());
 
-        let mut client_conn = client::connect(
-            "127.0.0.1:8080".parse().unwrap(),
-            Box::new(|a| {}),
-            Box::new(|| {}),
+        let mut client_conn = client::connect_transport(
+            Box::new(client),
+            Env::simulated(&clock, 3),
+            Config::default(),
         );
-        thread::sleep(Duration::from_secs_f32(0.1));
+        clock.advance(Duration::from_millis(10));
 
         client_conn.receive_and_send();
         // initialized, but not complete yet
-        assert_eq!(client_conn.session.as_ref().unwrap().sessionid, 0);
+        assert!(client_conn.connecting.is_some());
+        assert!(client_conn.session.is_none());
 
-        server_conn = connection_listener.listen_once(Box::new(|a| {}), Box::new(|| {}));
-        assert_eq!(server_conn.is_some(), true);
+        server_conn = connection_listener.listen_once();
+        assert!(server_conn.is_some());
 
         client_conn.receive_and_send();
-        assert_eq!(client_conn.session.as_ref().unwrap().sessionid, 0);
+        assert!(client_conn.session.is_none());
 
         let mut server_conn = server_conn.unwrap();
         server_conn.receive_and_send();
         // assert initialized
         assert_ne!(server_conn.session.unwrap().sessionid, 0);
 
+        clock.advance(Duration::from_millis(10));
         client_conn.receive_and_send();
+        assert!(client_conn.connecting.is_none());
         assert_ne!(client_conn.session.as_ref().unwrap().sessionid, 0);
+        assert_eq!(
+            client_conn.session.as_ref().unwrap().features.checksum,
+            ChecksumAlgorithm::Crc32c
+        );
+    }
+
+    #[test]
+    fn events() {
+        use crate::transport::config::Config;
+        use crate::transport::connection::*;
+
+        let (mut client_conn, mut server_conn) =
+            handshake_with(Config::default(), Config::default());
+        assert!(client_conn.is_established());
+
+        // send an object with a single chunk
+        let mut job = ObjectSendJob::new(Object {
+            object_type: 42,
+            object_id: 1,
+            fields: vec![ObjectFieldDescription {
+                field_type: 66,
+                length: 1,
+            }],
+        });
+        job.push_chunk(vec![1, 2, 3], 1);
+        client_conn.send_jobs.push(job);
+        client_conn.receive_and_send(); // header
+        client_conn.receive_and_send(); // chunk
+        assert_eq!(client_conn.poll_event(), Some(Event::ObjectComplete(1)));
+        assert_eq!(client_conn.send_jobs.len(), 0);
+
+        server_conn.receive_and_send();
+        server_conn.receive_and_send();
+        match server_conn.poll_event() {
+            Some(Event::NewObject(object)) => assert_eq!(object.object_type, 42),
+            e => panic!("Unexpected event {:?}", e),
+        }
+        assert_eq!(
+            server_conn.poll_event(),
+            Some(Event::Chunk {
+                object_id: 1,
+                chunk_id: 0,
+                data: Bytes::from(vec![1, 2, 3]),
+                num_tlvs: 1
+            })
+        );
+        assert_eq!(server_conn.poll_event(), None);
+    }
+
+    #[test]
+    fn object_acked() {
+        use crate::transport::config::Config;
+        use crate::transport::connection::*;
+
+        let (mut client_conn, mut server_conn) =
+            handshake_with(Config::default(), Config::default());
+        let mut job = ObjectSendJob::new(Object {
+            object_type: 42,
+            object_id: 1,
+            fields: vec![ObjectFieldDescription {
+                field_type: 66,
+                length: 1,
+            }],
+        });
+        job.push_chunk(vec![1, 2, 3], 1);
+        client_conn.send_jobs.push(job);
+        client_conn.receive_and_send();
+        client_conn.receive_and_send();
+        assert_eq!(client_conn.poll_event(), Some(Event::ObjectComplete(1)));
+
+        // ask for an ack of the chunk
+        client_conn.send_tlv(Tlv::ObjectAckRequest(ObjectAckRequest {
+            req_ack_object_chunks: vec![(1, 0)],
+        }));
+        server_conn.receive_and_send();
+        server_conn.receive_and_send();
+        client_conn.receive_and_send();
+        assert_eq!(
+            client_conn.poll_event(),
+            Some(Event::ObjectAcked {
+                object_id: 1,
+                chunk_id: 0
+            })
+        );
+        // acked once
+        server_conn.receive_and_send();
+        client_conn.receive_and_send();
+        assert_eq!(client_conn.poll_event(), None);
+    }
+
+    #[test]
+    fn handshake_ipv6() {
+        use crate::transport::client;
+        use crate::transport::config::Config;
+        use crate::transport::datagram::UdpTransport;
+        use crate::transport::server;
+        use crate::transport::sim::Env;
+        use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+        use std::thread;
+        use std::time::{Duration, Instant};
+
+        // IPv6 client to dual-stack listener, and IPv4 client to dual-stack listener
+        let mut cases: Vec<(IpAddr, IpAddr)> =
+            vec![(Ipv6Addr::UNSPECIFIED.into(), Ipv4Addr::LOCALHOST.into())];
+        if UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).is_ok() {
+            cases.push((Ipv6Addr::UNSPECIFIED.into(), Ipv6Addr::LOCALHOST.into()));
+        } else {
+            log::warn!("Skipping IPv6, ::1 is not available");
+            cases[0].0 = Ipv4Addr::UNSPECIFIED.into();
+        }
+        for (bind, ip) in cases {
+            let transport = UdpTransport::bind(SocketAddr::new(bind, 0)).unwrap();
+            let dest = SocketAddr::new(ip, transport.local_addr().unwrap().port());
+            let mut connection_listener = server::Listener::with_transport(
+                Box::new(transport),
+                Env::default(),
+                Config::default(),
+            );
+            let mut client_conn = client::connect(dest).unwrap();
+            let mut server_conn = None;
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !client_conn.is_established() && Instant::now() < deadline {
+                if server_conn.is_none() {
+                    server_conn = connection_listener.listen_once();
+                }
+                if let Some(server_conn) = &mut server_conn {
+                    server_conn.receive_and_send();
+                }
+                client_conn.receive_and_send();
+                thread::sleep(Duration::from_millis(1));
+            }
+            assert_ne!(client_conn.session.as_ref().unwrap().sessionid, 0);
+        }
+    }
+
+    #[test]
+    fn encrypted() {
+        use crate::transport::client;
+        use crate::transport::config::Config;
+        use crate::transport::connection::*;
+        use crate::transport::datagram::{DatagramTransport, MemoryTransport};
+        use crate::transport::server;
+        use crate::transport::sim::Env;
+        use std::io::{self, Write};
+
+        /// Records all datagrams sent by the client.
+        struct Wiretap(MemoryTransport, Arc<Mutex<Vec<Vec<u8>>>>);
+        impl DatagramTransport for Wiretap {
+            fn send(&self, buf: &[u8]) -> io::Result<usize> {
+                self.1.lock().unwrap().push(buf.to_vec());
+                self.0.send(buf)
+            }
+            fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+                self.0.recv(buf)
+            }
+            fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, String)> {
+                self.0.recv_from(buf)
+            }
+            fn send_to(&self, buf: &[u8], peer: &str) -> io::Result<usize> {
+                self.1.lock().unwrap().push(buf.to_vec());
+                self.0.send_to(buf, peer)
+            }
+            fn peer(&self) -> String {
+                self.0.peer()
+            }
+        }
+
+        let config = Config {
+            psk: Some(b"secret".to_vec()),
+            ..Config::default()
+        };
+        let (client, server) = MemoryTransport::pair();
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let client = Wiretap(client, Arc::clone(&sent));
+        let mut connection_listener =
+            server::Listener::with_transport(Box::new(server), Env::default(), config.clone());
+        let mut client_conn = client::connect_transport(Box::new(client), Env::default(), config);
+        let mut server_conn = connection_listener.listen_once().unwrap();
+        server_conn.receive_and_send();
+        client_conn.receive_and_send();
+        assert!(client_conn.is_established());
+        assert!(server_conn.session.as_ref().unwrap().cipher.is_some());
+
+        let payload = b"confidential payload";
+        let mut writer = client_conn.open_object(42);
+        writer.write_all(payload).unwrap();
+        writer.finish();
+        client_conn.receive_and_send();
+        server_conn.receive_and_send();
+        match server_conn.poll_event() {
+            Some(Event::NewObject(object)) => assert_eq!(object.object_type, 42),
+            e => panic!("Unexpected event {:?}", e),
+        }
+        match server_conn.poll_event() {
+            Some(Event::Chunk { data, .. }) => assert_eq!(&data[..], &payload[..]),
+            e => panic!("Unexpected event {:?}", e),
+        }
+        let sent = sent.lock().unwrap();
+        assert!(sent.len() > 1);
+        for datagram in sent.iter() {
+            assert!(!datagram.windows(payload.len()).any(|w| w == &payload[..]));
+        }
+    }
+
+    /// Client and server `Connection`s after the handshake in memory.
+    #[cfg(test)]
+    fn handshake_with(
+        client_config: crate::transport::config::Config,
+        server_config: crate::transport::config::Config,
+    ) -> (super::Connection, super::Connection) {
+        use crate::transport::client;
+        use crate::transport::datagram::MemoryTransport;
+        use crate::transport::server;
+        use crate::transport::sim::Env;
+
+        let (client, server) = MemoryTransport::pair();
+        let mut connection_listener =
+            server::Listener::with_transport(Box::new(server), Env::default(), server_config);
+        let mut client_conn =
+            client::connect_transport(Box::new(client), Env::default(), client_config);
+        let mut server_conn = connection_listener.listen_once().unwrap();
+        server_conn.receive_and_send();
+        client_conn.receive_and_send();
+        (client_conn, server_conn)
+    }
+
+    #[test]
+    fn encryption_required() {
+        use crate::transport::config::Config;
+        use crate::transport::connection::*;
+
+        let config = Config {
+            psk: Some(b"secret".to_vec()),
+            ..Config::default()
+        };
+        let (mut client_conn, mut server_conn) = handshake_with(Config::default(), config);
+        assert!(!client_conn.is_established());
+        let failed = Some(Event::HandshakeFailed(ErrorCode::EncryptionRequired));
+        assert_eq!(server_conn.poll_event(), failed);
+        assert_eq!(client_conn.poll_event(), failed);
+    }
+
+    #[test]
+    fn key_exchange() {
+        use crate::transport::config::Config;
+        use crate::transport::connection::*;
+        use crate::transport::crypto::StaticSecret;
+
+        let static_key = StaticSecret::from([3; 32]);
+        let server_config = Config {
+            static_key: Some(static_key.clone()),
+            ..Config::default()
+        };
+        // pinned and unpinned server key
+        for server_key in &[Some(PublicKey::from(&static_key)), None] {
+            let client_config = Config {
+                server_key: *server_key,
+                ..Config::default()
+            };
+            let (client_conn, server_conn) = handshake_with(client_config, server_config.clone());
+            assert!(client_conn.is_established());
+            assert!(client_conn.session.as_ref().unwrap().cipher.is_some());
+            assert!(server_conn.session.as_ref().unwrap().cipher.is_some());
+            assert!(client_conn.key_exchange.is_none());
+        }
+
+        // wrong pinned key
+        let client_config = Config {
+            server_key: Some(PublicKey::from(&StaticSecret::from([4; 32]))),
+            ..Config::default()
+        };
+        let (mut client_conn, _) = handshake_with(client_config.clone(), server_config);
+        assert!(!client_conn.is_established());
+        assert_eq!(
+            client_conn.poll_event(),
+            Some(Event::HandshakeFailed(ErrorCode::UnknownServerKey))
+        );
+        // pinned key, but the server has none
+        let (mut client_conn, _) = handshake_with(client_config, Config::default());
+        assert_eq!(
+            client_conn.poll_event(),
+            Some(Event::HandshakeFailed(ErrorCode::UnknownServerKey))
+        );
+        // no keys at all
+        let (client_conn, _) = handshake_with(Config::default(), Config::default());
+        assert!(client_conn.is_established());
+        assert!(client_conn.session.as_ref().unwrap().cipher.is_none());
+    }
+
+    #[test]
+    fn authentication() {
+        use crate::transport::config::{Config, Credentials};
+        use crate::transport::connection::*;
+        use std::collections::HashMap;
+
+        let mut users = HashMap::new();
+        users.insert(String::from("alice"), b"s3cr3t".to_vec());
+        let server_config = Config {
+            users: Some(Arc::new(users)),
+            ..Config::default()
+        };
+        let client_config = |secret: &[u8], method| Config {
+            credentials: Some(Credentials {
+                user: String::from("alice"),
+                secret: secret.to_vec(),
+                method,
+            }),
+            ..Config::default()
+        };
+
+        for &method in &[AuthMethod::Token, AuthMethod::ChallengeResponse] {
+            let (_, mut server_conn) =
+                handshake_with(client_config(b"s3cr3t", method), server_config.clone());
+            server_conn.receive_and_send();
+            assert_eq!(
+                server_conn.poll_event(),
+                Some(Event::Authenticated(String::from("alice")))
+            );
+            assert_eq!(server_conn.user(), Some("alice"));
+
+            let (mut client_conn, mut server_conn) =
+                handshake_with(client_config(b"guess", method), server_config.clone());
+            server_conn.receive_and_send();
+            client_conn.receive_and_send();
+            let failed = Some(Event::HandshakeFailed(ErrorCode::AuthenticationFailed));
+            assert_eq!(server_conn.poll_event(), failed);
+            assert_eq!(client_conn.poll_event(), failed);
+            assert_eq!(server_conn.user(), None);
+            assert!(!client_conn.is_established());
+        }
+
+        // no credentials
+        let (_, mut server_conn) = handshake_with(Config::default(), server_config);
+        server_conn.receive_and_send();
+        assert_eq!(server_conn.poll_event(), None);
+        assert_eq!(server_conn.user(), None);
+    }
+
+    #[test]
+    fn authentication_retransmission() {
+        use crate::transport::client;
+        use crate::transport::common::*;
+        use crate::transport::config::{Config, Credentials};
+        use crate::transport::connection::*;
+        use crate::transport::datagram::{DatagramTransport, MemoryTransport};
+        use crate::transport::server;
+        use crate::transport::sim::{Env, SimClock};
+        use std::collections::HashMap;
+        use std::io::Write;
+
+        let mut users = HashMap::new();
+        users.insert(String::from("alice"), b"s3cr3t".to_vec());
+        let server_config = Config {
+            users: Some(Arc::new(users)),
+            ..Config::default()
+        };
+        let client_config = Config {
+            credentials: Some(Credentials {
+                user: String::from("alice"),
+                secret: b"s3cr3t".to_vec(),
+                method: AuthMethod::ChallengeResponse,
+            }),
+            ..Config::default()
+        };
+        let clock = SimClock::new();
+        let (client, server) = MemoryTransport::pair();
+        let mut connection_listener = server::Listener::with_transport(
+            Box::new(server),
+            Env::simulated(&clock, 1),
+            server_config,
+        );
+        let mut client_conn =
+            client::connect_transport(Box::new(client), Env::simulated(&clock, 2), client_config);
+        let mut server_conn = connection_listener.listen_once().unwrap();
+        server_conn.receive_and_send();
+        client_conn.receive_and_send();
+        assert!(client_conn.is_established());
+
+        // the authentication is lost, requests are held back meanwhile
+        server_conn.transport.drop_next_datagram().unwrap();
+        let mut writer = client_conn.open_object(42);
+        writer.write_all(b"request").unwrap();
+        writer.finish();
+        client_conn.receive_and_send();
+        server_conn.receive_and_send();
+        assert_eq!(server_conn.poll_event(), None);
+        assert_eq!(server_conn.user(), None);
+
+        clock.advance(HANDSHAKE_RETRANSMISSION);
+        client_conn.receive_and_send();
+        server_conn.receive_and_send();
+        assert_eq!(
+            server_conn.poll_event(),
+            Some(Event::Authenticated(String::from("alice")))
+        );
+        assert_eq!(server_conn.poll_event(), None);
+        assert_eq!(client_conn.stats().retransmissions, 1);
+
+        // the request follows the acknowledgement
+        client_conn.receive_and_send();
+        client_conn.receive_and_send();
+        server_conn.receive_and_send();
+        match server_conn.poll_event() {
+            Some(Event::NewObject(object)) => assert_eq!(object.object_type, 42),
+            e => panic!("Unexpected event {:?}", e),
+        }
+        assert!(client_conn.authenticating.is_none());
+    }
+
+    #[test]
+    fn stateless_retry() {
+        use crate::common::{Cursor, WireFormat};
+        use crate::transport::client;
+        use crate::transport::common::*;
+        use crate::transport::config::Config;
+        use crate::transport::connection::*;
+        use crate::transport::datagram::{DatagramTransport, MemoryTransport};
+        use crate::transport::server;
+        use crate::transport::sim::Env;
+
+        let config = Config {
+            stateless_retry: true,
+            ..Config::default()
+        };
+        let (client, server) = MemoryTransport::pair();
+        let mut connection_listener =
+            server::Listener::with_transport(Box::new(server), Env::default(), config.clone());
+        let mut client_conn =
+            client::connect_transport(Box::new(client), Env::default(), Config::default());
+        // the first handshake is only answered with a cookie
+        assert!(connection_listener.listen_once().is_none());
+        client_conn.receive_and_send();
+        assert!(client_conn.retry_cookie.is_some());
+        let mut server_conn = connection_listener.listen_once().unwrap();
+        assert_eq!(server_conn.amplification_budget, None);
+        server_conn.receive_and_send();
+        client_conn.receive_and_send();
+        assert!(client_conn.is_established());
+        assert_eq!(
+            client_conn.session.as_ref().unwrap().sessionid,
+            server_conn.session.as_ref().unwrap().sessionid
+        );
+
+        // a forged cookie is answered with a new one
+        let (client, server) = MemoryTransport::pair();
+        let mut connection_listener =
+            server::Listener::with_transport(Box::new(server), Env::default(), config);
+        let mut frame = MessageFrame::default();
+        frame.version = PROTOCOL_VERSION;
+        frame.tlvs.push(Tlv::HostInformation(default_host_info()));
+        frame.tlvs.push(Tlv::RetryCookie(RetryCookie {
+            cookie: vec![0; 24],
+        }));
+        let mut handshake = Vec::new();
+        frame.write(&mut handshake);
+        client.send(&handshake).unwrap();
+        assert!(connection_listener.listen_once().is_none());
+        let mut buf = vec![0; MAX_UDP_BUFSIZE];
+        let n_bytes = client.recv(&mut buf).unwrap();
+        assert!(n_bytes <= handshake.len());
+        let mut reply = MessageFrame::default();
+        assert!(matches!(
+            reply.read(&mut Cursor::new(&buf[..n_bytes])),
+            Ok(())
+        ));
+        match &reply.tlvs[..] {
+            [Tlv::RetryCookie(retry)] => assert_ne!(retry.cookie, vec![0; 24]),
+            tlvs => panic!("Unexpected reply {:?}", tlvs),
+        }
+    }
+
+    #[test]
+    fn amplification_limit() {
+        use crate::transport::common::*;
+        use crate::transport::config::Config;
+        use std::io::Write;
+
+        let (mut client_conn, mut server_conn) =
+            handshake_with(Config::default(), Config::default());
+        let budget = server_conn.amplification_budget.unwrap();
+        let mut writer = server_conn.open_object(42);
+        writer.write_all(&[0; 10000]).unwrap();
+        writer.finish();
+        server_conn.receive_and_send();
+        let mut buf = vec![0; MAX_UDP_BUFSIZE];
+        let mut n_bytes = 0;
+        while let Ok(n) = client_conn.transport.recv(&mut buf) {
+            n_bytes += n;
+        }
+        assert!(n_bytes <= budget);
+
+        // any frame of the client with the session id validates its address
+        let mut writer = client_conn.open_object(42);
+        writer.write_all(b"hello").unwrap();
+        writer.finish();
+        client_conn.receive_and_send();
+        server_conn.receive_and_send();
+        server_conn.receive_and_send();
+        assert_eq!(server_conn.amplification_budget, None);
+        let mut n_bytes = 0;
+        while let Ok(n) = client_conn.transport.recv(&mut buf) {
+            n_bytes += n;
+        }
+        assert!(n_bytes > 10000);
+    }
+
+    #[test]
+    fn handshake_retransmission() {
+        use crate::transport::client;
+        use crate::transport::common::*;
+        use crate::transport::config::Config;
+        use crate::transport::connection::*;
+        use crate::transport::datagram::{DatagramTransport, MemoryTransport};
+        use crate::transport::server;
+        use crate::transport::sim::{Env, SimClock};
+
+        let encrypted = Config {
+            psk: Some(b"secret".to_vec()),
+            ..Config::default()
+        };
+        for config in vec![Config::default(), encrypted] {
+            let clock = SimClock::new();
+            let (client, server) = MemoryTransport::pair();
+            let mut client_conn = client::connect_transport(
+                Box::new(client),
+                Env::simulated(&clock, 1),
+                config.clone(),
+            );
+            // the client's handshake is lost
+            server.drop_next_datagram().unwrap();
+            let mut connection_listener = server::Listener::with_transport(
+                Box::new(server),
+                Env::simulated(&clock, 2),
+                config,
+            );
+            assert!(connection_listener.listen_once().is_none());
+            clock.advance(HANDSHAKE_RETRANSMISSION);
+            client_conn.receive_and_send();
+            let mut server_conn = connection_listener.listen_once().unwrap();
+
+            // the server's handshake is lost
+            server_conn.receive_and_send();
+            client_conn.transport.drop_next_datagram().unwrap();
+            client_conn.receive_and_send();
+            assert!(!client_conn.is_established());
+            clock.advance(HANDSHAKE_RETRANSMISSION);
+            client_conn.receive_and_send();
+            assert!(!client_conn.is_established());
+            // backoff
+            clock.advance(HANDSHAKE_RETRANSMISSION);
+            client_conn.receive_and_send();
+            server_conn.receive_and_send();
+            client_conn.receive_and_send();
+            assert!(client_conn.is_established());
+            assert_eq!(
+                client_conn.session.as_ref().unwrap().sessionid,
+                server_conn.session.as_ref().unwrap().sessionid
+            );
+        }
+    }
+
+    #[test]
+    fn connect_timeout() {
+        use crate::transport::client;
+        use crate::transport::config::Config;
+        use crate::transport::connection::*;
+        use crate::transport::datagram::MemoryTransport;
+        use crate::transport::sim::{Env, SimClock};
+        use std::time::Duration;
+
+        let clock = SimClock::new();
+        let (client, _server) = MemoryTransport::pair();
+        let config = Config {
+            connect_timeout: Some(Duration::from_secs(1)),
+            ..Config::default()
+        };
+        let mut client_conn =
+            client::connect_transport(Box::new(client), Env::simulated(&clock, 1), config);
+        for _ in 0..10 {
+            clock.advance(Duration::from_millis(100));
+            client_conn.receive_and_send();
+            assert_eq!(client_conn.poll_event(), None);
+        }
+        clock.advance(Duration::from_millis(100));
+        client_conn.receive_and_send();
+        assert_eq!(client_conn.poll_event(), Some(Event::Timeout));
+        assert!(client_conn.connecting.is_none());
+    }
+
+    #[test]
+    fn trace() {
+        use crate::transport::client;
+        use crate::transport::common::*;
+        use crate::transport::config::Config;
+        use crate::transport::connection::*;
+        use crate::transport::datagram::{DatagramTransport, MemoryTransport};
+        use crate::transport::server;
+        use crate::transport::sim::{Env, SimClock};
+        use crate::transport::trace::Tracer;
+        use std::io::{self, Write};
+
+        #[derive(Clone, Default)]
+        struct Lines(Arc<Mutex<Vec<u8>>>);
+        impl Write for Lines {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let lines = Lines::default();
+        let config = Config {
+            trace: Some(Tracer::new(lines.clone())),
+            ..Config::default()
+        };
+        let clock = SimClock::new();
+        let (client, server) = MemoryTransport::pair();
+        let mut client_conn =
+            client::connect_transport(Box::new(client), Env::simulated(&clock, 1), config);
+        let mut connection_listener = server::Listener::with_transport(
+            Box::new(server),
+            Env::simulated(&clock, 2),
+            Config::default(),
+        );
+        let mut server_conn = connection_listener.listen_once().unwrap();
+        // the server's handshake is lost
+        server_conn.receive_and_send();
+        client_conn.transport.drop_next_datagram().unwrap();
+        client_conn.receive_and_send();
+        clock.advance(HANDSHAKE_RETRANSMISSION);
+        client_conn.receive_and_send();
+        server_conn.receive_and_send();
+        client_conn.receive_and_send();
+        assert!(client_conn.is_established());
+
+        let trace = String::from_utf8(lines.0.lock().unwrap().clone()).unwrap();
+        let events: Vec<&str> = trace
+            .lines()
+            .map(|line| {
+                assert!(line.starts_with(r#"{"time":"#));
+                assert!(line.contains(r#""vantage":"client""#));
+                line.split(r#""name":""#)
+                    .nth(1)
+                    .unwrap()
+                    .split('"')
+                    .next()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                "connectivity:connection_started",
+                "transport:packet_sent",
+                "recovery:handshake_retransmitted",
+                "transport:packet_sent",
+                "transport:packet_received",
+                "connectivity:connection_state_updated",
+            ]
+        );
+        assert!(trace.contains(r#""tlvs":[{"type":"HostInformation"}]"#));
+        assert!(trace.contains(r#"{"old":"connecting","new":"established"}"#));
+        assert!(trace.lines().last().unwrap().starts_with(r#"{"time":200,"#));
+
+        // without the retransmission the handshake is a round trip sample
+        let lines = Lines::default();
+        let config = Config {
+            trace: Some(Tracer::new(lines.clone())),
+            ..Config::default()
+        };
+        let (client, server) = MemoryTransport::pair();
+        let mut client_conn =
+            client::connect_transport(Box::new(client), Env::simulated(&clock, 3), config);
+        let mut connection_listener = server::Listener::with_transport(
+            Box::new(server),
+            Env::simulated(&clock, 4),
+            Config::default(),
+        );
+        let mut server_conn = connection_listener.listen_once().unwrap();
+        clock.advance(Duration::from_millis(10));
+        server_conn.receive_and_send();
+        clock.advance(Duration::from_millis(10));
+        client_conn.receive_and_send();
+        let trace = String::from_utf8(lines.0.lock().unwrap().clone()).unwrap();
+        assert!(trace.contains(r#""name":"recovery:rtt_updated","data":{"rtt":20}"#));
+    }
+
+    #[test]
+    fn stats() {
+        use crate::transport::client;
+        use crate::transport::config::Config;
+        use crate::transport::connection::*;
+        use crate::transport::datagram::MemoryTransport;
+        use crate::transport::server;
+        use crate::transport::sim::{Env, SimClock};
+        use std::time::Duration;
+
+        let clock = SimClock::new();
+        let (client, server) = MemoryTransport::pair();
+        let mut client_conn = client::connect_transport(
+            Box::new(client),
+            Env::simulated(&clock, 1),
+            Config::default(),
+        );
+        let mut connection_listener = server::Listener::with_transport(
+            Box::new(server),
+            Env::simulated(&clock, 2),
+            Config::default(),
+        );
+        let mut server_conn = connection_listener.listen_once().unwrap();
+        clock.advance(Duration::from_millis(10));
+        server_conn.receive_and_send();
+        clock.advance(Duration::from_millis(10));
+        client_conn.receive_and_send();
+        assert_eq!(client_conn.stats().rtt, Some(Duration::from_millis(20)));
+
+        // send chunk 0 twice, then chunk 1
+        let mut job = ObjectSendJob::new(Object {
+            object_type: 42,
+            object_id: 1,
+            fields: vec![ObjectFieldDescription {
+                field_type: 66,
+                length: 2,
+            }],
+        });
+        job.push_chunk(vec![1, 2, 3], 1);
+        client_conn.send_jobs.push(job);
+        client_conn.receive_and_send();
+        client_conn.send_jobs[0].next_chunk = 0;
+        client_conn.send_jobs[0].push_chunk(vec![1, 2, 3], 1);
+        client_conn.receive_and_send();
+        client_conn.send_jobs[0].push_chunk(vec![4, 5], 1);
+        clock.advance(Duration::from_millis(10));
+        client_conn.receive_and_send();
+        // and a frame with a wrong checksum
+        client_conn
+            .transport
+            .send(&[3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])
+            .unwrap();
+        clock.advance(Duration::from_millis(10));
+        server_conn.receive_and_send();
+
+        let stats = server_conn.stats();
+        assert_eq!(stats.rtt, Some(Duration::from_millis(30)));
+        assert_eq!(stats.packets_received, 6);
+        assert_eq!(stats.packets_sent, 1);
+        assert_eq!(stats.bytes_sent, client_conn.stats().bytes_received);
+        assert_eq!(stats.checksum_failures, 1);
+        assert_eq!(stats.duplicates, 1);
+        assert_eq!(stats.data_bytes, 5);
+        assert_eq!(stats.elapsed, Duration::from_millis(40));
+        let object = server_conn.recv_jobs[0].stats();
+        assert_eq!((object.chunks, object.bytes, object.duplicates), (2, 5, 1));
+        assert_eq!(client_conn.stats().data_bytes, 8);
+    }
+
+    #[test]
+    fn connection_is_send_and_sync() {
+        use crate::transport::connection::Connection;
+        use crate::transport::jobs::ChunkSender;
+
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Connection>();
+        assert_send_sync::<ChunkSender>();
     }
 }
@@ -0,0 +1,494 @@
+//! Authenticated encryption of `MessageFrame`s, keyed by a pre-shared key
+//! and/or an X25519 key exchange during the handshake.
+//!
+//! Handshake frames are sent in cleartext. All later frames are sealed:
+//!
+//! ```text
+//! | version | session id | packet number | ciphertext ... | tag |
+//! |   u8    |    u64     |      u64      |                | 16  |
+//! ```
+//!
+//! The ciphertext holds the rest of the serialized frame. The first 17 bytes
+//! are authenticated as associated data. Each direction has its own key,
+//! derived from the session secret and the session id with HKDF-SHA256.
+//! The nonce consists of the lower 32 bits of the session id and the packet
+//! number, which starts at 0 and is never reused with the same key.
+//! Replayed packets are detected with a sliding window over packet numbers.
+//!
+//! The key exchange follows the Noise NX pattern, or NK if the client pins
+//! the server's static key: the client sends an ephemeral public key, the
+//! server answers with an ephemeral and its static public key. The session
+//! secret is the concatenation of both Diffie-Hellman results (ephemeral-
+//! ephemeral for forward secrecy, client ephemeral-server static to
+//! authenticate the server), the public keys, and the pre-shared key if any.
+
+use crate::transport::features;
+use crate::transport::frame::{AuthMethod, SessionId};
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::aead::{generic_array::GenericArray, AeadInPlace, NewAead};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac, NewMac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::sync::atomic::{AtomicU64, Ordering};
+pub use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Length of version and session id, which start every frame.
+const FRAME_HEADER_LEN: usize = 9;
+const PACKET_NUMBER_LEN: usize = 8;
+const TAG_LEN: usize = 16;
+/// Number of packets before the highest one received, which are still accepted.
+const REPLAY_WINDOW: u64 = 64;
+
+/// AEAD algorithms for sealing frames, advertised in `HostInformation`.
+#[derive(FromPrimitive, ToPrimitive, Debug, PartialEq, Eq, Clone, Copy)]
+#[repr(u8)]
+pub enum AeadAlgorithm {
+    ChaCha20Poly1305 = 1,
+    Aes256Gcm = 2,
+}
+
+impl AeadAlgorithm {
+    /// The first algorithm of the client's preferences which the server supports.
+    pub fn negotiate(client: &[AeadAlgorithm], server: &[AeadAlgorithm]) -> Option<Self> {
+        features::negotiate(client, server)
+    }
+}
+
+//////////////////////////
+// Key exchange
+
+/// Ephemeral key pair of one handshake.
+pub(super) struct KeyExchange {
+    ephemeral: StaticSecret,
+}
+
+impl KeyExchange {
+    pub(super) fn new<R: RngCore + ?Sized>(rng: &mut R) -> Self {
+        let mut secret = [0; 32];
+        rng.fill_bytes(&mut secret);
+        KeyExchange {
+            ephemeral: StaticSecret::from(secret),
+        }
+    }
+
+    pub(super) fn public_key(&self) -> [u8; 32] {
+        PublicKey::from(&self.ephemeral).to_bytes()
+    }
+
+    /// Session secret on the client, from the server's public keys.
+    /// None if a public key is invalid.
+    pub(super) fn client_secret(
+        &self,
+        server_ephemeral: &[u8; 32],
+        server_static: &[u8; 32],
+    ) -> Option<Vec<u8>> {
+        let ee = self
+            .ephemeral
+            .diffie_hellman(&PublicKey::from(*server_ephemeral));
+        let es = self
+            .ephemeral
+            .diffie_hellman(&PublicKey::from(*server_static));
+        if !ee.was_contributory() || !es.was_contributory() {
+            return None;
+        }
+        Some(session_secret(
+            ee.as_bytes(),
+            es.as_bytes(),
+            &self.public_key(),
+            server_ephemeral,
+            server_static,
+        ))
+    }
+
+    /// Session secret on the server, from its static key and the client's
+    /// ephemeral public key. None if the public key is invalid.
+    pub(super) fn server_secret(
+        &self,
+        static_key: &StaticSecret,
+        client_ephemeral: &[u8; 32],
+    ) -> Option<Vec<u8>> {
+        let client_ephemeral_key = PublicKey::from(*client_ephemeral);
+        let ee = self.ephemeral.diffie_hellman(&client_ephemeral_key);
+        let es = static_key.diffie_hellman(&client_ephemeral_key);
+        if !ee.was_contributory() || !es.was_contributory() {
+            return None;
+        }
+        Some(session_secret(
+            ee.as_bytes(),
+            es.as_bytes(),
+            client_ephemeral,
+            &self.public_key(),
+            PublicKey::from(static_key).as_bytes(),
+        ))
+    }
+}
+
+fn session_secret(
+    ee: &[u8; 32],
+    es: &[u8; 32],
+    client_ephemeral: &[u8; 32],
+    server_ephemeral: &[u8; 32],
+    server_static: &[u8; 32],
+) -> Vec<u8> {
+    let mut secret = Vec::with_capacity(5 * 32);
+    for part in &[ee, es, client_ephemeral, server_ephemeral, server_static] {
+        secret.extend_from_slice(&part[..]);
+    }
+    secret
+}
+
+//////////////////////////
+// Client authentication
+
+/// The proof of knowing `secret` for an `Authentication`.
+pub(super) fn auth_proof(method: AuthMethod, secret: &[u8], challenge: &[u8]) -> Vec<u8> {
+    match method {
+        AuthMethod::Token => secret.to_vec(),
+        AuthMethod::ChallengeResponse => {
+            let mut mac = Hmac::<Sha256>::new_varkey(secret).expect("HMAC takes keys of any size.");
+            mac.update(b"rft client authentication");
+            mac.update(challenge);
+            mac.finalize().into_bytes().to_vec()
+        }
+    }
+}
+
+/// Wether `proof` proves knowing `secret`, compared in constant time.
+pub(super) fn verify_auth_proof(
+    method: AuthMethod,
+    secret: &[u8],
+    challenge: &[u8],
+    proof: &[u8],
+) -> bool {
+    constant_time_eq(&auth_proof(method, secret, challenge), proof)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let difference = a
+        .iter()
+        .zip(b)
+        .fold(0, |difference, (a, b)| difference | (a ^ b));
+    a.len() == b.len() && difference == 0
+}
+
+//////////////////////////
+// Retry cookies
+
+const COOKIE_MAC_LEN: usize = 16;
+
+/// Cookie for a `RetryCookie`, which proves that a client receives datagrams
+/// sent to `peer`. It holds `timestamp` and a MAC keyed with the server's
+/// `key`, so the server can check it without keeping any state.
+pub(super) fn retry_cookie(key: &[u8; 32], peer: &str, timestamp: u64) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC takes keys of any size.");
+    mac.update(b"rft retry cookie");
+    mac.update(&timestamp.to_be_bytes());
+    mac.update(peer.as_bytes());
+    let mut cookie = timestamp.to_be_bytes().to_vec();
+    cookie.extend_from_slice(&mac.finalize().into_bytes()[..COOKIE_MAC_LEN]);
+    cookie
+}
+
+/// The timestamp of `cookie`, if it was made by `retry_cookie()` with `key` for `peer`.
+pub(super) fn check_retry_cookie(key: &[u8; 32], peer: &str, cookie: &[u8]) -> Option<u64> {
+    if cookie.len() != 8 + COOKIE_MAC_LEN {
+        return None;
+    }
+    let mut timestamp = [0; 8];
+    timestamp.copy_from_slice(&cookie[..8]);
+    let timestamp = u64::from_be_bytes(timestamp);
+    if constant_time_eq(&retry_cookie(key, peer, timestamp), cookie) {
+        Some(timestamp)
+    } else {
+        None
+    }
+}
+
+//////////////////////////
+// Sealing
+
+enum Cipher {
+    ChaCha20Poly1305(ChaCha20Poly1305),
+    Aes256Gcm(Box<Aes256Gcm>),
+}
+
+impl Cipher {
+    fn new(algorithm: AeadAlgorithm, key: &[u8; 32]) -> Self {
+        let key = GenericArray::from_slice(key);
+        match algorithm {
+            AeadAlgorithm::ChaCha20Poly1305 => Cipher::ChaCha20Poly1305(ChaCha20Poly1305::new(key)),
+            AeadAlgorithm::Aes256Gcm => Cipher::Aes256Gcm(Box::new(Aes256Gcm::new(key))),
+        }
+    }
+
+    fn seal(&self, nonce: &[u8; 12], ad: &[u8], buf: &mut [u8]) -> [u8; TAG_LEN] {
+        let nonce = GenericArray::from_slice(nonce);
+        let tag = match self {
+            Cipher::ChaCha20Poly1305(c) => c.encrypt_in_place_detached(nonce, ad, buf),
+            Cipher::Aes256Gcm(c) => c.encrypt_in_place_detached(nonce, ad, buf),
+        }
+        .expect("Frame too long to seal.");
+        let mut result = [0; TAG_LEN];
+        result.copy_from_slice(&tag);
+        result
+    }
+
+    fn open(&self, nonce: &[u8; 12], ad: &[u8], buf: &mut [u8], tag: &[u8]) -> bool {
+        let nonce = GenericArray::from_slice(nonce);
+        let tag = GenericArray::from_slice(tag);
+        match self {
+            Cipher::ChaCha20Poly1305(c) => c.decrypt_in_place_detached(nonce, ad, buf, tag),
+            Cipher::Aes256Gcm(c) => c.decrypt_in_place_detached(nonce, ad, buf, tag),
+        }
+        .is_ok()
+    }
+}
+
+/// Accepts each packet number only once, and only if it is not too old.
+#[derive(Default)]
+struct ReplayWindow {
+    /// Highest packet number received + 1, 0 if none was received.
+    end: u64,
+    /// Bit i is set if packet number `end - 1 - i` was received.
+    received: u64,
+}
+
+impl ReplayWindow {
+    fn is_new(&self, pn: u64) -> bool {
+        if pn >= self.end {
+            return true;
+        }
+        let age = self.end - 1 - pn;
+        age < REPLAY_WINDOW && self.received & (1 << age) == 0
+    }
+
+    fn insert(&mut self, pn: u64) {
+        if pn >= self.end {
+            let shift = pn + 1 - self.end;
+            self.received = if shift >= REPLAY_WINDOW {
+                0
+            } else {
+                self.received << shift
+            };
+            self.received |= 1;
+            self.end = pn + 1;
+        } else {
+            self.received |= 1 << (self.end - 1 - pn);
+        }
+    }
+}
+
+/// Seals outgoing and opens incoming frames of one session.
+pub(super) struct FrameCipher {
+    sealer: Cipher,
+    opener: Cipher,
+    sessionid: SessionId,
+    next_packet_number: AtomicU64,
+    replay_window: ReplayWindow,
+}
+
+impl FrameCipher {
+    /// `secret` is the session secret, see the module documentation.
+    pub(super) fn new(
+        algorithm: AeadAlgorithm,
+        secret: &[u8],
+        sessionid: SessionId,
+        is_server: bool,
+    ) -> Self {
+        let hkdf = Hkdf::<Sha256>::new(Some(&sessionid.to_be_bytes()), secret);
+        let derive = |label: &[u8]| {
+            let mut key = [0; 32];
+            hkdf.expand(label, &mut key).unwrap();
+            key
+        };
+        let client_key = derive(b"rft client to server");
+        let server_key = derive(b"rft server to client");
+        let (seal_key, open_key) = if is_server {
+            (server_key, client_key)
+        } else {
+            (client_key, server_key)
+        };
+        FrameCipher {
+            sealer: Cipher::new(algorithm, &seal_key),
+            opener: Cipher::new(algorithm, &open_key),
+            sessionid,
+            next_packet_number: AtomicU64::new(0),
+            replay_window: ReplayWindow::default(),
+        }
+    }
+
+    fn nonce(&self, pn: u64) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        nonce[..4].copy_from_slice(&(self.sessionid as u32).to_be_bytes());
+        nonce[4..].copy_from_slice(&pn.to_be_bytes());
+        nonce
+    }
+
+    /// Seal the frame which was serialized to `buf[start..]`, in place.
+    pub(super) fn seal(&self, buf: &mut Vec<u8>, start: usize) {
+        let pn = self.next_packet_number.fetch_add(1, Ordering::Relaxed);
+        let body = start + FRAME_HEADER_LEN;
+        let ciphertext = body + PACKET_NUMBER_LEN;
+        buf.splice(body..body, pn.to_be_bytes().iter().cloned());
+        let (ad, plaintext) = buf[start..].split_at_mut(ciphertext - start);
+        let tag = self.sealer.seal(&self.nonce(pn), ad, plaintext);
+        buf.extend_from_slice(&tag);
+    }
+
+    /// Open a sealed frame. Returns the frame as serialized before sealing,
+    /// or None if it is not authentic or was received before.
+    pub(super) fn open(&mut self, datagram: &[u8]) -> Option<Vec<u8>> {
+        let ciphertext = FRAME_HEADER_LEN + PACKET_NUMBER_LEN;
+        if datagram.len() < ciphertext + TAG_LEN {
+            return None;
+        }
+        let mut pn = [0; PACKET_NUMBER_LEN];
+        pn.copy_from_slice(&datagram[FRAME_HEADER_LEN..ciphertext]);
+        let pn = u64::from_be_bytes(pn);
+        if !self.replay_window.is_new(pn) {
+            log::debug!("Dropping replayed packet {}.", pn);
+            return None;
+        }
+
+        let tag_start = datagram.len() - TAG_LEN;
+        let mut plaintext = datagram[ciphertext..tag_start].to_vec();
+        let ad = &datagram[..ciphertext];
+        if !self
+            .opener
+            .open(&self.nonce(pn), ad, &mut plaintext, &datagram[tag_start..])
+        {
+            return None;
+        }
+        self.replay_window.insert(pn);
+
+        let mut frame = datagram[..FRAME_HEADER_LEN].to_vec();
+        frame.extend_from_slice(&plaintext);
+        Some(frame)
+    }
+}
+
+mod test {
+    #[test]
+    fn seal_open() {
+        use super::*;
+
+        for &algorithm in &[AeadAlgorithm::ChaCha20Poly1305, AeadAlgorithm::Aes256Gcm] {
+            let client = FrameCipher::new(algorithm, b"secret", 42, false);
+            let mut server = FrameCipher::new(algorithm, b"secret", 42, true);
+            let mut wrong_key = FrameCipher::new(algorithm, b"guess", 42, true);
+
+            let frame: Vec<u8> = (0..100).collect();
+            let mut sealed = frame.clone();
+            client.seal(&mut sealed, 0);
+            assert_eq!(sealed.len(), frame.len() + 8 + 16);
+            assert_ne!(&sealed[17..117], &frame[9..]);
+
+            assert_eq!(wrong_key.open(&sealed), None);
+            assert_eq!(server.open(&sealed), Some(frame.clone()));
+            // replayed
+            assert_eq!(server.open(&sealed), None);
+            // tampered
+            let mut sealed = frame.clone();
+            client.seal(&mut sealed, 0);
+            sealed[20] ^= 1;
+            assert_eq!(server.open(&sealed), None);
+        }
+    }
+
+    #[test]
+    fn key_exchange() {
+        use super::*;
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        let mut rng = StdRng::seed_from_u64(1);
+        let server_static = StaticSecret::from([7; 32]);
+        let server_static_public = PublicKey::from(&server_static).to_bytes();
+        let client = KeyExchange::new(&mut rng);
+        let server = KeyExchange::new(&mut rng);
+
+        let server_secret = server.server_secret(&server_static, &client.public_key());
+        let client_secret = client.client_secret(&server.public_key(), &server_static_public);
+        assert!(server_secret.is_some());
+        assert_eq!(server_secret, client_secret);
+
+        // a different static key, e.g. of a man in the middle
+        let other_static = PublicKey::from(&StaticSecret::from([8; 32])).to_bytes();
+        assert_ne!(
+            client.client_secret(&server.public_key(), &other_static),
+            server_secret
+        );
+        // low order point
+        assert_eq!(client.client_secret(&[0; 32], &server_static_public), None);
+    }
+
+    #[test]
+    fn auth_proof() {
+        use super::*;
+
+        for &method in &[AuthMethod::Token, AuthMethod::ChallengeResponse] {
+            let proof = auth_proof(method, b"secret", b"challenge");
+            assert!(verify_auth_proof(method, b"secret", b"challenge", &proof));
+            assert!(!verify_auth_proof(method, b"secreT", b"challenge", &proof));
+            assert!(!verify_auth_proof(
+                method,
+                b"secret",
+                b"challenge",
+                &proof[1..]
+            ));
+        }
+        let proof = auth_proof(AuthMethod::ChallengeResponse, b"secret", b"challenge");
+        assert_ne!(&proof[..], b"secret");
+        // replayed for another challenge
+        assert!(!verify_auth_proof(
+            AuthMethod::ChallengeResponse,
+            b"secret",
+            b"another challenge",
+            &proof
+        ));
+    }
+
+    #[test]
+    fn retry_cookie() {
+        use super::*;
+
+        let key = [7; 32];
+        let cookie = retry_cookie(&key, "127.0.0.1:1234", 42);
+        assert_eq!(
+            check_retry_cookie(&key, "127.0.0.1:1234", &cookie),
+            Some(42)
+        );
+        assert_eq!(check_retry_cookie(&key, "127.0.0.1:1235", &cookie), None);
+        assert_eq!(
+            check_retry_cookie(&[8; 32], "127.0.0.1:1234", &cookie),
+            None
+        );
+        let mut forged = cookie.clone();
+        forged[7] = 0;
+        assert_eq!(check_retry_cookie(&key, "127.0.0.1:1234", &forged), None);
+        assert_eq!(
+            check_retry_cookie(&key, "127.0.0.1:1234", &cookie[1..]),
+            None
+        );
+    }
+
+    #[test]
+    fn replay_window() {
+        use super::*;
+
+        let mut window = ReplayWindow::default();
+        for &pn in &[0, 2, 1, 100, 50] {
+            assert!(window.is_new(pn));
+            window.insert(pn);
+            assert!(!window.is_new(pn));
+        }
+        assert!(window.is_new(99));
+        // too old
+        assert!(!window.is_new(3));
+        window.insert(1000);
+        assert!(!window.is_new(100));
+        assert!(window.is_new(999));
+    }
+}
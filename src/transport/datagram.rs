@@ -0,0 +1,384 @@
+//! Unreliable datagram channels, which a `Connection` runs on.
+
+use crate::common::batch::BatchIo;
+use bytes::Bytes;
+use rand::{thread_rng, Rng};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::VecDeque;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+
+/// An unreliable, message oriented channel to a single peer.
+/// All operations are non-blocking.
+pub trait DatagramTransport: Send + Sync {
+    /// Send one datagram to the peer.
+    fn send(&self, buf: &[u8]) -> io::Result<usize>;
+
+    /// Receive one datagram from the peer. Datagrams longer than `buf` are
+    /// truncated. Fails with `io::ErrorKind::WouldBlock` if none is available.
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Used by a `Listener` on an unconnected transport: receive one datagram
+    /// from any peer, with the address of its sender. Fails with
+    /// `io::ErrorKind::WouldBlock` if none is available.
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, String)>;
+
+    /// Used by a `Listener` on an unconnected transport: send one datagram
+    /// to `peer`, an address as returned by `recv_from()`.
+    fn send_to(&self, buf: &[u8], peer: &str) -> io::Result<usize>;
+
+    /// Consume the next datagram without handling it, e.g. to lose it in tests.
+    fn drop_next_datagram(&self) -> io::Result<()> {
+        self.recv(&mut [0; 1]).map(|_| ())
+    }
+
+    /// Human readable address of the peer.
+    fn peer(&self) -> String;
+
+    /// Batch for use with `send_batch()` and `recv_batch()`.
+    fn new_batch(&self) -> BatchIo {
+        BatchIo::fallback()
+    }
+
+    /// Send the datagrams of `batch` in order, see `BatchIo::send()`.
+    fn send_batch(&self, batch: &mut BatchIo) -> io::Result<usize> {
+        batch.send_each(|datagram| self.send(datagram))
+    }
+
+    /// Receive the available datagrams, see `BatchIo::recv()`.
+    fn recv_batch(&self, batch: &mut BatchIo, max_datagram_size: usize) -> Vec<Bytes> {
+        batch.recv_each(max_datagram_size, |buf| self.recv(buf))
+    }
+}
+
+//////////////////////////
+// UDP
+
+/// `DatagramTransport` on a UDP socket, using batched I/O where available.
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    /// Bind to `bind` for listening. An IPv6 socket is dual-stack, i.e.
+    /// binding to `[::]` also accepts IPv4 peers as IPv4-mapped addresses.
+    pub fn bind(bind: SocketAddr) -> io::Result<Self> {
+        let socket = match bind {
+            SocketAddr::V4(_) => UdpSocket::bind(bind)?,
+            SocketAddr::V6(_) => bind_dual_stack(bind)?,
+        };
+        socket.set_nonblocking(true)?;
+        Ok(UdpTransport { socket })
+    }
+
+    /// Bind to a random local port from the ephemeral port range, on
+    /// `0.0.0.0` or `[::]` depending on the address family of `dest`,
+    /// and connect to `dest`.
+    pub fn connect(dest: SocketAddr) -> io::Result<Self> {
+        let mut bind: SocketAddr = match dest {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let port: u16 = thread_rng().gen_range(49152, 65535);
+        bind.set_port(port);
+
+        let socket: UdpSocket = UdpSocket::bind(bind)?;
+        socket.set_nonblocking(true)?;
+        socket.connect(dest)?;
+        Ok(UdpTransport { socket })
+    }
+
+    /// Address the socket is bound to, e.g. to learn the port after binding to port 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+/// Bind an IPv6 socket with `IPV6_V6ONLY` disabled, which is not possible with std.
+fn bind_dual_stack(bind: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::ipv6(), Type::dgram(), Some(Protocol::udp()))?;
+    if let Err(e) = socket.set_only_v6(false) {
+        log::warn!(
+            "Could not make socket dual-stack, accepting IPv6 only: {}",
+            e
+        );
+    }
+    socket.bind(&bind.into())?;
+    Ok(socket.into_udp_socket())
+}
+
+impl DatagramTransport for UdpTransport {
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.send(buf)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.socket.recv(buf)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, String)> {
+        let (n_bytes, src) = self.socket.recv_from(buf)?;
+        Ok((n_bytes, src.to_string()))
+    }
+
+    fn send_to(&self, buf: &[u8], peer: &str) -> io::Result<usize> {
+        let dest: SocketAddr = peer
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.socket.send_to(buf, dest)
+    }
+
+    fn peer(&self) -> String {
+        match self.socket.peer_addr() {
+            Ok(addr) => addr.to_string(),
+            Err(_) => String::from("(unconnected)"),
+        }
+    }
+
+    fn new_batch(&self) -> BatchIo {
+        BatchIo::new(&self.socket)
+    }
+
+    fn send_batch(&self, batch: &mut BatchIo) -> io::Result<usize> {
+        batch.send(&self.socket)
+    }
+
+    fn recv_batch(&self, batch: &mut BatchIo, max_datagram_size: usize) -> Vec<Bytes> {
+        batch.recv(&self.socket, max_datagram_size)
+    }
+}
+
+//////////////////////////
+// Unix datagram sockets
+
+#[cfg(unix)]
+pub use self::unix::UnixTransport;
+
+#[cfg(unix)]
+mod unix {
+    use super::DatagramTransport;
+    use rand::{thread_rng, Rng};
+    use std::fs;
+    use std::io;
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::UnixDatagram;
+    use std::path::{Path, PathBuf};
+
+    /// `DatagramTransport` on a unix domain datagram socket, for local IPC.
+    /// The socket file is removed when the transport is dropped.
+    pub struct UnixTransport {
+        socket: UnixDatagram,
+        path: PathBuf,
+    }
+
+    impl UnixTransport {
+        /// Bind to `path` for listening. A stale socket file is replaced,
+        /// any other file at `path` is left alone and fails with `AlreadyExists`.
+        pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+            let path = path.as_ref().to_path_buf();
+            match fs::symlink_metadata(&path) {
+                Ok(meta) if meta.file_type().is_socket() => fs::remove_file(&path)?,
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("{} exists and is no socket", path.display()),
+                    ))
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            let socket = UnixDatagram::bind(&path)?;
+            socket.set_nonblocking(true)?;
+            Ok(UnixTransport { socket, path })
+        }
+
+        /// Bind to a random path next to `dest`, as the peer can only
+        /// answer to a named socket, and connect to `dest`.
+        pub fn connect<P: AsRef<Path>>(dest: P) -> io::Result<Self> {
+            let dest = dest.as_ref();
+            let mut name = dest.file_name().unwrap_or_default().to_os_string();
+            name.push(format!(".{:08x}", thread_rng().gen::<u32>()));
+            let transport = UnixTransport::bind(dest.with_file_name(name))?;
+            transport.socket.connect(dest)?;
+            Ok(transport)
+        }
+    }
+
+    impl Drop for UnixTransport {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    impl DatagramTransport for UnixTransport {
+        fn send(&self, buf: &[u8]) -> io::Result<usize> {
+            self.socket.send(buf)
+        }
+
+        fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+            self.socket.recv(buf)
+        }
+
+        /// Datagrams from unnamed sockets can't be answered and are dropped.
+        fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, String)> {
+            loop {
+                let (n_bytes, src) = self.socket.recv_from(buf)?;
+                match src.as_pathname() {
+                    Some(path) => return Ok((n_bytes, path.display().to_string())),
+                    None => log::warn!("Ignoring datagram from unnamed unix socket."),
+                }
+            }
+        }
+
+        fn send_to(&self, buf: &[u8], peer: &str) -> io::Result<usize> {
+            self.socket.send_to(buf, peer)
+        }
+
+        fn peer(&self) -> String {
+            match self.socket.peer_addr() {
+                Ok(addr) => match addr.as_pathname() {
+                    Some(path) => path.display().to_string(),
+                    None => format!("{:?}", addr),
+                },
+                Err(_) => String::from("(unconnected)"),
+            }
+        }
+    }
+}
+
+//////////////////////////
+// In-memory
+
+type Queue = Arc<Mutex<VecDeque<Vec<u8>>>>;
+
+/// `DatagramTransport` within the process, e.g. for tests.
+/// Created in connected pairs by `MemoryTransport::pair()`.
+pub struct MemoryTransport {
+    inbox: Queue,
+    outbox: Queue,
+    name: String,
+}
+
+impl MemoryTransport {
+    /// Two transports, each sending to the other one.
+    pub fn pair() -> (MemoryTransport, MemoryTransport) {
+        let a: Queue = Arc::new(Mutex::new(VecDeque::new()));
+        let b: Queue = Arc::new(Mutex::new(VecDeque::new()));
+        (
+            MemoryTransport {
+                inbox: Arc::clone(&a),
+                outbox: Arc::clone(&b),
+                name: String::from("memory:b"),
+            },
+            MemoryTransport {
+                inbox: b,
+                outbox: a,
+                name: String::from("memory:a"),
+            },
+        )
+    }
+}
+
+impl DatagramTransport for MemoryTransport {
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.outbox.lock().unwrap().push_back(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.inbox.lock().unwrap().pop_front() {
+            Some(datagram) => {
+                let n = datagram.len().min(buf.len());
+                buf[..n].copy_from_slice(&datagram[..n]);
+                Ok(n)
+            }
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, String)> {
+        Ok((self.recv(buf)?, self.name.clone()))
+    }
+
+    /// There is only one peer, so `peer` is ignored.
+    fn send_to(&self, buf: &[u8], _peer: &str) -> io::Result<usize> {
+        self.send(buf)
+    }
+
+    fn peer(&self) -> String {
+        self.name.clone()
+    }
+}
+
+mod test {
+    /// Handshake and transfer of an object between client and server `Connection`s.
+    #[allow(dead_code)]
+    fn transfer(
+        client: Box<dyn super::DatagramTransport>,
+        server: Box<dyn super::DatagramTransport>,
+    ) {
+        use crate::transport::client;
+        use crate::transport::config::Config;
+        use crate::transport::connection::*;
+        use crate::transport::server;
+        use crate::transport::sim::Env;
+        use std::io::Write;
+
+        let mut connection_listener =
+            server::Listener::with_transport(server, Env::default(), Config::default());
+        let mut client_conn = client::connect_transport(client, Env::default(), Config::default());
+        let mut server_conn = connection_listener.listen_once().unwrap();
+        server_conn.receive_and_send();
+        client_conn.receive_and_send();
+        assert!(client_conn.is_established());
+
+        let mut writer = client_conn.open_object(42);
+        writer.write_all(&[1, 2, 3]).unwrap();
+        let object_id = writer.finish();
+        client_conn.receive_and_send();
+        server_conn.receive_and_send();
+        match server_conn.poll_event() {
+            Some(Event::NewObject(object)) => assert_eq!(object.object_id, object_id),
+            e => panic!("Unexpected event {:?}", e),
+        }
+        match server_conn.poll_event() {
+            Some(Event::Chunk { data, .. }) => assert_eq!(&data[..], &[1, 2, 3]),
+            e => panic!("Unexpected event {:?}", e),
+        }
+    }
+
+    #[test]
+    fn memory() {
+        use super::MemoryTransport;
+
+        let (client, server) = MemoryTransport::pair();
+        transfer(Box::new(client), Box::new(server));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn unix() {
+        use super::UnixTransport;
+
+        let path = std::env::temp_dir().join(format!("rft-test-{}.sock", std::process::id()));
+        let server = UnixTransport::bind(&path).unwrap();
+        let client = UnixTransport::connect(&path).unwrap();
+        transfer(Box::new(client), Box::new(server));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn unix_bind_keeps_files() {
+        use super::UnixTransport;
+        use std::fs;
+        use std::io::ErrorKind;
+
+        let path = std::env::temp_dir().join(format!("rft-test-{}.txt", std::process::id()));
+        fs::write(&path, b"not a socket").unwrap();
+        let e = UnixTransport::bind(&path).err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&path).unwrap(), b"not a socket");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+//! Features of a `Connection`, negotiated during the handshake from the
+//! `Capabilities` both hosts advertise in their `HostInformation`.
+
+use crate::transport::common::MAX_UDP_BUFSIZE;
+use crate::transport::crypto::AeadAlgorithm;
+use crate::transport::frame::*;
+
+/// The first of the client's preferences which the server supports.
+pub fn negotiate<T: PartialEq + Copy>(client: &[T], server: &[T]) -> Option<T> {
+    client
+        .iter()
+        .find(|algorithm| server.contains(algorithm))
+        .copied()
+}
+
+/// What both hosts of a `Connection` support, consulted by the `Connection`
+/// after the handshake. Where the hosts have preferences, the client's win.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Features {
+    /// Size of the largest datagram both hosts receive.
+    pub max_frame_size: usize,
+    pub checksum: ChecksumAlgorithm,
+    pub compression: Option<CompressionAlgorithm>,
+    /// Algorithm for sealing frames, if both hosts can encrypt.
+    /// Wether they actually do depends on their keys, see `crypto`.
+    pub aead: Option<AeadAlgorithm>,
+    pub fec: Option<FecScheme>,
+    /// Chunks are acknowledged as often as either host wants.
+    pub ack_freq: AckFreq,
+}
+
+impl Default for Features {
+    /// Features during the handshake.
+    fn default() -> Self {
+        Features {
+            max_frame_size: MAX_UDP_BUFSIZE,
+            checksum: ChecksumAlgorithm::default(),
+            compression: None,
+            aead: None,
+            fec: None,
+            ack_freq: AckFreq::default(),
+        }
+    }
+}
+
+impl Features {
+    pub fn negotiate(client: &Capabilities, server: &Capabilities) -> Self {
+        let max_frame_size = |capabilities: &Capabilities| {
+            capabilities
+                .max_frame_size
+                .map_or(MAX_UDP_BUFSIZE, usize::from)
+        };
+        let aead = match (&client.encryption, &server.encryption) {
+            (Some(client), Some(server)) => AeadAlgorithm::negotiate(&client.aeads, &server.aeads),
+            _ => None,
+        };
+        let ack_freq = match (&client.ack_freq, &server.ack_freq) {
+            (Some(AckFreq::Max), _) | (_, Some(AckFreq::Max)) => AckFreq::Max,
+            (Some(AckFreq::Min), Some(AckFreq::Min)) => AckFreq::Min,
+            _ => AckFreq::Default,
+        };
+        Features {
+            max_frame_size: max_frame_size(client).min(max_frame_size(server)),
+            checksum: ChecksumAlgorithm::negotiate(&client.checksums, &server.checksums),
+            compression: negotiate(&client.compression, &server.compression),
+            aead,
+            fec: negotiate(&client.fec, &server.fec),
+            ack_freq,
+        }
+    }
+}
+
+mod test {
+    #[test]
+    fn negotiate() {
+        use super::*;
+
+        let client = Capabilities {
+            max_frame_size: Some(1200),
+            checksums: vec![ChecksumAlgorithm::XxHash32, ChecksumAlgorithm::Crc32c],
+            compression: vec![CompressionAlgorithm::Lz4, CompressionAlgorithm::Zstd],
+            encryption: Some(Encryption {
+                aeads: vec![AeadAlgorithm::Aes256Gcm, AeadAlgorithm::ChaCha20Poly1305],
+                ..Encryption::default()
+            }),
+            fec: vec![FecScheme::XorParity],
+            ack_freq: Some(AckFreq::Min),
+        };
+        let server = Capabilities {
+            checksums: vec![ChecksumAlgorithm::Crc32c, ChecksumAlgorithm::XxHash32],
+            compression: vec![CompressionAlgorithm::Zstd, CompressionAlgorithm::Lz4],
+            encryption: Some(Encryption {
+                aeads: vec![AeadAlgorithm::ChaCha20Poly1305, AeadAlgorithm::Aes256Gcm],
+                ..Encryption::default()
+            }),
+            ack_freq: Some(AckFreq::Min),
+            ..Capabilities::default()
+        };
+        assert_eq!(
+            Features::negotiate(&client, &server),
+            Features {
+                max_frame_size: 1200,
+                checksum: ChecksumAlgorithm::XxHash32,
+                compression: Some(CompressionAlgorithm::Lz4),
+                aead: Some(AeadAlgorithm::Aes256Gcm),
+                fec: None,
+                ack_freq: AckFreq::Min,
+            }
+        );
+        // a host which advertises nothing
+        assert_eq!(
+            Features::negotiate(&client, &Capabilities::default()),
+            Features {
+                max_frame_size: 1200,
+                ..Features::default()
+            }
+        );
+    }
+}
@@ -1,9 +1,11 @@
 use crate::common::fnv1a32;
 use crate::common::*;
+use crate::error::RftError;
+use crate::transport::crypto::AeadAlgorithm;
 use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
 use leb128;
 use num::{FromPrimitive, ToPrimitive};
-use std::io::{Seek, SeekFrom, Write};
+use std::hash::Hasher;
 
 /////////////////////////////////
 // Basic Types
@@ -18,38 +20,61 @@ pub type ObjectFieldType = u8;
 /////////////////////////////////
 // MessageFrame
 
+/// Description of the `RftError::Decode` of a frame with a wrong checksum.
+pub const CHECKSUM_ERROR: &str = "Checksum error!";
+
+/// A `MessageFrame` which was read from a buffer borrows the data of
+/// its `ObjectChunk`s from that buffer.
 #[derive(Default, Debug, PartialEq)]
-pub struct MessageFrame {
+pub struct MessageFrame<'a> {
     pub version: Version,
     pub sid: SessionId,
-    pub tlvs: Vec<Tlv>,
+    pub tlvs: Vec<Tlv<'a>>,
+    /// Algorithm of the trailing checksum, which is not transmitted but negotiated.
+    /// Set it before reading. Handshake frames, i.e. frames with `HostInformation`,
+    /// always use FNV-1a, as the algorithm is not negotiated yet.
+    pub checksum: ChecksumAlgorithm,
+    /// Type codes of unknown TLVs which were skipped while reading.
+    pub skipped_tlvs: Vec<u8>,
+}
+
+impl<'a> MessageFrame<'a> {
+    fn checksum_algorithm(&self) -> ChecksumAlgorithm {
+        let is_handshake = self
+            .tlvs
+            .iter()
+            .any(|tlv| matches!(tlv, Tlv::HostInformation(_)));
+        if is_handshake {
+            ChecksumAlgorithm::Fnv1a32
+        } else {
+            self.checksum
+        }
+    }
 }
 
-impl WireFormat for MessageFrame {
-    fn write(&self, cursor: &mut Cursor) {
-        let start = cursor.position();
+impl<'a> WireFormat<'a> for MessageFrame<'a> {
+    fn write(&self, cursor: &mut Vec<u8>) {
+        let start = cursor.len();
         write_u8!(cursor, self.version);
         write_u64!(cursor, self.sid);
-        write_u8!(cursor, self.tlvs.len() as u8);
+        write_len!(cursor, self.tlvs.len());
         for tlv in &self.tlvs {
             tlv.write(cursor);
         }
-        let end = cursor.position();
-        let checksum = fnv1a32::Fnv32a::hash(cursor, start, end);
+        let checksum = self.checksum_algorithm().hash(&cursor[start..]);
         write_u32!(cursor, checksum);
     }
 
-    fn read(&mut self, cursor: &mut Cursor) -> ReadResult {
-        let start = cursor.position();
+    fn read(&mut self, cursor: &mut Cursor<'a>) -> Result<(), RftError> {
+        let start = cursor.position() as usize;
         self.version = read_u8!(cursor);
         self.sid = read_u64!(cursor);
         self.tlvs = Vec::new();
-        let mut num_tlvs = read_u8!(cursor);
+        self.skipped_tlvs = Vec::new();
+        let mut num_tlvs = read_len!(cursor);
         while num_tlvs > 0 {
             let tlv_type = read_u8!(cursor);
-            cursor
-                .seek(SeekFrom::Current(-1))
-                .expect("Seek back failed."); // TLV will read type again.
+            cursor.set_position(cursor.position() - 1); // TLV will read type again.
             let mut tlv = match FromPrimitive::from_u8(tlv_type) {
                 Some(TlvType::HostInformation) => Tlv::HostInformation(HostInformation::default()),
                 Some(TlvType::ObjectHeader) => Tlv::ObjectHeader(ObjectHeader::default()),
@@ -60,23 +85,77 @@ impl WireFormat for MessageFrame {
                 Some(TlvType::ObjectAckRequest) => {
                     Tlv::ObjectAckRequest(ObjectAckRequest::default())
                 }
+                Some(TlvType::Authentication) => Tlv::Authentication(Authentication::default()),
+                Some(TlvType::RetryCookie) => Tlv::RetryCookie(RetryCookie::default()),
+                Some(TlvType::AuthenticationAck) => {
+                    Tlv::AuthenticationAck(AuthenticationAck::default())
+                }
                 None => {
-                    return ReadResult::Err(ReadError::new(
-                        format!("Unknown transport message type code {}!", tlv_type).as_str(),
-                    ))
+                    self.skipped_tlvs.push(skip_unknown_tlv(cursor)?);
+                    num_tlvs -= 1;
+                    continue;
                 }
             };
-            tlv.read(cursor);
+            if let Err(e) = tlv.read(cursor) {
+                return Err(e.in_tlv(tlv_type));
+            }
             self.tlvs.push(tlv);
             num_tlvs -= 1;
         }
-        let end = cursor.position();
-        let checksum = fnv1a32::Fnv32a::hash(cursor, start, end);
+        let end = cursor.position() as usize;
+        let checksum = self
+            .checksum_algorithm()
+            .hash(&cursor.get_ref()[start..end]);
         let advertised_checksum = read_u32!(cursor);
         if checksum != advertised_checksum {
-            return ReadResult::Err(ReadError::new("Checksum error!"));
+            return Err(RftError::decode(cursor.position(), CHECKSUM_ERROR));
+        }
+        Ok(())
+    }
+}
+
+/////////////////////////////////
+// ChecksumAlgorithm
+
+/// Checksum over a `MessageFrame`, negotiated during the handshake.
+///
+/// Handshake frames, i.e. frames with `HostInformation`, always use the
+/// default FNV-1a, also when they are repeated after the negotiation: their
+/// receiver can't know the negotiated algorithm before reading them.
+#[derive(FromPrimitive, ToPrimitive, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[repr(u8)]
+pub enum ChecksumAlgorithm {
+    #[default]
+    Fnv1a32 = 0,
+    Crc32c = 1,
+    XxHash32 = 2,
+}
+
+impl ChecksumAlgorithm {
+    pub fn hash(self, buf: &[u8]) -> u32 {
+        match self {
+            ChecksumAlgorithm::Fnv1a32 => fnv1a32::Fnv32a::hash(buf),
+            ChecksumAlgorithm::Crc32c => crc32c::crc32c(buf),
+            ChecksumAlgorithm::XxHash32 => {
+                let mut hasher = twox_hash::XxHash32::with_seed(0);
+                hasher.write(buf);
+                hasher.finish() as u32
+            }
+        }
+    }
+
+    /// The first algorithm of the client's preferences which the server supports.
+    /// Hosts which advertise no algorithms only support FNV-1a.
+    pub fn negotiate(client: &[ChecksumAlgorithm], server: &[ChecksumAlgorithm]) -> Self {
+        let fnv_only = [ChecksumAlgorithm::Fnv1a32];
+        let client = if client.is_empty() { &fnv_only } else { client };
+        let server = if server.is_empty() { &fnv_only } else { server };
+        for algorithm in client {
+            if server.contains(algorithm) {
+                return *algorithm;
+            }
         }
-        ReadResult::Ok
+        ChecksumAlgorithm::Fnv1a32
     }
 }
 
@@ -84,30 +163,39 @@ impl WireFormat for MessageFrame {
 // Tlv
 
 #[derive(Debug, PartialEq)]
-pub enum Tlv {
+pub enum Tlv<'a> {
     HostInformation(HostInformation),
     ObjectHeader(ObjectHeader),
-    ObjectChunk(ObjectChunk),
+    ObjectChunk(ObjectChunk<'a>),
     ObjectSkip(ObjectSkip),
     ObjectAck(ObjectAck),
     ErrorMessage(ErrorMessage),
     ObjectAckRequest(ObjectAckRequest),
+    Authentication(Authentication),
+    RetryCookie(RetryCookie),
+    AuthenticationAck(AuthenticationAck),
 }
 
+/// Type codes of transport TLVs. Those which a peer must not skip have the
+/// `TLV_CRITICAL_BIT` set: a skipped handshake, object, retry or authentication
+/// would break the connection, or let a client believe it was authenticated.
 #[derive(FromPrimitive, ToPrimitive, Debug, PartialEq)]
 #[repr(u8)]
 enum TlvType {
-    HostInformation = 0x50,
-    ObjectHeader = 0x51,
-    ObjectChunk = 0x52,
+    HostInformation = 0x50 | TLV_CRITICAL_BIT,
+    ObjectHeader = 0x51 | TLV_CRITICAL_BIT,
+    ObjectChunk = 0x52 | TLV_CRITICAL_BIT,
     ObjectSkip = 0x53,
+    Authentication = 0x54 | TLV_CRITICAL_BIT,
+    RetryCookie = 0x55 | TLV_CRITICAL_BIT,
+    AuthenticationAck = 0x56 | TLV_CRITICAL_BIT,
     ObjectAck = 0x30,
     ErrorMessage = 0x31,
     ObjectAckRequest = 0x32,
 }
 
-impl WireFormat for Tlv {
-    fn write(&self, cursor: &mut Cursor) {
+impl<'a> WireFormat<'a> for Tlv<'a> {
+    fn write(&self, cursor: &mut Vec<u8>) {
         match self {
             Tlv::HostInformation(x) => x.write(cursor),
             Tlv::ObjectHeader(x) => x.write(cursor),
@@ -116,10 +204,13 @@ impl WireFormat for Tlv {
             Tlv::ObjectAck(x) => x.write(cursor),
             Tlv::ErrorMessage(x) => x.write(cursor),
             Tlv::ObjectAckRequest(x) => x.write(cursor),
+            Tlv::Authentication(x) => x.write(cursor),
+            Tlv::RetryCookie(x) => x.write(cursor),
+            Tlv::AuthenticationAck(x) => x.write(cursor),
         }
     }
 
-    fn read(&mut self, cursor: &mut Cursor) -> ReadResult {
+    fn read(&mut self, cursor: &mut Cursor<'a>) -> Result<(), RftError> {
         return match self {
             Tlv::HostInformation(x) => x.read(cursor),
             Tlv::ObjectHeader(x) => x.read(cursor),
@@ -128,6 +219,9 @@ impl WireFormat for Tlv {
             Tlv::ObjectAck(x) => x.read(cursor),
             Tlv::ErrorMessage(x) => x.read(cursor),
             Tlv::ObjectAckRequest(x) => x.read(cursor),
+            Tlv::Authentication(x) => x.read(cursor),
+            Tlv::RetryCookie(x) => x.read(cursor),
+            Tlv::AuthenticationAck(x) => x.read(cursor),
         };
     }
 }
@@ -135,23 +229,20 @@ impl WireFormat for Tlv {
 /////////////////////////////////
 // HostInformation
 
-#[derive(FromPrimitive, ToPrimitive, Debug, PartialEq, Clone)]
+#[derive(FromPrimitive, ToPrimitive, Debug, PartialEq, Clone, Default)]
 #[repr(u8)]
 pub enum AckFreq {
+    #[default]
     Default = 0x0,
     Min = 0x10,
     Max = 0x11,
 }
 
-impl Default for AckFreq {
-    fn default() -> Self {
-        AckFreq::Default
-    }
-}
-
-#[derive(FromPrimitive, ToPrimitive, Debug, PartialEq, Clone)]
+#[derive(FromPrimitive, ToPrimitive, Debug, PartialEq, Clone, WireFormat, Default)]
 #[repr(u8)]
+#[wire(or_default)]
 pub enum HostOs {
+    #[default]
     Linux = 1,
     Windows = 2,
     MacOS = 3,
@@ -160,165 +251,337 @@ pub enum HostOs {
     IOS = 6,
 }
 
-impl Default for HostOs {
-    fn default() -> Self {
-        HostOs::Linux
-    }
-}
-
-#[derive(FromPrimitive, ToPrimitive, Debug, PartialEq, Clone)]
+#[derive(FromPrimitive, ToPrimitive, Debug, PartialEq, Clone, Default)]
 #[repr(u8)]
 pub enum ApplicationId {
+    #[default]
     SOFT = 1,
 }
 
-impl Default for ApplicationId {
-    fn default() -> Self {
-        ApplicationId::SOFT
-    }
-}
-
-#[derive(Default, Debug, PartialEq, Clone)]
+#[derive(Default, Debug, PartialEq, Clone, WireFormat)]
+#[wire(tlv = "TlvType::HostInformation")]
 pub struct HostInformation {
-    pub rcv_window_size: u64, // LEB128
+    #[wire(leb128)]
+    pub rcv_window_size: u64,
     pub out_of_order_limit: u8,
-    pub ack_freq: AckFreq,
     pub os: HostOs,
-    pub app: ApplicationId,
+    /// None if the host runs an application we don't know.
+    #[wire(with = "application_id")]
+    pub app: Option<ApplicationId>,
     pub app_ver: Version,
+    pub capabilities: Capabilities,
 }
 
-impl WireFormat for HostInformation {
-    fn write(&self, cursor: &mut Cursor) {
-        write_tlv!(cursor, TlvType::HostInformation, {
-            write_u128!(cursor, self.rcv_window_size);
-            write_u8!(cursor, self.out_of_order_limit);
-            write_u8!(cursor, self.ack_freq.to_u8().unwrap());
-            write_u8!(cursor, self.os.to_u8().unwrap());
-            write_u8!(cursor, self.app.to_u8().unwrap());
-            write_u8!(cursor, self.app_ver);
-        });
+/// `HostInformation::app` is written as 0 if it is None.
+mod application_id {
+    use super::*;
+
+    pub fn write(app: &Option<ApplicationId>, buf: &mut Vec<u8>) {
+        write_u8!(buf, app.as_ref().map_or(0, |app| app.to_u8().unwrap()));
     }
 
-    fn read(&mut self, cursor: &mut Cursor) -> ReadResult {
-        read_tlv!(cursor, TlvType::HostInformation, {
-            self.rcv_window_size = read_u128!(cursor);
-            self.out_of_order_limit = read_u8!(cursor);
-            self.ack_freq = match FromPrimitive::from_u8(read_u8!(cursor)) {
-                Some(x) => x,
-                None => AckFreq::Default,
-            };
-            self.os = match FromPrimitive::from_u8(read_u8!(cursor)) {
-                Some(x) => x,
-                None => HostOs::Linux,
-            };
-            self.app = match FromPrimitive::from_u8(read_u8!(cursor)) {
-                Some(x) => x,
-                None => return ReadResult::Err(ReadError::new("Unknown application.")),
-            };
-            self.app_ver = read_u8!(cursor);
+    pub fn read(app: &mut Option<ApplicationId>, cursor: &mut Cursor) -> Result<(), RftError> {
+        *app = FromPrimitive::from_u8(read_u8!(cursor));
+        Ok(())
+    }
+}
+
+/////////////////////////////////
+// Capabilities
+
+/// Compression of chunk data. Reserved for negotiation, as rft does not
+/// compress yet and advertises no algorithm.
+#[derive(FromPrimitive, ToPrimitive, Debug, PartialEq, Eq, Clone, Copy)]
+#[repr(u8)]
+pub enum CompressionAlgorithm {
+    Zstd = 1,
+    Lz4 = 2,
+}
+
+/// Forward error correction of chunks. Reserved for negotiation, as rft does
+/// not send repair chunks yet and advertises no scheme.
+#[derive(FromPrimitive, ToPrimitive, Debug, PartialEq, Eq, Clone, Copy)]
+#[repr(u8)]
+pub enum FecScheme {
+    XorParity = 1,
+    ReedSolomon = 2,
+}
+
+/// Ability to seal frames after the handshake, see `crypto`.
+#[derive(Default, Debug, PartialEq, Clone)]
+pub struct Encryption {
+    /// Supported AEAD algorithms in order of preference.
+    pub aeads: Vec<AeadAlgorithm>,
+    /// Ephemeral X25519 public key for the key exchange.
+    pub ephemeral_key: Option<[u8; 32]>,
+    /// Static X25519 public key of a server.
+    pub static_key: Option<[u8; 32]>,
+    /// Wether the host mixes a pre-shared key into the session secret.
+    pub psk: bool,
+}
+
+const KEY_FLAG_EPHEMERAL: u8 = 1;
+const KEY_FLAG_STATIC: u8 = 2;
+const KEY_FLAG_PSK: u8 = 4;
+
+/// Optional features of a host, which are negotiated into the `Features` of
+/// a `Connection`. On the wire, a LEB128 count followed by one sub-TLV per
+/// present capability:
+///
+/// ```text
+/// | type |  length | value ... |
+/// |  u8  | LEB128  |           |
+/// ```
+///
+/// Unknown capabilities, unknown algorithms in lists and trailing bytes of a
+/// value are skipped, so features can be added without breaking older hosts.
+/// `WireFormat` is implemented by hand, as the derive can't express absent
+/// fields or skipping unknown entries.
+#[derive(Default, Debug, PartialEq, Clone)]
+pub struct Capabilities {
+    /// Size of the largest datagram the host receives. None for `MAX_UDP_BUFSIZE`.
+    pub max_frame_size: Option<u16>,
+    /// Supported checksum algorithms in order of preference. Empty means FNV-1a only.
+    pub checksums: Vec<ChecksumAlgorithm>,
+    /// Supported compression algorithms in order of preference.
+    pub compression: Vec<CompressionAlgorithm>,
+    /// None if the host can't encrypt.
+    pub encryption: Option<Encryption>,
+    /// Supported forward error correction schemes in order of preference.
+    pub fec: Vec<FecScheme>,
+    /// How often the host wants its chunks to be acknowledged.
+    pub ack_freq: Option<AckFreq>,
+}
+
+#[derive(FromPrimitive, ToPrimitive, Debug, PartialEq)]
+#[repr(u8)]
+enum CapabilityType {
+    MaxFrameSize = 1,
+    Checksums = 2,
+    Compression = 3,
+    Encryption = 4,
+    Fec = 5,
+    AckPolicy = 6,
+}
+
+/// Write a sub-TLV of `Capabilities` with the value written by `write`,
+/// counting it in `count`.
+fn write_capability<F: FnOnce(&mut Vec<u8>)>(
+    cursor: &mut Vec<u8>,
+    count: &mut usize,
+    capability: CapabilityType,
+    write: F,
+) {
+    *count += 1;
+    let mut value = Vec::new();
+    write(&mut value);
+    write_u8!(cursor, capability as u8);
+    write_len!(cursor, value.len());
+    cursor.extend_from_slice(&value);
+}
+
+/// Write a list of algorithms as a sub-TLV of `Capabilities`, if it isn't empty.
+fn write_algorithms<T: ToPrimitive>(
+    cursor: &mut Vec<u8>,
+    count: &mut usize,
+    capability: CapabilityType,
+    list: &[T],
+) {
+    if !list.is_empty() {
+        write_capability(cursor, count, capability, |cursor| {
+            for algorithm in list {
+                write_u8!(cursor, algorithm.to_u8().unwrap());
+            }
         });
-        ReadResult::Ok
+    }
+}
+
+/// Read a list of algorithms written by `write_algorithms()`, skipping unknown ones.
+fn read_algorithms<T: FromPrimitive>(value: &[u8]) -> Vec<T> {
+    value
+        .iter()
+        .filter_map(|&algorithm| FromPrimitive::from_u8(algorithm))
+        .collect()
+}
+
+impl<'a> WireFormat<'a> for Capabilities {
+    fn write(&self, buf: &mut Vec<u8>) {
+        let mut capabilities = Vec::new();
+        let cursor = &mut capabilities;
+        let mut count = 0;
+        if let Some(max_frame_size) = self.max_frame_size {
+            write_capability(cursor, &mut count, CapabilityType::MaxFrameSize, |cursor| {
+                write_u16!(cursor, max_frame_size);
+            });
+        }
+        write_algorithms(
+            cursor,
+            &mut count,
+            CapabilityType::Checksums,
+            &self.checksums,
+        );
+        write_algorithms(
+            cursor,
+            &mut count,
+            CapabilityType::Compression,
+            &self.compression,
+        );
+        if let Some(encryption) = &self.encryption {
+            write_capability(cursor, &mut count, CapabilityType::Encryption, |cursor| {
+                write_len!(cursor, encryption.aeads.len());
+                for aead in &encryption.aeads {
+                    write_u8!(cursor, aead.to_u8().unwrap());
+                }
+                let mut flags = 0;
+                if encryption.ephemeral_key.is_some() {
+                    flags |= KEY_FLAG_EPHEMERAL;
+                }
+                if encryption.static_key.is_some() {
+                    flags |= KEY_FLAG_STATIC;
+                }
+                if encryption.psk {
+                    flags |= KEY_FLAG_PSK;
+                }
+                write_u8!(cursor, flags);
+                for key in encryption
+                    .ephemeral_key
+                    .iter()
+                    .chain(encryption.static_key.iter())
+                {
+                    cursor.extend_from_slice(key);
+                }
+            });
+        }
+        write_algorithms(cursor, &mut count, CapabilityType::Fec, &self.fec);
+        if let Some(ack_freq) = &self.ack_freq {
+            write_capability(cursor, &mut count, CapabilityType::AckPolicy, |cursor| {
+                write_u8!(cursor, ack_freq.to_u8().unwrap());
+            });
+        }
+        write_len!(buf, count);
+        buf.extend_from_slice(&capabilities);
+    }
+
+    fn read(&mut self, cursor: &mut Cursor<'a>) -> Result<(), RftError> {
+        *self = Capabilities::default();
+        let num_capabilities = read_len!(cursor);
+        for _ in 0..num_capabilities {
+            let capability_type = read_u8!(cursor);
+            let length = read_len!(cursor);
+            let value = read_slice!(cursor, length);
+            let mut value_cursor = Cursor::new(value);
+            let value_cursor = &mut value_cursor;
+            match FromPrimitive::from_u8(capability_type) {
+                Some(CapabilityType::MaxFrameSize) => {
+                    self.max_frame_size = Some(read_u16!(value_cursor));
+                }
+                Some(CapabilityType::Checksums) => self.checksums = read_algorithms(value),
+                Some(CapabilityType::Compression) => self.compression = read_algorithms(value),
+                Some(CapabilityType::Encryption) => {
+                    let num_aeads = read_len!(value_cursor);
+                    let aeads = read_algorithms(read_slice!(value_cursor, num_aeads));
+                    let flags = read_u8!(value_cursor);
+                    let mut encryption = Encryption {
+                        aeads,
+                        psk: flags & KEY_FLAG_PSK != 0,
+                        ..Encryption::default()
+                    };
+                    if flags & KEY_FLAG_EPHEMERAL != 0 {
+                        let mut key = [0; 32];
+                        key.copy_from_slice(read_slice!(value_cursor, 32));
+                        encryption.ephemeral_key = Some(key);
+                    }
+                    if flags & KEY_FLAG_STATIC != 0 {
+                        let mut key = [0; 32];
+                        key.copy_from_slice(read_slice!(value_cursor, 32));
+                        encryption.static_key = Some(key);
+                    }
+                    self.encryption = Some(encryption);
+                }
+                Some(CapabilityType::Fec) => self.fec = read_algorithms(value),
+                Some(CapabilityType::AckPolicy) => {
+                    self.ack_freq = FromPrimitive::from_u8(read_u8!(value_cursor));
+                }
+                None => log::debug!("Skipping unknown capability {}.", capability_type),
+            }
+        }
+        Ok(())
     }
 }
 
 /////////////////////////////////
 // ObjectHeader
 
-#[derive(Default, Debug, PartialEq)]
+#[derive(Default, Debug, PartialEq, WireFormat)]
+#[wire(tlv = "TlvType::ObjectHeader")]
 pub struct ObjectHeader {
     pub object_id: ObjectId,
-    pub num_chunks: ChunkId, // LEB128
-    pub ack_req: bool,       // Ack required
+    #[wire(leb128)]
+    pub num_chunks: ChunkId,
+    #[wire(with = "ack_request")]
+    pub ack_req: bool, // Ack required
     pub object_type: ObjectType,
     pub fields: Vec<ObjectFieldDescription>,
 }
 
 const HEADER_ACK_REQUEST_BITMASK: u8 = 0b1000_0000;
 
-impl WireFormat for ObjectHeader {
-    fn write(&self, cursor: &mut Cursor) {
-        write_tlv!(cursor, TlvType::ObjectHeader, {
-            write_u64!(cursor, self.object_id);
-            write_u128!(cursor, self.num_chunks as u64);
-            match self.ack_req {
-                true => write_u8!(cursor, HEADER_ACK_REQUEST_BITMASK),
-                false => write_u8!(cursor, 0),
-            };
-            write_u8!(cursor, self.object_type);
-            write_u8!(cursor, self.fields.len() as u8);
-            for field in &self.fields {
-                field.write(cursor);
+/// `ObjectHeader::ack_req` is a flag in an otherwise reserved byte.
+mod ack_request {
+    use super::*;
+
+    pub fn write(ack_req: &bool, buf: &mut Vec<u8>) {
+        write_u8!(
+            buf,
+            if *ack_req {
+                HEADER_ACK_REQUEST_BITMASK
+            } else {
+                0
             }
-        });
+        );
     }
 
-    fn read(&mut self, cursor: &mut Cursor) -> ReadResult {
-        read_tlv!(cursor, TlvType::ObjectHeader, {
-            self.object_id = read_u64!(cursor);
-            self.num_chunks = read_u128!(cursor) as ChunkId;
-            self.ack_req = match read_u8!(cursor) {
-                HEADER_ACK_REQUEST_BITMASK => true,
-                _ => false,
-            };
-            self.object_type = read_u8!(cursor);
-            let mut num_fields = read_u8!(cursor);
-            while num_fields > 0 {
-                let mut field_description = ObjectFieldDescription::default();
-                field_description.read(cursor);
-                self.fields.push(field_description);
-                num_fields -= 1;
-            }
-        });
-        ReadResult::Ok
+    pub fn read(ack_req: &mut bool, cursor: &mut Cursor) -> Result<(), RftError> {
+        *ack_req = read_u8!(cursor) & HEADER_ACK_REQUEST_BITMASK != 0;
+        Ok(())
     }
 }
 
 /////////////////////////////////
 // ObjectField
 
-#[derive(Default, Debug, PartialEq, Clone)]
+#[derive(Default, Debug, PartialEq, Clone, WireFormat)]
 pub struct ObjectFieldDescription {
     pub field_type: ObjectFieldType,
+    #[wire(leb128)]
     pub length: ChunkId, // in nr. of chunks
 }
 
-impl WireFormat for ObjectFieldDescription {
-    fn write(&self, cursor: &mut Cursor) {
-        write_u8!(cursor, self.field_type);
-        write_u128!(cursor, self.length as u64);
-    }
-
-    fn read(&mut self, cursor: &mut Cursor) -> ReadResult {
-        self.field_type = read_u8!(cursor);
-        self.length = read_u128!(cursor) as ChunkId;
-        ReadResult::Ok
-    }
-}
-
 /////////////////////////////////
 // ObjectChunk
 
+/// `WireFormat` is implemented by hand, as `more_chunks` and `ack_required`
+/// share a flags byte, which the derive can't express.
 #[derive(Default, Debug, PartialEq)]
-pub struct ObjectChunk {
+pub struct ObjectChunk<'a> {
     pub object_id: ObjectId,
     pub chunk_id: ChunkId, // signed LEB128
     pub more_chunks: bool,
     pub ack_required: bool,
     pub num_enclosed_msgs: u8,
-    pub data: Vec<u8>,
+    /// Borrowed from the buffer the chunk was read from.
+    pub data: &'a [u8],
 }
 
-const MORE_CHUNKS_BITMASK: u16 = 0b1000_0000_0000_0000;
-const CHUNK_ACK_REQUEST_BITMASK: u16 = 0b0100_0000_0000_0000;
-const CHUNK_SIZE_BITMASK: u16 = 0b0000_0111_1111_1111;
+const MORE_CHUNKS_BITMASK: u8 = 0b1000_0000;
+const CHUNK_ACK_REQUEST_BITMASK: u8 = 0b0100_0000;
 
-impl WireFormat for ObjectChunk {
-    fn write(&self, cursor: &mut Cursor) {
+impl<'a> WireFormat<'a> for ObjectChunk<'a> {
+    fn write(&self, cursor: &mut Vec<u8>) {
         write_tlv!(cursor, TlvType::ObjectChunk, {
             write_u64!(cursor, self.object_id);
             write_i128!(cursor, self.chunk_id);
-            write_u16!(
+            write_u8!(
                 cursor,
                 {
                     if self.more_chunks {
@@ -332,214 +595,178 @@ impl WireFormat for ObjectChunk {
                     } else {
                         0
                     }
-                } | (self.data.len() + 1) as u16
+                }
             );
             write_u8!(cursor, self.num_enclosed_msgs);
-            cursor.write(&self.data).expect("Chunk data write failed!");
+            write_len!(cursor, self.data.len());
+            cursor.extend_from_slice(self.data);
         });
     }
 
-    fn read(&mut self, cursor: &mut Cursor) -> ReadResult {
+    fn read(&mut self, cursor: &mut Cursor<'a>) -> Result<(), RftError> {
         read_tlv!(cursor, TlvType::ObjectChunk, {
             self.object_id = read_u64!(cursor);
             self.chunk_id = read_i128!(cursor);
-            let mut chunksize = read_u16!(cursor);
-            self.more_chunks = (chunksize & MORE_CHUNKS_BITMASK) != 0;
-            self.ack_required = (chunksize & CHUNK_ACK_REQUEST_BITMASK) != 0;
+            let flags = read_u8!(cursor);
+            self.more_chunks = (flags & MORE_CHUNKS_BITMASK) != 0;
+            self.ack_required = (flags & CHUNK_ACK_REQUEST_BITMASK) != 0;
             self.num_enclosed_msgs = read_u8!(cursor);
-            chunksize = (chunksize & CHUNK_SIZE_BITMASK) - 1;
-            self.data.reserve(chunksize as usize);
-            while chunksize > 0 {
-                match cursor.read_u8() {
-                    Ok(byte) => self.data.push(byte),
-                    Err(err) => return ReadResult::Err(ReadError::new(&err.to_string())),
-                }
-                chunksize -= 1;
-            }
+            let chunksize = read_len!(cursor);
+            self.data = read_slice!(cursor, chunksize);
         });
-        ReadResult::Ok
+        Ok(())
     }
 }
 
 /////////////////////////////////
 // ObjectSkip
 
-#[derive(Default, Debug, PartialEq)]
+#[derive(Default, Debug, PartialEq, WireFormat)]
+#[wire(tlv = "TlvType::ObjectSkip")]
 pub struct ObjectSkip {
     object_id: ObjectId,
     skip_to: ChunkId,
 }
 
-impl WireFormat for ObjectSkip {
-    fn write(&self, cursor: &mut Cursor) {
-        write_tlv!(cursor, TlvType::ObjectSkip, {
-            write_u64!(cursor, self.object_id);
-            write_i128!(cursor, self.skip_to);
-        });
-    }
+/////////////////////////////////
+// Authentication
 
-    fn read(&mut self, cursor: &mut Cursor) -> ReadResult {
-        read_tlv!(cursor, TlvType::ObjectSkip, {
-            self.object_id = read_u64!(cursor);
-            self.skip_to = read_i128!(cursor);
-        });
-        ReadResult::Ok
-    }
+/// How a client proves its identity, see `Authentication`.
+#[derive(FromPrimitive, ToPrimitive, Debug, PartialEq, Eq, Clone, Copy, WireFormat, Default)]
+#[repr(u8)]
+pub enum AuthMethod {
+    /// The proof is the secret itself, so it should only be used on encrypted connections.
+    Token = 1,
+    /// The proof is an HMAC-SHA256 over a challenge from the handshake, keyed with the secret.
+    #[default]
+    ChallengeResponse = 2,
+}
+
+/// Sent by a client right after the handshake to authenticate as `user`.
+/// Retransmitted until the server answers with an `AuthenticationAck`.
+#[derive(Default, Debug, PartialEq, Clone, WireFormat)]
+#[wire(tlv = "TlvType::Authentication")]
+pub struct Authentication {
+    pub user: String,
+    pub method: AuthMethod,
+    pub proof: Vec<u8>,
+}
+
+/// Sent by a server for every `Authentication` it accepted, also repeated ones.
+/// The client sends no objects before it arrived.
+#[derive(Default, Debug, PartialEq, Clone, WireFormat)]
+#[wire(tlv = "TlvType::AuthenticationAck")]
+pub struct AuthenticationAck {
+    pub user: String,
 }
 
 /////////////////////////////////
-// ObjectAck
+// RetryCookie
 
-#[derive(Default, Debug, PartialEq)]
-pub struct ObjectAck {
-    pub acknowledged_object_chunks: Vec<(ObjectId, ChunkId)>,
+/// Sent by a server instead of its `HostInformation`, if it validates client
+/// addresses before creating any state. The client repeats its handshake with
+/// the `RetryCookie` after its `HostInformation`.
+#[derive(Default, Debug, PartialEq, Clone, WireFormat)]
+#[wire(tlv = "TlvType::RetryCookie")]
+pub struct RetryCookie {
+    pub cookie: Vec<u8>,
 }
 
-impl WireFormat for ObjectAck {
-    fn write(&self, cursor: &mut Cursor) {
-        write_tlv!(cursor, TlvType::ObjectChunk, {
-            write_u8!(cursor, self.acknowledged_object_chunks.len() as u8);
-            for chunk in &self.acknowledged_object_chunks {
-                write_u64!(cursor, chunk.0);
-                write_i128!(cursor, chunk.1);
-            }
-        });
-    }
+/////////////////////////////////
+// ObjectAck
 
-    fn read(&mut self, cursor: &mut Cursor) -> ReadResult {
-        read_tlv!(cursor, TlvType::ObjectChunk, {
-            let num_acks = read_u8!(cursor);
-            self.acknowledged_object_chunks.reserve(num_acks as usize);
-            while num_acks > 0 {
-                self.acknowledged_object_chunks
-                    .push((read_u64!(cursor), read_i128!(cursor)));
-            }
-        });
-        ReadResult::Ok
-    }
+#[derive(Default, Debug, PartialEq, WireFormat)]
+#[wire(tlv = "TlvType::ObjectAck")]
+pub struct ObjectAck {
+    pub acknowledged_object_chunks: Vec<(ObjectId, ChunkId)>,
 }
 
 /////////////////////////////////
 // ErrorMessage
 
-#[derive(FromPrimitive, ToPrimitive, Debug, PartialEq)]
+#[derive(FromPrimitive, ToPrimitive, Debug, PartialEq, Clone, WireFormat, Default)]
 #[repr(u8)]
-enum ErrorCode {
+pub enum ErrorCode {
+    #[default]
     None = 0,
     ChecksumError = 4,
     UnsupportedVersion = 5,
     SessionUnknown = 6,
     ObjectAbort = 8,
+    /// The peer requires frames to be encrypted, but no common AEAD
+    /// algorithm was found.
+    EncryptionRequired = 9,
+    /// The server did not authenticate with the pinned public key.
+    /// Only reported locally by clients.
+    UnknownServerKey = 10,
+    /// The client's `Authentication` was rejected.
+    AuthenticationFailed = 11,
 }
 
-impl Default for ErrorCode {
-    fn default() -> Self {
-        ErrorCode::None
-    }
-}
-
-#[derive(Default, Debug, PartialEq)]
+#[derive(Default, Debug, PartialEq, Clone, WireFormat)]
 pub struct MaxMinSupportedVersion {
-    max_ver: Version,
-    min_ver: Version,
+    pub max_ver: Version,
+    pub min_ver: Version,
 }
 
-type AbortedObjectIds = Vec<ObjectId>;
+pub type AbortedObjectIds = Vec<ObjectId>;
 
-#[derive(Debug, PartialEq)]
-enum ErrorData {
+#[derive(Debug, PartialEq, Clone, Default)]
+pub enum ErrorData {
     UnsupportedVersion(MaxMinSupportedVersion),
     ObjectAbort(AbortedObjectIds),
+    #[default]
     None,
 }
 
-impl Default for ErrorData {
-    fn default() -> Self {
-        ErrorData::None
-    }
-}
-
-#[derive(Default, Debug, PartialEq)]
+/// `WireFormat` is implemented by hand, as the encoding of `detail`
+/// depends on `code`, which the derive can't express.
+#[derive(Default, Debug, PartialEq, Clone)]
 pub struct ErrorMessage {
-    code: ErrorCode,
-    detail: ErrorData,
+    pub code: ErrorCode,
+    pub detail: ErrorData,
 }
 
-impl WireFormat for ErrorMessage {
-    fn write(&self, cursor: &mut Cursor) {
-        write_tlv!(cursor, TlvType::ObjectChunk, {
-            write_u8!(cursor, self.code.to_u8().unwrap());
+impl<'a> WireFormat<'a> for ErrorMessage {
+    fn write(&self, cursor: &mut Vec<u8>) {
+        write_tlv!(cursor, TlvType::ErrorMessage, {
+            self.code.write(cursor);
             match (&self.code, &self.detail) {
                 (ErrorCode::UnsupportedVersion, ErrorData::UnsupportedVersion(x)) => {
-                    write_u8!(cursor, x.max_ver);
-                    write_u8!(cursor, x.min_ver);
-                }
-                (ErrorCode::ObjectAbort, ErrorData::ObjectAbort(x)) => {
-                    write_u8!(cursor, x.len() as u8);
-                    for id in x {
-                        write_u64!(cursor, *id);
-                    }
+                    x.write(cursor)
                 }
+                (ErrorCode::ObjectAbort, ErrorData::ObjectAbort(x)) => x.write(cursor),
                 (_, _) => {}
             }
         });
     }
 
-    fn read(&mut self, cursor: &mut Cursor) -> ReadResult {
-        read_tlv!(cursor, TlvType::ObjectChunk, {
-            self.code = FromPrimitive::from_u8(read_u8!(cursor)).unwrap();
+    fn read(&mut self, cursor: &mut Cursor<'a>) -> Result<(), RftError> {
+        read_tlv!(cursor, TlvType::ErrorMessage, {
+            self.code.read(cursor)?;
             self.detail = match &self.code {
                 ErrorCode::UnsupportedVersion => {
-                    ErrorData::UnsupportedVersion(MaxMinSupportedVersion {
-                        max_ver: read_u8!(cursor),
-                        min_ver: read_u8!(cursor),
-                    })
+                    let mut versions = MaxMinSupportedVersion::default();
+                    versions.read(cursor)?;
+                    ErrorData::UnsupportedVersion(versions)
                 }
                 ErrorCode::ObjectAbort => {
-                    let mut result = Vec::new();
-                    let mut num_aborted_object_ids = read_u8!(cursor);
-                    result.reserve(num_aborted_object_ids as usize);
-                    while num_aborted_object_ids > 0 {
-                        result.push(read_u64!(cursor));
-                        num_aborted_object_ids -= 1;
-                    }
-                    ErrorData::ObjectAbort(result)
+                    let mut ids = AbortedObjectIds::new();
+                    ids.read(cursor)?;
+                    ErrorData::ObjectAbort(ids)
                 }
                 _ => ErrorData::None,
             }
         });
-        ReadResult::Ok
+        Ok(())
     }
 }
 
 /////////////////////////////////
 // ObjectAckRequest
 
-#[derive(Default, Debug, PartialEq)]
+#[derive(Default, Debug, PartialEq, WireFormat)]
+#[wire(tlv = "TlvType::ObjectAckRequest")]
 pub struct ObjectAckRequest {
     pub req_ack_object_chunks: Vec<(ObjectId, ChunkId)>,
 }
-
-impl WireFormat for ObjectAckRequest {
-    fn write(&self, cursor: &mut Cursor) {
-        write_tlv!(cursor, TlvType::ObjectChunk, {
-            write_u8!(cursor, self.req_ack_object_chunks.len() as u8);
-            for chunk in &self.req_ack_object_chunks {
-                write_u64!(cursor, chunk.0);
-                write_i128!(cursor, chunk.1);
-            }
-        });
-    }
-
-    fn read(&mut self, cursor: &mut Cursor) -> ReadResult {
-        read_tlv!(cursor, TlvType::ObjectChunk, {
-            let num_acks = read_u8!(cursor);
-            self.req_ack_object_chunks.reserve(num_acks as usize);
-            while num_acks > 0 {
-                self.req_ack_object_chunks
-                    .push((read_u64!(cursor), read_i128!(cursor)));
-            }
-        });
-        ReadResult::Ok
-    }
-}
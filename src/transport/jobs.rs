@@ -1,34 +1,63 @@
 use crate::transport::common;
 use crate::transport::connection::*;
 use crate::transport::frame::*;
+use crate::transport::stats::ObjectStats;
+use crate::transport::stream::ReadBuffer;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 //////////////////////////
 // Object metatype
 
 /// This is a description what can be sent and received by an application.
-/// While using a `Connection` the application will provide callbacks to
-/// receive and provide chunks of this object.
+/// While using a `Connection` the application will push chunks of this
+/// object into its `ObjectSendJob` and poll received chunks as `Event`s.
+#[derive(Debug, PartialEq, Clone)]
 pub struct Object {
     pub object_type: ObjectType,
     pub object_id: ObjectId,
     pub fields: Vec<ObjectFieldDescription>,
-    pub transmission_finished_callback: Box<TransmissionFinishedListener>,
 }
 
-/// Called by transport layer while it is working
-/// on an ObjectSendJob.
-/// Number of application tlvs in this chunk are returned in as second
-/// element of the tuple.
-pub type ChunkProvider = dyn FnMut(ChunkId) -> (Vec<u8>, u8);
-
-/// Called by connection once transfer is done.
-pub type TransmissionFinishedListener = dyn FnMut() -> ();
-
 //////////////////////////
 // ObjectSendJob
 
+/// Chunks which were pushed but not sent yet, together with the number of
+/// application tlvs in each chunk.
+type ChunkQueue = Arc<Mutex<VecDeque<(Vec<u8>, u8)>>>;
+
+/// Handle to push chunks into an `ObjectSendJob` from another thread,
+/// e.g. a thread doing disk I/O while the network loop keeps going.
+/// Obtained by `ObjectSendJob::chunk_sender()`.
+#[derive(Clone)]
+pub struct ChunkSender {
+    chunk_queue: ChunkQueue,
+}
+
+impl ChunkSender {
+    /// Queue the next chunk of the object for sending.
+    /// `num_tlvs` is the number of application tlvs in `data`.
+    pub fn push_chunk(&self, data: Vec<u8>, num_tlvs: u8) {
+        self.chunk_queue.lock().unwrap().push_back((data, num_tlvs));
+    }
+
+    /// Number of chunks which were pushed but not sent yet.
+    pub fn queued_chunks(&self) -> usize {
+        self.chunk_queue.lock().unwrap().len()
+    }
+
+    /// Wether the `ObjectSendJob` was dropped by the `Connection`,
+    /// i.e. nobody will send the pushed chunks anymore.
+    pub fn is_closed(&self) -> bool {
+        Arc::strong_count(&self.chunk_queue) == 1
+    }
+}
+
 /// Handler for an Object which is in sending transmission.
-/// Chunks are provided to the transport layer via Connection.send(_, _, get_chunk)
+/// Chunks are provided by the application via `push_chunk()` or a
+/// `ChunkSender`, in order of their chunk id. The transport layer sends
+/// them as soon as they are queued.
 pub struct ObjectSendJob {
     /// Abort sending by setting this flag to true.
     pub abort: bool,
@@ -38,17 +67,22 @@ pub struct ObjectSendJob {
     pub next_chunk: ChunkId,
     /// Object instance that was passed to new()
     object_in_transfer: Object,
-    /// Callback which is used by the connection to retrieve chunks.
-    get_chunk_callback: Box<ChunkProvider>,
+    /// Chunks pushed by the application which were not sent yet.
+    chunk_queue: ChunkQueue,
+    /// Chunks from `next_chunk` on, taken from the queue but not sent successfully yet.
+    pending_chunks: VecDeque<(Vec<u8>, u8)>,
+    stats: ObjectStats,
 }
 
 impl ObjectSendJob {
-    pub fn new(obj: Object, chunk_getter: Box<ChunkProvider>) -> Self {
+    pub fn new(obj: Object) -> Self {
         ObjectSendJob {
             abort: false,
             next_chunk: -1,
             object_in_transfer: obj,
-            get_chunk_callback: chunk_getter,
+            chunk_queue: Arc::new(Mutex::new(VecDeque::new())),
+            pending_chunks: VecDeque::new(),
+            stats: ObjectStats::default(),
         }
     }
 
@@ -60,12 +94,35 @@ impl ObjectSendJob {
         self.object_in_transfer.object_id
     }
 
+    /// Queue the next chunk of the object for sending.
+    /// `num_tlvs` is the number of application tlvs in `data`.
+    pub fn push_chunk(&mut self, data: Vec<u8>, num_tlvs: u8) {
+        self.chunk_sender().push_chunk(data, num_tlvs);
+    }
+
+    /// Number of chunks which were pushed but not sent yet.
+    pub fn queued_chunks(&self) -> usize {
+        self.chunk_queue.lock().unwrap().len() + self.pending_chunks.len()
+    }
+
+    /// Transfer statistics of the object so far.
+    pub fn stats(&self) -> &ObjectStats {
+        &self.stats
+    }
+
+    /// Returns a handle to push chunks into this job from another thread.
+    pub fn chunk_sender(&self) -> ChunkSender {
+        ChunkSender {
+            chunk_queue: Arc::clone(&self.chunk_queue),
+        }
+    }
+
     /// TODO
     pub(super) fn ack_required(&self) -> bool {
         false
     }
 
-    fn count_chunks(&self) -> ChunkId {
+    pub fn count_chunks(&self) -> ChunkId {
         let n_chunks: ChunkId = self
             .object_in_transfer
             .fields
@@ -75,19 +132,40 @@ impl ObjectSendJob {
         return n_chunks;
     }
 
-    /// wether self has a chunk after next_chunk
-    pub(super) fn has_next(&self) -> bool {
+    /// wether self has a chunk after chunk_id
+    pub(super) fn has_next(&self, chunk_id: ChunkId) -> bool {
         let n_chunks: ChunkId = self.count_chunks();
         let last_chunk = n_chunks - 1;
-        return self.next_chunk < last_chunk;
+        return chunk_id < last_chunk;
+    }
+
+    /// advances the state for having sent the first `n` frames returned by `send_next()`
+    pub(super) fn advance(&mut self, n: usize, now: Instant) {
+        for _ in 0..n {
+            if self.next_chunk >= 0 {
+                if let Some((chunk, _)) = self.pending_chunks.pop_front() {
+                    self.stats.chunk(chunk.len(), now);
+                }
+            } else {
+                self.stats.started = Some(now);
+            }
+            self.next_chunk += 1;
+        }
     }
 
-    fn send_o_header(&mut self, mut session: &EstablishedState) -> MessageFrame {
+    /// wether the header and all chunks were sent
+    pub(super) fn is_finished(&self) -> bool {
+        self.next_chunk >= self.count_chunks()
+    }
+
+    fn send_o_header(&self, session: &EstablishedState) -> MessageFrame<'_> {
         // build ObjectChunk message
-        let mut msg: MessageFrame = MessageFrame::default();
-        msg.sid = session.sessionid;
-        msg.version = common::PROTOCOL_VERSION;
-        msg.tlvs = Vec::new();
+        let mut msg = MessageFrame {
+            version: common::PROTOCOL_VERSION,
+            sid: session.sessionid,
+            checksum: session.features.checksum,
+            ..MessageFrame::default()
+        };
         let oh: ObjectHeader = ObjectHeader {
             object_id: self.object_id(),
             num_chunks: self.count_chunks(),
@@ -100,33 +178,53 @@ impl ObjectSendJob {
         return msg;
     }
 
-    fn send_o_chunk(&mut self, mut session: &EstablishedState) -> MessageFrame {
-        let (chunk, n_tlvs) = (self.get_chunk_callback)(self.next_chunk);
+    fn send_o_chunk(
+        &mut self,
+        session: &EstablishedState,
+        chunk_id: ChunkId,
+    ) -> Option<MessageFrame<'_>> {
+        let idx = (chunk_id - self.next_chunk.max(0)) as usize;
+        while self.pending_chunks.len() <= idx {
+            let chunk = self.chunk_queue.lock().unwrap().pop_front()?;
+            self.pending_chunks.push_back(chunk);
+        }
+        let more_chunks = self.has_next(chunk_id);
+        let (chunk, n_tlvs) = &self.pending_chunks[idx];
 
         // build ObjectChunk message
-        let mut msg: MessageFrame = MessageFrame::default();
-        msg.sid = session.sessionid;
-        msg.version = common::PROTOCOL_VERSION;
-        msg.tlvs = Vec::new();
+        let mut msg = MessageFrame {
+            version: common::PROTOCOL_VERSION,
+            sid: session.sessionid,
+            checksum: session.features.checksum,
+            ..MessageFrame::default()
+        };
         let oc: ObjectChunk = ObjectChunk {
             object_id: self.object_id(),
-            chunk_id: self.next_chunk,
-            more_chunks: self.has_next(),
+            chunk_id,
+            more_chunks,
             ack_required: self.ack_required(),
-            num_enclosed_msgs: n_tlvs,
+            num_enclosed_msgs: *n_tlvs,
             data: chunk,
         };
         msg.tlvs.push(Tlv::ObjectChunk(oc));
 
-        return msg;
+        return Some(msg);
     }
 
-    /// advances the state for having sent the returned chunk
-    pub(super) fn send_next(&mut self, session: &EstablishedState) -> Option<MessageFrame> {
-        if self.next_chunk == -1 {
+    /// Returns the frame for chunk `next_chunk + offset`, which is borrowed from self.
+    /// Frames are sent in order of their offset, so a batch of frames can be sent
+    /// before calling `advance()` with the number of frames sent successfully.
+    /// Returns None if the chunk was not pushed yet.
+    pub(super) fn send_next(
+        &mut self,
+        session: &EstablishedState,
+        offset: usize,
+    ) -> Option<MessageFrame<'_>> {
+        let chunk_id = self.next_chunk + offset as ChunkId;
+        if chunk_id == -1 {
             return Some(self.send_o_header(session));
-        } else if self.next_chunk < self.count_chunks() {
-            return Some(self.send_o_chunk(session));
+        } else if chunk_id < self.count_chunks() {
+            return self.send_o_chunk(session, chunk_id);
         }
         None
     }
@@ -135,17 +233,42 @@ impl ObjectSendJob {
 //////////////////////////
 // ObjectReceiveJob
 
-/// Will be called by the transport layer to pass a chunk on to
-/// the application. There is one ChunkListener per Object.
-/// Number of application tlvs in this chunk in `nr_tlv`.
-pub type ChunkListener = dyn FnMut(Vec<u8>, ChunkId, u8) -> ();
-
+/// Chunks of an ObjectReceiveJob are passed on to the application
+/// as `Event::Chunk`, or to its `ObjectReader` if there is one.
 pub struct ObjectReceiveJob {
-    pub chunk_received_callback: Box<ChunkListener>,
     /// Metadata about the object.
     pub object: Object,
     /// Abort receiving by setting this flag to true.
     pub abort: bool,
     /// -2, if no Ack required, else chunkid [-1,infty] which was requested to be acked.
     pub ack_req: ChunkId,
+    /// Buffer of the `ObjectReader` for this object, if any.
+    pub(super) reader: Option<Arc<Mutex<ReadBuffer>>>,
+    /// Wether each chunk was received, to count duplicates.
+    pub(super) received: Vec<bool>,
+    pub(super) stats: ObjectStats,
+}
+
+impl ObjectReceiveJob {
+    /// Transfer statistics of the object so far.
+    pub fn stats(&self) -> &ObjectStats {
+        &self.stats
+    }
+
+    /// Counts a received chunk. Returns false if it was received before.
+    pub(super) fn chunk_received(&mut self, chunk_id: ChunkId, bytes: usize, now: Instant) -> bool {
+        match self.received.get_mut(chunk_id as usize) {
+            Some(true) => {
+                self.stats.duplicates += 1;
+                false
+            }
+            Some(received) => {
+                *received = true;
+                self.stats.chunk(bytes, now);
+                true
+            }
+            // out of range, the application will complain
+            None => true,
+        }
+    }
 }
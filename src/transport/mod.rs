@@ -1,13 +1,21 @@
 pub mod client;
+pub mod config;
 pub mod connection;
+pub mod crypto;
+pub mod datagram;
+pub mod features;
 pub mod frame;
 pub mod jobs;
 pub mod server;
+pub mod sim;
+pub mod stats;
+pub mod stream;
+pub mod trace;
 
 mod common;
 
 #[cfg(test)]
-mod test;
+pub(crate) mod test;
 
 /// Maximum chunksize in bytes.
 pub const CHUNKSIZE: usize = 512;
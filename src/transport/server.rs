@@ -1,54 +1,320 @@
-use crate::transport::common::default_host_info;
+use crate::common::{Cursor, WireFormat};
+use crate::error::RftError;
+use crate::transport::common::*;
+use crate::transport::config::Config;
 use crate::transport::connection::*;
+use crate::transport::crypto::{check_retry_cookie, retry_cookie};
+use crate::transport::datagram::{DatagramTransport, UdpTransport};
+use crate::transport::frame::*;
+use crate::transport::sim::Env;
 use log;
-use std::net::{SocketAddr, UdpSocket};
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+/// A `RetryCookie` is accepted for this long after it was sent.
+const COOKIE_LIFETIME: Duration = Duration::from_secs(10);
+/// Datagrams which are kept for a single peer until its `Connection` receives
+/// them, or for peers without a `Connection` until `listen_once()`.
+/// Further ones are dropped.
+const MAX_QUEUED_DATAGRAMS: usize = 1024;
+
+/// Accepts any number of clients on a single transport, each with its own
+/// `Connection`. Datagrams are handed to the `Connection` by their session id,
+/// or by the address of their sender until the client learned its session id.
+/// Replies always go to the address the client connected from.
 pub struct Listener {
-    socket: UdpSocket,
+    demux: Arc<Mutex<Demux>>,
+    /// Environment for the `Connection`s: its clock is shared, and each one
+    /// gets a generator which is seeded from this one.
+    env: Env,
+    config: Config,
+    /// Key for the MACs of `RetryCookie`s, used if `config.stateless_retry` is set.
+    cookie_key: [u8; 32],
+    /// Timestamps in `RetryCookie`s are the seconds since this point in time.
+    started: Instant,
 }
 
 impl Listener {
-    pub fn new(bind: SocketAddr) -> Self {
-        let socket: UdpSocket = UdpSocket::bind(bind).expect("Could not bind to Socket.");
-        socket.set_nonblocking(true).unwrap();
+    /// Binds to `bind`. An IPv6 listener is dual-stack, i.e. binding to `[::]`
+    /// also accepts IPv4 clients as IPv4-mapped addresses.
+    /// Fails if `bind` can't be bound.
+    pub fn new(bind: SocketAddr) -> Result<Self, RftError> {
+        return Listener::with_config(bind, Config::default());
+    }
+
+    /// Like `new()`, with the given settings for the `Connection`s.
+    pub fn with_config(bind: SocketAddr, config: Config) -> Result<Self, RftError> {
+        let transport = UdpTransport::bind(bind)?;
+        return Ok(Listener::with_transport(
+            Box::new(transport),
+            Env::default(),
+            config,
+        ));
+    }
+
+    /// Listen on any unconnected transport,
+    /// with the given clock, randomness and settings for the `Connection`s.
+    pub fn with_transport(
+        transport: Box<dyn DatagramTransport>,
+        mut env: Env,
+        config: Config,
+    ) -> Self {
+        let mut cookie_key = [0; 32];
+        env.rng.fill_bytes(&mut cookie_key);
         Self {
-            socket: Some(socket),
+            demux: Arc::new(Mutex::new(Demux {
+                transport,
+                peers: HashMap::new(),
+                sessions: HashMap::new(),
+                unknown: VecDeque::new(),
+            })),
+            started: env.clock.now(),
+            env,
+            config,
+            cookie_key,
         }
     }
 
     /// Used by servers to listen for incoming connections.
-    /// non-blocking.
-    pub fn listen_once(
-        &mut self,
-        accept_callback: Box<ObjectListener>,
-        timeout_callback: Box<TimeoutListener>,
-    ) -> Option<Connection> {
-        // try to consume the socket to transfer its ownership to Connection
-        if let Some(socket) = &mut self.socket {
-            let mut buf: [u8; 10] = [0; 10];
-            if let Ok((_n_bytes, src)) = socket.peek_from(&mut buf) {
-                // heureka! We got a client!
-                let socket = self.socket.take().unwrap(); // consume known existing socket
-                socket.connect(src).unwrap();
-                let conn = Connection {
-                    send_jobs: Vec::new(),
-                    recv_jobs: Vec::new(),
-                    accept_callback,
-                    timeout_callback,
-                    socket,
-                    dest: src,
-                    is_server: true,
-                    self_info: default_host_info(),
-                    peer_info: None,
-                    session: None,
-                };
-                return Some(conn);
+    /// non-blocking. Returns the `Connection` to the next new client, if any,
+    /// which has to be driven alongside the ones returned before.
+    pub fn listen_once(&mut self) -> Option<Connection> {
+        let mut demux = self.demux.lock().unwrap();
+        demux.poll();
+        while let Some((peer, datagram)) = demux.unknown.pop_front() {
+            // a connection was accepted since the datagram was queued
+            if demux.peers.contains_key(&peer) {
+                demux.route(peer, datagram);
+                continue;
+            }
+            if !self.accepts(&*demux.transport, &peer, &datagram) {
+                continue;
+            }
+
+            // heureka! We got a client!
+            log::info!("Accepted connection from {}", peer);
+            let mut inbox = VecDeque::new();
+            inbox.push_back(datagram);
+            let (queued, unknown) = demux.unknown.drain(..).partition(|(from, _)| from == &peer);
+            demux.unknown = unknown;
+            inbox.extend(queued.into_iter().map(|(_, datagram)| datagram));
+            demux
+                .peers
+                .insert(peer.clone(), PeerState { inbox, sid: None });
+            drop(demux);
+
+            let transport = PeerTransport {
+                demux: Arc::clone(&self.demux),
+                peer,
             };
-            return None;
+            let env = Env {
+                clock: Arc::clone(&self.env.clock),
+                rng: Box::new(StdRng::from_rng(&mut self.env.rng).unwrap()),
+            };
+            let mut connection =
+                Connection::new(Box::new(transport), true, env, self.config.clone());
+            if self.config.stateless_retry {
+                connection.amplification_budget = None;
+            }
+            return Some(connection);
         }
-
-        // socket already consumed.
-        log::warn!("Listening on dead Listener. It is dead because it has already produced a connection. If the connection is completed, instanciate a new listener. ");
         return None;
     }
+
+    /// Wether `datagram` from the new client `peer` is a handshake, with a
+    /// valid `RetryCookie` if `config.stateless_retry` is set. Handshakes
+    /// without one are answered with a new cookie, and anything else is
+    /// dropped, without creating any state.
+    fn accepts(&self, transport: &dyn DatagramTransport, peer: &str, datagram: &[u8]) -> bool {
+        let mut frame = MessageFrame::default();
+        let mut cursor = Cursor::new(datagram);
+        let cookie = match frame.read(&mut cursor) {
+            Ok(()) => match &frame.tlvs[..] {
+                [Tlv::HostInformation(_)] => None,
+                [Tlv::HostInformation(_), Tlv::RetryCookie(retry)] => Some(&retry.cookie),
+                _ => {
+                    log::debug!("Dropping datagram from {}, which is no handshake.", peer);
+                    return false;
+                }
+            },
+            Err(e) => {
+                log::debug!("Dropping datagram from {}: {}", peer, e.to_string());
+                return false;
+            }
+        };
+        if !self.config.stateless_retry {
+            return true;
+        }
+
+        let now = self.env.clock.now();
+        let timestamp = now.duration_since(self.started).as_secs();
+        if let Some(cookie) = cookie {
+            match check_retry_cookie(&self.cookie_key, peer, cookie) {
+                Some(issued) if timestamp.saturating_sub(issued) <= COOKIE_LIFETIME.as_secs() => {
+                    return true
+                }
+                Some(_) => log::debug!("Retry cookie of {} expired.", peer),
+                None => log::debug!("Invalid retry cookie from {}.", peer),
+            }
+        }
+
+        // smaller than the handshake of the client, so there is no amplification
+        let reply = MessageFrame {
+            version: PROTOCOL_VERSION,
+            tlvs: vec![Tlv::RetryCookie(RetryCookie {
+                cookie: retry_cookie(&self.cookie_key, peer, timestamp),
+            })],
+            ..MessageFrame::default()
+        };
+        let mut reply_buf = Vec::new();
+        reply.write(&mut reply_buf);
+        log::debug!("Asking {} to retry the handshake.", peer);
+        if let Err(e) = transport.send_to(&reply_buf, peer) {
+            log::warn!("Failed to send retry cookie: {}", e);
+        }
+        return false;
+    }
+}
+
+//////////////////////////
+// Demultiplexing
+
+/// The transport of a `Listener`, shared by the `Connection`s it accepted.
+struct Demux {
+    transport: Box<dyn DatagramTransport>,
+    /// Accepted clients, by address.
+    peers: HashMap<String, PeerState>,
+    /// Addresses of the accepted clients, by session id.
+    sessions: HashMap<SessionId, String>,
+    /// Datagrams of senders without a `Connection`, for `listen_once()`.
+    unknown: VecDeque<(String, Vec<u8>)>,
+}
+
+struct PeerState {
+    /// Received datagrams, until the `Connection` receives them.
+    inbox: VecDeque<Vec<u8>>,
+    /// Learned from the first frame the `Connection` sends with it.
+    sid: Option<SessionId>,
+}
+
+impl Demux {
+    /// Receives all available datagrams, and hands them out.
+    fn poll(&mut self) {
+        let mut buf = vec![0; MAX_UDP_BUFSIZE];
+        loop {
+            match self.transport.recv_from(&mut buf) {
+                Ok((n_bytes, peer)) => self.route(peer, buf[..n_bytes].to_vec()),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    log::warn!("Listening failed: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Queues `datagram` for the `Connection` of its session id, or of its
+    /// sender, or for `listen_once()` if there is none.
+    fn route(&mut self, peer: String, datagram: Vec<u8>) {
+        let peer = match frame_sid(&datagram).and_then(|sid| self.sessions.get(&sid)) {
+            Some(session_peer) => session_peer.clone(),
+            None => peer,
+        };
+        match self.peers.get_mut(&peer) {
+            Some(state) if state.inbox.len() < MAX_QUEUED_DATAGRAMS => {
+                state.inbox.push_back(datagram)
+            }
+            None if self.unknown.len() < MAX_QUEUED_DATAGRAMS => {
+                self.unknown.push_back((peer, datagram))
+            }
+            _ => log::debug!("Dropping datagram from {}, too many are queued.", peer),
+        }
+    }
+}
+
+/// Session id in the header of a frame, None if it is not set yet.
+fn frame_sid(datagram: &[u8]) -> Option<SessionId> {
+    let mut sid = [0; 8];
+    sid.copy_from_slice(datagram.get(1..9)?);
+    match SessionId::from_be_bytes(sid) {
+        0 => None,
+        sid => Some(sid),
+    }
+}
+
+/// `DatagramTransport` of a `Connection` accepted by a `Listener`, which
+/// sends to the client's address and receives what the `Listener` hands
+/// out to it. It uses the fallback batches, as the datagrams of all
+/// clients are received together.
+struct PeerTransport {
+    demux: Arc<Mutex<Demux>>,
+    peer: String,
+}
+
+impl DatagramTransport for PeerTransport {
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut demux = self.demux.lock().unwrap();
+        let demux = &mut *demux;
+        if let Some(sid) = frame_sid(buf) {
+            let state = demux.peers.get_mut(&self.peer).unwrap();
+            if state.sid.is_none() {
+                match demux.sessions.entry(sid) {
+                    Entry::Occupied(_) => {
+                        log::warn!("Session id of {} is already taken.", self.peer)
+                    }
+                    Entry::Vacant(entry) => {
+                        entry.insert(self.peer.clone());
+                    }
+                }
+                state.sid = Some(sid);
+            }
+        }
+        demux.transport.send_to(buf, &self.peer)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut demux = self.demux.lock().unwrap();
+        let is_empty = demux.peers[&self.peer].inbox.is_empty();
+        if is_empty {
+            demux.poll();
+        }
+        match demux.peers.get_mut(&self.peer).unwrap().inbox.pop_front() {
+            Some(datagram) => {
+                let n = datagram.len().min(buf.len());
+                buf[..n].copy_from_slice(&datagram[..n]);
+                Ok(n)
+            }
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, String)> {
+        Ok((self.recv(buf)?, self.peer.clone()))
+    }
+
+    fn send_to(&self, buf: &[u8], peer: &str) -> io::Result<usize> {
+        self.demux.lock().unwrap().transport.send_to(buf, peer)
+    }
+
+    fn peer(&self) -> String {
+        self.peer.clone()
+    }
+}
+
+impl Drop for PeerTransport {
+    /// Later datagrams of the client go to `listen_once()` again.
+    fn drop(&mut self) {
+        let mut demux = self.demux.lock().unwrap();
+        if let Some(PeerState { sid: Some(sid), .. }) = demux.peers.remove(&self.peer) {
+            if demux.sessions.get(&sid) == Some(&self.peer) {
+                demux.sessions.remove(&sid);
+            }
+        }
+    }
 }
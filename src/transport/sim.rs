@@ -0,0 +1,297 @@
+//! Clock, randomness and network of a `Connection`, which can be replaced
+//! for deterministic simulation: a `SimClock` only advances when told to,
+//! all randomness is drawn from seeded generators, and a `SimNetwork`
+//! delivers datagrams in memory with latency and Markov chain packet loss.
+//! A server and its clients can then be driven from a single thread.
+
+use crate::common::batch::BatchIo;
+use crate::transport::datagram::DatagramTransport;
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+//////////////////////////
+// Env
+
+/// Source of the current time.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The real time.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Virtual time, which starts at the time of creation and only moves on by
+/// `advance()`. Clones share the same time.
+#[derive(Clone)]
+pub struct SimClock {
+    start: Instant,
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl SimClock {
+    pub fn new() -> Self {
+        SimClock {
+            start: Instant::now(),
+            elapsed: Arc::new(Mutex::new(Duration::from_secs(0))),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+
+    /// Virtual time passed since creation.
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Default for SimClock {
+    fn default() -> Self {
+        SimClock::new()
+    }
+}
+
+impl Clock for SimClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+}
+
+/// Everything a `Connection` needs from its environment besides the transport.
+pub struct Env {
+    pub clock: Arc<dyn Clock>,
+    /// Used for session ids.
+    pub rng: Box<dyn RngCore + Send + Sync>,
+}
+
+impl Env {
+    /// Environment for simulation, with randomness derived from `seed`.
+    pub fn simulated(clock: &SimClock, seed: u64) -> Self {
+        Env {
+            clock: Arc::new(clock.clone()),
+            rng: Box::new(StdRng::seed_from_u64(seed)),
+        }
+    }
+}
+
+impl Default for Env {
+    /// Real time and randomness.
+    fn default() -> Self {
+        Env {
+            clock: Arc::new(SystemClock),
+            rng: Box::new(StdRng::from_entropy()),
+        }
+    }
+}
+
+//////////////////////////
+// SimNetwork
+
+/// Markov chain model of packet loss, with the probabilities for packet n+1
+/// being lost given that packet n was not lost (p) or was lost (q).
+/// If p = q, packets are lost independently.
+pub struct LossModel {
+    pub p: f64,
+    pub q: f64,
+    last_lost: bool,
+}
+
+impl LossModel {
+    pub fn new(p: f64, q: f64) -> Self {
+        LossModel {
+            p,
+            q,
+            last_lost: false,
+        }
+    }
+
+    /// Wether the next packet is lost.
+    pub fn next_lost<R: Rng + ?Sized>(&mut self, rng: &mut R) -> bool {
+        let probability = if self.last_lost { self.q } else { self.p };
+        self.last_lost = rng.gen_bool(probability);
+        self.last_lost
+    }
+}
+
+/// Datagrams on their way to one end of the network, with their arrival time
+/// and the end which sent them.
+type Inbox = VecDeque<(Instant, usize, Vec<u8>)>;
+
+struct NetworkState {
+    rng: StdRng,
+    loss: LossModel,
+    latency: Duration,
+    /// One for each end of the network, see `SimNetwork::pair()` and `SimNetwork::connect()`.
+    inboxes: Vec<Inbox>,
+    num_sent: usize,
+    num_lost: usize,
+}
+
+/// A simulated network between two endpoints, see `SimNetwork::pair()`,
+/// and possibly more clients of the second one, see `SimNetwork::connect()`.
+/// Clones share the same network.
+#[derive(Clone)]
+pub struct SimNetwork {
+    clock: SimClock,
+    state: Arc<Mutex<NetworkState>>,
+}
+
+impl SimNetwork {
+    /// Lossless network without latency, with randomness derived from `seed`.
+    pub fn new(clock: &SimClock, seed: u64) -> Self {
+        SimNetwork {
+            clock: clock.clone(),
+            state: Arc::new(Mutex::new(NetworkState {
+                rng: StdRng::seed_from_u64(seed),
+                loss: LossModel::new(0.0, 0.0),
+                latency: Duration::from_secs(0),
+                inboxes: vec![VecDeque::new(), VecDeque::new()],
+                num_sent: 0,
+                num_lost: 0,
+            })),
+        }
+    }
+
+    pub fn set_loss(&self, loss: LossModel) {
+        self.state.lock().unwrap().loss = loss;
+    }
+
+    /// Time from sending a datagram until it can be received.
+    pub fn set_latency(&self, latency: Duration) {
+        self.state.lock().unwrap().latency = latency;
+    }
+
+    /// Number of datagrams sent in both directions, including lost ones.
+    pub fn num_sent(&self) -> usize {
+        self.state.lock().unwrap().num_sent
+    }
+
+    pub fn num_lost(&self) -> usize {
+        self.state.lock().unwrap().num_lost
+    }
+
+    /// The two endpoints of the network.
+    pub fn pair(&self) -> (SimTransport, SimTransport) {
+        (
+            SimTransport {
+                network: self.clone(),
+                side: 0,
+                peer: 1,
+            },
+            SimTransport {
+                network: self.clone(),
+                side: 1,
+                peer: 0,
+            },
+        )
+    }
+
+    /// Another endpoint, which sends to `server`, e.g. a further client
+    /// of the second endpoint of `pair()`.
+    pub fn connect(&self, server: &SimTransport) -> SimTransport {
+        let mut state = self.state.lock().unwrap();
+        state.inboxes.push(VecDeque::new());
+        SimTransport {
+            network: self.clone(),
+            side: state.inboxes.len() - 1,
+            peer: server.side,
+        }
+    }
+}
+
+/// `DatagramTransport` on one end of a `SimNetwork`.
+pub struct SimTransport {
+    network: SimNetwork,
+    side: usize,
+    /// The end which `send()` sends to.
+    peer: usize,
+}
+
+impl SimTransport {
+    /// Sends `buf` to the end `to`, unless it is lost.
+    fn send_to_side(&self, buf: &[u8], to: usize) -> io::Result<usize> {
+        let now = self.network.clock.now();
+        let mut guard = self.network.state.lock().unwrap();
+        let state = &mut *guard;
+        if to >= state.inboxes.len() {
+            return Err(io::ErrorKind::AddrNotAvailable.into());
+        }
+        state.num_sent += 1;
+        if state.loss.next_lost(&mut state.rng) {
+            state.num_lost += 1;
+        } else {
+            let arrival = now + state.latency;
+            state.inboxes[to].push_back((arrival, self.side, buf.to_vec()));
+        }
+        Ok(buf.len())
+    }
+}
+
+impl DatagramTransport for SimTransport {
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.send_to_side(buf, self.peer)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.recv_from(buf).map(|(n, _)| n)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, String)> {
+        let now = self.network.clock.now();
+        let mut state = self.network.state.lock().unwrap();
+        let inbox = &mut state.inboxes[self.side];
+        match inbox.front() {
+            Some((arrival, _, _)) if *arrival <= now => {
+                let (_, from, datagram) = inbox.pop_front().unwrap();
+                let n = datagram.len().min(buf.len());
+                buf[..n].copy_from_slice(&datagram[..n]);
+                Ok((n, format!("sim:{}", from)))
+            }
+            _ => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn send_to(&self, buf: &[u8], peer: &str) -> io::Result<usize> {
+        match peer.strip_prefix("sim:").and_then(|side| side.parse().ok()) {
+            Some(side) => self.send_to_side(buf, side),
+            None => Err(io::ErrorKind::InvalidInput.into()),
+        }
+    }
+
+    fn peer(&self) -> String {
+        format!("sim:{}", self.peer)
+    }
+
+    fn new_batch(&self) -> BatchIo {
+        BatchIo::fallback()
+    }
+}
+
+mod test {
+    #[test]
+    fn loss_is_reproducible() {
+        use super::*;
+
+        let lost = |seed| -> Vec<bool> {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut loss = LossModel::new(0.1, 0.5);
+            (0..1000).map(|_| loss.next_lost(&mut rng)).collect()
+        };
+        assert_eq!(lost(7), lost(7));
+        assert_ne!(lost(7), lost(8));
+        let n_lost = lost(7).iter().filter(|&&l| l).count();
+        // stationary loss rate p / (1 - q + p) = 1/6
+        assert!(n_lost > 100 && n_lost < 250, "{} lost", n_lost);
+    }
+}
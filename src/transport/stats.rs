@@ -0,0 +1,195 @@
+//! Transfer statistics of a `Connection` and of its objects, see
+//! `Connection::stats()`, `ObjectSendJob::stats()` and `ObjectReceiveJob::stats()`.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Counters of a `Connection` since it was created.
+///
+/// There is no congestion window: a `Connection` has no congestion control
+/// yet and sends its chunks as fast as the transport takes them, so there is
+/// no window to report.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub packets_sent: u64,
+    /// Bytes of all datagrams sent, including headers and checksums.
+    pub bytes_sent: u64,
+    /// Datagrams received, including dropped ones.
+    pub packets_received: u64,
+    pub bytes_received: u64,
+    /// Frames sent again because the peer did not answer. Only handshakes
+    /// and authentications are retransmitted so far, lost chunks are not
+    /// counted.
+    pub retransmissions: u64,
+    /// Received frames which were dropped because their checksum did not
+    /// match, or which could not be opened on an encrypted session.
+    pub checksum_failures: u64,
+    /// Received object headers and chunks which were already received.
+    pub duplicates: u64,
+    /// Chunk data sent and received, without duplicates.
+    pub data_bytes: u64,
+    /// Round trip time of the handshake, None if it was retransmitted.
+    pub rtt: Option<Duration>,
+    /// Time since the `Connection` was created.
+    pub elapsed: Duration,
+}
+
+impl Stats {
+    /// Adds the counters of `other`, e.g. to sum up connections.
+    /// `rtt` and `elapsed` are kept.
+    pub fn add(&mut self, other: &Stats) {
+        self.packets_sent += other.packets_sent;
+        self.bytes_sent += other.bytes_sent;
+        self.packets_received += other.packets_received;
+        self.bytes_received += other.bytes_received;
+        self.retransmissions += other.retransmissions;
+        self.checksum_failures += other.checksum_failures;
+        self.duplicates += other.duplicates;
+        self.data_bytes += other.data_bytes;
+    }
+
+    /// Chunk data bytes per second, None if no time has passed.
+    pub fn goodput(&self) -> Option<f64> {
+        rate(self.data_bytes, self.elapsed)
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "sent {} packets ({} bytes), received {} packets ({} bytes), {} handshake/authentication retransmissions, {} checksum failures, {} duplicates, RTT ",
+            self.packets_sent,
+            self.bytes_sent,
+            self.packets_received,
+            self.bytes_received,
+            self.retransmissions,
+            self.checksum_failures,
+            self.duplicates
+        )?;
+        match self.rtt {
+            Some(rtt) => write!(f, "{:.1} ms", rtt.as_secs_f64() * 1000.0)?,
+            None => write!(f, "unknown")?,
+        }
+        write!(
+            f,
+            ", goodput {} over {:.1} s",
+            format_rate(self.goodput()),
+            self.elapsed.as_secs_f64()
+        )
+    }
+}
+
+/// Counters of a single object which is sent or received.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ObjectStats {
+    /// Chunks sent or received, without duplicates.
+    pub chunks: u64,
+    /// Chunk data sent or received, without duplicates.
+    pub bytes: u64,
+    /// Chunks received more than once. Always 0 for sending objects.
+    pub duplicates: u64,
+    /// When the object header was sent or received.
+    pub started: Option<Instant>,
+    /// When the last new chunk was sent or received.
+    pub last_chunk: Option<Instant>,
+}
+
+impl ObjectStats {
+    /// Time from the object header to the last chunk so far.
+    pub fn duration(&self) -> Duration {
+        match (self.started, self.last_chunk) {
+            (Some(started), Some(last_chunk)) => last_chunk.saturating_duration_since(started),
+            _ => Duration::from_secs(0),
+        }
+    }
+
+    /// Chunk data bytes per second, None if no time has passed.
+    pub fn goodput(&self) -> Option<f64> {
+        rate(self.bytes, self.duration())
+    }
+
+    /// Counts a new chunk with `bytes` of data.
+    pub(super) fn chunk(&mut self, bytes: usize, now: Instant) {
+        self.chunks += 1;
+        self.bytes += bytes as u64;
+        self.last_chunk = Some(now);
+    }
+}
+
+impl fmt::Display for ObjectStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} chunks ({} bytes), {} duplicates, goodput {} over {:.1} s",
+            self.chunks,
+            self.bytes,
+            self.duplicates,
+            format_rate(self.goodput()),
+            self.duration().as_secs_f64()
+        )
+    }
+}
+
+fn rate(bytes: u64, duration: Duration) -> Option<f64> {
+    if duration.as_secs_f64() > 0.0 {
+        Some(bytes as f64 / duration.as_secs_f64())
+    } else {
+        None
+    }
+}
+
+/// Bytes per second in human readable units.
+fn format_rate(rate: Option<f64>) -> String {
+    let rate = match rate {
+        Some(rate) => rate,
+        None => return String::from("unknown"),
+    };
+    let units = ["B/s", "KiB/s", "MiB/s", "GiB/s"];
+    let mut value = rate;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < units.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, units[unit])
+}
+
+mod test {
+    #[test]
+    fn display() {
+        use super::*;
+
+        let stats = Stats {
+            packets_sent: 3,
+            bytes_sent: 300,
+            packets_received: 5,
+            bytes_received: 4000,
+            retransmissions: 1,
+            checksum_failures: 0,
+            duplicates: 2,
+            data_bytes: 3072,
+            rtt: Some(Duration::from_micros(2500)),
+            elapsed: Duration::from_secs(2),
+        };
+        assert_eq!(stats.goodput(), Some(1536.0));
+        assert_eq!(
+            stats.to_string(),
+            "sent 3 packets (300 bytes), received 5 packets (4000 bytes), \
+             1 handshake/authentication retransmissions, 0 checksum failures, 2 duplicates, \
+             RTT 2.5 ms, goodput 1.5 KiB/s over 2.0 s"
+        );
+
+        let now = Instant::now();
+        let mut object = ObjectStats {
+            started: Some(now),
+            ..ObjectStats::default()
+        };
+        assert_eq!(object.goodput(), None);
+        object.chunk(512, now + Duration::from_millis(500));
+        assert_eq!(
+            object.to_string(),
+            "1 chunks (512 bytes), 0 duplicates, goodput 1.0 KiB/s over 0.5 s"
+        );
+    }
+}
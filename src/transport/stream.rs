@@ -0,0 +1,269 @@
+use crate::transport::connection::*;
+use crate::transport::frame::*;
+use crate::transport::jobs::*;
+use bytes::Bytes;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+/// Field type used for the single field of objects sent via `BufferedObjectWriter`.
+pub const STREAM_FIELD_TYPE: ObjectFieldType = 0;
+
+//////////////////////////
+// BufferedObjectWriter
+
+/// `std::io::Write` adapter to send an object over a `Connection`.
+/// Created by `Connection::open_object()`.
+///
+/// Written bytes are split into chunks of `CHUNKSIZE` bytes, or less if the
+/// peer only receives smaller frames. As the object header, which is sent
+/// first, announces the number of chunks, all chunks are buffered in memory
+/// and the object is handed over to the `Connection` for sending once the
+/// writer is finished or dropped. To stream a large object, e.g. a file,
+/// announce its chunks in an `ObjectSendJob` and push them with a `ChunkSender`.
+pub struct BufferedObjectWriter<'a> {
+    connection: &'a mut Connection,
+    object_type: ObjectType,
+    object_id: ObjectId,
+    chunks: Vec<Vec<u8>>,
+}
+
+impl<'a> BufferedObjectWriter<'a> {
+    pub(super) fn new(
+        connection: &'a mut Connection,
+        object_type: ObjectType,
+        object_id: ObjectId,
+    ) -> Self {
+        BufferedObjectWriter {
+            connection,
+            object_type,
+            object_id,
+            chunks: Vec::new(),
+        }
+    }
+
+    pub fn object_id(&self) -> ObjectId {
+        self.object_id
+    }
+
+    /// Hand the object over to the `Connection` for sending.
+    /// Same as dropping the writer, but returns the id of the object.
+    pub fn finish(self) -> ObjectId {
+        self.object_id
+    }
+}
+
+impl<'a> io::Write for BufferedObjectWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let chunk_size = self.connection.max_chunk_size();
+        let mut written = 0;
+        while written < buf.len() {
+            match self.chunks.last_mut() {
+                Some(chunk) if chunk.len() < chunk_size => {
+                    let n = (chunk_size - chunk.len()).min(buf.len() - written);
+                    chunk.extend_from_slice(&buf[written..written + n]);
+                    written += n;
+                }
+                _ => self.chunks.push(Vec::with_capacity(chunk_size)),
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> Drop for BufferedObjectWriter<'a> {
+    fn drop(&mut self) {
+        let mut job = ObjectSendJob::new(Object {
+            object_type: self.object_type,
+            object_id: self.object_id,
+            fields: vec![ObjectFieldDescription {
+                field_type: STREAM_FIELD_TYPE,
+                length: self.chunks.len() as ChunkId,
+            }],
+        });
+        for chunk in self.chunks.drain(..) {
+            job.push_chunk(chunk, 0);
+        }
+        self.connection.send_jobs.push(job);
+    }
+}
+
+//////////////////////////
+// ObjectReader
+
+/// Chunks of a receiving object which were not read yet.
+#[derive(Default)]
+pub struct ReadBuffer {
+    chunks: BTreeMap<ChunkId, Bytes>,
+    /// Chunk to be read next.
+    next_chunk: ChunkId,
+    /// Number of bytes of `next_chunk` which were already read.
+    offset: usize,
+    num_chunks: ChunkId,
+}
+
+impl ReadBuffer {
+    pub(super) fn new(num_chunks: ChunkId) -> Self {
+        ReadBuffer {
+            num_chunks,
+            ..Default::default()
+        }
+    }
+
+    pub(super) fn push_chunk(&mut self, chunk_id: ChunkId, data: Bytes) {
+        if chunk_id >= self.next_chunk && chunk_id < self.num_chunks {
+            self.chunks.entry(chunk_id).or_insert(data);
+        }
+    }
+}
+
+/// `std::io::Read` adapter to receive an object over a `Connection`.
+/// Created by `Connection::read_object()`.
+///
+/// The reader does not borrow the `Connection`, which still has to be driven
+/// by `Connection::receive_and_send()`. As long as the next chunk was not
+/// received, `read()` fails with `io::ErrorKind::WouldBlock`. Once all
+/// chunks were read, `read()` returns 0.
+pub struct ObjectReader {
+    object: Object,
+    buffer: Arc<Mutex<ReadBuffer>>,
+}
+
+impl ObjectReader {
+    pub(super) fn new(object: Object, buffer: Arc<Mutex<ReadBuffer>>) -> Self {
+        ObjectReader { object, buffer }
+    }
+
+    /// Metadata about the object.
+    pub fn object(&self) -> &Object {
+        &self.object
+    }
+}
+
+impl io::Read for ObjectReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut buffer = self.buffer.lock().unwrap();
+        loop {
+            if buffer.next_chunk >= buffer.num_chunks {
+                return Ok(0);
+            }
+            let offset = buffer.offset;
+            let next_chunk = buffer.next_chunk;
+            let (n, exhausted) = match buffer.chunks.get(&next_chunk) {
+                Some(chunk) => {
+                    let n = (chunk.len() - offset).min(buf.len());
+                    buf[..n].copy_from_slice(&chunk[offset..offset + n]);
+                    (n, offset + n == chunk.len())
+                }
+                None => return Err(io::ErrorKind::WouldBlock.into()),
+            };
+            if exhausted {
+                buffer.chunks.remove(&next_chunk);
+                buffer.next_chunk += 1;
+                buffer.offset = 0;
+            } else {
+                buffer.offset += n;
+            }
+            // skip empty chunks, 0 tells the end of the object
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+        }
+    }
+}
+
+mod test {
+    #[test]
+    fn read_write() {
+        use crate::transport::client;
+        use crate::transport::config::Config;
+        use crate::transport::connection::*;
+        use crate::transport::server;
+        use crate::transport::sim::{Env, SimClock, SimNetwork};
+        use std::io::{ErrorKind, Read, Write};
+        use std::time::Duration;
+
+        let clock = SimClock::new();
+        let network = SimNetwork::new(&clock, 1);
+        network.set_latency(Duration::from_millis(5));
+        let (client, server) = network.pair();
+        let mut connection_listener = server::Listener::with_transport(
+            Box::new(server),
+            Env::simulated(&clock, 2),
+            Config::default(),
+        );
+        let mut client_conn = client::connect_transport(
+            Box::new(client),
+            Env::simulated(&clock, 3),
+            Config::default(),
+        );
+        let mut server_conn = None;
+        while !client_conn.is_established() {
+            clock.advance(Duration::from_millis(1));
+            if server_conn.is_none() {
+                server_conn = connection_listener.listen_once();
+            }
+            if let Some(server_conn) = &mut server_conn {
+                server_conn.receive_and_send();
+            }
+            client_conn.receive_and_send();
+        }
+        let mut server_conn = server_conn.unwrap();
+
+        let sent: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+        let mut writer = client_conn.open_object(42);
+        writer.write_all(&sent).unwrap();
+        let object_id = writer.finish();
+
+        let mut reader = None;
+        let mut received = Vec::new();
+        for _ in 0..100 {
+            clock.advance(Duration::from_millis(1));
+            client_conn.receive_and_send();
+            server_conn.receive_and_send();
+            while let Some(event) = server_conn.poll_event() {
+                if let Event::NewObject(object) = event {
+                    assert_eq!(object.object_id, object_id);
+                    reader = server_conn.read_object(object.object_id);
+                }
+            }
+            if let Some(reader) = &mut reader {
+                let mut buf = [0; 100];
+                match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => received.extend_from_slice(&buf[..n]),
+                    Err(e) => assert_eq!(e.kind(), ErrorKind::WouldBlock),
+                }
+            }
+        }
+        assert_eq!(received, sent);
+    }
+
+    #[test]
+    fn read_empty_chunks() {
+        use super::*;
+        use std::io::Read;
+
+        let object = Object {
+            object_type: 42,
+            object_id: 1,
+            fields: vec![ObjectFieldDescription {
+                field_type: STREAM_FIELD_TYPE,
+                length: 4,
+            }],
+        };
+        let mut buffer = ReadBuffer::new(4);
+        buffer.push_chunk(0, Bytes::from_static(b"ab"));
+        buffer.push_chunk(1, Bytes::new());
+        buffer.push_chunk(2, Bytes::new());
+        buffer.push_chunk(3, Bytes::from_static(b"c"));
+        let mut reader = ObjectReader::new(object, Arc::new(Mutex::new(buffer)));
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"abc");
+    }
+}
@@ -1,8 +1,6 @@
-use crate::common::{Cursor, ReadResult, WireFormat};
+use crate::common::{Cursor, WireFormat};
 use crate::transport::frame::*;
 use itertools::Itertools;
-use std::fs::File;
-use std::io::{Seek, SeekFrom};
 
 #[test]
 fn test_serialize_message_frame() {
@@ -10,6 +8,8 @@ fn test_serialize_message_frame() {
     let message_frame = MessageFrame {
         version: 1,
         sid: 123,
+        checksum: ChecksumAlgorithm::Fnv1a32,
+        skipped_tlvs: Vec::new(),
         tlvs: vec![
             Tlv::ObjectHeader(ObjectHeader {
                 object_id: 1,
@@ -36,18 +36,14 @@ fn test_serialize_message_frame() {
 
     // Serialize it!
     let mut buffer = Vec::new();
-    let mut cursor = Cursor::new(buffer);
-    message_frame.write(&mut cursor);
-    println!(
-        "Encoded message frame: {:02x}",
-        cursor.get_ref().iter().format(" ")
-    );
+    message_frame.write(&mut buffer);
+    println!("Encoded message frame: {:02x}", buffer.iter().format(" "));
 
     // Deserialize it!
-    cursor.seek(SeekFrom::Start(0)).unwrap();
+    let mut cursor = Cursor::new(&buffer[..]);
     let mut parsed_message_frame = MessageFrame::default();
     match parsed_message_frame.read(&mut cursor) {
-        ReadResult::Err(x) => println!("Error: {}", &x.to_string()),
+        Err(x) => println!("Error: {}", &x.to_string()),
         _ => {}
     }
 
@@ -74,3 +70,387 @@ fn test_serialize_message_frame() {
         }
     }
 }
+
+#[test]
+fn test_serialize_object_chunk() {
+    let data: Vec<u8> = (0..100).collect();
+    let message_frame = MessageFrame {
+        version: 2,
+        sid: 42,
+        checksum: ChecksumAlgorithm::Fnv1a32,
+        skipped_tlvs: Vec::new(),
+        tlvs: vec![Tlv::ObjectChunk(ObjectChunk {
+            object_id: 7,
+            chunk_id: 3,
+            more_chunks: true,
+            ack_required: false,
+            num_enclosed_msgs: 1,
+            data: &data,
+        })],
+    };
+
+    let mut buffer = Vec::new();
+    message_frame.write(&mut buffer);
+
+    let mut cursor = Cursor::new(&buffer[..]);
+    let mut parsed_message_frame = MessageFrame::default();
+    match parsed_message_frame.read(&mut cursor) {
+        Err(x) => panic!("Error: {}", &x.to_string()),
+        _ => {}
+    }
+    assert_eq!(message_frame, parsed_message_frame);
+
+    // chunk data is borrowed from the buffer, not copied
+    match &parsed_message_frame.tlvs[0] {
+        Tlv::ObjectChunk(chunk) => {
+            let buffer_range = buffer.as_ptr_range();
+            assert!(buffer_range.contains(&chunk.data.as_ptr()));
+        }
+        _ => panic!("Expected ObjectChunk"),
+    }
+}
+
+#[test]
+fn test_checksum_algorithms() {
+    let frame = |checksum| MessageFrame {
+        version: 2,
+        sid: 42,
+        checksum,
+        skipped_tlvs: Vec::new(),
+        tlvs: vec![Tlv::ObjectChunk(ObjectChunk {
+            object_id: 7,
+            chunk_id: 3,
+            more_chunks: false,
+            ack_required: false,
+            num_enclosed_msgs: 0,
+            data: b"some data",
+        })],
+    };
+    let algorithms = [
+        ChecksumAlgorithm::Fnv1a32,
+        ChecksumAlgorithm::Crc32c,
+        ChecksumAlgorithm::XxHash32,
+    ];
+    for &written in &algorithms {
+        let mut buffer = Vec::new();
+        frame(written).write(&mut buffer);
+        for &expected in &algorithms {
+            let mut parsed = MessageFrame::default();
+            parsed.checksum = expected;
+            let ok = match parsed.read(&mut Cursor::new(&buffer[..])) {
+                Ok(()) => true,
+                Err(_) => false,
+            };
+            assert_eq!(ok, written == expected);
+        }
+    }
+
+    // CRC32C test vector
+    assert_eq!(ChecksumAlgorithm::Crc32c.hash(b"123456789"), 0xe306_9283);
+}
+
+#[test]
+fn test_negotiate_checksum() {
+    use ChecksumAlgorithm::*;
+    assert_eq!(
+        ChecksumAlgorithm::negotiate(&[], &[Crc32c, Fnv1a32]),
+        Fnv1a32
+    );
+    assert_eq!(
+        ChecksumAlgorithm::negotiate(&[Crc32c, XxHash32], &[]),
+        Fnv1a32
+    );
+    assert_eq!(
+        ChecksumAlgorithm::negotiate(&[XxHash32, Crc32c], &[Crc32c, XxHash32]),
+        XxHash32
+    );
+    assert_eq!(
+        ChecksumAlgorithm::negotiate(&[Crc32c], &[XxHash32]),
+        Fnv1a32
+    );
+}
+
+#[test]
+fn test_host_information_capabilities() {
+    // without capabilities, from an unknown application
+    let buffer = [0xd0, 0x06, 50, 50, 1, 0x7f, 1, 0];
+    let mut host_info = HostInformation::default();
+    match host_info.read(&mut Cursor::new(&buffer[..])) {
+        Ok(()) => {}
+        Err(e) => panic!("{}", e),
+    }
+    assert_eq!(host_info.rcv_window_size, 50);
+    assert_eq!(host_info.app, None);
+    assert_eq!(host_info.capabilities, Capabilities::default());
+
+    host_info.app = Some(ApplicationId::SOFT);
+    host_info.capabilities = Capabilities {
+        max_frame_size: Some(1200),
+        checksums: vec![ChecksumAlgorithm::Crc32c],
+        compression: vec![CompressionAlgorithm::Zstd],
+        encryption: Some(Encryption {
+            aeads: vec![crate::transport::crypto::AeadAlgorithm::Aes256Gcm],
+            ephemeral_key: Some([1; 32]),
+            static_key: None,
+            psk: true,
+        }),
+        fec: vec![FecScheme::ReedSolomon],
+        ack_freq: Some(AckFreq::Min),
+    };
+    let mut written = Vec::new();
+    host_info.write(&mut written);
+    let mut parsed = HostInformation::default();
+    match parsed.read(&mut Cursor::new(&written[..])) {
+        Ok(()) => {}
+        Err(e) => panic!("{}", e),
+    }
+    assert_eq!(parsed, host_info);
+
+    // unknown capabilities, unknown algorithms and trailing bytes are skipped
+    let buffer = [
+        0xd0, 0x13, 50, 50, 1, 1, 1, 3, // 3 capabilities
+        0x7f, 2, 0xaa, 0xbb, // unknown
+        2, 2, 0x7f, 1, // checksums
+        1, 3, 0x04, 0xb0, 0xcc, // max frame size
+    ];
+    let mut parsed = HostInformation::default();
+    match parsed.read(&mut Cursor::new(&buffer[..])) {
+        Ok(()) => {}
+        Err(e) => panic!("{}", e),
+    }
+    assert_eq!(
+        parsed.capabilities,
+        Capabilities {
+            max_frame_size: Some(1200),
+            checksums: vec![ChecksumAlgorithm::Crc32c],
+            ..Capabilities::default()
+        }
+    );
+}
+
+#[test]
+fn test_skip_unknown_tlvs() {
+    let frame = |unknown_type: u8| {
+        let mut buffer = vec![1, 0, 0, 0, 0, 0, 0, 0, 7, 2];
+        buffer.extend_from_slice(&[unknown_type, 0x02, 0xaa, 0xbb]);
+        RetryCookie {
+            cookie: vec![1, 2, 3],
+        }
+        .write(&mut buffer);
+        let checksum = ChecksumAlgorithm::Fnv1a32.hash(&buffer);
+        buffer.extend_from_slice(&checksum.to_be_bytes());
+        buffer
+    };
+
+    // an unknown TLV is skipped, the following ones are read
+    let buffer = frame(0x60);
+    let mut parsed = MessageFrame::default();
+    match parsed.read(&mut Cursor::new(&buffer[..])) {
+        Ok(()) => {}
+        Err(e) => panic!("{}", e),
+    }
+    assert_eq!(parsed.sid, 7);
+    assert_eq!(parsed.skipped_tlvs, vec![0x60]);
+    assert_eq!(
+        parsed.tlvs,
+        vec![Tlv::RetryCookie(RetryCookie {
+            cookie: vec![1, 2, 3]
+        })]
+    );
+
+    // an unknown critical TLV rejects the frame
+    let buffer = frame(0x60 | crate::common::TLV_CRITICAL_BIT);
+    let mut parsed = MessageFrame::default();
+    assert!(matches!(parsed.read(&mut Cursor::new(&buffer[..])), Err(_)));
+}
+
+#[test]
+fn test_critical_tlvs() {
+    use crate::common::TLV_CRITICAL_BIT;
+
+    let type_code = |tlv: Tlv| {
+        let mut buffer = Vec::new();
+        tlv.write(&mut buffer);
+        buffer[0]
+    };
+    // must be understood
+    assert_eq!(
+        type_code(Tlv::HostInformation(HostInformation::default())),
+        0x50 | TLV_CRITICAL_BIT
+    );
+    assert_eq!(
+        type_code(Tlv::ObjectChunk(ObjectChunk::default())),
+        0x52 | TLV_CRITICAL_BIT
+    );
+    assert_eq!(
+        type_code(Tlv::Authentication(Authentication::default())),
+        0x54 | TLV_CRITICAL_BIT
+    );
+    assert_eq!(
+        type_code(Tlv::RetryCookie(RetryCookie::default())),
+        0x55 | TLV_CRITICAL_BIT
+    );
+    // may be skipped
+    assert_eq!(type_code(Tlv::ObjectSkip(ObjectSkip::default())), 0x53);
+    assert_eq!(type_code(Tlv::ObjectAck(ObjectAck::default())), 0x30);
+
+    // a peer which does not know a critical TLV rejects the frame
+    let mut buffer = Vec::new();
+    Tlv::Authentication(Authentication::default()).write(&mut buffer);
+    assert!(crate::common::skip_unknown_tlv(&mut Cursor::new(&buffer[..])).is_err());
+}
+
+#[test]
+fn test_large_counts_and_lengths() {
+    // more than 255 tlvs and a chunk of more than 64 KiB
+    let data = vec![0x5a; 70_000];
+    let mut tlvs: Vec<Tlv> = (0..300)
+        .map(|object_id| {
+            Tlv::ObjectHeader(ObjectHeader {
+                object_id,
+                num_chunks: 1,
+                ..ObjectHeader::default()
+            })
+        })
+        .collect();
+    tlvs.push(Tlv::ObjectChunk(ObjectChunk {
+        object_id: 1,
+        data: &data,
+        ..ObjectChunk::default()
+    }));
+    let message_frame = MessageFrame {
+        version: 3,
+        sid: 42,
+        checksum: ChecksumAlgorithm::Crc32c,
+        skipped_tlvs: Vec::new(),
+        tlvs,
+    };
+
+    let mut buffer = Vec::new();
+    message_frame.write(&mut buffer);
+    let mut parsed_message_frame = MessageFrame {
+        checksum: ChecksumAlgorithm::Crc32c,
+        ..MessageFrame::default()
+    };
+    match parsed_message_frame.read(&mut Cursor::new(&buffer[..])) {
+        Ok(()) => {}
+        Err(e) => panic!("{}", e),
+    }
+    assert_eq!(message_frame, parsed_message_frame);
+
+    // the limit is checked when writing
+    let mut limited = vec![1, 2, 3];
+    assert!(message_frame.write_limited(&mut limited, 9000).is_err());
+    assert_eq!(limited, vec![1, 2, 3]);
+    assert!(message_frame
+        .write_limited(&mut limited, buffer.len())
+        .is_ok());
+    assert_eq!(&limited[3..], &buffer[..]);
+}
+
+/// Inputs of the fuzz target `target`, which are replayed by the tests.
+#[allow(dead_code)]
+pub fn fuzz_corpus(target: &str) -> Vec<Vec<u8>> {
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("fuzz/corpus")
+        .join(target);
+    let mut corpus: Vec<Vec<u8>> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| std::fs::read(entry.unwrap().path()).unwrap())
+        .collect();
+    corpus.sort();
+    corpus
+}
+
+#[test]
+fn test_fuzz_corpus_message_frame() {
+    // like fuzz/fuzz_targets/message_frame.rs
+    let corpus = fuzz_corpus("message_frame");
+    assert!(!corpus.is_empty());
+    let mut num_ok = 0;
+    for data in &corpus {
+        for &checksum in &[
+            ChecksumAlgorithm::Fnv1a32,
+            ChecksumAlgorithm::Crc32c,
+            ChecksumAlgorithm::XxHash32,
+        ] {
+            let mut frame = MessageFrame {
+                checksum,
+                ..MessageFrame::default()
+            };
+            if let Ok(()) = frame.read(&mut Cursor::new(&data[..])) {
+                num_ok += 1;
+            }
+        }
+        // every prefix is rejected, but does not panic
+        for len in 0..data.len() {
+            let mut frame = MessageFrame::default();
+            assert!(matches!(frame.read(&mut Cursor::new(&data[..len])), Err(_)));
+        }
+    }
+    assert!(num_ok > 0);
+}
+
+#[test]
+fn test_derived_wire_format() {
+    // each message is written with its own type code
+    let tlvs = vec![
+        Tlv::ObjectAck(ObjectAck {
+            acknowledged_object_chunks: vec![(1, -1), (2, 300)],
+        }),
+        Tlv::ErrorMessage(ErrorMessage {
+            code: ErrorCode::ObjectAbort,
+            detail: ErrorData::ObjectAbort(vec![3, 4]),
+        }),
+        Tlv::ObjectAckRequest(ObjectAckRequest {
+            req_ack_object_chunks: vec![(5, 6)],
+        }),
+    ];
+    for (tlv, type_code) in tlvs.iter().zip(&[0x30, 0x31, 0x32]) {
+        let mut buffer = Vec::new();
+        tlv.write(&mut buffer);
+        assert_eq!(buffer[0], *type_code);
+    }
+
+    let message_frame = MessageFrame {
+        version: 3,
+        sid: 42,
+        checksum: ChecksumAlgorithm::Fnv1a32,
+        skipped_tlvs: Vec::new(),
+        tlvs,
+    };
+    let mut buffer = Vec::new();
+    message_frame.write(&mut buffer);
+    let mut parsed_message_frame = MessageFrame::default();
+    match parsed_message_frame.read(&mut Cursor::new(&buffer[..])) {
+        Ok(()) => {}
+        Err(e) => panic!("{}", e),
+    }
+    assert_eq!(message_frame, parsed_message_frame);
+
+    // unknown enum codes are rejected
+    let mut method = AuthMethod::default();
+    assert!(matches!(method.read(&mut Cursor::new(&[0x7f][..])), Err(_)));
+    // unless they have a default
+    let mut os = HostOs::MacOS;
+    assert!(matches!(os.read(&mut Cursor::new(&[0x7f][..])), Ok(())));
+    assert_eq!(os, HostOs::Linux);
+
+    // flags are read with their bitmask, so reserved bits may be set
+    let header = ObjectHeader {
+        ack_req: true,
+        ..ObjectHeader::default()
+    };
+    let (mut with_flag, mut without_flag) = (Vec::new(), Vec::new());
+    header.write(&mut with_flag);
+    ObjectHeader::default().write(&mut without_flag);
+    let flags = (0..with_flag.len())
+        .find(|&i| with_flag[i] != without_flag[i])
+        .unwrap();
+    with_flag[flags] |= 0x01;
+    let mut parsed_header = ObjectHeader::default();
+    parsed_header
+        .read(&mut Cursor::new(&with_flag[..]))
+        .unwrap();
+    assert_eq!(parsed_header, header);
+}
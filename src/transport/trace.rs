@@ -0,0 +1,190 @@
+//! Structured event trace of a `Connection`, in the spirit of qlog: one JSON
+//! object per line, for plotting the timeline of a session offline, e.g.
+//!
+//! ```text
+//! {"time":0.2,"vantage":"client","peer":"127.0.0.1:1234","name":"transport:packet_sent","data":{...}}
+//! ```
+//!
+//! `time` is in milliseconds since the `Connection` was created. Events are
+//! - `connectivity:connection_started` with the initial `state`,
+//! - `connectivity:connection_state_updated` with the `old` and `new` state,
+//!   which is one of `connecting`, `accepted`, `established`, `failed` or `closed`,
+//! - `connectivity:connection_timeout`,
+//! - `transport:packet_sent` and `transport:packet_received` with the datagram
+//!   `length`, the session id and a summary of the TLVs, including acks,
+//! - `transport:packet_dropped` with the `reason`,
+//! - `recovery:handshake_retransmitted` and `recovery:authentication_retransmitted`
+//!   with the current `backoff` in milliseconds,
+//! - `recovery:rtt_updated` with the measured `rtt` in milliseconds, once the
+//!   handshake is answered.
+
+use crate::common::json::Json;
+use crate::transport::frame::*;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Destination of traces, see `Config::trace`. Clones write to the same
+/// destination, so all connections of a server may share one file, telling
+/// their lines apart by `peer`.
+#[derive(Clone)]
+pub struct Tracer {
+    out: Arc<Mutex<dyn Write + Send>>,
+}
+
+impl Tracer {
+    pub fn new<W: Write + Send + 'static>(out: W) -> Self {
+        Tracer {
+            out: Arc::new(Mutex::new(out)),
+        }
+    }
+
+    /// Traces into a new file at `path`. Lines are written unbuffered, so
+    /// nothing is lost if the process exits.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Tracer::new(File::create(path)?))
+    }
+
+    fn write_line(&self, event: &Json) {
+        let line = format!("{}\n", event);
+        if let Err(e) = self.out.lock().unwrap().write_all(line.as_bytes()) {
+            log::warn!("Failed to write trace: {}", e);
+        }
+    }
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Tracer")
+    }
+}
+
+/// Trace of a single `Connection`.
+pub(super) struct Trace {
+    tracer: Tracer,
+    started: Instant,
+    vantage: &'static str,
+    peer: String,
+    /// Last traced state of the connection.
+    pub(super) state: &'static str,
+    /// Summaries of the frames in the batch which is sent next.
+    queued_frames: Vec<Json>,
+}
+
+impl Trace {
+    pub(super) fn new(
+        tracer: Tracer,
+        now: Instant,
+        is_server: bool,
+        peer: String,
+        state: &'static str,
+    ) -> Self {
+        let mut trace = Trace {
+            tracer,
+            started: now,
+            vantage: if is_server { "server" } else { "client" },
+            peer,
+            state,
+            queued_frames: Vec::new(),
+        };
+        trace.event(
+            now,
+            "connectivity:connection_started",
+            Json::object(vec![("state", state.into())]),
+        );
+        trace
+    }
+
+    pub(super) fn event(&mut self, now: Instant, name: &'static str, data: Json) {
+        let time = now.duration_since(self.started).as_secs_f64() * 1000.0;
+        self.tracer.write_line(&Json::object(vec![
+            ("time", time.into()),
+            ("vantage", self.vantage.into()),
+            ("peer", self.peer.as_str().into()),
+            ("name", name.into()),
+            ("data", data),
+        ]));
+    }
+
+    /// Remembers `frame` as the next datagram of the batch, which is traced
+    /// by `frames_sent()` once it was sent.
+    pub(super) fn queue_frame(&mut self, frame: &MessageFrame) {
+        self.queued_frames.push(frame_summary(frame));
+    }
+
+    /// Traces the first of the queued frames, which were sent as datagrams of
+    /// the given lengths, and forgets all of them.
+    pub(super) fn frames_sent(&mut self, now: Instant, lengths: &[usize]) {
+        let frames: Vec<Json> = self.queued_frames.drain(..).collect();
+        for (mut frame, length) in frames.into_iter().zip(lengths) {
+            frame.push("length", (*length).into());
+            self.event(now, "transport:packet_sent", frame);
+        }
+    }
+
+    pub(super) fn frame_received(&mut self, now: Instant, frame: &MessageFrame, length: usize) {
+        let mut data = frame_summary(frame);
+        data.push("length", length.into());
+        self.event(now, "transport:packet_received", data);
+    }
+}
+
+fn frame_summary(frame: &MessageFrame) -> Json {
+    Json::object(vec![
+        ("sid", frame.sid.into()),
+        (
+            "tlvs",
+            Json::Array(frame.tlvs.iter().map(tlv_summary).collect()),
+        ),
+    ])
+}
+
+fn object_chunks(ids: &[(ObjectId, ChunkId)]) -> Json {
+    Json::Array(
+        ids.iter()
+            .map(|(object_id, chunk_id)| vec![Json::from(*object_id), Json::from(*chunk_id)].into())
+            .collect(),
+    )
+}
+
+fn tlv_summary(tlv: &Tlv) -> Json {
+    match tlv {
+        Tlv::HostInformation(_) => Json::object(vec![("type", "HostInformation".into())]),
+        Tlv::ObjectHeader(header) => Json::object(vec![
+            ("type", "ObjectHeader".into()),
+            ("object_id", header.object_id.into()),
+            ("num_chunks", header.num_chunks.into()),
+        ]),
+        Tlv::ObjectChunk(chunk) => Json::object(vec![
+            ("type", "ObjectChunk".into()),
+            ("object_id", chunk.object_id.into()),
+            ("chunk_id", chunk.chunk_id.into()),
+            ("data_length", chunk.data.len().into()),
+        ]),
+        Tlv::ObjectSkip(_) => Json::object(vec![("type", "ObjectSkip".into())]),
+        Tlv::ObjectAck(ack) => Json::object(vec![
+            ("type", "ObjectAck".into()),
+            ("acks", object_chunks(&ack.acknowledged_object_chunks)),
+        ]),
+        Tlv::ObjectAckRequest(request) => Json::object(vec![
+            ("type", "ObjectAckRequest".into()),
+            ("requests", object_chunks(&request.req_ack_object_chunks)),
+        ]),
+        Tlv::ErrorMessage(err) => Json::object(vec![
+            ("type", "ErrorMessage".into()),
+            ("code", format!("{:?}", err.code).into()),
+        ]),
+        Tlv::Authentication(auth) => Json::object(vec![
+            ("type", "Authentication".into()),
+            ("user", auth.user.as_str().into()),
+        ]),
+        Tlv::RetryCookie(_) => Json::object(vec![("type", "RetryCookie".into())]),
+        Tlv::AuthenticationAck(ack) => Json::object(vec![
+            ("type", "AuthenticationAck".into()),
+            ("user", ack.user.as_str().into()),
+        ]),
+    }
+}